@@ -704,6 +704,40 @@ static CLIENT_CONFIG: fn() -> Communicator = communicator! {
             kannader_types::TlsHandler::Rustls
         }
 
+        // Number of TLS sessions kept for resumption, 0 disabling resumption
+        fn tls_session_cache_size(&self) -> (u64) {
+            256
+        }
+
+        // For how long to skip STARTTLS with a server after negotiating TLS
+        // with it failed, 0 making the client try it every time
+        fn broken_starttls_ttl_in_millis(&self) -> (i64) {
+            // 1 hour in ms
+            60 * 60 * 1000
+        }
+
+        fn use_ipv6(&self) -> (bool) {
+            true
+        }
+
+        fn connection_attempt_delay_in_millis(&self) -> (i64) {
+            250
+        }
+
+        fn max_mx_attempts(&self) -> (u64) {
+            10
+        }
+
+        fn accept_lf_only_replies(&self) -> (bool) {
+            false
+        }
+
+        // Fold the header lines longer than 998 octets at their whitespace
+        // before sending the mails
+        fn fold_long_headers(&self) -> (bool) {
+            false
+        }
+
         fn banner_read_timeout_in_millis(&self) -> (i64) {
             // 5 minutes in ms
             5 * 60 * 1000
@@ -748,6 +782,55 @@ static CLIENT_CONFIG: fn() -> Communicator = communicator! {
             // 10 minutes in ms
             10 * 60 * 1000
         }
+
+        fn rset_reply_timeout_in_millis(&self) -> (i64) {
+            // 5 minutes in ms
+            5 * 60 * 1000
+        }
+
+        fn overall_delivery_timeout_in_millis(&self) -> (i64) {
+            // 30 minutes in ms
+            30 * 60 * 1000
+        }
+
+        // Port to connect to when the next hop does not give one, eg. 587 on
+        // networks that block outbound connections to port 25
+        fn smtp_port(&self) -> (u16) {
+            25
+        }
+
+        // `None` sends the mail for `domain` to its MXs, `Some((host, port))`
+        // relays it to `host` instead
+        fn next_hop(
+            &self,
+            domain: () smtp_message::Hostname,
+        ) -> (Option<(smtp_message::Hostname, u16)>)
+        {
+            None
+        }
+
+        // Domain to send the mail to for recipients without one, eg.
+        // `<postmaster>`, as if it were theirs. `None` makes the deliveries to
+        // such recipients fail permanently.
+        fn domainless_recipient_domain(&self) -> (Option<smtp_message::Hostname>) {
+            None
+        }
+
+        // Local address to connect from when connecting to `ip`, `None`
+        // letting the operating system pick it
+        fn source_ip(&self, ip: () std::net::IpAddr) -> (Option<std::net::IpAddr>) {
+            None
+        }
+
+        // Name to announce in EHLO when connecting from `source_ip`, `None`
+        // meaning `ehlo_hostname`
+        fn ehlo_hostname_for(
+            &self,
+            source_ip: () std::net::IpAddr,
+        ) -> (Option<smtp_message::Hostname>)
+        {
+            None
+        }
     }
 };
 
@@ -812,7 +895,18 @@ static QUEUE_CONFIG: fn() -> Communicator = communicator! {
             );
         }
 
-        fn found_inflight_check_delay(&self) -> (std::time::Duration) {
+        fn log_delivery_latency(
+            &self,
+            id: () smtp_queue_types::QueueId,
+            latency: () std::time::Duration,
+        ) -> (()) {
+            info!({ queue_id: ?id, latency: ?latency }, "Delivered mail");
+        }
+
+        fn found_inflight_check_delay(
+            &self,
+            schedule: () smtp_queue_types::ScheduleInfo,
+        ) -> (std::time::Duration) {
             std::time::Duration::from_secs(3600)
         }
 
@@ -823,6 +917,30 @@ static QUEUE_CONFIG: fn() -> Communicator = communicator! {
                 d.mul_f64(2.0)
             }
         }
+
+        fn scheduler_tick_interval(&self) -> (Option<std::time::Duration>) {
+            None
+        }
+
+        fn max_rate_per_destination(
+            &self,
+            domain: () smtp_message::Hostname,
+        ) -> (Option<(u32, std::time::Duration)>)
+        {
+            None
+        }
+
+        fn serialize_per_recipient(&self) -> (bool) {
+            false
+        }
+
+        fn max_concurrent_sends(&self) -> (u64) {
+            100
+        }
+
+        fn recover_in_background(&self) -> (bool) {
+            false
+        }
     }
 };
 
@@ -836,6 +954,11 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         fn tls_cert_file(&self) -> (std::path::PathBuf) ;
         fn tls_key_file(&self) -> (std::path::PathBuf) ;
 
+        // Number of TLS sessions kept for resumption, 0 disabling resumption
+        fn tls_session_cache_size(&self) -> (u64) {
+            256
+        }
+
         fn welcome_banner_reply(
             &self,
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
@@ -848,6 +971,19 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
         ) -> (smtp_server_types::SerializableDecision<smtp_server_types::HelloInfo>) ;
 
+        // `reverse_dns` holds the names of the PTR records of `ip` that
+        // resolve back to `ip`, or is `None` if the DNS lookups failed
+        fn verify_hello(
+            &self,
+            ip: () std::net::IpAddr,
+            hostname: () smtp_message::Hostname,
+            reverse_dns: () Option<Vec<smtp_message::Hostname>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_server_types::HelloVerification)
+        {
+            smtp_server_types::HelloVerification::Pass
+        }
+
         fn can_do_tls(
             &self,
             conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
@@ -876,6 +1012,65 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
         ) -> (smtp_server_types::SerializableDecision<smtp_message::Email>) ;
 
+        fn always_accept_postmaster(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            true
+        }
+
+        // The local hostname is not known here, so only `<postmaster>` is
+        // recognized by default
+        fn is_postmaster(
+            &self,
+            email: () smtp_message::Email,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            email.hostname.is_none()
+                && email.localpart.unquote().as_str().eq_ignore_ascii_case("postmaster")
+        }
+
+        fn canonicalize_recipients(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            false
+        }
+
+        // Whether to refuse the recipients whose domain has no MX nor
+        // address records, or a null MX
+        fn verify_recipient_routability(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            false
+        }
+
+        fn max_rejected_rcpts(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (u64)
+        {
+            0
+        }
+
+        fn max_connections_per_ip(&self) -> (u64)
+        {
+            0
+        }
+
+        fn max_pipelined_commands(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (u64)
+        {
+            10_000
+        }
+
         fn filter_data(
             &self,
             meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
@@ -888,6 +1083,125 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             }
         }
 
+        fn is_trusted_frontend(&self, peer_addr: () std::net::IpAddr) -> (bool)
+        {
+            false
+        }
+
+        fn xclient_allowed(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            conn_meta.xclient.is_none()
+        }
+
+        fn require_helo_before_mail(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            true
+        }
+
+        fn require_tls_for_data(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            false
+        }
+
+        fn accept_bare_lf_data_end(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (bool)
+        {
+            false
+        }
+
+        fn max_headers_size(
+            &self,
+            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (u64)
+        {
+            0
+        }
+
+        fn filter_headers(
+            &self,
+            headers: () Vec<u8>,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_server_types::SerializableDecision<()>)
+        {
+            smtp_server_types::SerializableDecision::Accept {
+                reply: smtp_server_types::reply::okay_data().convert(),
+                res: (),
+            }
+        }
+
+        // Priority with which the queue sends the mail, higher-priority mails
+        // being sent first among the ones that are due. `filter_headers` can
+        // record the `X-Priority` header in `meta` for this hook to use it.
+        fn mail_priority(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (u8)
+        {
+            0
+        }
+
+        // Metadata stored with the mail in the queue, that is then available
+        // when delivering or bouncing it
+        fn queue_metadata(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (kannader_types::Meta)
+        {
+            kannader_types::Meta::default()
+        }
+
+        // Header fields to prepend to the mail before it gets enqueued, eg.
+        // `Authentication-Results` with the SPF result in `meta.spf`, below
+        // the `Received-SPF` one that is always added for it. Each one
+        // includes its final CRLF, and the ones that are already in the mail
+        // are not added again.
+        fn mail_headers(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (Vec<String>)
+        {
+            Vec::new()
+        }
+
+        // Called on each chunk of the mail contents while they are received,
+        // eg. to scan them for viruses. The returned bytes, which must stay
+        // dot-escaped, replace the chunk in the mail that gets enqueued.
+        fn filter_content_chunk(
+            &self,
+            chunk: () Vec<u8>,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (Vec<u8>)
+        {
+            chunk
+        }
+
+        // Called once all the chunks went through `filter_content_chunk`.
+        // Returning a reply rejects the mail with it, without enqueuing it.
+        fn filter_content_end(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (Option<smtp_message::Reply>)
+        {
+            None
+        }
+
         fn handle_rset(
             &self,
             meta: (&mut) Option<smtp_server_types::MailMetadata<Vec<u8>>>,
@@ -975,6 +1289,18 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             }
         }
 
+        // Called on the lines that are not a known command, without their
+        // trailing CRLF. Returning None answers them with
+        // `command_unrecognized`.
+        fn unknown_command(
+            &self,
+            line: () Vec<u8>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (Option<smtp_server_types::SerializableDecision<()>>)
+        {
+            None
+        }
+
         fn already_did_hello(
             &self,
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
@@ -983,12 +1309,28 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             smtp_server_types::reply::bad_sequence().convert()
         }
 
+        fn hello_softfail(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::hello_softfail().convert()
+        }
+
+        fn hello_rejected(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::hello_rejected().convert()
+        }
+
         fn mail_before_hello(
             &self,
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
         ) -> (smtp_message::Reply)
         {
-            smtp_server_types::reply::bad_sequence().convert()
+            smtp_server_types::reply::hello_required().convert()
         }
 
         fn already_in_mail(
@@ -1007,6 +1349,22 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             smtp_server_types::reply::bad_sequence().convert()
         }
 
+        fn too_many_rejected_rcpts(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::too_many_rejected_rcpts().convert()
+        }
+
+        fn too_many_pipelined_commands(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::too_many_pipelined_commands().convert()
+        }
+
         fn data_before_rcpt(
             &self,
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
@@ -1031,6 +1389,22 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             smtp_server_types::reply::command_not_supported().convert()
         }
 
+        fn xclient_forbidden(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::xclient_forbidden().convert()
+        }
+
+        fn xclient_invalid(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::xclient_invalid().convert()
+        }
+
         fn command_unrecognized(
             &self,
             conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
@@ -1063,6 +1437,14 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             smtp_server_types::reply::handle_mail_did_not_call_complete().convert()
         }
 
+        fn session_too_long(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::session_too_long().convert()
+        }
+
         fn reply_write_timeout_in_millis(&self) -> (i64)
         {
             // 5 minutes in milliseconds
@@ -1074,6 +1456,18 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             // 5 minutes in milliseconds
             5 * 60 * 1000
         }
+
+        fn data_read_timeout_in_millis(&self) -> (i64)
+        {
+            // 3 minutes in milliseconds
+            3 * 60 * 1000
+        }
+
+        fn max_session_duration_in_millis(&self) -> (i64)
+        {
+            // 1 hour in milliseconds
+            60 * 60 * 1000
+        }
     }
 };
 
@@ -9,3 +9,18 @@ pub enum TlsHandler {
 pub enum QueueStorage {
     Fs(PathBuf),
 }
+
+/// Metadata that the configuration attaches to each mail when enqueuing it,
+/// which is stored alongside it in the queue until it is delivered or bounced
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Meta {
+    /// Identity the client authenticated as, eg. its TLS certificate's
+    pub auth_identity: Option<String>,
+
+    /// Score given to the mail by the spam filter, if any
+    pub spam_score: Option<f64>,
+
+    /// `Received:` headers of the mail, most recent first
+    pub received_chain: Vec<String>,
+}
@@ -1,8 +1,8 @@
-use std::{io, pin::Pin};
+use std::{io, net::IpAddr, pin::Pin};
 
 use async_trait::async_trait;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tracing::error;
+use tracing::{error, info, warn};
 
 use smtp_message::Hostname;
 
@@ -51,6 +51,33 @@ impl smtp_client::Config for ClientConfig {
         run_hook!(must_do_tls() || false)
     }
 
+    fn broken_starttls_ttl(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(run_hook!(
+            broken_starttls_ttl_in_millis() || 60 * 60 * 1000
+        ))
+    }
+
+    fn use_ipv6(&self) -> bool {
+        run_hook!(use_ipv6() || true)
+    }
+
+    fn connection_attempt_delay(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(run_hook!(connection_attempt_delay_in_millis() || 250))
+    }
+
+    fn max_mx_attempts(&self) -> usize {
+        let attempts: u64 = run_hook!(max_mx_attempts() || 10);
+        attempts as usize
+    }
+
+    fn accept_lf_only_replies(&self) -> bool {
+        run_hook!(accept_lf_only_replies() || false)
+    }
+
+    fn fold_long_headers(&self) -> bool {
+        run_hook!(fold_long_headers() || false)
+    }
+
     /// Note: If this function can only fail, make can_do_tls return false
     async fn tls_connect<IO>(&self, io: IO) -> io::Result<DynAsyncReadWrite>
     where
@@ -60,7 +87,8 @@ impl smtp_client::Config for ClientConfig {
         let handler = run_hook!(tls_handler() || TlsHandler::Rustls);
         match handler {
             TlsHandler::Rustls => {
-                // TODO: what should `nodomainyet` be here? for SNI maybe?
+                // TODO: what should `nodomainyet` be here? for SNI maybe? It is also
+                // the key under which the TLS sessions are kept for resumption
                 // TODO: switch everywhere to tokio?
                 use async_compat::CompatExt;
                 use std::convert::TryFrom;
@@ -126,4 +154,49 @@ impl smtp_client::Config for ClientConfig {
             data_end_reply_timeout_in_millis() || 10 * 60 * 1000
         ))
     }
+
+    fn rset_reply_timeout(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(run_hook!(rset_reply_timeout_in_millis() || 5 * 60 * 1000))
+    }
+
+    fn overall_delivery_timeout(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(run_hook!(
+            overall_delivery_timeout_in_millis() || 30 * 60 * 1000
+        ))
+    }
+
+    fn smtp_port(&self) -> u16 {
+        run_hook!(smtp_port() || 25)
+    }
+
+    fn next_hop(&self, domain: &Hostname) -> smtp_client::NextHop {
+        match run_hook!(next_hop(domain.clone()) || None) {
+            Some((host, port)) => smtp_client::NextHop::Relay(host, port),
+            None => smtp_client::NextHop::Mx,
+        }
+    }
+
+    fn domainless_recipient_domain(&self) -> Option<Hostname> {
+        run_hook!(domainless_recipient_domain() || None)
+    }
+
+    fn source_ip(&self, ip: IpAddr) -> Option<IpAddr> {
+        run_hook!(source_ip(ip) || None)
+    }
+
+    fn ehlo_hostname_for(&self, source_ip: IpAddr) -> Hostname {
+        run_hook!(ehlo_hostname_for(source_ip) || None).unwrap_or_else(|| self.ehlo_hostname())
+    }
+
+    fn on_connection_attempt(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        res: Result<(), &smtp_client::TransportError>,
+    ) {
+        match res {
+            Ok(()) => info!(%ip, port, "Connected to remote server"),
+            Err(e) => warn!(%ip, port, error = ?e, "Failed connecting to remote server"),
+        }
+    }
 }
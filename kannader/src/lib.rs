@@ -7,19 +7,27 @@
 // TODO: make everything configurable, and actually implement the wasm scheme
 // described in the docs
 
-use std::{convert::TryFrom, io, path::PathBuf, sync::Arc, time::SystemTime};
+use std::{
+    convert::TryFrom,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
 use anyhow::Context;
 use easy_parallel::Parallel;
 use futures::StreamExt;
 use scoped_tls::scoped_thread_local;
 use smol::{future::FutureExt, unblock};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use smtp_queue_fs::FsStorage;
 
 const NUM_THREADS: usize = 4;
-const DATABUF_SIZE: usize = 16 * 1024;
 
 mod client_config;
 mod queue_config;
@@ -33,8 +41,7 @@ use queue_transport::QueueTransport;
 use server_config::ServerConfig;
 use wasm_config::WasmConfig;
 
-#[derive(serde::Deserialize, serde::Serialize)]
-pub struct Meta;
+pub use kannader_types::Meta;
 
 struct NoCertVerifier;
 
@@ -106,40 +113,67 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
     let ex = &Arc::new(smol::Executor::new());
 
     let (stop_signal, local_shutdown) = smol::channel::unbounded::<()>();
+    let shutting_down = Arc::new(AtomicBool::new(false));
 
     let (_, res): (_, anyhow::Result<()>) = Parallel::new()
         .each(0..NUM_THREADS, |_| {
             let wasm_config = WasmConfig::new(&opt.dirs, &opt.config, &engine, &module)
                 .context("Preparing the wasm configuration blob")?;
             WASM_CONFIG.set(&wasm_config, || {
+                // Keep running the executor until the main thread is done shutting down,
+                // so that the deliveries in progress can complete meanwhile
                 smol::block_on(ex.run(async {
-                    shutdown
-                        .recv()
-                        .or(local_shutdown.recv())
-                        .await
-                        .context("Receiving shutdown notification")
+                    // Nothing is ever sent on `stop_signal`, it only gets dropped
+                    let _ = local_shutdown.recv().await;
+                    Ok(())
                 }))
             })
         })
         .finish(move || {
             let wasm_config = &wasm_config;
             WASM_CONFIG.set(wasm_config, move || {
-                smol::block_on(async move {
+                // This thread also runs the executor, so that the queue tasks (including
+                // the recovery, if it is configured to happen in the background) make
+                // progress alongside the accept loop
+                smol::block_on(ex.run(async move {
                     // Prepare the clients
                     debug!("Preparing the client configuration");
-                    // TODO: see for configuring persistence, for more performance?
-                    let tls_client_cfg = rustls::ClientConfig::builder()
+                    let client_session_cache_size = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.client_config.tls_session_cache_size)(&mut *store)
+                            .context("Getting the size of the client TLS session cache")?
+                    };
+                    let mut tls_client_cfg = rustls::ClientConfig::builder()
                         .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
                         .with_kx_groups(&rustls::ALL_KX_GROUPS)
                         .with_protocol_versions(rustls::ALL_VERSIONS)
                         .context("Configuring the rustls client")?
                         .with_custom_certificate_verifier(Arc::new(NoCertVerifier))
                         .with_no_client_auth();
+                    // TODO: sessions are all remembered under the same server name
+                    // until tls_connect knows the destination, see client_config.rs
+                    if client_session_cache_size > 0 {
+                        tls_client_cfg.session_storage =
+                            rustls::client::ClientSessionMemoryCache::new(
+                                client_session_cache_size as usize,
+                            );
+                    } else {
+                        tls_client_cfg.session_storage =
+                            Arc::new(rustls::client::NoClientSessionStorage {});
+                        tls_client_cfg.enable_tickets = false;
+                    }
                     let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_client_cfg));
+                    let (resolver_cfg, mut resolver_opts) =
+                        trust_dns_resolver::system_conf::read_system_conf()
+                            .context("Reading the system resolver configuration")?;
+                    // Resolve both IP versions, for the client to race them
+                    resolver_opts.ip_strategy =
+                        trust_dns_resolver::config::LookupIpStrategy::Ipv4AndIpv6;
+                    let resolver = async_std_resolver::resolver(resolver_cfg, resolver_opts)
+                        .await
+                        .context("Configuring a resolver from system configuration")?;
                     let client = smtp_client::Client::new(
-                        async_std_resolver::resolver_from_system_conf()
-                            .await
-                            .context("Configuring a resolver from system configuration")?,
+                        resolver.clone(),
                         Arc::new(ClientConfig::new(connector)),
                     );
 
@@ -155,6 +189,16 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                             .await
                             .context("Opening the queue storage folder")?,
                     };
+                    for e in storage.check_symlinks().await {
+                        warn!(error = ?e, "Found a broken mail in the queue storage");
+                    }
+                    let (removed, errors) = storage.cleanup_orphans().await;
+                    if removed > 0 {
+                        info!("Removed {} partially enqueued mails from the queue", removed);
+                    }
+                    for e in errors {
+                        warn!(error = ?e, "Failed removing partially enqueued mails");
+                    }
                     let queue = smtp_queue::Queue::new(
                         ex.clone(),
                         QueueConfig::new(),
@@ -177,6 +221,11 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                         (wasm_config.server_config.tls_key_file)(&mut *store)
                             .context("Getting the path to the TLS key file")?
                     };
+                    let server_session_cache_size = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_session_cache_size)(&mut *store)
+                            .context("Getting the size of the server TLS session cache")?
+                    };
                     let tls_server_cfg = unblock(move || {
                         // Load the certificates and keys
                         let certs = rustls_pemfile::certs(&mut io::BufReader::new(
@@ -209,9 +258,8 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                         let key = rustls::PrivateKey(keys.into_iter().next().unwrap());
 
                         // Configure rustls
-                        // TODO: see for configuring persistence, for more performance?
                         // TODO: support SNI
-                        let tls_server_cfg = rustls::ServerConfig::builder()
+                        let mut tls_server_cfg = rustls::ServerConfig::builder()
                             .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
                             .with_kx_groups(&rustls::ALL_KX_GROUPS)
                             .with_protocol_versions(rustls::ALL_VERSIONS)
@@ -220,36 +268,81 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                             .with_single_cert(certs, key)
                             .context("Setting the key and certificates")?;
 
+                        // Let the returning clients resume their session, either
+                        // from our cache or from a ticket they kept, to skip the
+                        // full handshake
+                        if server_session_cache_size > 0 {
+                            tls_server_cfg.session_storage =
+                                rustls::server::ServerSessionMemoryCache::new(
+                                    server_session_cache_size as usize,
+                                );
+                            tls_server_cfg.ticketer = rustls::Ticketer::new()
+                                .context("Setting up the TLS session tickets")?;
+                        } else {
+                            tls_server_cfg.session_storage =
+                                Arc::new(rustls::server::NoServerSessionStorage {});
+                        }
+
                         Ok(tls_server_cfg)
                     })
-                    .await?;
-                    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_server_cfg));
+                    .await;
+                    let acceptor = match tls_server_cfg {
+                        Ok(cfg) => Some(tokio_rustls::TlsAcceptor::from(Arc::new(cfg))),
+                        Err(e) => {
+                            warn!(
+                                error = ?e,
+                                "Could not load the TLS configuration, not advertising STARTTLS"
+                            );
+                            None
+                        }
+                    };
 
                     debug!("Reopening the listener as async");
-                    let server_cfg = Arc::new(ServerConfig::new(acceptor, queue));
+                    let server_cfg = Arc::new(ServerConfig::new(
+                        acceptor,
+                        queue.clone(),
+                        resolver,
+                        shutting_down.clone(),
+                    ));
                     let listener = smol::net::TcpListener::try_from(listener)
                         .context("Making listener async")?;
                     let mut incoming = listener.incoming();
 
-                    info!("Server up, waiting for connections");
-                    while let Some(stream) = incoming.next().await {
-                        let stream = stream.context("Receiving a new incoming stream")?;
-                        // TODO: attach uuid metadata to stream for logging purposes (or in
-                        // smtp-server directly?)
-                        tracing::trace!("New incoming stream");
-                        ex.spawn(smtp_server::interact(
-                            stream,
-                            smtp_server::IsAlreadyTls::No,
-                            Vec::new(), // TODO
-                            server_cfg.clone(),
-                        ))
-                        .detach();
-                    }
+                    let accept = async {
+                        info!("Server up, waiting for connections");
+                        while let Some(stream) = incoming.next().await {
+                            let stream = stream.context("Receiving a new incoming stream")?;
+                            // TODO: attach uuid metadata to stream for logging purposes (or in
+                            // smtp-server directly?)
+                            tracing::trace!("New incoming stream");
+                            let peer_addr = stream.peer_addr().ok().map(|a| a.ip());
+                            ex.spawn(smtp_server::interact(
+                                stream,
+                                peer_addr,
+                                smtp_server::IsAlreadyTls::No,
+                                Vec::new(), // TODO
+                                server_cfg.clone(),
+                            ))
+                            .detach();
+                        }
+                        Ok::<_, anyhow::Error>(())
+                    };
+                    // New connections keep being accepted, so as to be told that the server
+                    // is shutting down, until the queue is done with the deliveries in progress
+                    let stop = async {
+                        // Both a message and the closing of the channel ask for the shutdown
+                        let _ = shutdown.recv().await;
+                        info!("Shutting down, waiting for the deliveries in progress");
+                        shutting_down.store(true, Ordering::SeqCst);
+                        queue.shutdown().await;
+                        Ok(())
+                    };
+                    let res = accept.or(stop).await;
 
                     std::mem::drop(stop_signal);
 
-                    Ok(())
-                })
+                    res
+                }))
             })
         });
 
@@ -3,6 +3,7 @@ use std::time::Duration;
 use async_trait::async_trait;
 use tracing::error;
 
+use smtp_message::Hostname;
 use smtp_queue::QueueId;
 
 use crate::{Meta, WASM_CONFIG};
@@ -64,8 +65,12 @@ impl smtp_queue::Config<Meta, smtp_queue_fs::Error> for QueueConfig {
         run_hook!(log_too_big_duration(id, too_big, new) || ())
     }
 
-    fn found_inflight_check_delay(&self) -> Duration {
-        run_hook!(found_inflight_check_delay() || Duration::from_secs(3600))
+    async fn log_delivery_latency(&self, id: QueueId, latency: Duration) {
+        run_hook!(log_delivery_latency(id, latency) || ())
+    }
+
+    fn found_inflight_check_delay(&self, schedule: smtp_queue::ScheduleInfo) -> Duration {
+        run_hook!(found_inflight_check_delay(schedule) || Duration::from_secs(3600))
     }
 
     fn io_error_next_retry_delay(&self, d: Duration) -> Duration {
@@ -79,4 +84,25 @@ impl smtp_queue::Config<Meta, smtp_queue_fs::Error> for QueueConfig {
             }
         )
     }
+
+    fn scheduler_tick_interval(&self) -> Option<Duration> {
+        run_hook!(scheduler_tick_interval() || None)
+    }
+
+    fn max_rate_per_destination(&self, domain: &Hostname) -> Option<(u32, Duration)> {
+        run_hook!(max_rate_per_destination(domain.clone()) || None)
+    }
+
+    fn serialize_per_recipient(&self) -> bool {
+        run_hook!(serialize_per_recipient() || false)
+    }
+
+    fn max_concurrent_sends(&self) -> usize {
+        let max: u64 = run_hook!(max_concurrent_sends() || 100);
+        max as usize
+    }
+
+    fn recover_in_background(&self) -> bool {
+        run_hook!(recover_in_background() || false)
+    }
 }
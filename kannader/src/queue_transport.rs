@@ -1,9 +1,9 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use futures::AsyncRead;
 use tracing::{info, warn};
 
-use smtp_message::Hostname;
-
 use crate::{ClientConfig, Meta};
 
 // TODO: are there things that are interesting to configure in here?
@@ -40,42 +40,39 @@ fn transport_error_client_to_queue(
     }
 }
 
-pub struct QueueTransport<C, P>(smtp_client::Client<C, P, ClientConfig>)
+pub struct QueueTransport<C, P, Cfg = ClientConfig>(Arc<smtp_client::Client<C, P, Cfg>>)
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
-    P: trust_dns_resolver::ConnectionProvider<Conn = C>;
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+    Cfg: 'static + smtp_client::Config;
 
-impl<C, P> QueueTransport<C, P>
+impl<C, P, Cfg> QueueTransport<C, P, Cfg>
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
     P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+    Cfg: 'static + smtp_client::Config,
 {
-    pub fn new(client: smtp_client::Client<C, P, ClientConfig>) -> QueueTransport<C, P> {
-        QueueTransport(client)
+    pub fn new(client: smtp_client::Client<C, P, Cfg>) -> QueueTransport<C, P, Cfg> {
+        QueueTransport(Arc::new(client))
     }
 }
 
 #[async_trait]
-impl<C, P> smtp_queue::Transport<Meta> for QueueTransport<C, P>
+impl<C, P, Cfg> smtp_queue::Transport<Meta> for QueueTransport<C, P, Cfg>
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
     P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+    Cfg: 'static + smtp_client::Config,
 {
     type Destination = smtp_client::Destination;
-    type Sender = QueueTransportSender;
+    type Sender = QueueTransportSender<C, P, Cfg>;
 
     async fn destination(
         &self,
         meta: &smtp_queue::MailMetadata<Meta>,
     ) -> Result<Self::Destination, smtp_queue::TransportFailure> {
-        // TODO: this should most likely be a const or similar; and definitely not
-        // recomputed on each call to destination
-        let localhost = Hostname::parse(b"localhost")
-            .expect("failed to parse constant hostname")
-            .1
-            .to_owned();
         self.0
-            .get_destination(meta.to.hostname.as_ref().unwrap_or(&localhost))
+            .get_recipient_destination(&meta.to)
             .await
             .map_err(|e| {
                 transport_error_client_to_queue(
@@ -90,11 +87,14 @@ where
         dest: &Self::Destination,
     ) -> Result<Self::Sender, smtp_queue::TransportFailure> {
         info!(destination = %dest, "Connecting to remote server");
-        // TODO: log the IP to which we're connecting
         self.0
             .connect(dest)
             .await
-            .map(QueueTransportSender)
+            .map(|sender| QueueTransportSender {
+                client: self.0.clone(),
+                dest: dest.clone(),
+                sender,
+            })
             .map_err(|e| {
                 transport_error_client_to_queue(
                     e,
@@ -104,10 +104,24 @@ where
     }
 }
 
-pub struct QueueTransportSender(smtp_client::Sender<ClientConfig>);
+pub struct QueueTransportSender<C, P, Cfg>
+where
+    C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+    Cfg: 'static + smtp_client::Config,
+{
+    client: Arc<smtp_client::Client<C, P, Cfg>>,
+    dest: smtp_client::Destination,
+    sender: smtp_client::Sender<Cfg>,
+}
 
 #[async_trait]
-impl smtp_queue::TransportSender<Meta> for QueueTransportSender {
+impl<C, P, Cfg> smtp_queue::TransportSender<Meta> for QueueTransportSender<C, P, Cfg>
+where
+    C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+    Cfg: 'static + smtp_client::Config,
+{
     async fn send<Reader>(
         &mut self,
         meta: &smtp_queue::MailMetadata<Meta>,
@@ -117,11 +131,145 @@ impl smtp_queue::TransportSender<Meta> for QueueTransportSender {
         Reader: Send + AsyncRead,
     {
         // TODO: pass through mail id so that it's possible to log it
-        self.0
-            .send(meta.from.as_ref(), &meta.to, mail)
+        self.sender
+            .send(meta.from.as_ref(), &meta.to, mail, None)
             .await
             .map_err(|e| {
                 transport_error_client_to_queue(e, "Transport error while trying to send email")
             })
     }
+
+    async fn reset(&mut self) -> Result<(), smtp_queue::TransportFailure> {
+        self.sender.reset().await.map_err(|e| {
+            transport_error_client_to_queue(e, "Transport error while trying to reset connection")
+        })
+    }
+
+    /// Gives the connection back to the pool of the client, for the next
+    /// mail to the same destination to reuse it
+    async fn quit(self) {
+        self.client.release(&self.dest, self.sender).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use futures::{io::BufReader, AsyncBufReadExt, AsyncWriteExt};
+    use smtp_message::{Email, Hostname};
+    use smtp_queue::{MailMetadata, Transport, TransportSender};
+
+    use super::*;
+
+    struct TestConfig {
+        smtp_port: u16,
+    }
+
+    #[async_trait]
+    impl smtp_client::Config for TestConfig {
+        fn ehlo_hostname(&self) -> Hostname {
+            Hostname::parse(b"test.example.org").unwrap().1.to_owned()
+        }
+
+        fn can_do_tls(&self) -> bool {
+            false
+        }
+
+        fn smtp_port(&self) -> u16 {
+            self.smtp_port
+        }
+
+        async fn tls_connect<IO>(&self, _io: IO) -> io::Result<smtp_client::DynAsyncReadWrite>
+        where
+            IO: 'static + Unpin + Send + futures::AsyncRead + futures::AsyncWrite,
+        {
+            unimplemented!()
+        }
+    }
+
+    /// Answers positively to everything the client says on `io`
+    async fn serve(io: smol::net::TcpStream) {
+        let mut reader = BufReader::new(io.clone());
+        let mut io = io;
+        io.write_all(b"220 test.example.org Ready\r\n")
+            .await
+            .unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).await.unwrap() > 0 {
+            let reply: &[u8] = match line.get(..4).map(|c| c.to_ascii_uppercase()) {
+                Some(c) if c == "DATA" => {
+                    io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    while line != ".\r\n" {
+                        line.clear();
+                        reader.read_line(&mut line).await.unwrap();
+                    }
+                    b"250 2.0.0 Queued\r\n"
+                }
+                Some(c) if c == "QUIT" => b"221 2.0.0 Bye\r\n",
+                _ => b"250 2.0.0 Okay\r\n",
+            };
+            io.write_all(reply).await.unwrap();
+            line.clear();
+        }
+    }
+
+    #[test]
+    fn reuses_connections() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let connections = Arc::new(AtomicUsize::new(0));
+            let server = smol::spawn({
+                let connections = connections.clone();
+                async move {
+                    loop {
+                        let (io, _) = listener.accept().await.unwrap();
+                        connections.fetch_add(1, Ordering::SeqCst);
+                        smol::spawn(serve(io)).detach();
+                    }
+                }
+            });
+
+            let resolver = async_std_resolver::resolver(
+                trust_dns_resolver::config::ResolverConfig::default(),
+                trust_dns_resolver::config::ResolverOpts::default(),
+            )
+            .await
+            .unwrap();
+            let client =
+                smtp_client::Client::new(resolver, Arc::new(TestConfig { smtp_port: port }));
+            let transport = QueueTransport::new(client);
+            let meta = MailMetadata {
+                from: None,
+                to: Email::parse_bracketed(b"<foo@[127.0.0.1]>").unwrap(),
+                metadata: Meta::default(),
+                first_seen: None,
+            };
+            let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+            for _ in 0..2 {
+                let dest = transport
+                    .destination(&meta)
+                    .await
+                    .unwrap_or_else(|_| panic!("getting destination"));
+                let mut sender = transport
+                    .connect(&dest)
+                    .await
+                    .unwrap_or_else(|_| panic!("connecting"));
+                sender
+                    .send(&meta, mail)
+                    .await
+                    .unwrap_or_else(|_| panic!("sending"));
+                sender.quit().await;
+            }
+            assert_eq!(connections.load(Ordering::SeqCst), 1);
+            server.cancel().await;
+        })
+    }
 }
@@ -1,22 +1,42 @@
-use std::{io, pin::Pin};
-
+use std::{
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std_resolver::AsyncStdResolver;
 use async_trait::async_trait;
 use chrono::Utc;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::error;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::{error, info, warn};
+use trust_dns_resolver::error::ResolveErrorKind;
 
 use smtp_message::{Email, Hostname, MaybeUtf8, Reply};
 use smtp_queue_fs::FsStorage;
-use smtp_server::{reply, Decision, HelloInfo};
+use smtp_server::{
+    filter::{ContentFilter, FilterVerdict, PrependHeaders},
+    reply,
+    route::RouteCache,
+    spf, Decision, HelloInfo, HelloVerification, MailRoute, OpenConnections, SpfResult,
+};
 
-use crate::{Meta, QueueConfig, DATABUF_SIZE, WASM_CONFIG};
+use crate::{Meta, QueueConfig, WASM_CONFIG};
 
 pub type ConnMeta = smtp_server::ConnectionMetadata<Vec<u8>>;
 pub type MailMeta = smtp_server::MailMetadata<Vec<u8>>;
 
 pub struct ServerConfig<T> {
-    acceptor: tokio_rustls::TlsAcceptor,
+    acceptor: Option<tokio_rustls::TlsAcceptor>,
     queue: smtp_queue::Queue<Meta, QueueConfig, FsStorage<Meta>, T>,
+    open_connections: OpenConnections,
+    resolver: AsyncStdResolver,
+    route_cache: RouteCache,
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl<T> ServerConfig<T>
@@ -24,10 +44,61 @@ where
     T: smtp_queue::Transport<Meta>,
 {
     pub fn new(
-        acceptor: tokio_rustls::TlsAcceptor,
+        acceptor: Option<tokio_rustls::TlsAcceptor>,
         queue: smtp_queue::Queue<Meta, QueueConfig, FsStorage<Meta>, T>,
+        resolver: AsyncStdResolver,
+        shutting_down: Arc<AtomicBool>,
     ) -> ServerConfig<T> {
-        ServerConfig { acceptor, queue }
+        ServerConfig {
+            acceptor,
+            queue,
+            open_connections: OpenConnections::new(),
+            resolver,
+            // TODO: make the cache configurable
+            route_cache: RouteCache::new(Duration::from_secs(60 * 60), 10_000),
+            shutting_down,
+        }
+    }
+
+    /// Names that the PTR records of `ip` point to, and that resolve back to
+    /// `ip` in turn. Returns `None` if a lookup failed for another reason
+    /// than there being no such record.
+    async fn forward_confirmed_names(&self, ip: IpAddr) -> Option<Vec<Hostname>> {
+        let ptrs = match self.resolver.reverse_lookup(ip).await {
+            Ok(ptrs) => ptrs,
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => return Some(Vec::new()),
+                _ => {
+                    warn!(error = ?e, %ip, "Failed looking up the reverse DNS of the client");
+                    return None;
+                }
+            },
+        };
+        let mut names = Vec::new();
+        for ptr in ptrs.iter() {
+            match self.resolver.lookup_ip(ptr.clone()).await {
+                Ok(ips) if ips.iter().any(|i| i == ip) => {
+                    let name = ptr.to_ascii();
+                    let name = name.trim_end_matches('.');
+                    match Hostname::parse(name.as_bytes()) {
+                        Ok(([], host)) => names.push(host),
+                        _ => warn!(%ip, name, "Reverse DNS of the client is not a valid hostname"),
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { .. } => (),
+                    _ => {
+                        warn!(
+                            error = ?e, %ip, name = %ptr,
+                            "Failed looking up the reverse DNS name of the client"
+                        );
+                        return None;
+                    }
+                },
+            }
+        }
+        Some(names)
     }
 }
 
@@ -55,6 +126,68 @@ macro_rules! run_hook {
     };
 }
 
+/// Name of this server, for the `Received-SPF` header: the one it announces
+/// when sending mails
+fn local_hostname() -> Option<Hostname> {
+    WASM_CONFIG.with(|wasm_config| {
+        let mut store = wasm_config.store.borrow_mut();
+        match (wasm_config.client_config.ehlo_hostname)(&mut *store) {
+            Ok(hostname) => Some(hostname),
+            Err(e) => {
+                error!(error = ?e, "Internal server error in ‘client_config_ehlo_hostname’");
+                None
+            }
+        }
+    })
+}
+
+async fn abort_enqueuer<T>(enqueuer: smtp_queue::Enqueuer<Meta, QueueConfig, FsStorage<Meta>, T>)
+where
+    T: smtp_queue::Transport<Meta>,
+{
+    if let Err(e) = enqueuer.abort().await {
+        let e = anyhow::Error::new(e);
+        error!(error = ?e, "Internal server error while dropping a partially written mail");
+    }
+}
+
+/// Content filter handing the mail contents to the `filter_content_chunk` and
+/// `filter_content_end` hooks
+struct WasmContentFilter<'a> {
+    meta: &'a mut MailMeta,
+    conn_meta: &'a mut ConnMeta,
+}
+
+#[async_trait]
+impl ContentFilter for WasmContentFilter<'_> {
+    async fn chunk(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let filtered: Option<Vec<u8>> = run_hook!(
+            filter_content_chunk(chunk.to_vec(), &mut *self.meta, &mut *self.conn_meta) || None
+        );
+        match filtered {
+            Some(filtered) => {
+                out.extend_from_slice(&filtered);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Error while running the ‘filter_content_chunk’ hook",
+            )),
+        }
+    }
+
+    async fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<FilterVerdict> {
+        let rejection: Option<Reply> = run_hook!(
+            filter_content_end(&mut *self.meta, &mut *self.conn_meta)
+                || Some(reply::internal_server_error().convert())
+        );
+        Ok(match rejection {
+            None => FilterVerdict::Accept,
+            Some(reply) => FilterVerdict::Reject(reply),
+        })
+    }
+}
+
 #[async_trait]
 impl<T> smtp_server::Config for ServerConfig<T>
 where
@@ -76,6 +209,10 @@ where
         run_hook!(welcome_banner_reply(conn_meta) || reply::internal_server_error().convert())
     }
 
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
     fn hello_banner(&self, _: &ConnMeta) -> &str {
         unimplemented!()
     }
@@ -89,6 +226,20 @@ where
         run_hook!(filter_hello(is_extended, hostname, conn_meta))
     }
 
+    async fn verify_hello(
+        &self,
+        ip: IpAddr,
+        hostname: &Hostname,
+        conn_meta: &mut ConnMeta,
+    ) -> HelloVerification {
+        let names = self.forward_confirmed_names(ip).await;
+        run_hook!(verify_hello(ip, hostname.clone(), names, conn_meta) || HelloVerification::Pass)
+    }
+
+    fn tls_configured(&self) -> bool {
+        self.acceptor.is_some()
+    }
+
     fn can_do_tls(&self, conn_meta: &ConnMeta) -> bool {
         // Unfortunately, there is no good way to gracefully fail here
         run_hook!(
@@ -108,7 +259,7 @@ where
     async fn tls_accept<IO>(
         &self,
         io: IO,
-        _conn_meta: &mut ConnMeta,
+        conn_meta: &mut ConnMeta,
     ) -> io::Result<
         duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
     >
@@ -122,7 +273,17 @@ where
         // multiple SNI hostnames / multiple IP addresses
         // TODO: switch everything to tokio?
         use async_compat::CompatExt;
-        let io = self.acceptor.accept(io.compat()).await?;
+        let acceptor = self
+            .acceptor
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "TLS is not configured"))?;
+        let io = acceptor.accept(io.compat()).await?;
+        conn_meta.tls_peer = io
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| smtp_server::tls::peer_identity(&cert.0));
         let (r, w) = io.compat().split();
         let io = duplexify::Duplex::new(
             Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
@@ -145,6 +306,10 @@ where
         run_hook!(filter_from(from, meta, conn_meta))
     }
 
+    async fn spf_check(&self, ip: IpAddr, domain: &str, _: &mut ConnMeta) -> SpfResult {
+        spf::check_host(&self.resolver, ip, domain).await
+    }
+
     async fn filter_to(
         &self,
         to: Email,
@@ -154,10 +319,143 @@ where
         run_hook!(filter_to(to, meta, conn_meta))
     }
 
+    fn verify_recipient_routability(&self, conn_meta: &ConnMeta) -> bool {
+        run_hook!(verify_recipient_routability((*conn_meta).clone()) || false)
+    }
+
+    async fn recipient_route(&self, domain: &str, _: &mut ConnMeta) -> MailRoute {
+        self.route_cache.mail_route(&self.resolver, domain).await
+    }
+
+    fn always_accept_postmaster(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            always_accept_postmaster((*conn_meta).clone())
+                || panic!("Error while running the ‘always_accept_postmaster’ hook")
+        )
+    }
+
+    fn is_postmaster(&self, email: &Email, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            is_postmaster(email.clone(), (*conn_meta).clone())
+                || panic!("Error while running the ‘is_postmaster’ hook")
+        )
+    }
+
+    fn canonicalize_recipients(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            canonicalize_recipients((*conn_meta).clone())
+                || panic!("Error while running the ‘canonicalize_recipients’ hook")
+        )
+    }
+
+    fn max_rejected_rcpts(&self, conn_meta: &ConnMeta) -> usize {
+        // Unfortunately, there is no good way to gracefully fail here
+        let max: u64 = run_hook!(
+            max_rejected_rcpts((*conn_meta).clone())
+                || panic!("Error while running the ‘max_rejected_rcpts’ hook")
+        );
+        max as usize
+    }
+
+    fn max_connections_per_ip(&self) -> usize {
+        // Unfortunately, there is no good way to gracefully fail here
+        let max: u64 = run_hook!(
+            max_connections_per_ip()
+                || panic!("Error while running the ‘max_connections_per_ip’ hook")
+        );
+        max as usize
+    }
+
+    fn open_connections(&self) -> Option<&OpenConnections> {
+        Some(&self.open_connections)
+    }
+
+    fn max_pipelined_commands(&self, conn_meta: &ConnMeta) -> usize {
+        // Unfortunately, there is no good way to gracefully fail here
+        let max: u64 = run_hook!(
+            max_pipelined_commands((*conn_meta).clone())
+                || panic!("Error while running the ‘max_pipelined_commands’ hook")
+        );
+        max as usize
+    }
+
     async fn filter_data(&self, meta: &mut MailMeta, conn_meta: &mut ConnMeta) -> Decision<()> {
         run_hook!(filter_data(meta, conn_meta))
     }
 
+    fn is_trusted_frontend(&self, peer_addr: IpAddr) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            is_trusted_frontend(peer_addr)
+                || panic!("Error while running the ‘is_trusted_frontend’ hook")
+        )
+    }
+
+    fn xclient_allowed(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            xclient_allowed((*conn_meta).clone())
+                || panic!("Error while running the ‘xclient_allowed’ hook")
+        )
+    }
+
+    fn require_helo_before_mail(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            require_helo_before_mail((*conn_meta).clone())
+                || panic!("Error while running the ‘require_helo_before_mail’ hook")
+        )
+    }
+
+    fn require_tls_for_data(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            require_tls_for_data((*conn_meta).clone())
+                || panic!("Error while running the ‘require_tls_for_data’ hook")
+        )
+    }
+
+    fn accept_bare_lf_data_end(&self, conn_meta: &ConnMeta) -> bool {
+        // Unfortunately, there is no good way to gracefully fail here
+        run_hook!(
+            accept_bare_lf_data_end((*conn_meta).clone())
+                || panic!("Error while running the ‘accept_bare_lf_data_end’ hook")
+        )
+    }
+
+    async fn log_bare_lf_data_end(&self, _conn_meta: &mut ConnMeta) {
+        warn!("Accepted a DATA ended by a bare LF, normalized it to CRLF");
+    }
+
+    async fn log_tls_handshake_failure(&self, error: &io::Error, _conn_meta: &mut ConnMeta) {
+        warn!(error = ?error, "TLS handshake failed after STARTTLS, closing the connection");
+    }
+
+    async fn log_interrupted_data(&self, _conn_meta: &mut ConnMeta) {
+        info!("Client closed the connection before the end of the mail, dropped it");
+    }
+
+    fn max_headers_size(&self, conn_meta: &ConnMeta) -> usize {
+        // Unfortunately, there is no good way to gracefully fail here
+        let size: u64 = run_hook!(
+            max_headers_size((*conn_meta).clone())
+                || panic!("Error while running the ‘max_headers_size’ hook")
+        );
+        size as usize
+    }
+
+    async fn filter_headers(
+        &self,
+        headers: &[u8],
+        meta: &mut MailMeta,
+        conn_meta: &mut ConnMeta,
+    ) -> Decision<()> {
+        run_hook!(filter_headers(headers.to_vec(), meta, conn_meta))
+    }
+
     /// Note: the EscapedDataReader has an inner buffer size of
     /// [`RDBUF_SIZE`](RDBUF_SIZE), which means that reads should not happen
     /// with more than this buffer size.
@@ -168,8 +466,8 @@ where
     async fn handle_mail<'resp, R>(
         &'resp self,
         stream: &mut smtp_message::EscapedDataReader<'_, R>,
-        meta: MailMeta,
-        _conn_meta: &'resp mut ConnMeta,
+        mut meta: MailMeta,
+        conn_meta: &'resp mut ConnMeta,
     ) -> Decision<()>
     where
         R: Send + Unpin + AsyncRead,
@@ -187,80 +485,104 @@ where
             }
         };
         // TODO: MUST add Received header at least
-        // TODO: factor out with the similar logic in smtp-client
-        let mut buf = [0; DATABUF_SIZE];
-        loop {
-            match stream.read(&mut buf).await {
-                Ok(0) => {
-                    // End of stream
-                    break;
-                }
-                Ok(n) => {
-                    // Got n bytes
-                    if let Err(e) = enqueuer.write_all(&buf[..n]).await {
-                        error!(error = ?e, "Internal server error while writing data to queue");
-                        loop {
-                            match stream.read(&mut buf).await {
-                                Ok(0) => break,
-                                Ok(_) => (),
-                                Err(e) => {
-                                    error!(error = ?e, "Internal server error while reading data from network");
-                                    break;
-                                }
-                            }
-                        }
-                        return Decision::Reject {
-                            reply: reply::internal_server_error().convert(),
-                        };
-                    }
-                }
-                Err(e) => {
-                    error!(error = ?e, "Internal server error while reading data from network");
-                    return Decision::Reject {
-                        reply: reply::internal_server_error().convert(),
-                    };
-                }
+        let mut headers: Vec<String> = run_hook!(mail_headers(&mut meta, conn_meta) || Vec::new());
+        let client_ip = smtp_server::Config::client_ip(self, conn_meta);
+        if let (Some(result), Some(ip)) = (meta.spf, client_ip) {
+            if let Some(receiver) = local_hostname() {
+                let header =
+                    spf::received_spf_header(result, receiver.raw(), ip, meta.from.as_ref());
+                headers.insert(0, header);
             }
         }
-
-        if !stream.is_finished() {
-            // Stream isn't finished, as we read until end-of-stream it means that there was
-            // an error somewhere
-            error!("Stream stopped returning any bytes without actually finishing");
-            Decision::Reject {
-                reply: reply::internal_server_error().convert(),
+        let verdict = {
+            let mut filter = WasmContentFilter {
+                meta: &mut meta,
+                conn_meta: &mut *conn_meta,
+            };
+            let mut filter = PrependHeaders::new(headers, &mut filter);
+            smtp_server::filter::apply_content_filter(stream, &mut filter, &mut enqueuer).await
+        };
+        match verdict {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted && !stream.is_finished() => {
+                // The client went away mid-DATA, which log_interrupted_data reports: only
+                // drop what was already written to the queue
+                abort_enqueuer(enqueuer).await;
+                Decision::Kill {
+                    reply: None,
+                    res: Err(e),
+                }
             }
-        } else {
-            // Stream is finished, let's complete it then commit the file to the queue and
-            // acept
-            stream.complete();
-            let from = &meta.from;
-            let destinations = meta
-                .to
-                .into_iter()
-                .map(move |to| {
-                    (
-                        smtp_queue::MailMetadata {
-                            from: from.clone(),
-                            to,
-                            metadata: Meta,
-                        },
-                        smtp_queue::ScheduleInfo {
-                            at: Utc::now(),
-                            last_attempt: None,
-                        },
-                    )
-                })
-                .collect();
-            if let Err(e) = enqueuer.commit(destinations).await {
-                error!(error = ?e, "Internal server error while committing mail");
+            Err(e) => {
+                error!(error = ?e, "Internal server error while writing data to queue");
+                abort_enqueuer(enqueuer).await;
+                // Finish reading the mail, without letting a slow client hold on
+                // to this worker forever
+                let timeout = smtp_server::Config::data_read_timeout(self);
+                if let Err(e) = smtp_server::drain_data(stream, timeout).await {
+                    error!(error = ?e, "Error while reading the rest of a failed mail");
+                    return Decision::Kill {
+                        reply: Some(reply::internal_server_error().convert()),
+                        res: Err(e),
+                    };
+                }
+                if stream.is_finished() {
+                    stream.complete();
+                }
                 Decision::Reject {
                     reply: reply::internal_server_error().convert(),
                 }
-            } else {
-                Decision::Accept {
-                    reply: reply::okay_mail().convert(),
-                    res: (),
+            }
+            Ok(FilterVerdict::Reject(reply)) => {
+                // The filter only gives its verdict once the whole mail was read
+                stream.complete();
+                abort_enqueuer(enqueuer).await;
+                Decision::Reject { reply }
+            }
+            Ok(FilterVerdict::Accept) => {
+                // Stream is finished, let's complete it then commit the file to the queue and
+                // acept
+                stream.complete();
+                let priority: u8 = run_hook!(mail_priority(&mut meta, conn_meta) || 0);
+                let queue_meta: Meta =
+                    run_hook!(queue_metadata(&mut meta, conn_meta) || Meta::default());
+                let from = &meta.from;
+                let destinations = meta
+                    .to
+                    .into_iter()
+                    .map(move |to| {
+                        (
+                            smtp_queue::MailMetadata {
+                                from: from.clone(),
+                                to,
+                                metadata: queue_meta.clone(),
+                                first_seen: Some(Utc::now()),
+                            },
+                            smtp_queue::ScheduleInfo {
+                                at: Utc::now(),
+                                last_attempt: None,
+                                priority,
+                            },
+                        )
+                    })
+                    .collect();
+                match enqueuer.commit(destinations).await {
+                    Err(e) => {
+                        error!(error = ?e, "Internal server error while committing mail");
+                        Decision::Reject {
+                            reply: reply::internal_server_error().convert(),
+                        }
+                    }
+                    Ok(ids) => {
+                        for id in ids {
+                            let span = smtp_queue::mail_span(&id);
+                            let _enter = span.enter();
+                            info!("Queued mail");
+                        }
+                        Decision::Accept {
+                            reply: reply::okay_mail().convert(),
+                            res: (),
+                        }
+                    }
                 }
             }
         }
@@ -302,12 +624,30 @@ where
         run_hook!(handle_quit(conn_meta))
     }
 
+    async fn unknown_command(&self, line: &[u8], conn_meta: &mut ConnMeta) -> Option<Decision<()>> {
+        let decision: Option<smtp_server_types::SerializableDecision<()>> = run_hook!(
+            unknown_command(line.to_vec(), conn_meta)
+                || Some(smtp_server_types::SerializableDecision::Reject {
+                    reply: reply::internal_server_error().convert(),
+                })
+        );
+        decision.map(|d| d.into())
+    }
+
     fn already_did_hello(&self, conn_meta: &mut ConnMeta) -> Reply {
         run_hook!(already_did_hello(conn_meta) || reply::bad_sequence().convert())
     }
 
+    fn hello_softfail(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(hello_softfail(conn_meta) || reply::hello_softfail().convert())
+    }
+
+    fn hello_rejected(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(hello_rejected(conn_meta) || reply::hello_rejected().convert())
+    }
+
     fn mail_before_hello(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(mail_before_hello(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(mail_before_hello(conn_meta) || reply::hello_required().convert())
     }
 
     fn already_in_mail(&self, conn_meta: &mut ConnMeta) -> Reply {
@@ -318,6 +658,17 @@ where
         run_hook!(rcpt_before_mail(conn_meta) || reply::bad_sequence().convert())
     }
 
+    fn too_many_rejected_rcpts(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(too_many_rejected_rcpts(conn_meta) || reply::too_many_rejected_rcpts().convert())
+    }
+
+    fn too_many_pipelined_commands(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(
+            too_many_pipelined_commands(conn_meta)
+                || reply::too_many_pipelined_commands().convert()
+        )
+    }
+
     fn data_before_rcpt(&self, conn_meta: &mut ConnMeta) -> Reply {
         run_hook!(data_before_rcpt(conn_meta) || reply::bad_sequence().convert())
     }
@@ -330,6 +681,14 @@ where
         run_hook!(starttls_unsupported(conn_meta) || reply::command_not_supported().convert())
     }
 
+    fn xclient_forbidden(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(xclient_forbidden(conn_meta) || reply::xclient_forbidden().convert())
+    }
+
+    fn xclient_invalid(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(xclient_invalid(conn_meta) || reply::xclient_invalid().convert())
+    }
+
     fn command_unrecognized(&self, conn_meta: &mut ConnMeta) -> Reply {
         run_hook!(command_unrecognized(conn_meta) || reply::command_unrecognized().convert())
     }
@@ -352,6 +711,10 @@ where
         )
     }
 
+    fn session_too_long(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(session_too_long(conn_meta) || reply::session_too_long().convert())
+    }
+
     fn reply_write_timeout(&self) -> chrono::Duration {
         // Unfortunately, there is no good way to gracefully fail here
         chrono::Duration::milliseconds(run_hook!(
@@ -367,4 +730,20 @@ where
                 || panic!("Error while running the ‘command_read_timeout’ hook")
         ))
     }
+
+    fn data_read_timeout(&self) -> chrono::Duration {
+        // Unfortunately, there is no good way to gracefully fail here
+        chrono::Duration::milliseconds(run_hook!(
+            data_read_timeout_in_millis()
+                || panic!("Error while running the ‘data_read_timeout’ hook")
+        ))
+    }
+
+    fn max_session_duration(&self) -> chrono::Duration {
+        // Unfortunately, there is no good way to gracefully fail here
+        chrono::Duration::milliseconds(run_hook!(
+            max_session_duration_in_millis()
+                || panic!("Error while running the ‘max_session_duration’ hook")
+        ))
+    }
 }
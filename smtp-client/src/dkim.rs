@@ -0,0 +1,316 @@
+//! Outbound DKIM signing, as per RFC 6376.
+//!
+//! Only the `rsa-sha256` algorithm with the `relaxed/relaxed` canonicalization
+//! is supported. Signing happens in the client, just before sending the mail
+//! to each destination: the whole mail must be known to compute the body hash
+//! before sending the `DKIM-Signature` header, so it is read into memory.
+
+use chrono::{DateTime, Utc};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+
+use smtp_message::DataUnescaper;
+
+/// Headers that are signed by default, when present in the mail
+pub const DEFAULT_SIGNED_HEADERS: &[&str] = &[
+    "from",
+    "reply-to",
+    "subject",
+    "date",
+    "to",
+    "cc",
+    "message-id",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum DkimError {
+    #[error("Parsing the DKIM private key: {0}")]
+    InvalidKey(ring::error::KeyRejected),
+
+    #[error("Computing the DKIM signature")]
+    Signing,
+}
+
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: RsaKeyPair,
+    signed_headers: Vec<String>,
+}
+
+impl DkimSigner {
+    /// `pkcs8_key` is the DER-encoded PKCS#8 RSA private key, whose public
+    /// key is published at `<selector>._domainkey.<domain>`
+    pub fn new(
+        domain: String,
+        selector: String,
+        pkcs8_key: &[u8],
+    ) -> Result<DkimSigner, DkimError> {
+        Ok(DkimSigner {
+            domain,
+            selector,
+            key: RsaKeyPair::from_pkcs8(pkcs8_key).map_err(DkimError::InvalidKey)?,
+            signed_headers: DEFAULT_SIGNED_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+        })
+    }
+
+    /// Headers to sign, instead of `DEFAULT_SIGNED_HEADERS`
+    pub fn with_signed_headers(mut self, headers: Vec<String>) -> DkimSigner {
+        self.signed_headers = headers.into_iter().map(|h| h.to_lowercase()).collect();
+        self
+    }
+
+    /// Computes the `DKIM-Signature` header line, including its final CRLF,
+    /// to prepend to `mail`.
+    ///
+    /// `mail` is the mail as sent in DATA, ie. dot-stuffed and ending with
+    /// the `.` line.
+    pub fn sign(&self, mail: &[u8], timestamp: DateTime<Utc>) -> Result<Vec<u8>, DkimError> {
+        let mail = unescape(mail);
+        let (headers, body) = split_headers(&mail);
+        let headers = parse_headers(headers);
+
+        let body_hash = digest::digest(&digest::SHA256, &relaxed_body(body));
+
+        // Sign the header instances bottom-up, as per RFC 6376 section 5.4.2
+        let mut signed_names = Vec::new();
+        let mut signed_data = Vec::new();
+        for name in &self.signed_headers {
+            for (_, h) in headers.iter().rev().filter(|(n, _)| n == name) {
+                signed_names.push(name.as_str());
+                signed_data.extend_from_slice(&relaxed_header(h));
+            }
+        }
+
+        let mut header = format!(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d={}; s={}; t={}; h={}; bh={}; b=",
+            self.domain,
+            self.selector,
+            timestamp.timestamp(),
+            signed_names.join(":"),
+            base64::encode(body_hash.as_ref()),
+        );
+        let mut dkim_header = Vec::from(&b"DKIM-Signature: "[..]);
+        dkim_header.extend_from_slice(header.as_bytes());
+        let mut dkim_header = relaxed_header(&dkim_header);
+        // The DKIM-Signature header is hashed without its final CRLF
+        dkim_header.truncate(dkim_header.len() - 2);
+        signed_data.extend_from_slice(&dkim_header);
+
+        let mut signature = vec![0; self.key.public_modulus_len()];
+        self.key
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                &signed_data,
+                &mut signature,
+            )
+            .map_err(|_| DkimError::Signing)?;
+        header.push_str(&base64::encode(&signature));
+
+        Ok(format!("DKIM-Signature: {}\r\n", header).into_bytes())
+    }
+}
+
+/// Removes the dot-stuffing and the final `.` line from `mail`
+fn unescape(mail: &[u8]) -> Vec<u8> {
+    let mut mail = mail.to_vec();
+    let res = DataUnescaper::new(true).unescape(&mut mail);
+    mail.truncate(res.written);
+    mail
+}
+
+/// Returns the header block, with the CRLF of its last line, and the body
+fn split_headers(mail: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(body) = mail.strip_prefix(b"\r\n") {
+        return (&[], body);
+    }
+    match mail.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&mail[..i + 2], &mail[i + 4..]),
+        None => (mail, &[]),
+    }
+}
+
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut res = Vec::new();
+    let mut rem = data;
+    while let Some(i) = rem.windows(2).position(|w| w == b"\r\n") {
+        res.push(&rem[..i]);
+        rem = &rem[i + 2..];
+    }
+    if !rem.is_empty() {
+        res.push(rem);
+    }
+    res
+}
+
+/// Returns the lowercase name and the raw contents of each header field
+fn parse_headers(headers: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut res: Vec<(String, Vec<u8>)> = Vec::new();
+    for line in split_lines(headers) {
+        match (line.first(), res.last_mut()) {
+            (Some(b' ') | Some(b'\t'), Some((_, h))) => {
+                h.extend_from_slice(b"\r\n");
+                h.extend_from_slice(line);
+            }
+            _ => {
+                let name = line.split(|&c| c == b':').next().unwrap_or(line);
+                let name = String::from_utf8_lossy(name).trim().to_lowercase();
+                res.push((name, line.to_vec()));
+            }
+        }
+    }
+    res
+}
+
+/// Replaces all runs of whitespace by a single space
+fn compress_wsp(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for &c in data {
+        if c == b' ' || c == b'\t' {
+            if res.last() != Some(&b' ') {
+                res.push(b' ');
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn trim_wsp(mut data: &[u8]) -> &[u8] {
+    while let Some(d) = data.strip_prefix(b" ") {
+        data = d;
+    }
+    while let Some(d) = data.strip_suffix(b" ") {
+        data = d;
+    }
+    data
+}
+
+/// Relaxed canonicalization of a header field, as per RFC 6376 section 3.4.2
+fn relaxed_header(header: &[u8]) -> Vec<u8> {
+    let unfolded = header
+        .iter()
+        .copied()
+        .filter(|&c| c != b'\r' && c != b'\n')
+        .collect::<Vec<u8>>();
+    let colon = unfolded
+        .iter()
+        .position(|&c| c == b':')
+        .unwrap_or(unfolded.len());
+    let name = String::from_utf8_lossy(&unfolded[..colon])
+        .trim()
+        .to_lowercase();
+    let value = compress_wsp(unfolded.get(colon + 1..).unwrap_or(&[]));
+    let mut res = name.into_bytes();
+    res.push(b':');
+    res.extend_from_slice(trim_wsp(&value));
+    res.extend_from_slice(b"\r\n");
+    res
+}
+
+/// Relaxed canonicalization of the body, as per RFC 6376 section 3.4.4
+fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut lines = split_lines(body)
+        .into_iter()
+        .map(|l| {
+            let mut l = compress_wsp(l);
+            if l.last() == Some(&b' ') {
+                l.pop();
+            }
+            l
+        })
+        .collect::<Vec<_>>();
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    let mut res = Vec::with_capacity(body.len());
+    for l in lines {
+        res.extend_from_slice(&l);
+        res.extend_from_slice(b"\r\n");
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use ring::signature::{UnparsedPublicKey, RSA_PKCS1_2048_8192_SHA256};
+
+    use super::*;
+
+    fn decode_pem(pem: &str) -> Vec<u8> {
+        let der = pem
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<String>();
+        base64::decode(der).unwrap()
+    }
+
+    /// Returns the PKCS#1 public key, to check the signatures against
+    fn test_public_key() -> Vec<u8> {
+        let spki = decode_pem(include_str!("../res/dkim-test.pub"));
+        // Skip the SubjectPublicKeyInfo header, that is always the same for
+        // 2048-bit RSA keys, to get to the RSAPublicKey
+        spki[24..].to_vec()
+    }
+
+    #[test]
+    fn signs_known_mail() {
+        let key = decode_pem(include_str!("../res/dkim-test.key"));
+        let signer = DkimSigner::new("example.org".into(), "test".into(), &key)
+            .unwrap()
+            .with_signed_headers(vec![
+                "From".into(),
+                "Subject".into(),
+                "Date".into(),
+                "To".into(),
+            ]);
+        let mail = b"From: Alice <alice@example.org>\r\n\
+                     To:   bob@example.com\r\n\
+                     Subject: Hello\r\n  \t world \r\n\
+                     Date: Thu, 1 Jan 2020 00:00:00 +0000\r\n\
+                     X-Unsigned: foo\r\n\
+                     \r\n\
+                     Hi  Bob, \r\n\
+                     ..leading dot\r\n\
+                     \r\n\
+                     \r\n\
+                     .\r\n";
+        let header = signer.sign(mail, Utc.timestamp(1_600_000_000, 0)).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        let fields = "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.org; s=test; \
+                      t=1600000000; h=from:subject:date:to; \
+                      bh=IprQDITUJyyAXomLlUyKPc1rYfd3FPRzd4lv0BBjSzo=; b=";
+        let b = header
+            .strip_prefix("DKIM-Signature: ")
+            .and_then(|h| h.strip_prefix(fields))
+            .and_then(|h| h.strip_suffix("\r\n"))
+            .unwrap_or_else(|| panic!("unexpected DKIM-Signature header: {:?}", header));
+
+        // The signed headers, canonicalized by hand
+        let signed_data = format!(
+            "from:Alice <alice@example.org>\r\n\
+             subject:Hello world\r\n\
+             date:Thu, 1 Jan 2020 00:00:00 +0000\r\n\
+             to:bob@example.com\r\n\
+             dkim-signature:{}",
+            fields
+        );
+        UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, test_public_key())
+            .verify(signed_data.as_bytes(), &base64::decode(b).unwrap())
+            .expect("the DKIM signature does not match the public key");
+    }
+}
@@ -1,51 +1,194 @@
 use std::{
-    cmp, collections::BTreeMap, fmt, future::Future, io, net::IpAddr, ops::Range, pin::Pin,
-    sync::Arc,
+    cmp,
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
 use async_trait::async_trait;
-use bitflags::bitflags;
-use chrono::Utc;
-use futures::{pin_mut, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use rand::prelude::SliceRandom;
+use chrono::{DateTime, Utc};
+use futures::{
+    future::Either, pin_mut, stream::FuturesUnordered, AsyncRead, AsyncReadExt, AsyncWrite,
+    AsyncWriteExt, StreamExt,
+};
+use rand::{prelude::SliceRandom, rngs::StdRng, SeedableRng};
 use smol::net::TcpStream;
-use tracing::trace;
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{trace, warn};
 use trust_dns_resolver::{
     error::{ResolveError, ResolveErrorKind},
-    proto::error::ProtoError,
+    proto::{
+        error::ProtoError,
+        rr::{
+            rdata::{
+                tlsa::{CertUsage, Matching, Selector},
+                TLSA,
+            },
+            RData, RecordType,
+        },
+        xfer::DnsRequestOptions,
+    },
     AsyncResolver, IntoName,
 };
 
 use smtp_message::{
-    nom, Command, Email, EnhancedReplyCodeSubject, Hostname, Parameters, Reply, ReplyCodeKind,
+    nom, Command, DataUnescaper, Email, EnhancedReplyCodeSubject, Hostname, Localpart,
+    MaybeUtf8, ParameterName, Parameters, Reply, ReplyCodeKind, ReplyLine,
 };
 
+pub mod dkim;
+#[cfg(test)]
+mod mock_resolver;
+
+pub use dkim::{DkimError, DkimSigner};
+
 const SMTP_PORT: u16 = 25;
 
 const RDBUF_SIZE: usize = 16 * 1024;
 const DATABUF_SIZE: usize = 16 * 1024;
 const MINIMUM_FREE_BUFSPACE: usize = 128;
 
+/// Size of the BDAT chunks that the mails of unknown size are sent in
+const BDAT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum number of lines of a reply, as each line is parsed on its own so
+/// only the size of a single line is limited by the size of the buffer
+const MAX_REPLY_LINES: usize = 1024;
+
+/// Maximum length of a line, without its CRLF, as per RFC 5321 section
+/// 4.5.3.1.6
+const MAX_LINE_LENGTH: usize = 998;
+
 const ZERO_DURATION: std::time::Duration = std::time::Duration::from_secs(0);
 
 pub type DynAsyncReadWrite =
     duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>;
 
-#[derive(Eq, Hash, PartialEq)]
+/// Where to send the mail for a recipient domain
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NextHop {
+    /// Resolve the MXs of the domain, as usual
+    Mx,
+
+    /// Send to this host and port, without any MX lookup, eg. for a
+    /// smart-host or an internal relay
+    Relay(Hostname, u16),
+}
+
+/// Order in which to try the addresses of a host
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpVersionPreference {
+    /// Follow the order given by the DNS server
+    AsResolved,
+    Ipv4First,
+    Ipv6First,
+    /// Alternate between the IPv6 and IPv4 addresses, starting with IPv6, as
+    /// per RFC 8305: when racing the addresses, IPv6 gets a head start of
+    /// `Config::connection_attempt_delay`, yet a black-holed IPv6 only delays
+    /// the connection by as much
+    Interleaved,
+}
+
+/// How to pick among the MXs that have the same preference
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MxBalancing {
+    /// Shuffle them independently for each connection
+    Random,
+
+    /// Start each connection to a domain with the MX following the one the
+    /// previous connection to this domain started with, so that draining
+    /// many mails to it at once spreads them evenly across its MXs
+    RoundRobin,
+}
+
+/// What to do with mails that have lines longer than allowed by RFC 5321
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LongLinePolicy {
+    /// Send the mail as-is, and let the remote server decide
+    Send,
+
+    /// Fail the delivery with `TransportError::LineTooLong`
+    Reject,
+
+    /// Break the long header lines by inserting CRLF followed by a space,
+    /// like postfix does, which turns them into folded headers. Long lines
+    /// in the body cannot be broken without altering its contents, so they
+    /// still fail the delivery with `TransportError::LineTooLong`.
+    Wrap,
+}
+
+/// What to do with mails that have 8-bit data, when the server does not
+/// support 8BITMIME (RFC 6152)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EightBitPolicy {
+    /// Send the mail as-is, and let the remote server decide
+    SendAnyway,
+
+    /// Fail the delivery with `TransportError::EightBitUnsupported`
+    Reject,
+
+    /// Re-encode the body of the mail as quoted-printable. This is only
+    /// possible for single-part mails whose headers are 7-bit, the other ones
+    /// failing with `TransportError::EightBitUnsupported`.
+    Downgrade,
+}
+
+#[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Destination {
     host: Hostname,
+    port: Option<u16>,
+    is_relay: bool,
+}
+
+impl Destination {
+    /// Connect to `port` instead of `Config::smtp_port`, eg. for relaying
+    /// through a submission server on port 587. MX records never carry a port,
+    /// so the override applies to all the MXs of the destination.
+    pub fn with_port(mut self, port: u16) -> Destination {
+        self.port = Some(port);
+        self
+    }
 }
 
 impl fmt::Display for Destination {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.host.fmt(f)
+        match self.port {
+            None => self.host.fmt(f),
+            Some(port) => write!(f, "{}:{}", self.host, port),
+        }
     }
 }
 
 #[async_trait]
-pub trait Config {
+pub trait Config: Send + Sync {
     fn ehlo_hostname(&self) -> Hostname<String>;
 
+    /// Name to announce in EHLO when connecting from `source_ip`, as returned
+    /// by `source_ip`. It should match the reverse DNS of `source_ip`.
+    #[allow(unused_variables)]
+    fn ehlo_hostname_for(&self, source_ip: IpAddr) -> Hostname<String> {
+        self.ehlo_hostname()
+    }
+
+    /// Called once per IP that a connection was attempted to, with whether
+    /// it succeeded, eg. for finding out which address of a host is broken
+    #[allow(unused_variables)]
+    fn on_connection_attempt(&self, ip: IpAddr, port: u16, res: Result<(), &TransportError>) {}
+
+    /// Local address to connect from when connecting to `ip`, eg. for hosts
+    /// with multiple addresses. `None` lets the operating system pick it.
+    #[allow(unused_variables)]
+    fn source_ip(&self, ip: IpAddr) -> Option<IpAddr> {
+        None
+    }
+
     fn can_do_tls(&self) -> bool {
         true
     }
@@ -55,11 +198,64 @@ pub trait Config {
         false
     }
 
+    /// For how long to skip STARTTLS with a server after negotiating TLS with
+    /// it failed and the client fell back to plaintext, so that the following
+    /// deliveries do not each waste a connection on it. Setting this to 0
+    /// makes the client try STARTTLS every time.
+    fn broken_starttls_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
+
+    /// Username and password to authenticate with, eg. for relaying through a
+    /// smarthost, along with the SASL mechanisms to use by order of
+    /// preference. Only `Mechanism::Plain` and `Mechanism::Login` are
+    /// supported, and the first one the server also supports is used.
+    fn auth(&self) -> Option<(String, String, Vec<Mechanism>)> {
+        None
+    }
+
+    /// Whether to send the credentials returned by `auth` over connections
+    /// that did not negotiate STARTTLS, instead of failing with
+    /// `TransportError::AuthRequiresTls`
+    fn allow_auth_without_tls(&self) -> bool {
+        false
+    }
+
     /// Note: If this function can only fail, make can_do_tls return false
     async fn tls_connect<IO>(&self, io: IO) -> io::Result<DynAsyncReadWrite>
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
 
+    /// Whether to look up the TLSA records of the hosts before connecting to
+    /// them, as per RFC 7672. This only makes sense if the resolver passed to
+    /// `Client::new` validates DNSSEC, as the records are otherwise trivially
+    /// spoofed.
+    fn use_dane(&self) -> bool {
+        false
+    }
+
+    /// Negotiates TLS like `tls_connect`, but checks the certificate of the
+    /// server against `tlsa`, that only holds the usable records: the usages
+    /// are `CertUsage::TrustAnchor` or `CertUsage::DomainIssued`, and the
+    /// matching types are `Raw`, `Sha256` or `Sha512`. The name of the server
+    /// is not to be checked for `DomainIssued` records.
+    ///
+    /// Note: This is only called if `use_dane` returns true
+    #[allow(unused_variables)]
+    async fn tls_connect_with_dane<IO>(
+        &self,
+        io: IO,
+        tlsa: &[TLSA],
+    ) -> io::Result<DynAsyncReadWrite>
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DANE is not supported by this configuration",
+        ))
+    }
+
     fn banner_read_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(5)
     }
@@ -76,6 +272,10 @@ pub trait Config {
         chrono::Duration::minutes(2)
     }
 
+    fn auth_reply_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(2)
+    }
+
     fn mail_reply_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(5)
     }
@@ -95,10 +295,180 @@ pub trait Config {
     fn data_end_reply_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(10)
     }
+
+    fn rset_reply_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(5)
+    }
+
+    fn noop_reply_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(5)
+    }
+
+    fn quit_reply_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(2)
+    }
+
+    /// Maximum number of idle connections to each destination that
+    /// `Client::release` keeps for `Client::connect` to reuse. Setting this
+    /// to 0 disables the connection pool.
+    fn connection_pool_size(&self) -> usize {
+        4
+    }
+
+    /// Time after which an idle connection of the pool is closed
+    fn connection_idle_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(1)
+    }
+
+    /// Number of times a connection can be reused from the pool, after which
+    /// `Client::release` closes it instead of pooling it again
+    fn max_connection_reuses(&self) -> usize {
+        100
+    }
+
+    /// Bound on the time taken by a whole delivery, ie. `Client::connect`
+    /// followed by `Sender::send`, whatever the per-command timeouts above.
+    /// When a sender is reused, each additional `Sender::send` gets this
+    /// whole time again.
+    fn overall_delivery_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(30)
+    }
+
+    /// Port to connect to for the destinations that do not set one with
+    /// `Destination::with_port`, eg. 587 for relaying from networks that
+    /// block outbound connections to port 25
+    fn smtp_port(&self) -> u16 {
+        SMTP_PORT
+    }
+
+    /// Addresses this server is reachable at. The client will never connect
+    /// to any of these, so as to avoid sending mail in a loop to itself when
+    /// an MX points back to us.
+    fn local_addresses(&self) -> &[IpAddr] {
+        &[]
+    }
+
+    /// Order in which to try the addresses of each MX, as well as the ones of
+    /// the domain itself when it has no MX
+    fn ip_version_preference(&self) -> IpVersionPreference {
+        IpVersionPreference::Interleaved
+    }
+
+    /// Time to wait for the TCP connection to an address of a host to be
+    /// established before also trying its next address, in the spirit of RFC
+    /// 8305. The first connection established wins, the other attempts are
+    /// cancelled, and the SMTP session is only set up on the winner.
+    fn connection_attempt_delay(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(250)
+    }
+
+    /// Whether to connect over IPv6. Disabling it avoids wasting connection
+    /// attempts on hosts without IPv6 egress.
+    fn use_ipv6(&self) -> bool {
+        true
+    }
+
+    fn mx_balancing(&self) -> MxBalancing {
+        MxBalancing::Random
+    }
+
+    /// Seed for shuffling the MXs with `MxBalancing::Random`. When set, each
+    /// connection tries the MXs of a domain in the same order, which makes it
+    /// reproducible, eg. for tests or for investigating a routing decision.
+    /// By default, the MXs are shuffled with the thread-local generator.
+    fn mx_shuffle_seed(&self) -> Option<u64> {
+        None
+    }
+
+    /// Maximum number of MXs of a domain to try, across all preference
+    /// levels, before giving up on it for this delivery. At least one MX is
+    /// always tried.
+    fn max_mx_attempts(&self) -> usize {
+        10
+    }
+
+    /// If this returns a signer, outgoing mails are DKIM-signed with it. Note
+    /// that signing requires reading each mail fully into memory before
+    /// sending it.
+    fn dkim_signer(&self) -> Option<&DkimSigner> {
+        None
+    }
+
+    /// Number of consecutive network failures to connect to a destination,
+    /// within `circuit_breaker_window`, after which `Client::connect` stops
+    /// trying to connect to it for `circuit_breaker_cooldown`. After the
+    /// cooldown, a single connection attempt is let through to probe the
+    /// destination. Setting this to 0 disables the circuit breaker.
+    fn circuit_breaker_threshold(&self) -> usize {
+        5
+    }
+
+    fn circuit_breaker_window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(10)
+    }
+
+    fn circuit_breaker_cooldown(&self) -> chrono::Duration {
+        chrono::Duration::minutes(5)
+    }
+
+    /// Where to send the mail for recipients at `domain`
+    #[allow(unused_variables)]
+    fn next_hop(&self, domain: &Hostname) -> NextHop {
+        NextHop::Mx
+    }
+
+    /// Domain to send the mail to for recipients without one, eg.
+    /// `<postmaster>`, as if it were theirs: `next_hop` can then route it to a
+    /// relay. If this returns `None`, delivering to such recipients fails
+    /// with `TransportError::NoRecipientDomain`.
+    fn domainless_recipient_domain(&self) -> Option<Hostname> {
+        None
+    }
+
+    /// Whether to accept replies whose lines end with a lone LF instead of
+    /// CRLF, as sent by some broken servers
+    fn accept_lf_only_replies(&self) -> bool {
+        false
+    }
+
+    /// What to do with mails that have lines longer than 998 octets. Anything
+    /// other than `LongLinePolicy::Send` requires reading each mail fully
+    /// into memory before sending it.
+    fn long_line_policy(&self) -> LongLinePolicy {
+        LongLinePolicy::Send
+    }
+
+    /// Whether to fold the header lines longer than 998 octets at their
+    /// whitespace, as allowed by RFC 5322 section 2.2.3, which unlike
+    /// `LongLinePolicy::Wrap` does not change the value of the headers. This
+    /// happens before `long_line_policy` is applied to the lines that are
+    /// still too long, and requires reading each mail fully into memory
+    /// before sending it.
+    fn fold_long_headers(&self) -> bool {
+        false
+    }
+
+    /// What to do with mails that have 8-bit data, when the server does not
+    /// advertise 8BITMIME. Anything other than `EightBitPolicy::SendAnyway`
+    /// requires reading each mail sent to such servers fully into memory
+    /// before sending it.
+    fn on_8bit_to_7bit_only(&self) -> EightBitPolicy {
+        EightBitPolicy::SendAnyway
+    }
+
+    /// Whether to speak LMTP (RFC 2033) instead of SMTP, eg. for handing the
+    /// mails over to a local delivery agent. The greeting is then LHLO, and
+    /// the server replies once per accepted recipient after the mail contents.
+    fn use_lmtp(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum TransportError {
+    #[error("Recipient ‘{0}’ has no domain")]
+    NoRecipientDomain(String),
+
     #[error("Retrieving MX DNS records for ‘{0}’")]
     DnsMx(String, #[source] ResolveError),
 
@@ -108,9 +478,24 @@ pub enum TransportError {
     #[error("Retrieving IP DNS records for ‘{1}’")]
     DnsIp(trust_dns_resolver::Name, #[source] ResolveError),
 
+    #[error("Retrieving TLSA DNS records for ‘{0}’")]
+    DnsTlsa(trust_dns_resolver::Name, #[source] ResolveError),
+
     #[error("Connecting to ‘{0}’ port ‘{1}’")]
     Connecting(IpAddr, u16, #[source] io::Error),
 
+    #[error("Refusing to connect to ‘{0}’, which is one of our local addresses (mail loop?)")]
+    LocalAddress(IpAddr),
+
+    #[error("Not connecting to ‘{0}’, as IPv6 is disabled")]
+    Ipv6Disabled(IpAddr),
+
+    #[error("No usable IP address for ‘{0}’")]
+    NoUsableAddress(trust_dns_resolver::Name),
+
+    #[error("Not connecting to ‘{0}’, which failed too many times recently")]
+    CircuitOpen(String),
+
     #[error("Receiving reply bytes")]
     ReceivingReplyBytes(#[source] io::Error),
 
@@ -129,6 +514,9 @@ pub enum TransportError {
     #[error("Timed out while sending a command")]
     TimedOutSendingCommand,
 
+    #[error("Timed out before the end of the delivery")]
+    TimedOutDelivering,
+
     #[error("Sending command")]
     SendingCommand(#[source] io::Error),
 
@@ -138,6 +526,21 @@ pub enum TransportError {
     #[error("Cannot do TLS with remote server")]
     CannotDoTls,
 
+    #[error("TLS is required but the remote server does not advertise STARTTLS")]
+    TlsRequiredButUnavailable,
+
+    #[error("Certificate of the remote server does not match its TLSA records")]
+    DaneValidationFailed(#[source] io::Error),
+
+    #[error("Refusing to authenticate over a connection without TLS")]
+    AuthRequiresTls,
+
+    #[error("Remote server supports none of the configured authentication mechanisms")]
+    NoAuthMechanism,
+
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(Reply),
+
     // TODO: add the command as error context
     #[error("Mail-level transient issue: {0}")]
     TransientMail(Reply),
@@ -168,8 +571,25 @@ pub enum TransportError {
 
     #[error("Reading the mail from the provided reader")]
     ReadingMail(#[source] io::Error),
+
+    #[error("Signing the mail with DKIM")]
+    SigningMail(#[source] DkimError),
+
+    #[error("Mail does not match the declared BDAT size of ‘{0}’ bytes")]
+    BdatSizeMismatch(u64),
+
+    #[error("Mail has a line longer than {} octets", MAX_LINE_LENGTH)]
+    LineTooLong,
+
+    #[error("Mail has 8-bit data, which the server does not support")]
+    EightBitUnsupported,
+
+    #[error("Mail has an address with a UTF-8 localpart, which the server does not support")]
+    Smtputf8Unsupported,
 }
 
+/// Ordered from the least to the most severe
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum TransportErrorSeverity {
     Local,
     NetworkTransient,
@@ -185,21 +605,38 @@ impl TransportError {
     pub fn severity(&self) -> TransportErrorSeverity {
         // TODO: Re-run over all these failure modes and check that the kind assignment
         // is correct. Maybe add categories like ProtocolPermanent for invalid
-        // hostnames, or LocalTransient for local errors like “too many sockets opened”?
+        // hostnames?
         match self {
+            TransportError::NoRecipientDomain(_) => TransportErrorSeverity::MailboxPermanent,
             TransportError::DnsMx(_, _) => TransportErrorSeverity::NetworkTransient,
             TransportError::HostToTrustDns(_, _) => TransportErrorSeverity::Local,
             TransportError::DnsIp(_, _) => TransportErrorSeverity::NetworkTransient,
+            TransportError::DnsTlsa(_, _) => TransportErrorSeverity::NetworkTransient,
+            TransportError::Connecting(_, _, e) if is_resource_exhaustion(e) => {
+                TransportErrorSeverity::Local
+            }
             TransportError::Connecting(_, _, _) => TransportErrorSeverity::NetworkTransient,
+            TransportError::LocalAddress(_) => TransportErrorSeverity::MailSystemPermanent,
+            TransportError::Ipv6Disabled(_) => TransportErrorSeverity::MailSystemTransient,
+            TransportError::NoUsableAddress(_) => TransportErrorSeverity::MailSystemTransient,
+            TransportError::CircuitOpen(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::ReceivingReplyBytes(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::TimedOutWaitingForReply => TransportErrorSeverity::NetworkTransient,
             TransportError::ConnectionAborted => TransportErrorSeverity::NetworkTransient,
             TransportError::TooLongReply(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::SyntaxError(_) => TransportErrorSeverity::MailSystemTransient,
             TransportError::TimedOutSendingCommand => TransportErrorSeverity::NetworkTransient,
+            TransportError::TimedOutDelivering => TransportErrorSeverity::NetworkTransient,
             TransportError::SendingCommand(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::NegotiatingTls(_) => TransportErrorSeverity::NetworkTransient, /* TODO: MailSystemPermanent? */
             TransportError::CannotDoTls => TransportErrorSeverity::NetworkTransient, /* TODO: MailSystemPermanent? */
+            TransportError::TlsRequiredButUnavailable => {
+                TransportErrorSeverity::MailSystemTransient
+            }
+            TransportError::DaneValidationFailed(_) => TransportErrorSeverity::MailSystemTransient,
+            TransportError::AuthRequiresTls => TransportErrorSeverity::MailSystemTransient,
+            TransportError::NoAuthMechanism => TransportErrorSeverity::MailSystemTransient,
+            TransportError::AuthenticationFailed(_) => TransportErrorSeverity::MailSystemPermanent,
             TransportError::TransientMail(_) => TransportErrorSeverity::MailTransient,
             TransportError::TransientMailbox(_) => TransportErrorSeverity::MailboxTransient,
             TransportError::TransientMailSystem(_) => TransportErrorSeverity::MailSystemTransient,
@@ -210,10 +647,32 @@ impl TransportError {
             TransportError::TimedOutSendingData => TransportErrorSeverity::NetworkTransient,
             TransportError::SendingData(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::ReadingMail(_) => TransportErrorSeverity::Local,
+            TransportError::SigningMail(_) => TransportErrorSeverity::Local,
+            TransportError::BdatSizeMismatch(_) => TransportErrorSeverity::Local,
+            TransportError::LineTooLong => TransportErrorSeverity::MailPermanent,
+            TransportError::EightBitUnsupported => TransportErrorSeverity::MailPermanent,
+            TransportError::Smtputf8Unsupported => TransportErrorSeverity::MailPermanent,
         }
     }
 }
 
+/// Whether `err` comes from our own system running out of resources, like
+/// file descriptors or ephemeral ports, rather than from the remote host: the
+/// mail is not at fault, and all deliveries should back off until it clears
+fn is_resource_exhaustion(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::AddrNotAvailable
+        || matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// Keeps the least severe of the errors, as it is the most likely to go away
+/// when retrying, or the earliest one if they are as severe
+fn least_severe(kept: Option<TransportError>, new: TransportError) -> Option<TransportError> {
+    match kept {
+        Some(kept) if kept.severity() <= new.severity() => Some(kept),
+        _ => Some(new),
+    }
+}
+
 async fn read_for_reply<T>(
     fut: impl Future<Output = io::Result<T>>,
     waiting_for_reply_since: &chrono::DateTime<Utc>,
@@ -234,16 +693,55 @@ async fn read_for_reply<T>(
     .await
 }
 
+/// Connects to `addr` from local address `source_ip`
+async fn connect_from(source_ip: IpAddr, addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = smol::unblock(move || {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.bind(&SocketAddr::new(source_ip, 0).into())?;
+        socket.connect(&addr.into())?;
+        Ok::<_, io::Error>(std::net::TcpStream::from(socket))
+    })
+    .await?;
+    TcpStream::try_from(stream)
+}
+
+/// Replaces the lone LFs in `rdbuf[range]` with CRLFs, returning the new end
+/// of the range, or `None` if there is not enough space left in `rdbuf`
+fn crlf_lone_lfs(rdbuf: &mut [u8], range: Range<usize>) -> Option<usize> {
+    let start = range.start;
+    let is_lone_lf = |buf: &[u8], i: usize| buf[i] == b'\n' && (i == start || buf[i - 1] != b'\r');
+    let lone_lfs = range.clone().filter(|&i| is_lone_lf(rdbuf, i)).count();
+    let end = range.end + lone_lfs;
+    if end > rdbuf.len() {
+        return None;
+    }
+    // Move the bytes from the end, so that none is overwritten before being moved
+    let mut w = end;
+    for i in range.rev() {
+        let lone_lf = is_lone_lf(rdbuf, i);
+        w -= 1;
+        rdbuf[w] = rdbuf[i];
+        if lone_lf {
+            w -= 1;
+            rdbuf[w] = b'\r';
+        }
+    }
+    Some(end)
+}
+
 async fn read_reply<IO>(
     io: &mut IO,
     rdbuf: &mut [u8; RDBUF_SIZE],
     unhandled: &mut Range<usize>,
     timeout: chrono::Duration,
+    accept_lf_only: bool,
 ) -> Result<Reply, TransportError>
 where
     IO: Unpin + Send + AsyncRead + AsyncWrite,
 {
     let start = Utc::now();
+    // Text of the already parsed lines of the reply
+    let mut text = Vec::new();
     // TODO: try to think of unifying this logic with the one in smtp-server?
     if (*unhandled).is_empty() {
         *unhandled = 0..read_for_reply(io.read(rdbuf), &start, timeout).await?;
@@ -252,13 +750,23 @@ where
         }
     }
     loop {
+        if accept_lf_only {
+            unhandled.end = match crlf_lone_lfs(rdbuf, unhandled.clone()) {
+                Some(end) => end,
+                None => {
+                    return Err(TransportError::TooLongReply(
+                        String::from_utf8_lossy(&rdbuf[unhandled.clone()]).to_string(),
+                    ))
+                }
+            };
+        }
         trace!(
             buf = String::from_utf8_lossy(&rdbuf[unhandled.clone()]).as_ref(),
             "Trying to parse from buffer"
         );
-        match Reply::<&str>::parse(&rdbuf[unhandled.clone()]) {
+        match ReplyLine::<&str>::parse(&rdbuf[unhandled.clone()]) {
             Err(nom::Err::Incomplete(n)) => {
-                // Don't have enough data to handle command, let's fetch more
+                // Don't have enough data to handle the line, let's fetch more
                 if unhandled.start != 0 {
                     // Do we have to copy the data to the beginning of the buffer?
                     let missing = match n {
@@ -274,9 +782,7 @@ where
                 if unhandled.end == rdbuf.len() {
                     // If we reach here, it means that unhandled is already
                     // basically the full buffer. Which means that we have to
-                    // error out that the reply is too big.
-                    // TODO: maybe there's something intelligent to be done here, like parsing reply
-                    // line per reply line?
+                    // error out that the reply line is too big.
                     return Err(TransportError::TooLongReply(
                         String::from_utf8_lossy(&rdbuf[unhandled.clone()]).to_string(),
                     ));
@@ -297,13 +803,25 @@ where
                     String::from_utf8_lossy(&rdbuf[unhandled.clone()]).to_string(),
                 ));
             }
-            Ok((rem, reply)) => {
-                // Got a reply
+            Ok((rem, line)) => {
+                // Got a reply line
+                let line_start = unhandled.start;
                 unhandled.start = unhandled.end - rem.len();
-                // TODO: when polonius is ready, we can remove this allocation by returning a
-                // borrow of the input buffer (with NLL it conflicts with the mutable borrow of
-                // rdbuf in the other match arm)
-                return Ok(reply.into_owned());
+                text.push(line.text.to_owned());
+                if line.last {
+                    // The code of the last line is the one of the reply, like
+                    // in Reply::parse
+                    return Ok(Reply {
+                        code: line.code,
+                        ecode: line.ecode.map(|c| c.to_owned()),
+                        text,
+                    });
+                }
+                if text.len() >= MAX_REPLY_LINES {
+                    return Err(TransportError::TooLongReply(
+                        String::from_utf8_lossy(&rdbuf[line_start..unhandled.start]).to_string(),
+                    ));
+                }
             }
         }
     }
@@ -333,20 +851,60 @@ async fn send_command<IO>(
 where
     IO: Unpin + Send + AsyncRead + AsyncWrite,
 {
-    trace!(
-        cmd = String::from_utf8_lossy(&{
-            let mut v = Vec::new();
-            for s in cmd.as_io_slices() {
-                v.extend_from_slice(&*s);
-            }
-            v
-        })
-        .as_ref(),
-        "Sending command"
-    );
+    send_commands(io, std::slice::from_ref(&cmd), timeout).await
+}
+
+/// Same as `send_command`, but sends all of `cmds` in a single write, for
+/// pipelining them
+async fn send_commands<IO>(
+    io: &mut IO,
+    cmds: &[Command<&str>],
+    timeout: chrono::Duration,
+) -> Result<(), TransportError>
+where
+    IO: Unpin + Send + AsyncRead + AsyncWrite,
+{
+    // Gather the commands in a single buffer, as streams without support for
+    // vectored writes would otherwise write each of their parts separately
+    let mut buf = Vec::new();
+    for cmd in cmds {
+        let start = buf.len();
+        for s in cmd.as_io_slices() {
+            buf.extend_from_slice(&*s);
+        }
+        trace!(
+            cmd = String::from_utf8_lossy(&buf[start..]).as_ref(),
+            "Sending command"
+        );
+    }
+    smol::future::or(
+        async {
+            io.write_all(&buf)
+                .await
+                .map_err(TransportError::SendingCommand)?;
+            Ok(())
+        },
+        async {
+            smol::Timer::after(timeout.to_std().unwrap_or(ZERO_DURATION)).await;
+            Err(TransportError::TimedOutSendingCommand)
+        },
+    )
+    .await
+}
+
+/// Same as `send_command`, for lines that are not commands, eg. SASL
+/// responses. `line` is not logged, as it may hold credentials.
+async fn send_line<IO>(
+    io: &mut IO,
+    line: &str,
+    timeout: chrono::Duration,
+) -> Result<(), TransportError>
+where
+    IO: Unpin + Send + AsyncRead + AsyncWrite,
+{
     smol::future::or(
         async {
-            io.write_all_vectored(&mut cmd.as_io_slices().collect::<Vec<_>>())
+            io.write_all(format!("{}\r\n", line).as_bytes())
                 .await
                 .map_err(TransportError::SendingCommand)?;
             Ok(())
@@ -359,6 +917,116 @@ where
     .await
 }
 
+enum CircuitState {
+    Closed {
+        failures: usize,
+        since: DateTime<Utc>,
+    },
+    Open {
+        until: DateTime<Utc>,
+    },
+    Probing {
+        since: DateTime<Utc>,
+    },
+}
+
+/// Circuit breakers of the client, keyed by destination
+#[derive(Default)]
+struct CircuitBreakers {
+    states: Mutex<HashMap<Hostname, CircuitState>>,
+}
+
+impl CircuitBreakers {
+    /// Returns whether a connection to `host` can be attempted
+    fn allows<Cfg: Config>(&self, cfg: &Cfg, host: &Hostname, now: DateTime<Utc>) -> bool {
+        let mut states = self.states.lock().unwrap();
+        match states.get(host) {
+            None | Some(CircuitState::Closed { .. }) => true,
+            Some(CircuitState::Open { until }) if *until > now => false,
+            // If the probe did not complete, eg. because the connection attempt
+            // was cancelled, allow another one after the cooldown
+            Some(CircuitState::Probing { since })
+                if now - *since < cfg.circuit_breaker_cooldown() =>
+            {
+                false
+            }
+            Some(CircuitState::Open { .. }) | Some(CircuitState::Probing { .. }) => {
+                states.insert(host.clone(), CircuitState::Probing { since: now });
+                true
+            }
+        }
+    }
+
+    /// Records the result of a connection attempt to `host`, `failed` being
+    /// whether it failed at the network level
+    fn record<Cfg: Config>(&self, cfg: &Cfg, host: &Hostname, failed: bool, now: DateTime<Utc>) {
+        let threshold = cfg.circuit_breaker_threshold();
+        if threshold == 0 {
+            return;
+        }
+        let mut states = self.states.lock().unwrap();
+        if !failed {
+            states.remove(host);
+            return;
+        }
+        let (failures, since) = match states.get(host) {
+            Some(CircuitState::Closed { failures, since })
+                if now - *since < cfg.circuit_breaker_window() =>
+            {
+                (failures + 1, *since)
+            }
+            Some(CircuitState::Probing { .. }) => (threshold, now),
+            _ => (1, now),
+        };
+        let state = if failures >= threshold {
+            trace!("Opening the circuit breaker for {}", host);
+            CircuitState::Open {
+                until: now + cfg.circuit_breaker_cooldown(),
+            }
+        } else {
+            CircuitState::Closed { failures, since }
+        };
+        states.insert(host.clone(), state);
+    }
+}
+
+/// Sorts `ips` by `preference`, keeping the DNS order among the addresses of
+/// a single IP version, and removes the IPv6 ones unless `use_ipv6`
+fn order_addresses<I>(ips: I, preference: IpVersionPreference, use_ipv6: bool) -> Vec<IpAddr>
+where
+    I: IntoIterator<Item = IpAddr>,
+{
+    let mut ips = ips
+        .into_iter()
+        .filter(|ip| use_ipv6 || ip.is_ipv4())
+        .collect::<Vec<_>>();
+    match preference {
+        IpVersionPreference::AsResolved => (),
+        IpVersionPreference::Ipv4First => ips.sort_by_key(|ip| ip.is_ipv6()),
+        IpVersionPreference::Ipv6First => ips.sort_by_key(|ip| ip.is_ipv4()),
+        IpVersionPreference::Interleaved => {
+            let (v6, v4): (Vec<_>, Vec<_>) = ips.into_iter().partition(|ip| ip.is_ipv6());
+            let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+            ips = Vec::new();
+            loop {
+                match (v6.next(), v4.next()) {
+                    (None, None) => break,
+                    (a, b) => ips.extend(a.into_iter().chain(b)),
+                }
+            }
+        }
+    }
+    ips
+}
+
+/// A TCP connection to a server, on which no SMTP exchange happened yet
+struct TcpConnection {
+    io: TcpStream,
+    /// The local address `io` was bound to, if it was picked by
+    /// `Config::source_ip`
+    source_ip: Option<IpAddr>,
+}
+
 pub struct Client<C, P, Cfg>
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
@@ -367,6 +1035,15 @@ where
 {
     resolver: AsyncResolver<C, P>,
     cfg: Arc<Cfg>,
+    circuit_breakers: CircuitBreakers,
+    /// Number of connections started to each domain with
+    /// `MxBalancing::RoundRobin`
+    mx_rotations: Mutex<HashMap<String, usize>>,
+    /// Idle connections given back with `release`, most recent last
+    pool: Mutex<HashMap<Destination, Vec<IdleSender<Cfg>>>>,
+    /// Servers with which negotiating TLS failed, and until when STARTTLS is
+    /// not attempted with them
+    broken_starttls: Mutex<HashMap<(IpAddr, u16), DateTime<Utc>>>,
 }
 
 impl<C, P, Cfg> Client<C, P, Cfg>
@@ -375,31 +1052,176 @@ where
     P: trust_dns_resolver::ConnectionProvider<Conn = C>,
     Cfg: Config,
 {
-    /// Note: Passing as `resolver` something that is configured with
-    /// `Ipv6andIpv4` may lead to unexpected behavior, as the client will
-    /// attempt to connect to both the Ipv6 and the Ipv4 address if whichever
-    /// comes first doesn't successfully connect. In particular, it means that
-    /// performance could be degraded.
+    /// Note: `resolver` should be configured with `Ipv6andIpv4`, so that the
+    /// client can race the IPv6 and IPv4 addresses of each host as per
+    /// `Config::ip_version_preference`. With `Ipv6thenIpv4` or `Ipv4thenIpv6`,
+    /// only the addresses of the second version are returned when there are
+    /// none of the first.
     pub fn new(resolver: AsyncResolver<C, P>, cfg: Arc<Cfg>) -> Client<C, P, Cfg> {
-        Client { resolver, cfg }
+        Client {
+            resolver,
+            cfg,
+            circuit_breakers: CircuitBreakers::default(),
+            mx_rotations: Mutex::new(HashMap::new()),
+            pool: Mutex::new(HashMap::new()),
+            broken_starttls: Mutex::new(HashMap::new()),
+        }
     }
 
     pub async fn get_destination(&self, host: &Hostname) -> Result<Destination, TransportError> {
         // TODO: already resolve here, but that means having to handle DNS expiration
         // down the road
-        Ok(Destination { host: host.clone() })
+        Ok(match self.cfg.next_hop(host) {
+            NextHop::Mx => Destination {
+                host: host.clone(),
+                port: None,
+                is_relay: false,
+            },
+            NextHop::Relay(relay, port) => Destination {
+                host: relay,
+                port: Some(port),
+                is_relay: true,
+            },
+        })
+    }
+
+    /// Where to send the mail for `to`, following
+    /// `Config::domainless_recipient_domain` if it has no domain
+    pub async fn get_recipient_destination(
+        &self,
+        to: &Email,
+    ) -> Result<Destination, TransportError> {
+        match to.hostname {
+            Some(ref host) => self.get_destination(host).await,
+            None => match self.cfg.domainless_recipient_domain() {
+                Some(host) => self.get_destination(&host).await,
+                None => Err(TransportError::NoRecipientDomain(to.to_string())),
+            },
+        }
     }
 
+    /// Connects to `dest`, unless the connections to it failed too often
+    /// recently, in which case this returns `TransportError::CircuitOpen`
+    /// without trying
+    ///
+    /// If a connection to `dest` was given back with `release`, it is reused
+    /// instead, provided it is still idle for less than
+    /// `Config::connection_idle_timeout` and the server replies to NOOP.
+    ///
+    /// This starts the time allotted to the delivery by
+    /// `Config::overall_delivery_timeout`, the remainder of which is left to
+    /// the first `Sender::send`.
     pub async fn connect(&self, dest: &Destination) -> Result<Sender<Cfg>, TransportError> {
-        match dest.host {
-            Hostname::Ipv4 { ip, .. } => self.connect_to_ip(IpAddr::V4(ip), SMTP_PORT).await,
-            Hostname::Ipv6 { ip, .. } => self.connect_to_ip(IpAddr::V6(ip), SMTP_PORT).await,
-            Hostname::AsciiDomain { ref raw } => self.connect_to_mx(raw).await,
-            Hostname::Utf8Domain { ref punycode, .. } => self.connect_to_mx(punycode).await,
+        let cfg = &*self.cfg;
+        if let Some(sender) = self.check_out_pooled(dest).await {
+            return Ok(sender);
+        }
+        if !self.circuit_breakers.allows(cfg, &dest.host, Utc::now()) {
+            return Err(TransportError::CircuitOpen(dest.to_string()));
+        }
+        let deadline = Instant::now()
+            + cfg
+                .overall_delivery_timeout()
+                .to_std()
+                .unwrap_or(ZERO_DURATION);
+        let port = dest.port.unwrap_or_else(|| cfg.smtp_port());
+        let res = smol::future::or(
+            async {
+                match dest.host {
+                    Hostname::Ipv4 { ip, .. } => self.connect_to_ip(IpAddr::V4(ip), port).await,
+                    Hostname::Ipv6 { ip, .. } => self.connect_to_ip(IpAddr::V6(ip), port).await,
+                    Hostname::AsciiDomain { ref raw } if dest.is_relay => {
+                        self.connect_to_domain(raw, port).await
+                    }
+                    Hostname::Utf8Domain { ref punycode, .. } if dest.is_relay => {
+                        self.connect_to_domain(punycode, port).await
+                    }
+                    Hostname::AsciiDomain { ref raw } => self.connect_to_mx(raw, port).await,
+                    Hostname::Utf8Domain { ref punycode, .. } => {
+                        self.connect_to_mx(punycode, port).await
+                    }
+                }
+            },
+            async {
+                smol::Timer::at(deadline).await;
+                Err(TransportError::TimedOutDelivering)
+            },
+        )
+        .await;
+        let failed = matches!(
+            res.as_ref().map_err(|e| e.severity()),
+            Err(TransportErrorSeverity::NetworkTransient)
+        );
+        self.circuit_breakers
+            .record(cfg, &dest.host, failed, Utc::now());
+        res.map(|sender| Sender {
+            deadline: Some(deadline),
+            ..sender
+        })
+    }
+
+    /// Gives back `sender`, connected to `dest`, once done sending mails with
+    /// it. It is reset with RSET and kept idle for the next `connect` to
+    /// `dest`, unless the pool of `dest` is full or the sender was already
+    /// reused `Config::max_connection_reuses` times, in which case the
+    /// connection is closed.
+    pub async fn release(&self, dest: &Destination, mut sender: Sender<Cfg>) {
+        let cfg = &*self.cfg;
+        let pool_size = cfg.connection_pool_size();
+        if pool_size == 0 || sender.reuses >= cfg.max_connection_reuses() {
+            // Boxed here and below, as a sender is big enough for the
+            // future of release to otherwise double in size
+            Box::pin(sender.quit()).await;
+            return;
+        }
+        if let Err(e) = sender.reset().await {
+            trace!(error = ?e, "Failed resetting the sender, not pooling it");
+            return;
+        }
+        let ttl = cfg
+            .connection_idle_timeout()
+            .to_std()
+            .unwrap_or(ZERO_DURATION);
+        let now = Instant::now();
+        let evicted = {
+            let mut pool = self.pool.lock().unwrap();
+            let idle = pool.entry(dest.clone()).or_default();
+            let (mut evicted, alive): (Vec<_>, Vec<_>) =
+                idle.drain(..).partition(|s| s.expires() <= now);
+            *idle = alive;
+            if idle.len() < pool_size {
+                idle.push(sender.into_idle(ttl));
+            } else {
+                evicted.push(sender.into_idle(ttl));
+            }
+            evicted
+        };
+        for sender in evicted {
+            Box::pin(sender.close()).await;
+        }
+    }
+
+    /// Returns the most recently released sender to `dest` that is still
+    /// usable, closing the expired ones along the way
+    async fn check_out_pooled(&self, dest: &Destination) -> Option<Sender<Cfg>> {
+        loop {
+            let idle = self.pool.lock().unwrap().get_mut(dest)?.pop()?;
+            // Boxed, as a sender is big enough for the future of connect to
+            // otherwise double in size
+            if let Some(mut sender) = Box::pin(idle.check_out()).await {
+                sender.reuses += 1;
+                return Some(sender);
+            }
         }
     }
 
-    pub async fn connect_to_mx(&self, host: &str) -> Result<Sender<Cfg>, TransportError> {
+    /// Connects to `port` on the MXs of `host`, which is usually the SMTP port
+    /// as MX records do not carry a port
+    pub async fn connect_to_mx(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Sender<Cfg>, TransportError> {
         // TODO: consider adding a `.` at the end of `host`... but is it
         // actually allowed?
         // Run MX lookup
@@ -413,7 +1235,7 @@ where
                         .connect_to_host(
                             host.into_name()
                                 .map_err(|e| TransportError::HostToTrustDns(host.to_owned(), e))?,
-                            SMTP_PORT,
+                            port,
                         )
                         .await;
                 } else {
@@ -439,28 +1261,56 @@ where
                 .connect_to_host(
                     host.into_name()
                         .map_err(|e| TransportError::HostToTrustDns(host.to_owned(), e))?,
-                    SMTP_PORT,
+                    port,
                 )
                 .await;
         }
 
+        let balancing = self.cfg.mx_balancing();
+        let rotation = match balancing {
+            MxBalancing::Random => 0,
+            MxBalancing::RoundRobin => {
+                let mut rotations = self.mx_rotations.lock().unwrap();
+                let next = rotations.entry(host.to_owned()).or_insert(0);
+                let rotation = *next;
+                *next = next.wrapping_add(1);
+                rotation
+            }
+        };
+
+        let mut seeded_rng = self.cfg.mx_shuffle_seed().map(StdRng::seed_from_u64);
+
         // By increasing order of priority, try each MX
-        // TODO: definitely should not return the first error but the first least severe
-        // error
         let mut first_error = None;
-        for (_, mut mxes) in mx_records {
-            // Among a single priority level, randomize the order
-            // TODO: consider giving a way to seed for reproducibility?
-            mxes.shuffle(&mut rand::thread_rng());
+        let mut attempts_left = cmp::max(1, self.cfg.max_mx_attempts());
+        'levels: for (_, mut mxes) in mx_records {
+            // Among a single priority level, spread the load
+            match balancing {
+                MxBalancing::Random => match seeded_rng {
+                    Some(ref mut rng) => mxes.shuffle(rng),
+                    None => mxes.shuffle(&mut rand::thread_rng()),
+                },
+                MxBalancing::RoundRobin => {
+                    // The DNS server may return the records in any order
+                    mxes.sort();
+                    let len = mxes.len();
+                    mxes.rotate_left(rotation % len);
+                }
+            }
 
             // Then try to connect to each address
             // TODO: sometimes the DNS server already returns the IP alongside the MX record
             // in the answer to the MX request, in which case we could directly
             // connect_to_ip
             for mx in mxes {
-                match self.connect_to_host(mx.clone(), SMTP_PORT).await {
+                if attempts_left == 0 {
+                    trace!("Giving up on the remaining MXs of {}", host);
+                    break 'levels;
+                }
+                attempts_left -= 1;
+                match self.connect_to_host(mx.clone(), port).await {
                     Ok(sender) => return Ok(sender),
-                    Err(e) => first_error = first_error.or(Some(e)),
+                    Err(e) => first_error = least_severe(first_error, e),
                 }
             }
         }
@@ -476,6 +1326,21 @@ where
         Err(first_error.unwrap())
     }
 
+    /// Connects to `port` on the A/AAAA records of `host`, skipping the MX
+    /// lookup
+    async fn connect_to_domain(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_host(
+            host.into_name()
+                .map_err(|e| TransportError::HostToTrustDns(host.to_owned(), e))?,
+            port,
+        )
+        .await
+    }
+
     async fn connect_to_host(
         &self,
         name: trust_dns_resolver::Name,
@@ -486,21 +1351,157 @@ where
             .resolver
             .lookup_ip(name.clone())
             .await
-            .map_err(|e| TransportError::DnsIp(name, e))?;
+            .map_err(|e| TransportError::DnsIp(name.clone(), e))?;
+        let addresses = order_addresses(
+            lookup.iter(),
+            self.cfg.ip_version_preference(),
+            self.cfg.use_ipv6(),
+        );
+        if addresses.is_empty() {
+            return Err(TransportError::NoUsableAddress(name));
+        }
+        let tlsa = match self.cfg.use_dane() {
+            true => self.lookup_tlsa(&name, port).await?,
+            false => None,
+        };
 
-        // Following the order given by the DNS server, attempt connecting
-        // TODO: definitely should not return the first error but the first least severe
-        // error
+        // Following the configured order, attempt connecting, skipping our own
+        // addresses: if all the addresses are local, this is a misconfiguration
+        // that will not fix itself by retrying
+        let local_addresses = self.cfg.local_addresses();
         let mut first_error = None;
-        for ip in lookup.iter() {
-            match self.connect_to_ip(ip, port).await {
+        let mut local_error = None;
+        let mut addresses = addresses
+            .into_iter()
+            .filter(|ip| {
+                if local_addresses.contains(ip) {
+                    trace!("Skipping local address {}", ip);
+                    local_error.get_or_insert(TransportError::LocalAddress(*ip));
+                    return false;
+                }
+                true
+            })
+            .collect::<VecDeque<_>>();
+        while let Some((ip, conn)) = self.race_tcp(&mut addresses, port, &mut first_error).await {
+            let res = self
+                .setup_ip_connection(ip, port, conn, tlsa.as_deref())
+                .await;
+            self.cfg
+                .on_connection_attempt(ip, port, res.as_ref().map(|_| ()));
+            match res {
                 Ok(sender) => return Ok(sender),
-                Err(e) => first_error = first_error.or(Some(e)),
+                Err(e) => first_error = least_severe(first_error, e),
             }
         }
 
-        // See comment on connect_to_mx above for why this unwrap is correct
-        Err(first_error.unwrap())
+        // See comment on connect_to_mx above for why this unwrap is correct, with
+        // skipped local addresses being counted as errors
+        Err(first_error.or(local_error).unwrap())
+    }
+
+    /// Opens a TCP connection to one of `addresses`, in the spirit of RFC
+    /// 8305: each address gets `Config::connection_attempt_delay` to connect
+    /// before the next one is also attempted, and the first connection wins.
+    /// Only the TCP connection is raced, so that a server slow to send its
+    /// banner does not get several SMTP sessions.
+    ///
+    /// The addresses are removed from `addresses` as they are attempted, but
+    /// the ones whose attempt got cancelled by the winner are put back, to be
+    /// retried if the winner turns out to be unusable. Returns `None` once all
+    /// the addresses failed, with their errors merged into `first_error`.
+    async fn race_tcp(
+        &self,
+        addresses: &mut VecDeque<IpAddr>,
+        port: u16,
+        first_error: &mut Option<TransportError>,
+    ) -> Option<(IpAddr, TcpConnection)> {
+        let delay = self
+            .cfg
+            .connection_attempt_delay()
+            .to_std()
+            .unwrap_or(ZERO_DURATION);
+        let mut attempts = FuturesUnordered::new();
+        let mut pending = Vec::new();
+        loop {
+            // Start the next attempt, be it the first one or because the previous
+            // one failed or is taking too long
+            if let Some(ip) = addresses.pop_front() {
+                pending.push(ip);
+                attempts.push(async move { (ip, self.open_tcp(ip, port).await) });
+            }
+            let res = if !addresses.is_empty() {
+                smol::future::or(async { attempts.next().await }, async {
+                    smol::Timer::after(delay).await;
+                    None
+                })
+                .await
+            } else {
+                attempts.next().await
+            };
+            match res {
+                Some((ip, Ok(conn))) => {
+                    // Dropping the attempts still running closes their sockets
+                    pending.retain(|p| *p != ip);
+                    for p in pending.into_iter().rev() {
+                        addresses.push_front(p);
+                    }
+                    return Some((ip, conn));
+                }
+                Some((ip, Err(e))) => {
+                    pending.retain(|p| *p != ip);
+                    self.cfg.on_connection_attempt(ip, port, Err(&e));
+                    *first_error = least_severe(first_error.take(), e);
+                }
+                None if attempts.is_empty() && addresses.is_empty() => return None,
+                None => (),
+            }
+        }
+    }
+
+    /// Returns the usable TLSA records of `port` on `name`, or `None` if
+    /// there are none, in which case TLS is not authenticated with DANE
+    async fn lookup_tlsa(
+        &self,
+        name: &trust_dns_resolver::Name,
+        port: u16,
+    ) -> Result<Option<Vec<TLSA>>, TransportError> {
+        let tlsa_name = trust_dns_resolver::Name::from_ascii(format!("_{}._tcp", port))
+            .and_then(|n| n.append_domain(name))
+            .map_err(|e| TransportError::HostToTrustDns(name.to_ascii(), e))?;
+        let lookup = match self
+            .resolver
+            .lookup(tlsa_name, RecordType::TLSA, DnsRequestOptions::default())
+            .await
+        {
+            Ok(l) => l,
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => return Ok(None),
+                _ => return Err(TransportError::DnsTlsa(name.clone(), e)),
+            },
+        };
+        let tlsa = lookup
+            .iter()
+            .filter_map(|rdata| match rdata {
+                RData::TLSA(tlsa) => Some(tlsa),
+                _ => None,
+            })
+            .filter(|tlsa| {
+                matches!(
+                    tlsa.cert_usage(),
+                    CertUsage::TrustAnchor | CertUsage::DomainIssued
+                ) && matches!(tlsa.selector(), Selector::Full | Selector::Spki)
+                    && matches!(
+                        tlsa.matching(),
+                        Matching::Raw | Matching::Sha256 | Matching::Sha512
+                    )
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if tlsa.is_empty() {
+            trace!("No usable TLSA record for {}, not using DANE", name);
+            return Ok(None);
+        }
+        Ok(Some(tlsa))
     }
 
     pub async fn connect_to_ip(
@@ -508,29 +1509,142 @@ where
         ip: IpAddr,
         port: u16,
     ) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_ip_with_tlsa(ip, port, None).await
+    }
+
+    /// If `tlsa` is set, TLS is mandatory and the certificate of the server
+    /// must match one of these records
+    async fn connect_to_ip_with_tlsa(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        tlsa: Option<&[TLSA]>,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        let res = match self.open_tcp(ip, port).await {
+            Ok(conn) => self.setup_ip_connection(ip, port, conn, tlsa).await,
+            Err(e) => Err(e),
+        };
+        self.cfg
+            .on_connection_attempt(ip, port, res.as_ref().map(|_| ()));
+        res
+    }
+
+    /// Sets up the SMTP session on `conn`, freshly opened to `ip` on `port`
+    async fn setup_ip_connection(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        conn: TcpConnection,
+        tlsa: Option<&[TLSA]>,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        let can_skip_tls = !self.cfg.must_do_tls() && tlsa.is_none();
+        let try_tls = !can_skip_tls || !self.is_starttls_broken(ip, port);
+        match self.setup_tcp_stream(conn, try_tls, tlsa).await {
+            // The connection is unusable once the TLS handshake failed, so
+            // reconnect to deliver in plaintext, unless TLS is mandatory
+            Err(TransportError::NegotiatingTls(e)) if can_skip_tls => {
+                warn!(
+                    error = ?e,
+                    "Negotiating TLS with {}:{} failed, retrying without it",
+                    ip,
+                    port
+                );
+                let conn = self.open_tcp(ip, port).await?;
+                let res = self.setup_tcp_stream(conn, false, None).await;
+                if res.is_ok() {
+                    let ttl = self.cfg.broken_starttls_ttl();
+                    if ttl > chrono::Duration::zero() {
+                        let mut broken = self.broken_starttls.lock().unwrap();
+                        broken.insert((ip, port), Utc::now() + ttl);
+                    }
+                }
+                res
+            }
+            res => res,
+        }
+    }
+
+    /// Returns whether negotiating TLS with `ip` on `port` failed recently
+    fn is_starttls_broken(&self, ip: IpAddr, port: u16) -> bool {
+        let mut broken = self.broken_starttls.lock().unwrap();
+        match broken.get(&(ip, port)) {
+            Some(until) if *until > Utc::now() => true,
+            Some(_) => {
+                broken.remove(&(ip, port));
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Opens a TCP connection to `ip` on `port`, without any SMTP exchange
+    async fn open_tcp(&self, ip: IpAddr, port: u16) -> Result<TcpConnection, TransportError> {
         // TODO: introduce a connection uuid to associate log messages together
         trace!("Connecting to ip {}:{}", ip, port);
-        // TODO: bind to specified outgoing IP address with net2 (first bind the builder
-        // to the outgoing IP, then connect)
-        let io = TcpStream::connect((ip, port))
-            .await
-            .map_err(|e| TransportError::Connecting(ip, port, e))?;
-        let (reader, writer) = io.split();
-        self.connect_to_stream(duplexify::Duplex::new(Box::pin(reader), Box::pin(writer)))
-            .await
+        if self.cfg.local_addresses().contains(&ip) {
+            return Err(TransportError::LocalAddress(ip));
+        }
+        if ip.is_ipv6() && !self.cfg.use_ipv6() {
+            return Err(TransportError::Ipv6Disabled(ip));
+        }
+        let source_ip = self.cfg.source_ip(ip);
+        let io = match source_ip {
+            None => TcpStream::connect((ip, port)).await,
+            Some(source_ip) => connect_from(source_ip, SocketAddr::new(ip, port)).await,
+        }
+        .map_err(|e| TransportError::Connecting(ip, port, e))?;
+        Ok(TcpConnection { io, source_ip })
+    }
+
+    async fn setup_tcp_stream(
+        &self,
+        conn: TcpConnection,
+        try_tls: bool,
+        tlsa: Option<&[TLSA]>,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        let (reader, writer) = conn.io.split();
+        // Boxed, as a sender is big enough for the futures of the callers,
+        // that may set up two sessions, to otherwise overflow the stack
+        Box::pin(self.setup_stream(
+            duplexify::Duplex::new(Box::pin(reader), Box::pin(writer)),
+            conn.source_ip,
+            try_tls,
+            tlsa,
+        ))
+        .await
     }
 
     // TODO: add a connect_to_{host,ip}_smtps
 
+    /// Note: as `io` cannot be reopened, a failed TLS handshake is returned
+    /// as `TransportError::NegotiatingTls`, instead of falling back to
+    /// plaintext like the other `connect_*` functions do
     pub async fn connect_to_stream(
         &self,
         io: DynAsyncReadWrite,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        self.setup_stream(io, None, true, None).await
+    }
+
+    /// `source_ip` is the local address `io` was bound to, if it was picked
+    /// by `Config::source_ip`. STARTTLS is only attempted if `try_tls` is set,
+    /// and is mandatory and authenticated with DANE if `tlsa` is set.
+    async fn setup_stream(
+        &self,
+        io: DynAsyncReadWrite,
+        source_ip: Option<IpAddr>,
+        try_tls: bool,
+        tlsa: Option<&[TLSA]>,
     ) -> Result<Sender<Cfg>, TransportError> {
         let mut sender = Sender {
             io,
             rdbuf: [0; RDBUF_SIZE],
             unhandled: 0..0,
-            extensions: Extensions::empty(),
+            capabilities: Capabilities::default(),
+            source_ip,
+            deadline: None,
+            pipelining_desynced: false,
+            reuses: 0,
             cfg: self.cfg.clone(),
         };
         // TODO: Are there interesting things to do with replies apart from checking
@@ -542,6 +1656,7 @@ where
             &mut sender.rdbuf,
             &mut sender.unhandled,
             self.cfg.banner_read_timeout(),
+            self.cfg.accept_lf_only_replies(),
         )
         .await?;
         verify_reply(reply, ReplyCodeKind::PositiveCompletion)?;
@@ -553,7 +1668,7 @@ where
 
         // Send STARTTLS if possible
         let mut did_tls = false;
-        if sender.extensions.contains(Extensions::STARTTLS) && self.cfg.can_do_tls() {
+        if try_tls && sender.capabilities.starttls && self.cfg.can_do_tls() {
             // Send STARTTLS and check the reply
             send_command(
                 &mut sender.io,
@@ -566,24 +1681,29 @@ where
                 &mut sender.rdbuf,
                 &mut sender.unhandled,
                 self.cfg.starttls_reply_timeout(),
+                self.cfg.accept_lf_only_replies(),
             )
             .await?;
             if let Ok(()) = verify_reply(reply, ReplyCodeKind::PositiveCompletion) {
                 // TODO: pipelining is forbidden across starttls, check unhandled.empty()
                 // Negotiate STARTTLS
-                sender.io = self
-                    .cfg
-                    .tls_connect(sender.io)
-                    .await
-                    .map_err(TransportError::NegotiatingTls)?;
+                sender.io = match tlsa {
+                    None => self
+                        .cfg
+                        .tls_connect(sender.io)
+                        .await
+                        .map_err(TransportError::NegotiatingTls)?,
+                    Some(tlsa) => self
+                        .cfg
+                        .tls_connect_with_dane(sender.io, tlsa)
+                        .await
+                        .map_err(TransportError::DaneValidationFailed)?,
+                };
                 // TODO: in case this call fails, maybe log? also, if
                 // we have must_do_tls, this server should probably be
                 // removed from the retry list as no matching ciphers
                 // is probably a permanent error.
                 //
-                // TODO: Retry without TLS enabled! Currently servers that support starttls but
-                // only with ancient ciphers are unreachable
-                //
                 // TODO: Split out the error condition “network error” from “negotiation failed”
                 // so as to know whether we should try STARTTLS again next time
 
@@ -597,24 +1717,40 @@ where
                 // returns a permanent error we definitely should bounce
             }
         }
-        if !did_tls && self.cfg.must_do_tls() {
+        if !did_tls && (tlsa.is_some() || self.cfg.must_do_tls()) {
+            if !sender.capabilities.starttls {
+                return Err(TransportError::TlsRequiredButUnavailable);
+            }
             return Err(TransportError::CannotDoTls);
         }
 
-        // TODO: AUTH
+        if let Some((username, password, mechanisms)) = self.cfg.auth() {
+            if !did_tls && !self.cfg.allow_auth_without_tls() {
+                return Err(TransportError::AuthRequiresTls);
+            }
+            let mechanism = mechanisms
+                .into_iter()
+                .filter(|m| matches!(m, Mechanism::Plain | Mechanism::Login))
+                .find(|m| sender.capabilities.auth.contains(m))
+                .ok_or(TransportError::NoAuthMechanism)?;
+            self.send_auth(&mut sender, mechanism, &username, &password)
+                .await?;
+        }
 
         Ok(sender)
     }
 
     async fn send_ehlo(&self, sender: &mut Sender<Cfg>) -> Result<(), TransportError> {
-        send_command(
-            &mut sender.io,
-            Command::Ehlo {
-                hostname: self.cfg.ehlo_hostname().to_ref(),
-            },
-            self.cfg.command_write_timeout(),
-        )
-        .await?;
+        let hostname = match sender.source_ip {
+            Some(source_ip) => self.cfg.ehlo_hostname_for(source_ip),
+            None => self.cfg.ehlo_hostname(),
+        };
+        let hostname = hostname.to_ref();
+        let cmd = match self.cfg.use_lmtp() {
+            true => Command::Lhlo { hostname },
+            false => Command::Ehlo { hostname },
+        };
+        send_command(&mut sender.io, cmd, self.cfg.command_write_timeout()).await?;
 
         // Parse the reply and verify it
         let reply = read_reply(
@@ -622,24 +1758,160 @@ where
             &mut sender.rdbuf,
             &mut sender.unhandled,
             self.cfg.ehlo_reply_timeout(),
+            self.cfg.accept_lf_only_replies(),
         )
         .await?;
-        sender.extensions = Extensions::empty();
-        for line in reply.text.iter() {
-            // TODO: parse other extensions that may be of interest (eg. pipelining)
-            if line.as_str().eq_ignore_ascii_case("STARTTLS") {
-                sender.extensions.insert(Extensions::STARTTLS);
-            }
-        }
+        sender.capabilities = Capabilities::from_ehlo_reply(&reply);
         verify_reply(reply, ReplyCodeKind::PositiveCompletion)?;
 
         Ok(())
     }
+
+    async fn send_auth(
+        &self,
+        sender: &mut Sender<Cfg>,
+        mechanism: Mechanism,
+        username: &str,
+        password: &str,
+    ) -> Result<(), TransportError> {
+        use ReplyCodeKind::*;
+        let steps = match mechanism {
+            Mechanism::Plain => vec![(
+                format!(
+                    "AUTH PLAIN {}",
+                    base64::encode(format!("\0{}\0{}", username, password))
+                ),
+                PositiveCompletion,
+            )],
+            Mechanism::Login => vec![
+                (String::from("AUTH LOGIN"), PositiveIntermediate),
+                (base64::encode(username), PositiveIntermediate),
+                (base64::encode(password), PositiveCompletion),
+            ],
+            Mechanism::Other(_) => unreachable!("unsupported mechanisms are never selected"),
+        };
+        trace!(?mechanism, "Authenticating");
+        for (line, expected) in steps {
+            send_line(&mut sender.io, &line, self.cfg.command_write_timeout()).await?;
+            let reply = read_reply(
+                &mut sender.io,
+                &mut sender.rdbuf,
+                &mut sender.unhandled,
+                self.cfg.auth_reply_timeout(),
+                self.cfg.accept_lf_only_replies(),
+            )
+            .await?;
+            // Retrying with the same credentials would just fail again
+            if reply.code.kind() == PermanentNegative {
+                return Err(TransportError::AuthenticationFailed(reply));
+            }
+            verify_reply(reply, expected)?;
+        }
+        Ok(())
+    }
+}
+
+/// SASL mechanism advertised with the AUTH extension
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mechanism {
+    Plain,
+    Login,
+    /// Any other mechanism, in upper case
+    Other(String),
+}
+
+impl Mechanism {
+    fn parse(name: &str) -> Mechanism {
+        let name = name.to_ascii_uppercase();
+        match &name as &str {
+            "PLAIN" => Mechanism::Plain,
+            "LOGIN" => Mechanism::Login,
+            _ => Mechanism::Other(name),
+        }
+    }
+}
+
+/// Extensions the server advertised in its reply to EHLO
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Capabilities {
+    pub starttls: bool,
+    /// Maximum size of a mail, with `Some(0)` meaning the server supports
+    /// SIZE but has no fixed maximum
+    pub size: Option<u64>,
+    pub eight_bit_mime: bool,
+    pub smtputf8: bool,
+    pub pipelining: bool,
+    /// Empty if the server does not support AUTH
+    pub auth: Vec<Mechanism>,
+    pub chunking: bool,
+    pub dsn: bool,
+}
+
+impl Capabilities {
+    /// Keywords are matched ignoring case, and the unknown ones are ignored
+    fn from_ehlo_reply(reply: &Reply) -> Capabilities {
+        let mut res = Capabilities::default();
+        // The first line is the server's greeting, not an extension
+        for line in reply.text.iter().skip(1) {
+            let mut words = line.as_str().split_whitespace();
+            let keyword = words.next().unwrap_or("").to_ascii_uppercase();
+            match &keyword as &str {
+                "STARTTLS" => res.starttls = true,
+                // A missing or invalid argument means there is no known maximum
+                "SIZE" => res.size = Some(words.next().and_then(|s| s.parse().ok()).unwrap_or(0)),
+                "8BITMIME" => res.eight_bit_mime = true,
+                "SMTPUTF8" => res.smtputf8 = true,
+                "PIPELINING" => res.pipelining = true,
+                "AUTH" => {
+                    for mechanism in words.map(Mechanism::parse) {
+                        if !res.auth.contains(&mechanism) {
+                            res.auth.push(mechanism);
+                        }
+                    }
+                }
+                "CHUNKING" => res.chunking = true,
+                "DSN" => res.dsn = true,
+                _ => (),
+            }
+        }
+        res
+    }
+}
+
+/// Replies the server gave after the mail contents sent by `Sender::send_many`
+#[derive(Debug)]
+pub enum FinalReplies {
+    /// With SMTP, a single reply holds for all the recipients accepted by
+    /// RCPT TO. Only positive replies are returned this way.
+    Shared(Reply),
+    /// With LMTP, there is one reply per recipient accepted by RCPT TO, in the
+    /// order of these recipients. Each of them may be positive or negative.
+    PerRecipient(Vec<Reply>),
+}
+
+/// Outcome of `Sender::send_many`
+#[derive(Debug)]
+pub struct Delivery {
+    /// For each recipient, in the order they were given, the error with which
+    /// RCPT TO rejected it, if any
+    pub rejected: Vec<Option<TransportError>>,
+    pub final_replies: FinalReplies,
 }
 
-bitflags! {
-    struct Extensions: u8 {
-        const STARTTLS = 0b1;
+impl Delivery {
+    /// Final reply for the `i`-th recipient, eg. to cite it in a success DSN.
+    /// Returns `None` if RCPT TO rejected this recipient.
+    pub fn final_reply(&self, i: usize) -> Option<&Reply> {
+        if self.rejected.get(i)?.is_some() {
+            return None;
+        }
+        match &self.final_replies {
+            FinalReplies::Shared(reply) => Some(reply),
+            FinalReplies::PerRecipient(replies) => {
+                let accepted_before = self.rejected[..i].iter().filter(|r| r.is_none()).count();
+                replies.get(accepted_before)
+            }
+        }
     }
 }
 
@@ -647,7 +1919,17 @@ pub struct Sender<Cfg> {
     io: DynAsyncReadWrite,
     rdbuf: [u8; RDBUF_SIZE],
     unhandled: Range<usize>,
-    extensions: Extensions,
+    capabilities: Capabilities,
+    source_ip: Option<IpAddr>,
+    /// End of the time allotted to the next `send`, if it is the first one
+    /// after `Client::connect`
+    deadline: Option<Instant>,
+    /// Set when the replies got out of sync with the pipelined commands, so
+    /// that the next mails are sent without pipelining
+    pipelining_desynced: bool,
+    /// Number of times the sender was given back by `Client::connect` from
+    /// the connection pool
+    reuses: usize,
     cfg: Arc<Cfg>,
 }
 
@@ -655,18 +1937,172 @@ impl<Cfg> Sender<Cfg>
 where
     Cfg: Config,
 {
-    // TODO: Figure out a way to batch a single mail (with the same metadata) going
-    // out to multiple recipients, so as to just use multiple RCPT TO
+    /// Extensions the server advertised in its latest reply to EHLO, ie. the
+    /// one after STARTTLS if TLS was negotiated
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     /// Note: `mail` must be a reader of the *already escaped and
     /// CRLF-dot-CRLF-terminated* message! If this is not the format
     /// you have, please looking into the `smtp-message` crate's
     /// utilities.
+    ///
+    /// If `size` is set, it must be the length of the message once unescaped,
+    /// ie. without the dot-stuffing nor the final `.` line. Then, if the server
+    /// supports CHUNKING, the message is sent as a single `BDAT <size> LAST`
+    /// chunk instead of with DATA. If `mail` turns out not to be `size` bytes
+    /// long, this returns `TransportError::BdatSizeMismatch` and the sender
+    /// must be discarded. If the server supports SIZE, this length is also
+    /// announced on MAIL FROM, so that it can refuse too big mails upfront.
+    /// If `size` is not set and the server supports CHUNKING, the message is
+    /// sent in BDAT chunks of 64KiB, followed by `BDAT 0 LAST`.
+    ///
+    /// If the server supports PIPELINING, the commands up to DATA are sent at
+    /// once. Should the replies get out of sync with them, the next mails on
+    /// this sender are sent without pipelining.
+    ///
+    /// If the delivery takes longer than `Config::overall_delivery_timeout`,
+    /// this returns `TransportError::TimedOutDelivering` and the sender must
+    /// be discarded too.
     pub async fn send<Reader>(
         &mut self,
         from: Option<&Email>,
         to: &Email,
         mail: Reader,
+        size: Option<u64>,
     ) -> Result<(), TransportError>
+    where
+        Reader: AsyncRead,
+    {
+        let delivery = self
+            .send_many(from, std::slice::from_ref(to), mail, size)
+            .await?;
+        match delivery.final_replies {
+            FinalReplies::Shared(_) => Ok(()),
+            // The only recipient was accepted, otherwise send_many would have failed
+            FinalReplies::PerRecipient(mut replies) => {
+                verify_reply(replies.remove(0), ReplyCodeKind::PositiveCompletion)
+            }
+        }
+    }
+
+    /// Same as `send`, but sends the mail once to all the recipients of `to`,
+    /// which must not be empty.
+    ///
+    /// Recipients rejected by RCPT TO are reported in the returned
+    /// `Delivery`, the mail being sent to the other ones. If all of them are
+    /// rejected, this returns the error of the first one instead. With SMTP, a
+    /// negative reply after the mail contents is returned as an error too, as
+    /// it holds for all the recipients.
+    pub async fn send_many<Reader>(
+        &mut self,
+        from: Option<&Email>,
+        to: &[Email],
+        mail: Reader,
+        size: Option<u64>,
+    ) -> Result<Delivery, TransportError>
+    where
+        Reader: AsyncRead,
+    {
+        let (rejected, final_replies) = self.send_transaction(from, to, mail, size).await?;
+        match final_replies {
+            Some(final_replies) => Ok(Delivery {
+                rejected,
+                final_replies,
+            }),
+            None => Err(rejected.into_iter().flatten().next().unwrap()),
+        }
+    }
+
+    /// Same as `send_many`, but returns the outcome of the delivery for each
+    /// recipient of `to`, in the same order. Recipients rejected by RCPT TO get
+    /// their own error even if all of them are, as do with LMTP the ones
+    /// rejected after the mail contents. Errors that hold for the whole
+    /// transaction, eg. a rejection of MAIL FROM or a network error, are
+    /// returned as the outer error.
+    ///
+    /// `mail` is read only once, as a single DATA sends it to all the accepted
+    /// recipients, so it needs to be neither buffered nor seekable.
+    pub async fn send_batch<Reader>(
+        &mut self,
+        from: Option<&Email>,
+        to: &[Email],
+        mail: Reader,
+        size: Option<u64>,
+    ) -> Result<Vec<Result<(), TransportError>>, TransportError>
+    where
+        Reader: AsyncRead,
+    {
+        let (rejected, final_replies) = self.send_transaction(from, to, mail, size).await?;
+        let mut replies = match final_replies {
+            Some(FinalReplies::PerRecipient(replies)) => replies,
+            _ => Vec::new(),
+        }
+        .into_iter();
+        Ok(rejected
+            .into_iter()
+            .map(|rejected| match (rejected, replies.next()) {
+                (Some(e), _) => Err(e),
+                (None, None) => Ok(()),
+                (None, Some(reply)) => verify_reply(reply, ReplyCodeKind::PositiveCompletion),
+            })
+            .collect())
+    }
+
+    /// Runs a whole mail transaction before the delivery deadline. Returns,
+    /// for each recipient, the error with which RCPT TO rejected it if any,
+    /// and the replies after the mail contents, or `None` if all the
+    /// recipients were rejected and the contents thus not sent.
+    async fn send_transaction<Reader>(
+        &mut self,
+        from: Option<&Email>,
+        to: &[Email],
+        mail: Reader,
+        size: Option<u64>,
+    ) -> Result<(Vec<Option<TransportError>>, Option<FinalReplies>), TransportError>
+    where
+        Reader: AsyncRead,
+    {
+        assert!(!to.is_empty(), "sending a mail without recipients");
+        let timeout = self
+            .cfg
+            .overall_delivery_timeout()
+            .to_std()
+            .unwrap_or(ZERO_DURATION);
+        let deadline = self
+            .deadline
+            .take()
+            .unwrap_or_else(|| Instant::now() + timeout);
+        let pipelining = self.capabilities.pipelining && !self.pipelining_desynced;
+        let res = smol::future::or(
+            self.send_before_deadline(from, to, mail, size, pipelining),
+            async {
+                smol::Timer::at(deadline).await;
+                Err(TransportError::TimedOutDelivering)
+            },
+        )
+        .await;
+        if pipelining
+            && matches!(
+                res,
+                Err(TransportError::SyntaxError(_) | TransportError::UnexpectedReplyCode(_))
+            )
+        {
+            warn!("Replies to pipelined commands got out of sync, no longer pipelining");
+            self.pipelining_desynced = true;
+        }
+        res
+    }
+
+    async fn send_before_deadline<Reader>(
+        &mut self,
+        from: Option<&Email>,
+        to: &[Email],
+        mail: Reader,
+        size: Option<u64>,
+        pipelining: bool,
+    ) -> Result<(Vec<Option<TransportError>>, Option<FinalReplies>), TransportError>
     where
         Reader: AsyncRead,
     {
@@ -675,99 +2111,2850 @@ where
                 send_command(&mut self.io, $cmd, self.cfg.command_write_timeout())
             };
         }
+        macro_rules! read_raw_reply {
+            ($timeout:expr) => {
+                read_reply(
+                    &mut self.io,
+                    &mut self.rdbuf,
+                    &mut self.unhandled,
+                    $timeout,
+                    self.cfg.accept_lf_only_replies(),
+                )
+            };
+        }
         macro_rules! read_reply {
             ($expected:expr, $timeout:expr) => {
-                async {
-                    let reply =
-                        read_reply(&mut self.io, &mut self.rdbuf, &mut self.unhandled, $timeout)
-                            .await?;
-                    verify_reply(reply, $expected)
-                }
+                async { verify_reply(read_raw_reply!($timeout).await?, $expected) }
             };
         }
 
-        // MAIL FROM
-        send_command!(Command::Mail {
+        // Internationalized addresses can only be sent as is to servers that
+        // support SMTPUTF8, as per RFC 6531. Otherwise, UTF-8 domains are sent
+        // in their punycode form, but UTF-8 localparts cannot be sent at all.
+        let emails = || from.into_iter().chain(to);
+        let smtputf8 = self.capabilities.smtputf8 && emails().any(is_utf8_email);
+        if !self.capabilities.smtputf8 && emails().any(has_utf8_localpart) {
+            return Err(TransportError::Smtputf8Unsupported);
+        }
+
+        // Check the 8-bit data and the line lengths and sign the mail if need
+        // be, before starting the transaction so that a failure doesn't leave
+        // it half-done
+        pin_mut!(mail);
+        let cfg = self.cfg.clone();
+        let long_line_policy = cfg.long_line_policy();
+        let fold_headers = cfg.fold_long_headers();
+        let eight_bit_policy = match self.capabilities.eight_bit_mime {
+            true => EightBitPolicy::SendAnyway,
+            false => cfg.on_8bit_to_7bit_only(),
+        };
+        let streamable = long_line_policy == LongLinePolicy::Send
+            && !fold_headers
+            && eight_bit_policy == EightBitPolicy::SendAnyway;
+        let (mail, added_size) = match (cfg.dkim_signer(), streamable) {
+            (None, true) => (Either::Left(mail), 0),
+            (signer, _) => {
+                let mut buf = Vec::new();
+                mail.read_to_end(&mut buf)
+                    .await
+                    .map_err(TransportError::ReadingMail)?;
+                let mut added_size = 0;
+                if !buf.is_ascii() {
+                    match eight_bit_policy {
+                        EightBitPolicy::SendAnyway => (),
+                        EightBitPolicy::Reject => return Err(TransportError::EightBitUnsupported),
+                        EightBitPolicy::Downgrade => {
+                            let downgraded =
+                                downgrade_8bit(&buf).ok_or(TransportError::EightBitUnsupported)?;
+                            added_size = unescaped_len(&downgraded) - unescaped_len(&buf);
+                            buf = downgraded;
+                        }
+                    }
+                }
+                if fold_headers && has_long_lines(&buf) {
+                    let folded = fold_long_headers(&buf);
+                    added_size += (folded.len() - buf.len()) as i64;
+                    buf = folded;
+                }
+                if has_long_lines(&buf) {
+                    match long_line_policy {
+                        LongLinePolicy::Send => (),
+                        LongLinePolicy::Reject => return Err(TransportError::LineTooLong),
+                        LongLinePolicy::Wrap => {
+                            let wrapped = wrap_long_headers(&buf);
+                            added_size += (wrapped.len() - buf.len()) as i64;
+                            buf = wrapped;
+                            if has_long_lines(&buf) {
+                                return Err(TransportError::LineTooLong);
+                            }
+                        }
+                    }
+                }
+                let header = match signer {
+                    None => Vec::new(),
+                    Some(signer) => signer
+                        .sign(&buf, Utc::now())
+                        .map_err(TransportError::SigningMail)?,
+                };
+                added_size += header.len() as i64;
+                let mail = futures::io::Cursor::new(header).chain(futures::io::Cursor::new(buf));
+                (Either::Right(mail), added_size)
+            }
+        };
+        let size = size.map(|size| (size as i64 + added_size) as u64);
+        let bdat_size = size.filter(|_| self.capabilities.chunking);
+        let chunked = size.is_none() && self.capabilities.chunking;
+        let size_param = size
+            .filter(|_| self.capabilities.size.is_some())
+            .map(|size| size.to_string());
+
+        let mut params = Vec::new();
+        if let Some(ref size) = size_param {
+            params.push((ParameterName::Other("SIZE"), Some(MaybeUtf8::Ascii(&**size))));
+        }
+        if smtputf8 {
+            params.push((ParameterName::Other("SMTPUTF8"), None));
+        }
+        let mut cmds = vec![Command::Mail {
             path: None,
-            email: from.map(|f| f.to_ref()),
+            email: from.map(|f| sendable_email(f, smtputf8)),
+            params: Parameters(params),
+        }];
+        cmds.extend(to.iter().map(|to| Command::Rcpt {
+            path: None,
+            email: sendable_email(to, smtputf8),
             params: Parameters(Vec::new()),
-        })
-        .await?;
-        read_reply!(
-            ReplyCodeKind::PositiveCompletion,
-            self.cfg.mail_reply_timeout()
-        )
-        .await?;
+        }));
+        let pipelined_data = pipelining && bdat_size.is_none() && !chunked;
+        if pipelined_data {
+            cmds.push(Command::Data);
+        }
+        let mut cmds = cmds.into_iter();
+        if pipelining {
+            // Send all the commands up to DATA at once, their replies being
+            // read in order afterwards
+            let cmds = cmds.by_ref().collect::<Vec<_>>();
+            send_commands(&mut self.io, &cmds, self.cfg.command_write_timeout()).await?;
+        }
+        macro_rules! send_next_command {
+            () => {
+                if let Some(cmd) = cmds.next() {
+                    send_command!(cmd).await?;
+                }
+            };
+        }
+
+        // MAIL FROM
+        send_next_command!();
+        let reply = read_raw_reply!(self.cfg.mail_reply_timeout()).await?;
+        let mail_res = match verify_reply(reply, ReplyCodeKind::PositiveCompletion) {
+            // Without pipelining, the recipients have not been sent yet
+            Err(e) if !pipelining => return Err(e),
+            res => res,
+        };
 
         // RCPT TO
-        send_command!(Command::Rcpt {
-            path: None,
-            email: to.to_ref(),
-            params: Parameters(Vec::new()),
-        })
-        .await?;
-        read_reply!(
-            ReplyCodeKind::PositiveCompletion,
-            self.cfg.rcpt_reply_timeout()
-        )
-        .await?;
+        let mut rejected = Vec::with_capacity(to.len());
+        for _ in to {
+            send_next_command!();
+            let reply = read_raw_reply!(self.cfg.rcpt_reply_timeout()).await?;
+            match verify_reply(reply, ReplyCodeKind::PositiveCompletion) {
+                Ok(()) => rejected.push(None),
+                // A rejection of RCPT TO is about this recipient only, whatever
+                // the enhanced status code says, so that the queue can bounce
+                // just this recipient
+                Err(TransportError::TransientMail(r)) => {
+                    rejected.push(Some(TransportError::TransientMailbox(r)))
+                }
+                Err(TransportError::PermanentMail(r)) => {
+                    rejected.push(Some(TransportError::PermanentMailbox(r)))
+                }
+                Err(
+                    e @ (TransportError::TransientMailbox(_)
+                    | TransportError::TransientMailSystem(_)
+                    | TransportError::PermanentMailbox(_)
+                    | TransportError::PermanentMailSystem(_)),
+                ) => rejected.push(Some(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        let accepted = rejected.iter().filter(|r| r.is_none()).count();
+        if pipelined_data {
+            // DATA was sent too, so its reply must be read even if the
+            // transaction already failed
+            let reply = read_raw_reply!(self.cfg.data_init_reply_timeout()).await?;
+            if mail_res.is_ok() && accepted > 0 {
+                verify_reply(reply, ReplyCodeKind::PositiveIntermediate)?;
+            } else if reply.code.kind() == ReplyCodeKind::PositiveIntermediate {
+                // The server accepted DATA without any valid recipient, as per
+                // RFC 2920 end the transaction with an empty mail
+                send_line(&mut self.io, ".", self.cfg.command_write_timeout()).await?;
+                read_raw_reply!(self.cfg.data_end_reply_timeout()).await?;
+            }
+        }
+        mail_res?;
+        if accepted == 0 {
+            return Ok((rejected, None));
+        }
 
-        // DATA
-        send_command!(Command::Data).await?;
-        read_reply!(
-            ReplyCodeKind::PositiveIntermediate,
-            self.cfg.data_init_reply_timeout()
-        )
-        .await?;
+        match bdat_size {
+            // DATA, unless it was already pipelined or the mail is chunked
+            None if pipelined_data || chunked => (),
+            None => {
+                send_command!(Command::Data).await?;
+                read_reply!(
+                    ReplyCodeKind::PositiveIntermediate,
+                    self.cfg.data_init_reply_timeout()
+                )
+                .await?;
+            }
+
+            // BDAT, which is not a `Command` as its size could not be borrowed
+            // by `Command::as_io_slices`
+            Some(size) => {
+                let cmd = format!("BDAT {} LAST\r\n", size);
+                trace!(cmd = cmd.as_str(), "Sending command");
+                smol::future::or(
+                    async {
+                        self.io
+                            .write_all(cmd.as_bytes())
+                            .await
+                            .map_err(TransportError::SendingCommand)
+                    },
+                    async {
+                        smol::Timer::after(
+                            cfg.command_write_timeout()
+                                .to_std()
+                                .unwrap_or(ZERO_DURATION),
+                        )
+                        .await;
+                        Err(TransportError::TimedOutSendingCommand)
+                    },
+                )
+                .await?;
+            }
+        }
 
-        // Send the contents of the email
+        // Send the contents of the email, unescaping them for BDAT
         {
+            macro_rules! send_data {
+                ($data:expr) => {
+                    smol::future::or(
+                        async {
+                            self.io
+                                .write_all($data)
+                                .await
+                                .map_err(TransportError::SendingData)
+                        },
+                        async {
+                            smol::Timer::after(
+                                cfg.data_block_write_timeout()
+                                    .to_std()
+                                    .unwrap_or(ZERO_DURATION),
+                            )
+                            .await;
+                            Err(TransportError::TimedOutSendingData)
+                        },
+                    )
+                };
+            }
+            macro_rules! send_chunk {
+                ($chunk:expr) => {
+                    async {
+                        let cmd = format!("BDAT {}", $chunk.len());
+                        trace!(cmd = cmd.as_str(), "Sending command");
+                        send_line(&mut self.io, &cmd, cfg.command_write_timeout()).await?;
+                        send_data!($chunk).await?;
+                        read_reply!(
+                            ReplyCodeKind::PositiveCompletion,
+                            cfg.data_init_reply_timeout()
+                        )
+                        .await
+                    }
+                };
+            }
+
             pin_mut!(mail);
-            let cfg = self.cfg.clone();
             let mut databuf = [0; DATABUF_SIZE];
+            let mut unescaper = if bdat_size.is_some() || chunked {
+                Some(DataUnescaper::new(true))
+            } else {
+                None
+            };
+            // Number of bytes left at the start of databuf by the unescaper
+            let mut unhandled = 0;
+            let mut sent = 0;
+            // Unescaped contents not sent yet, if the mail is chunked
+            let mut chunk = Vec::new();
             loop {
-                match mail.read(&mut databuf).await {
+                match mail.read(&mut databuf[unhandled..]).await {
                     Ok(0) => {
                         // End of stream
                         break;
                     }
                     Ok(n) => {
-                        // Got n bytes, try sending with a timeout
-                        smol::future::or(
-                            async {
-                                self.io
-                                    .write_all(&databuf[..n])
-                                    .await
-                                    .map_err(TransportError::SendingData)
-                            },
-                            async {
-                                smol::Timer::after(
-                                    cfg.data_block_write_timeout()
-                                        .to_std()
-                                        .unwrap_or(ZERO_DURATION),
-                                )
-                                .await;
-                                Err(TransportError::TimedOutSendingData)
-                            },
-                        )
-                        .await?;
+                        let (written, handled) = match unescaper {
+                            None => (n, n),
+                            Some(ref mut unescaper) => {
+                                let res = unescaper.unescape(&mut databuf[..unhandled + n]);
+                                (res.written, res.unhandled_idx)
+                            }
+                        };
+                        sent += written as u64;
+                        if let Some(size) = bdat_size {
+                            if sent > size {
+                                return Err(TransportError::BdatSizeMismatch(size));
+                            }
+                        }
+
+                        // Got written bytes, try sending with a timeout
+                        if chunked {
+                            chunk.extend_from_slice(&databuf[..written]);
+                            while chunk.len() >= BDAT_CHUNK_SIZE {
+                                send_chunk!(&chunk[..BDAT_CHUNK_SIZE]).await?;
+                                chunk.drain(..BDAT_CHUNK_SIZE);
+                            }
+                        } else {
+                            send_data!(&databuf[..written]).await?;
+                        }
+                        databuf.copy_within(handled..unhandled + n, 0);
+                        unhandled = unhandled + n - handled;
                     }
                     Err(e) => return Err(TransportError::ReadingMail(e)),
                 }
             }
+            if let Some(size) = bdat_size {
+                if sent != size {
+                    return Err(TransportError::BdatSizeMismatch(size));
+                }
+            }
+            if chunked {
+                if !chunk.is_empty() {
+                    send_chunk!(&chunk[..]).await?;
+                }
+                trace!(cmd = "BDAT 0 LAST", "Sending command");
+                send_line(&mut self.io, "BDAT 0 LAST", cfg.command_write_timeout()).await?;
+            }
+        }
+
+        // Wait for the reply, or for one reply per accepted recipient with LMTP
+        let final_replies = if self.cfg.use_lmtp() {
+            let mut replies = Vec::with_capacity(accepted);
+            for _ in 0..accepted {
+                replies.push(read_raw_reply!(self.cfg.data_end_reply_timeout()).await?);
+            }
+            FinalReplies::PerRecipient(replies)
+        } else {
+            let reply = read_raw_reply!(self.cfg.data_end_reply_timeout()).await?;
+            verify_reply(reply.clone(), ReplyCodeKind::PositiveCompletion)?;
+            FinalReplies::Shared(reply)
+        };
+
+        Ok((rejected, Some(final_replies)))
+    }
+
+    /// Aborts the current transaction, if any, so that the sender can be used
+    /// for sending another mail, eg. after `send` failed.
+    ///
+    /// If this fails, the state of the connection is unknown and the sender
+    /// must be discarded. In particular, if the server closed the connection,
+    /// this returns `TransportError::ConnectionAborted`.
+    pub async fn reset(&mut self) -> Result<(), TransportError> {
+        let timeout = self.cfg.rset_reply_timeout();
+        self.send_simple_command(Command::Rset, timeout).await
+    }
+
+    /// Keeps the sender idle, eg. in a connection pool, for at most `ttl`.
+    /// Until then, `IdleSender::check_out` gives it back for sending more
+    /// mails, after which it closes the connection instead.
+    pub fn into_idle(self, ttl: std::time::Duration) -> IdleSender<Cfg> {
+        IdleSender {
+            expires: Instant::now() + ttl,
+            sender: self,
+        }
+    }
+
+    /// Sends QUIT and closes the connection. Errors are only logged, as the
+    /// connection is gone either way.
+    pub async fn quit(mut self) {
+        let timeout = self.cfg.quit_reply_timeout();
+        if let Err(e) = self.send_simple_command(Command::Quit, timeout).await {
+            trace!(error = ?e, "Failed sending QUIT");
+        }
+        if let Err(e) = self.io.close().await {
+            trace!(error = ?e, "Failed closing the connection");
         }
+    }
 
-        // Wait for a reply
-        read_reply!(
-            ReplyCodeKind::PositiveCompletion,
-            self.cfg.data_end_reply_timeout()
+    /// Sends `cmd`, that must not be part of a mail transaction, and checks
+    /// that the server accepted it
+    async fn send_simple_command(
+        &mut self,
+        cmd: Command<&str>,
+        timeout: chrono::Duration,
+    ) -> Result<(), TransportError> {
+        send_command(&mut self.io, cmd, self.cfg.command_write_timeout()).await?;
+        let reply = read_reply(
+            &mut self.io,
+            &mut self.rdbuf,
+            &mut self.unhandled,
+            timeout,
+            self.cfg.accept_lf_only_replies(),
         )
         .await?;
-
-        Ok(())
+        verify_reply(reply, ReplyCodeKind::PositiveCompletion)
     }
 }
 
-// TODO: is it important to call QUIT before closing the TCP stream?
+/// Sender waiting for its next mail, as returned by `Sender::into_idle`
+pub struct IdleSender<Cfg> {
+    sender: Sender<Cfg>,
+    expires: Instant,
+}
 
-// TODO: add tests
+impl<Cfg> IdleSender<Cfg>
+where
+    Cfg: Config,
+{
+    /// Time after which `check_out` no longer gives the sender back
+    pub fn expires(&self) -> Instant {
+        self.expires
+    }
+
+    /// Gives the sender back if it was idle for less than its ttl and the
+    /// server still replies to NOOP. Otherwise, this returns `None`, having
+    /// closed the connection with QUIT if the ttl passed.
+    pub async fn check_out(mut self) -> Option<Sender<Cfg>> {
+        if Instant::now() >= self.expires {
+            self.sender.quit().await;
+            return None;
+        }
+        let noop = Command::Noop {
+            string: MaybeUtf8::Ascii(""),
+        };
+        let timeout = self.sender.cfg.noop_reply_timeout();
+        match self.sender.send_simple_command(noop, timeout).await {
+            Ok(()) => Some(self.sender),
+            Err(e) => {
+                trace!(error = ?e, "Idle sender failed NOOP, dropping it");
+                None
+            }
+        }
+    }
+
+    /// Closes the connection with QUIT, whether or not the ttl passed
+    pub async fn close(self) {
+        self.sender.quit().await
+    }
+}
+
+/// Returns the lines of `mail`, without their line ending
+fn lines(mail: &[u8]) -> impl Iterator<Item = &[u8]> {
+    mail.split(|&c| c == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+}
+
+fn has_utf8_localpart(email: &Email) -> bool {
+    matches!(
+        email.localpart,
+        Localpart::Utf8 { .. } | Localpart::QuotedUtf8 { .. }
+    )
+}
+
+fn is_utf8_email(email: &Email) -> bool {
+    has_utf8_localpart(email) || matches!(email.hostname, Some(Hostname::Utf8Domain { .. }))
+}
+
+/// Returns `email` as it is to be sent, that is with its domain in punycode
+/// form unless `smtputf8` was negotiated
+fn sendable_email(email: &Email, smtputf8: bool) -> Email<&str> {
+    match email.hostname {
+        Some(Hostname::Utf8Domain { ref punycode, .. }) if !smtputf8 => Email {
+            localpart: email.localpart.to_ref(),
+            hostname: Some(Hostname::AsciiDomain { raw: punycode }),
+        },
+        _ => email.to_ref(),
+    }
+}
+
+fn has_long_lines(mail: &[u8]) -> bool {
+    lines(mail).any(|l| l.len() > MAX_LINE_LENGTH)
+}
+
+/// Breaks the header lines of `mail` that are too long, by inserting CRLF
+/// followed by a space. As continuation lines start with a space, this never
+/// requires additional dot-stuffing. The body is left as-is.
+fn wrap_long_headers(mail: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(mail.len() + mail.len() / MAX_LINE_LENGTH * 3);
+    let mut in_headers = true;
+    for (i, line) in mail.split(|&c| c == b'\n').enumerate() {
+        if i != 0 {
+            res.push(b'\n');
+        }
+        let (mut rem, cr) = match line.strip_suffix(b"\r") {
+            Some(l) => (l, true),
+            None => (line, false),
+        };
+        in_headers &= !rem.is_empty();
+        let mut max = MAX_LINE_LENGTH;
+        while in_headers && rem.len() > max {
+            res.extend_from_slice(&rem[..max]);
+            res.extend_from_slice(b"\r\n ");
+            rem = &rem[max..];
+            // Leave room for the leading space
+            max = MAX_LINE_LENGTH - 1;
+        }
+        res.extend_from_slice(rem);
+        if cr {
+            res.push(b'\r');
+        }
+    }
+    res
+}
+
+/// Folds the header lines of `mail` that are too long, by inserting CRLF
+/// before the last whitespace that keeps each line short enough. Lines that
+/// have no such whitespace are left as-is.
+fn fold_long_headers(mail: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(mail.len() + mail.len() / MAX_LINE_LENGTH * 2);
+    let mut in_headers = true;
+    for (i, line) in mail.split(|&c| c == b'\n').enumerate() {
+        if i != 0 {
+            res.push(b'\n');
+        }
+        let (mut rem, cr) = match line.strip_suffix(b"\r") {
+            Some(l) => (l, true),
+            None => (line, false),
+        };
+        in_headers &= !rem.is_empty();
+        while in_headers && rem.len() > MAX_LINE_LENGTH {
+            // Folding right before the whitespace the line starts with would
+            // leave an empty line, which would end the headers
+            let fold = rem[1..=MAX_LINE_LENGTH]
+                .iter()
+                .rposition(|&c| c == b' ' || c == b'\t')
+                .map(|i| i + 1)
+                .filter(|&i| rem[..i].iter().any(|&c| c != b' ' && c != b'\t'));
+            let fold = match fold {
+                Some(fold) => fold,
+                None => break,
+            };
+            res.extend_from_slice(&rem[..fold]);
+            res.extend_from_slice(b"\r\n");
+            rem = &rem[fold..];
+        }
+        res.extend_from_slice(rem);
+        if cr {
+            res.push(b'\r');
+        }
+    }
+    res
+}
+
+/// Length of `mail`, which is dot-escaped and CRLF-dot-CRLF-terminated, once
+/// unescaped
+fn unescaped_len(mail: &[u8]) -> i64 {
+    let escaped_dots = lines(mail).filter(|l| l.starts_with(b".")).count();
+    // The final `.` line is not escaping anything, and is removed with its CRLF
+    (mail.len() - escaped_dots) as i64 - 2
+}
+
+/// Returns the name of the header field whose first line is `line`
+fn header_name(line: &[u8]) -> &[u8] {
+    let name = line.split(|&c| c == b':').next().unwrap_or(line);
+    let len = name
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &name[..len]
+}
+
+/// Re-encodes the body of `mail`, which is dot-escaped, as quoted-printable.
+/// Returns `None` if this would require rewriting the MIME structure of the
+/// mail, ie. if it is multipart or has 8-bit headers.
+fn downgrade_8bit(mail: &[u8]) -> Option<Vec<u8>> {
+    let mut lines = lines(mail);
+
+    // Headers, each field being made of its first line followed by its folded
+    // continuation lines
+    let mut fields: Vec<Vec<&[u8]>> = Vec::new();
+    for line in &mut lines {
+        if line.is_empty() {
+            break;
+        }
+        if !line.is_ascii() {
+            return None;
+        }
+        match fields.last_mut() {
+            Some(field) if line.starts_with(b" ") || line.starts_with(b"\t") => field.push(line),
+            _ => fields.push(vec![line]),
+        }
+    }
+
+    let mut res = Vec::with_capacity(mail.len() * 2);
+    let mut has_mime_version = false;
+    for field in fields {
+        let name = header_name(field[0]);
+        if name.eq_ignore_ascii_case(b"Content-Type") {
+            let value = field.concat()[name.len()..]
+                .iter()
+                .skip_while(|&&c| c == b':' || c.is_ascii_whitespace())
+                .map(|c| c.to_ascii_lowercase())
+                .collect::<Vec<u8>>();
+            if value.starts_with(b"multipart/") || value.starts_with(b"message/") {
+                return None;
+            }
+        } else if name.eq_ignore_ascii_case(b"Content-Transfer-Encoding") {
+            continue;
+        }
+        has_mime_version |= name.eq_ignore_ascii_case(b"MIME-Version");
+        for line in field {
+            res.extend_from_slice(line);
+            res.extend_from_slice(b"\r\n");
+        }
+    }
+    if !has_mime_version {
+        res.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    }
+    res.extend_from_slice(b"Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+
+    for line in lines {
+        if line == b"." {
+            break;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(line);
+        // Length of the encoded line, that must stay under 76 octets
+        let mut len = 0;
+        for (i, &c) in line.iter().enumerate() {
+            let is_blank = c == b' ' || c == b'\t';
+            let mut encode = !(is_blank || c.is_ascii_graphic()) || c == b'=';
+            // Trailing whitespace may be removed in transit
+            encode |= is_blank && i + 1 == line.len();
+            if len + if encode { 3 } else { 1 } > 75 {
+                res.extend_from_slice(b"=\r\n");
+                len = 0;
+            }
+            // Leading dots would need dot-stuffing
+            encode |= len == 0 && c == b'.';
+            if encode {
+                res.extend_from_slice(format!("={:02X}", c).as_bytes());
+                len += 3;
+            } else {
+                res.push(c);
+                len += 1;
+            }
+        }
+        res.extend_from_slice(b"\r\n");
+    }
+    res.extend_from_slice(b".\r\n");
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        task::{Context, Poll},
+    };
+
+    use trust_dns_resolver::{
+        proto::{op::ResponseCode, rr::RecordType},
+        Name,
+    };
+
+    use smtp_message::ReplyCode;
+
+    use super::{
+        mock_resolver::{MockConnection, MockConnectionProvider, MockDns},
+        *,
+    };
+
+    /// How `TestConfig` negotiates TLS
+    #[derive(Clone, Copy, PartialEq)]
+    enum TestTls {
+        /// Never tries STARTTLS
+        Disabled,
+
+        /// Tries STARTTLS, but always fails the TLS handshake
+        Failing { mandatory: bool },
+
+        /// Pretends to negotiate TLS without touching the stream, checking the
+        /// server with DANE. Only TLSA records with `b"good"` as data match
+        /// the certificate of the server.
+        Dane,
+    }
+
+    /// Configuration of the client for all the tests, that keeps the defaults
+    /// of `Config` unless the tests say otherwise
+    struct TestConfig {
+        local_addresses: Vec<IpAddr>,
+        use_ipv6: bool,
+        /// Connects from this address, announcing a name specific to it
+        source_ip: Option<IpAddr>,
+        smtp_port: u16,
+        overall_delivery_timeout: chrono::Duration,
+        mx_balancing: MxBalancing,
+        mx_shuffle_seed: Option<u64>,
+        max_mx_attempts: usize,
+        circuit_breaker_threshold: usize,
+        domainless_recipient_domain: Option<Hostname>,
+        use_lmtp: bool,
+        long_line_policy: LongLinePolicy,
+        on_8bit_to_7bit_only: EightBitPolicy,
+        /// Mechanisms to authenticate with as user `user` and password `pass`
+        auth_mechanisms: Option<Vec<Mechanism>>,
+        allow_auth_without_tls: bool,
+        tls: TestTls,
+        /// Records the connection attempts and whether they succeeded
+        attempts: Mutex<Vec<(IpAddr, bool)>>,
+        /// Records the TLSA records that DANE is attempted with
+        tlsa: Mutex<Vec<TLSA>>,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> TestConfig {
+            TestConfig {
+                local_addresses: Vec::new(),
+                use_ipv6: true,
+                source_ip: None,
+                smtp_port: SMTP_PORT,
+                overall_delivery_timeout: chrono::Duration::minutes(30),
+                mx_balancing: MxBalancing::Random,
+                mx_shuffle_seed: None,
+                max_mx_attempts: 10,
+                circuit_breaker_threshold: 5,
+                domainless_recipient_domain: None,
+                use_lmtp: false,
+                long_line_policy: LongLinePolicy::Send,
+                on_8bit_to_7bit_only: EightBitPolicy::SendAnyway,
+                auth_mechanisms: None,
+                allow_auth_without_tls: false,
+                tls: TestTls::Disabled,
+                attempts: Mutex::new(Vec::new()),
+                tlsa: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Config for TestConfig {
+        fn ehlo_hostname(&self) -> Hostname<String> {
+            Hostname::parse(b"test.example.org").unwrap().1.to_owned()
+        }
+
+        fn ehlo_hostname_for(&self, source_ip: IpAddr) -> Hostname<String> {
+            let name = format!("ip-{}.example.org", source_ip.to_string().replace('.', "-"));
+            Hostname::parse(name.as_bytes()).unwrap().1.to_owned()
+        }
+
+        fn on_connection_attempt(&self, ip: IpAddr, _port: u16, res: Result<(), &TransportError>) {
+            self.attempts.lock().unwrap().push((ip, res.is_ok()));
+        }
+
+        fn source_ip(&self, _ip: IpAddr) -> Option<IpAddr> {
+            self.source_ip
+        }
+
+        fn can_do_tls(&self) -> bool {
+            self.tls != TestTls::Disabled
+        }
+
+        fn must_do_tls(&self) -> bool {
+            self.tls == TestTls::Failing { mandatory: true }
+        }
+
+        fn auth(&self) -> Option<(String, String, Vec<Mechanism>)> {
+            let mechanisms = self.auth_mechanisms.clone()?;
+            Some(("user".into(), "pass".into(), mechanisms))
+        }
+
+        fn allow_auth_without_tls(&self) -> bool {
+            self.allow_auth_without_tls
+        }
+
+        async fn tls_connect<IO>(&self, io: IO) -> io::Result<DynAsyncReadWrite>
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+        {
+            match self.tls {
+                TestTls::Disabled => unimplemented!(),
+                TestTls::Failing { .. } => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no shared cipher",
+                )),
+                TestTls::Dane => {
+                    let (reader, writer) = io.split();
+                    Ok(duplexify::Duplex::new(Box::pin(reader), Box::pin(writer)))
+                }
+            }
+        }
+
+        fn use_dane(&self) -> bool {
+            self.tls == TestTls::Dane
+        }
+
+        async fn tls_connect_with_dane<IO>(
+            &self,
+            io: IO,
+            tlsa: &[TLSA],
+        ) -> io::Result<DynAsyncReadWrite>
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+        {
+            self.tlsa.lock().unwrap().extend_from_slice(tlsa);
+            if !tlsa.iter().any(|t| t.cert_data() == b"good") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "certificate does not match",
+                ));
+            }
+            self.tls_connect(io).await
+        }
+
+        fn overall_delivery_timeout(&self) -> chrono::Duration {
+            self.overall_delivery_timeout
+        }
+
+        fn smtp_port(&self) -> u16 {
+            self.smtp_port
+        }
+
+        fn local_addresses(&self) -> &[IpAddr] {
+            &self.local_addresses
+        }
+
+        fn use_ipv6(&self) -> bool {
+            self.use_ipv6
+        }
+
+        fn mx_balancing(&self) -> MxBalancing {
+            self.mx_balancing
+        }
+
+        fn mx_shuffle_seed(&self) -> Option<u64> {
+            self.mx_shuffle_seed
+        }
+
+        fn max_mx_attempts(&self) -> usize {
+            self.max_mx_attempts
+        }
+
+        fn circuit_breaker_threshold(&self) -> usize {
+            self.circuit_breaker_threshold
+        }
+
+        fn next_hop(&self, domain: &Hostname) -> NextHop {
+            if domain.to_string() == "relayed.example.org" {
+                let relay = Hostname::parse(b"relay.example.org").unwrap().1.to_owned();
+                NextHop::Relay(relay, 2525)
+            } else {
+                NextHop::Mx
+            }
+        }
+
+        fn domainless_recipient_domain(&self) -> Option<Hostname> {
+            self.domainless_recipient_domain.clone()
+        }
+
+        fn long_line_policy(&self) -> LongLinePolicy {
+            self.long_line_policy
+        }
+
+        fn on_8bit_to_7bit_only(&self) -> EightBitPolicy {
+            self.on_8bit_to_7bit_only
+        }
+
+        fn use_lmtp(&self) -> bool {
+            self.use_lmtp
+        }
+    }
+
+    type TestClient = Client<MockConnection, MockConnectionProvider, TestConfig>;
+
+    /// Client that has no DNS record to resolve names with
+    fn new_client(cfg: TestConfig) -> TestClient {
+        Client::new(MockDns::default().resolver(), Arc::new(cfg))
+    }
+
+    /// Listens on an ephemeral port of `ip`
+    async fn listen(ip: impl Into<IpAddr>) -> (smol::net::TcpListener, u16) {
+        let listener = smol::net::TcpListener::bind((ip.into(), 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    /// Sends the banner on `io`, then answers the EHLO, or LHLO, of the client
+    /// with `ehlo`. Returns the greeting line of the client.
+    async fn greet(io: &mut (impl AsyncRead + AsyncWrite + Unpin), ehlo: &[u8]) -> String {
+        io.write_all(b"220 test.example.org Ready\r\n")
+            .await
+            .unwrap();
+        let hello = read_line(io).await;
+        assert!(
+            hello.starts_with("EHLO ") || hello.starts_with("LHLO "),
+            "unexpected greeting: {:?}",
+            hello
+        );
+        io.write_all(ehlo).await.unwrap();
+        hello
+    }
+
+    /// Accepts a connection on `listener` and greets the client on it
+    async fn accept(
+        listener: &smol::net::TcpListener,
+        ehlo: &[u8],
+    ) -> (smol::net::TcpStream, String) {
+        let (mut io, _) = listener.accept().await.unwrap();
+        let hello = greet(&mut io, ehlo).await;
+        (io, hello)
+    }
+
+    /// Listens on `addr`, with an accept queue that is already full, so that
+    /// connecting to it stalls until the returned sockets are dropped
+    fn stalled_listener(addr: SocketAddr) -> (Socket, std::net::TcpStream) {
+        let listener = Socket::new(Domain::for_address(addr), Type::STREAM, None).unwrap();
+        listener.bind(&addr.into()).unwrap();
+        listener.listen(0).unwrap();
+        let filler = std::net::TcpStream::connect(addr).unwrap();
+        (listener, filler)
+    }
+
+    async fn read_line(io: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut c = [0];
+            io.read_exact(&mut c).await.unwrap();
+            line.push(c[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn refuses_local_addresses() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_ip("localhost", IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .with_ip("localhost", IpAddr::V6(Ipv6Addr::LOCALHOST))
+                .resolver();
+            let cfg = TestConfig {
+                local_addresses: vec![
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ],
+                ..TestConfig::default()
+            };
+            let client = Client::new(resolver, Arc::new(cfg));
+
+            // Directly connecting to a local IP is refused
+            let res = client
+                .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), SMTP_PORT)
+                .await;
+            assert!(matches!(res, Err(TransportError::LocalAddress(_))));
+
+            // A host that resolves only to local IPs is refused, permanently
+            let res = client
+                .connect_to_host(Name::from_ascii("localhost.").unwrap(), SMTP_PORT)
+                .await;
+            match res {
+                Err(e @ TransportError::LocalAddress(_)) => assert!(matches!(
+                    e.severity(),
+                    TransportErrorSeverity::MailSystemPermanent
+                )),
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("unexpectedly connected to a local address"),
+            }
+        })
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        smol::block_on(async {
+            let client = new_client(TestConfig {
+                circuit_breaker_threshold: 3,
+                ..TestConfig::default()
+            });
+            let cfg = &*client.cfg;
+            let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap();
+            let breakers = &client.circuit_breakers;
+            let start = Utc::now();
+            let after = |minutes| start + chrono::Duration::minutes(minutes);
+
+            // Failures outside of the window do not add up
+            breakers.record(cfg, &host, true, after(-20));
+            breakers.record(cfg, &host, true, after(-20));
+            breakers.record(cfg, &host, true, start);
+            assert!(breakers.allows(cfg, &host, start));
+
+            // But reaching the threshold within the window opens the circuit
+            breakers.record(cfg, &host, true, start);
+            breakers.record(cfg, &host, true, start);
+            assert!(!breakers.allows(cfg, &host, after(1)));
+            match client.connect(&dest).await {
+                Err(TransportError::CircuitOpen(_)) => (),
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("unexpectedly connected through an open circuit"),
+            }
+
+            // After the cooldown, a single probe is allowed, and its failure
+            // opens the circuit again
+            assert!(breakers.allows(cfg, &host, after(6)));
+            assert!(!breakers.allows(cfg, &host, after(6)));
+            breakers.record(cfg, &host, true, after(6));
+            assert!(!breakers.allows(cfg, &host, after(7)));
+
+            // While a successful probe closes the circuit
+            assert!(breakers.allows(cfg, &host, after(12)));
+            breakers.record(cfg, &host, false, after(12));
+            assert!(breakers.allows(cfg, &host, after(12)));
+            breakers.record(cfg, &host, true, after(12));
+            assert!(breakers.allows(cfg, &host, after(12)));
+        })
+    }
+
+    #[test]
+    fn connects_to_port_override() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            assert_ne!(port, SMTP_PORT);
+            let server = async {
+                let (_, hello) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(hello, "EHLO test.example.org\r\n");
+            };
+
+            let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap().with_port(port);
+            assert_eq!(dest.to_string(), format!("[127.0.0.1]:{}", port));
+            let (res, ()) = futures::join!(client.connect(&dest), server);
+            if let Err(e) = res {
+                panic!("failed connecting to the port override: {:?}", e);
+            }
+        })
+    }
+
+    #[test]
+    fn connects_to_configured_port() {
+        smol::block_on(async {
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            assert_ne!(port, SMTP_PORT);
+            let client = new_client(TestConfig {
+                smtp_port: port,
+                ..TestConfig::default()
+            });
+            let server = async {
+                let (_, hello) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(hello, "EHLO test.example.org\r\n");
+            };
+
+            let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap();
+            let (res, ()) = futures::join!(client.connect(&dest), server);
+            if let Err(e) = res {
+                panic!("failed connecting to the configured port: {:?}", e);
+            }
+        })
+    }
+
+    #[test]
+    fn bounds_whole_delivery() {
+        smol::block_on(async {
+            let client = new_client(TestConfig {
+                overall_delivery_timeout: chrono::Duration::milliseconds(500),
+                ..TestConfig::default()
+            });
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            // Each reply comes well within its own timeout, but the delivery
+            // as a whole would take 600ms
+            let server = async {
+                let delay = || smol::Timer::after(std::time::Duration::from_millis(150));
+                let (mut io, _) = listener.accept().await.unwrap();
+                delay().await;
+                io.write_all(b"220 test.example.org Ready\r\n")
+                    .await
+                    .unwrap();
+                assert!(read_line(&mut io).await.starts_with("EHLO "));
+                delay().await;
+                io.write_all(b"250 test.example.org\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                delay().await;
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                delay().await;
+                // The client may have given up already
+                let _ = io.write_all(b"250 2.0.0 Okay\r\n").await;
+            };
+            let client = async {
+                let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+                let dest = client.get_destination(&host).await.unwrap().with_port(port);
+                let mut sender = client.connect(&dest).await.expect("connecting");
+                let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                sender.send(None, &to, mail, None).await
+            };
+
+            let (res, ()) = futures::join!(client, server);
+            match res {
+                Err(e @ TransportError::TimedOutDelivering) => assert!(matches!(
+                    e.severity(),
+                    TransportErrorSeverity::NetworkTransient
+                )),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        })
+    }
+
+    #[test]
+    fn tries_mxs_by_preference() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 20, "backup.example.org")
+                .with_mx("example.org", 10, "primary.example.org")
+                .with_ip("backup.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("primary.example.org", "127.0.0.3".parse().unwrap())
+                .resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            // Listen on all addresses, to see which MX the client picked
+            let (listener, port) = listen(Ipv4Addr::UNSPECIFIED).await;
+            let server = async {
+                let (io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                io.local_addr().unwrap().ip()
+            };
+
+            let (res, ip) = futures::join!(client.connect_to_mx("example.org", port), server);
+            if let Err(e) = res {
+                panic!("failed connecting to the MX: {:?}", e);
+            }
+            assert_eq!(ip, "127.0.0.3".parse::<IpAddr>().unwrap());
+        })
+    }
+
+    #[test]
+    fn caps_mx_attempts() {
+        smol::block_on(async {
+            let mut dns = MockDns::default();
+            for i in 0..20u8 {
+                let mx = format!("mx{}.example.org", i);
+                dns = dns
+                    .with_mx("example.org", 10 * (u16::from(i) / 3), &mx)
+                    .with_ip(&mx, IpAddr::from([127, 0, 0, 2 + i]));
+            }
+            let cfg = TestConfig {
+                max_mx_attempts: 5,
+                ..TestConfig::default()
+            };
+            let client = Client::new(dns.resolver(), Arc::new(cfg));
+            let (listener, port) = listen(Ipv4Addr::UNSPECIFIED).await;
+            let mut attempted = Vec::new();
+            // Each MX closes the connection right away, so that the next one gets tried
+            let res = smol::future::or(client.connect_to_mx("example.org", port), async {
+                loop {
+                    let (io, _) = listener.accept().await.unwrap();
+                    attempted.push(io.local_addr().unwrap().ip());
+                }
+            })
+            .await;
+            match res {
+                Err(TransportError::ConnectionAborted) => (),
+                res => panic!("unexpected result {:?}", res.map(|_| ())),
+            }
+            assert_eq!(attempted.len(), 5);
+        })
+    }
+
+    #[test]
+    fn shuffles_mxs_with_seed() {
+        let mx_order = |seed| {
+            smol::block_on(async move {
+                let mut dns = MockDns::default();
+                for i in 0..8u8 {
+                    let mx = format!("mx{}.example.org", i);
+                    dns = dns
+                        .with_mx("example.org", 10, &mx)
+                        .with_ip(&mx, IpAddr::from([127, 0, 0, 2 + i]));
+                }
+                let cfg = TestConfig {
+                    mx_shuffle_seed: Some(seed),
+                    ..TestConfig::default()
+                };
+                let client = Client::new(dns.resolver(), Arc::new(cfg));
+                let (listener, port) = listen(Ipv4Addr::UNSPECIFIED).await;
+                let mut attempted = Vec::new();
+                // Each MX closes the connection right away, so that all of them get tried
+                let res = smol::future::or(client.connect_to_mx("example.org", port), async {
+                    loop {
+                        let (io, _) = listener.accept().await.unwrap();
+                        attempted.push(io.local_addr().unwrap().ip());
+                    }
+                })
+                .await;
+                assert!(matches!(res, Err(TransportError::ConnectionAborted)));
+                attempted
+            })
+        };
+        let order = mx_order(42);
+        assert_eq!(order.len(), 8);
+        assert_eq!(mx_order(42), order);
+        // 8! orders make it very unlikely for another seed to give the same one
+        assert_ne!(mx_order(43), order);
+    }
+
+    #[test]
+    fn reports_each_connection_attempt() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 10, "mx.example.org")
+                .with_ip("mx.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("mx.example.org", "127.0.0.3".parse().unwrap())
+                .resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            let (listener, port) = listen(Ipv4Addr::UNSPECIFIED).await;
+            // The first address closes the connection right away
+            let server = async {
+                loop {
+                    let (mut io, _) = listener.accept().await.unwrap();
+                    if io.local_addr().unwrap().ip() == "127.0.0.2".parse::<IpAddr>().unwrap() {
+                        continue;
+                    }
+                    greet(&mut io, b"250 test.example.org\r\n").await;
+                    return io;
+                }
+            };
+
+            let (res, _io) = futures::join!(client.connect_to_mx("example.org", port), server);
+            if let Err(e) = res {
+                panic!("failed connecting to the MX: {:?}", e);
+            }
+            assert_eq!(
+                *client.cfg.attempts.lock().unwrap(),
+                vec![
+                    ("127.0.0.2".parse().unwrap(), false),
+                    ("127.0.0.3".parse().unwrap(), true),
+                ]
+            );
+        })
+    }
+
+    #[test]
+    fn races_stalled_addresses() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 10, "mx.example.org")
+                .with_ip("mx.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("mx.example.org", "127.0.0.3".parse().unwrap())
+                .resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            let (listener, port) = listen("127.0.0.3".parse::<IpAddr>().unwrap()).await;
+            // The first address never completes the TCP handshake
+            let stalled_addr = SocketAddr::new("127.0.0.2".parse().unwrap(), port);
+            let (stalled, filler) = stalled_listener(stalled_addr);
+
+            let start = Instant::now();
+            let (res, _io) = futures::join!(
+                client.connect_to_mx("example.org", port),
+                accept(&listener, b"250 test.example.org\r\n"),
+            );
+            if let Err(e) = res {
+                panic!("failed connecting to the MX: {:?}", e);
+            }
+            // Way before the connect timeout of the stalled address
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+            // The cancelled attempt does not go on once the address unstalls
+            drop(filler);
+            let stalled = smol::net::TcpListener::try_from(std::net::TcpListener::from(stalled))
+                .unwrap();
+            let _filler = stalled.accept().await.unwrap();
+            let late = smol::future::or(async { Some(stalled.accept().await) }, async {
+                smol::Timer::after(std::time::Duration::from_millis(1500)).await;
+                None
+            });
+            assert!(late.await.is_none(), "the stalled address got a session");
+        })
+    }
+
+    #[test]
+    fn races_ipv6_and_ipv4() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 10, "mx.example.org")
+                .with_ip("mx.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("mx.example.org", IpAddr::V6(Ipv6Addr::LOCALHOST))
+                .resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            let (listener, port) = listen("127.0.0.2".parse::<IpAddr>().unwrap()).await;
+            // IPv6 is black-holed
+            let (_v6_listener, _v6_filler) =
+                stalled_listener(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port));
+
+            let start = Instant::now();
+            let (res, _io) = futures::join!(
+                client.connect_to_mx("example.org", port),
+                accept(&listener, b"250 test.example.org\r\n"),
+            );
+            if let Err(e) = res {
+                panic!("failed connecting to the MX: {:?}", e);
+            }
+            // IPv6 was attempted first, despite being resolved last, so IPv4
+            // only got attempted after the connection attempt delay
+            assert!(start.elapsed() >= std::time::Duration::from_millis(250));
+            // Way before the connect timeout of the IPv6 address
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        })
+    }
+
+    #[test]
+    fn sets_up_single_session_on_slow_banner() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 10, "mx.example.org")
+                .with_ip("mx.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("mx.example.org", "127.0.0.3".parse().unwrap())
+                .resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            let (slow, port) = listen("127.0.0.2".parse::<IpAddr>().unwrap()).await;
+            let other = smol::net::TcpListener::bind(("127.0.0.3", port))
+                .await
+                .unwrap();
+            // Like a server doing greet-pause, the first address waits longer
+            // than the connection attempt delay before sending its banner
+            let server = async {
+                let (mut io, _) = slow.accept().await.unwrap();
+                smol::Timer::after(std::time::Duration::from_millis(500)).await;
+                greet(&mut io, b"250 test.example.org\r\n").await;
+                io
+            };
+            let unused = async {
+                other.accept().await.unwrap();
+                panic!("the second address got a connection");
+            };
+
+            let (res, _io) = futures::join!(
+                client.connect_to_mx("example.org", port),
+                smol::future::or(server, unused),
+            );
+            if let Err(e) = res {
+                panic!("failed connecting to the MX: {:?}", e);
+            }
+        })
+    }
+
+    #[test]
+    fn balances_equal_preference_mxs() {
+        smol::block_on(async {
+            let resolver = MockDns::default()
+                .with_mx("example.org", 10, "mx1.example.org")
+                .with_mx("example.org", 10, "mx2.example.org")
+                .with_mx("example.org", 10, "mx3.example.org")
+                .with_mx("example.org", 20, "backup.example.org")
+                .with_ip("mx1.example.org", "127.0.0.2".parse().unwrap())
+                .with_ip("mx2.example.org", "127.0.0.3".parse().unwrap())
+                .with_ip("mx3.example.org", "127.0.0.4".parse().unwrap())
+                .with_ip("backup.example.org", "127.0.0.5".parse().unwrap())
+                .resolver();
+            let cfg = TestConfig {
+                mx_balancing: MxBalancing::RoundRobin,
+                ..TestConfig::default()
+            };
+            let client = Client::new(resolver, Arc::new(cfg));
+            // Listen on all addresses, to see which MX the client picked
+            let (listener, port) = listen(Ipv4Addr::UNSPECIFIED).await;
+            let mut counts = HashMap::new();
+            for _ in 0..30 {
+                let server = async {
+                    let (io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                    io.local_addr().unwrap().ip()
+                };
+                let (res, ip) = futures::join!(client.connect_to_mx("example.org", port), server);
+                res.unwrap();
+                *counts.entry(ip.to_string()).or_insert(0) += 1;
+            }
+            let expected = ["127.0.0.2", "127.0.0.3", "127.0.0.4"]
+                .iter()
+                .map(|ip| (ip.to_string(), 10))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(counts, expected);
+        })
+    }
+
+    #[test]
+    fn reports_nonexistent_domains() {
+        smol::block_on(async {
+            let mut dns = MockDns::default();
+            for rtype in &[RecordType::MX, RecordType::A, RecordType::AAAA] {
+                dns = dns.with_error("nowhere.example.org", *rtype, ResponseCode::NXDomain);
+            }
+            let resolver = dns.resolver();
+            let client = Client::new(resolver, Arc::new(TestConfig::default()));
+            match client.connect_to_mx("nowhere.example.org", SMTP_PORT).await {
+                Err(TransportError::DnsIp(_, e)) => assert!(matches!(
+                    e.kind(),
+                    ResolveErrorKind::NoRecordsFound {
+                        response_code: ResponseCode::NXDomain,
+                        ..
+                    }
+                )),
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("connected to a nonexistent domain"),
+            }
+        })
+    }
+
+    #[test]
+    fn announces_name_of_source_ip() {
+        smol::block_on(async {
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            for (source_ip, ehlo) in &[
+                ("127.0.0.1", "EHLO ip-127-0-0-1.example.org\r\n"),
+                ("127.0.0.2", "EHLO ip-127-0-0-2.example.org\r\n"),
+            ] {
+                let source_ip = source_ip.parse::<IpAddr>().unwrap();
+                let client = new_client(TestConfig {
+                    source_ip: Some(source_ip),
+                    ..TestConfig::default()
+                });
+                let server = async {
+                    let (io, hello) = accept(&listener, b"250 test.example.org\r\n").await;
+                    assert_eq!(io.peer_addr().unwrap().ip(), source_ip);
+                    assert_eq!(hello, *ehlo);
+                };
+                let (res, ()) = futures::join!(
+                    client.connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+                    server
+                );
+                if let Err(e) = res {
+                    panic!("failed connecting from {}: {:?}", source_ip, e);
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn reset_reports_closed_connection() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            // Accepts the first RSET, and closes the connection upon the second
+            let server = async {
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                let mut buf = [0; 128];
+                let read = io.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..read], b"RSET\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                let read = io.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..read], b"RSET\r\n");
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                assert!(sender.reset().await.is_ok());
+                sender.reset().await
+            };
+
+            let (res, ()) = futures::join!(client, server);
+            match res {
+                Err(e @ TransportError::ConnectionAborted) => assert!(matches!(
+                    e.severity(),
+                    TransportErrorSeverity::NetworkTransient
+                )),
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(()) => panic!("reset succeeded on a closed connection"),
+            }
+        })
+    }
+
+    #[test]
+    fn falls_back_to_plaintext_after_failed_tls() {
+        smol::block_on(async {
+            let client = new_client(TestConfig {
+                tls: TestTls::Failing { mandatory: false },
+                ..TestConfig::default()
+            });
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                // The handshake fails after STARTTLS was accepted
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 STARTTLS\r\n").await;
+                assert_eq!(read_line(&mut io).await, "STARTTLS\r\n");
+                io.write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                    .await
+                    .unwrap();
+
+                // So the client reconnects, without trying STARTTLS again
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 STARTTLS\r\n").await;
+                assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                sender.reset().await
+            };
+
+            let (res, ()) = futures::join!(client, server);
+            res.unwrap();
+        })
+    }
+
+    #[test]
+    fn remembers_broken_starttls() {
+        smol::block_on(async {
+            let client = new_client(TestConfig {
+                tls: TestTls::Failing { mandatory: false },
+                ..TestConfig::default()
+            });
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                // The handshake fails once, then the client falls back to plaintext
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 STARTTLS\r\n").await;
+                assert_eq!(read_line(&mut io).await, "STARTTLS\r\n");
+                io.write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                    .await
+                    .unwrap();
+
+                // Then all the following connections skip STARTTLS
+                for _ in 0..2 {
+                    let (mut io, _) =
+                        accept(&listener, b"250-test.example.org\r\n250 STARTTLS\r\n").await;
+                    assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                }
+            };
+            let client = async {
+                for _ in 0..2 {
+                    let mut sender = client
+                        .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                        .await
+                        .unwrap();
+                    sender.reset().await.unwrap();
+                }
+            };
+
+            futures::join!(client, server);
+        })
+    }
+
+    #[test]
+    fn requires_tls_when_mandatory() {
+        let connect = |starttls: &'static [u8]| {
+            smol::block_on(async move {
+                let client = new_client(TestConfig {
+                    tls: TestTls::Failing { mandatory: true },
+                    ..TestConfig::default()
+                });
+                let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+                let server = async {
+                    let (mut io, _) = accept(&listener, starttls).await;
+                    if starttls.ends_with(b"STARTTLS\r\n") {
+                        assert_eq!(read_line(&mut io).await, "STARTTLS\r\n");
+                        io.write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                            .await
+                            .unwrap();
+                    }
+                };
+                let client = client.connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+                match futures::join!(client, server).0 {
+                    Ok(_) => panic!("connected without TLS"),
+                    Err(e) => e,
+                }
+            })
+        };
+
+        let err = connect(b"250 test.example.org\r\n");
+        assert!(
+            matches!(err, TransportError::TlsRequiredButUnavailable),
+            "unexpected error: {:?}",
+            err
+        );
+
+        // A failed handshake is not retried in plaintext
+        let err = connect(b"250-test.example.org\r\n250 STARTTLS\r\n");
+        assert!(
+            matches!(err, TransportError::NegotiatingTls(_)),
+            "unexpected error: {:?}",
+            err
+        );
+    }
+
+    /// Connects to the MX of example.org, that has `tlsa` as TLSA records and
+    /// expects the lines of `script` after EHLO, answering each of them with
+    /// its reply. Returns the TLSA records DANE was attempted with.
+    fn connect_with_dane(
+        tlsa: Vec<TLSA>,
+        script: &'static [(&'static str, &'static [u8])],
+    ) -> (Result<(), TransportError>, Vec<TLSA>) {
+        smol::block_on(async move {
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let tlsa_name = format!("_{}._tcp.mx.example.org", port);
+            let resolver = tlsa
+                .into_iter()
+                .fold(MockDns::default(), |dns, t| dns.with_tlsa(&tlsa_name, t))
+                .with_mx("example.org", 10, "mx.example.org")
+                .with_ip("mx.example.org", IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .resolver();
+            let cfg = Arc::new(TestConfig {
+                tls: TestTls::Dane,
+                ..TestConfig::default()
+            });
+            let client = Client::new(resolver, cfg.clone());
+            let server = async {
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 STARTTLS\r\n").await;
+                for &(line, reply) in script {
+                    assert_eq!(read_line(&mut io).await, line);
+                    io.write_all(reply).await.unwrap();
+                }
+            };
+            let client = async {
+                let mut sender = client.connect_to_mx("example.org", port).await?;
+                sender.reset().await
+            };
+            let (res, ()) = futures::join!(client, server);
+            let tlsa = cfg.tlsa.lock().unwrap().clone();
+            (res, tlsa)
+        })
+    }
+
+    #[test]
+    fn authenticates_tls_with_dane() {
+        let record =
+            |usage, data: &[u8]| TLSA::new(usage, Selector::Spki, Matching::Sha256, data.to_vec());
+        let tls_script: &[(&str, &[u8])] = &[
+            ("STARTTLS\r\n", b"220 2.0.0 Ready to start TLS\r\n"),
+            ("EHLO test.example.org\r\n", b"250 test.example.org\r\n"),
+            ("RSET\r\n", b"250 2.0.0 Okay\r\n"),
+        ];
+
+        // Only the usable records are passed on
+        let good = record(CertUsage::DomainIssued, b"good");
+        let unusable = record(CertUsage::CA, b"good");
+        let (res, tlsa) = connect_with_dane(vec![good.clone(), unusable.clone()], tls_script);
+        res.unwrap();
+        assert_eq!(tlsa, vec![good]);
+
+        // A mismatch is not retried in plaintext
+        let bad = record(CertUsage::TrustAnchor, b"bad");
+        let (res, tlsa) = connect_with_dane(
+            vec![bad.clone()],
+            &[("STARTTLS\r\n", b"220 2.0.0 Ready to start TLS\r\n")],
+        );
+        assert!(
+            matches!(res, Err(TransportError::DaneValidationFailed(_))),
+            "unexpected result: {:?}",
+            res
+        );
+        assert_eq!(tlsa, vec![bad]);
+
+        // Without usable records, TLS is negotiated as usual
+        let (res, tlsa) = connect_with_dane(vec![unusable], tls_script);
+        res.unwrap();
+        assert_eq!(tlsa, Vec::new());
+        let (res, tlsa) = connect_with_dane(Vec::new(), tls_script);
+        res.unwrap();
+        assert_eq!(tlsa, Vec::new());
+    }
+
+    /// Connects to a server supporting AUTH LOGIN and PLAIN, which expects the
+    /// lines of `script` after EHLO and answers each of them with its reply
+    fn authenticate(
+        cfg: TestConfig,
+        script: &'static [(&'static str, &'static [u8])],
+    ) -> Result<(), TransportError> {
+        smol::block_on(async move {
+            let client = new_client(cfg);
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) = accept(
+                    &listener,
+                    b"250-test.example.org\r\n250 AUTH LOGIN PLAIN\r\n",
+                )
+                .await;
+                for (line, reply) in script {
+                    assert_eq!(read_line(&mut io).await, *line);
+                    io.write_all(reply).await.unwrap();
+                }
+            };
+            let client = client.connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            futures::join!(client, server).0.map(|_| ())
+        })
+    }
+
+    #[test]
+    fn authenticates_with_plain_and_login() {
+        let cfg = |mechanisms| TestConfig {
+            auth_mechanisms: Some(mechanisms),
+            allow_auth_without_tls: true,
+            ..TestConfig::default()
+        };
+
+        authenticate(
+            cfg(vec![Mechanism::Plain, Mechanism::Login]),
+            &[(
+                "AUTH PLAIN AHVzZXIAcGFzcw==\r\n",
+                b"235 2.7.0 Authentication successful\r\n",
+            )],
+        )
+        .expect("authenticating with PLAIN");
+
+        authenticate(
+            cfg(vec![Mechanism::Other("CRAM-MD5".into()), Mechanism::Login]),
+            &[
+                ("AUTH LOGIN\r\n", b"334 VXNlcm5hbWU6\r\n"),
+                ("dXNlcg==\r\n", b"334 UGFzc3dvcmQ6\r\n"),
+                ("cGFzcw==\r\n", b"235 2.7.0 Authentication successful\r\n"),
+            ],
+        )
+        .expect("authenticating with LOGIN");
+
+        match authenticate(
+            cfg(vec![Mechanism::Plain]),
+            &[(
+                "AUTH PLAIN AHVzZXIAcGFzcw==\r\n",
+                b"535 5.7.8 Authentication credentials invalid\r\n",
+            )],
+        ) {
+            Err(e @ TransportError::AuthenticationFailed(_)) => {
+                assert_eq!(e.severity(), TransportErrorSeverity::MailSystemPermanent)
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let res = authenticate(cfg(vec![Mechanism::Other("CRAM-MD5".into())]), &[]);
+        assert!(
+            matches!(res, Err(TransportError::NoAuthMechanism)),
+            "unexpected result: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn refuses_auth_without_tls() {
+        let cfg = TestConfig {
+            auth_mechanisms: Some(vec![Mechanism::Plain]),
+            ..TestConfig::default()
+        };
+        let res = authenticate(cfg, &[]);
+        assert!(
+            matches!(res, Err(TransportError::AuthRequiresTls)),
+            "unexpected result: {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn reads_lf_only_replies() {
+        let read = |accept_lf_only| {
+            smol::block_on(async move {
+                let mut io = futures::io::Cursor::new(
+                    b"250-test.example.org\n250-PIPELINING\r\n250 CHUNKING\n220 Next\n".to_vec(),
+                );
+                let mut rdbuf = [0; RDBUF_SIZE];
+                let mut unhandled = 0..0;
+                let timeout = chrono::Duration::seconds(1);
+                let first =
+                    read_reply(&mut io, &mut rdbuf, &mut unhandled, timeout, accept_lf_only).await?;
+                let second =
+                    read_reply(&mut io, &mut rdbuf, &mut unhandled, timeout, accept_lf_only).await?;
+                Ok::<_, TransportError>((first, second))
+            })
+        };
+
+        let (first, second) = read(true).expect("reading in lenient mode");
+        assert_eq!(first.code, ReplyCode::OKAY);
+        assert_eq!(
+            first.text.iter().map(|t| t.as_str()).collect::<Vec<_>>(),
+            vec!["test.example.org", "PIPELINING", "CHUNKING"]
+        );
+        assert_eq!(second.code, ReplyCode::SERVICE_READY);
+
+        assert!(read(false).is_err());
+    }
+
+    #[test]
+    fn reads_replies_longer_than_buffer() {
+        let read = |input: Vec<u8>| {
+            smol::block_on(async move {
+                let mut io = futures::io::Cursor::new(input);
+                let mut rdbuf = [0; RDBUF_SIZE];
+                let mut unhandled = 0..0;
+                let timeout = chrono::Duration::seconds(1);
+                let first = read_reply(&mut io, &mut rdbuf, &mut unhandled, timeout, false).await?;
+                let second =
+                    read_reply(&mut io, &mut rdbuf, &mut unhandled, timeout, false).await?;
+                Ok::<_, TransportError>((first, second))
+            })
+        };
+
+        // 200 lines of 200 bytes do not fit in the buffer all at once
+        let padding = "a".repeat(180);
+        let mut input = Vec::new();
+        for i in 0..200 {
+            input.extend_from_slice(format!("250-X-LONG-{:03} {}\r\n", i, padding).as_bytes());
+        }
+        input.extend_from_slice(b"250 CHUNKING\r\n220 Next\r\n");
+        assert!(input.len() > 2 * RDBUF_SIZE);
+        let (first, second) = read(input).expect("reading the replies");
+        assert_eq!(first.code, ReplyCode::OKAY);
+        assert_eq!(first.text.len(), 201);
+        assert_eq!(first.text[199].as_str(), format!("X-LONG-199 {}", padding));
+        assert_eq!(first.text[200].as_str(), "CHUNKING");
+        assert!(Capabilities::from_ehlo_reply(&first).chunking);
+        assert_eq!(second.code, ReplyCode::SERVICE_READY);
+
+        // But the number of lines and the length of each line are limited
+        let too_many_lines = b"250-hello\r\n".repeat(MAX_REPLY_LINES);
+        let res = read(too_many_lines);
+        assert!(matches!(res, Err(TransportError::TooLongReply(_))));
+        let mut too_long_line = b"250 ".to_vec();
+        too_long_line.extend_from_slice(&[b'a'; RDBUF_SIZE]);
+        let res = read(too_long_line);
+        assert!(matches!(res, Err(TransportError::TooLongReply(_))));
+    }
+
+    #[test]
+    fn parses_postfix_ehlo_capabilities() {
+        let parse = |banner: &'static [u8]| {
+            smol::block_on(async move {
+                let mut io = futures::io::Cursor::new(banner.to_vec());
+                let mut rdbuf = [0; RDBUF_SIZE];
+                let mut unhandled = 0..0;
+                let timeout = chrono::Duration::seconds(1);
+                let reply = read_reply(&mut io, &mut rdbuf, &mut unhandled, timeout, false)
+                    .await
+                    .expect("reading the reply");
+                Capabilities::from_ehlo_reply(&reply)
+            })
+        };
+
+        assert_eq!(
+            parse(
+                b"250-mail.example.org\r\n\
+                  250-PIPELINING\r\n\
+                  250-SIZE 10240000\r\n\
+                  250-VRFY\r\n\
+                  250-ETRN\r\n\
+                  250-STARTTLS\r\n\
+                  250-AUTH PLAIN LOGIN\r\n\
+                  250-AUTH=PLAIN LOGIN\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-8BITMIME\r\n\
+                  250-DSN\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 CHUNKING\r\n"
+            ),
+            Capabilities {
+                starttls: true,
+                size: Some(10240000),
+                eight_bit_mime: true,
+                smtputf8: true,
+                pipelining: true,
+                auth: vec![Mechanism::Plain, Mechanism::Login],
+                chunking: true,
+                dsn: true,
+            }
+        );
+
+        assert_eq!(
+            parse(
+                b"250-STARTTLS.example.org greets you\r\n\
+                  250-size\r\n\
+                  250-Auth login cram-md5\r\n\
+                  250 8bitMime\r\n"
+            ),
+            Capabilities {
+                size: Some(0),
+                auth: vec![Mechanism::Login, Mechanism::Other("CRAM-MD5".into())],
+                eight_bit_mime: true,
+                ..Capabilities::default()
+            }
+        );
+    }
+
+    #[test]
+    fn routes_to_next_hop() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+
+            let host = Hostname::parse(b"relayed.example.org").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap();
+            assert_eq!(dest.to_string(), "relay.example.org:2525");
+            assert!(dest.is_relay);
+
+            let host = Hostname::parse(b"other.example.org").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap();
+            assert_eq!(dest.to_string(), "other.example.org");
+            assert!(!dest.is_relay);
+        })
+    }
+
+    #[test]
+    fn routes_domainless_recipients() {
+        smol::block_on(async {
+            let to = Email::parse_bracketed(b"<postmaster>").unwrap();
+            assert!(to.hostname.is_none());
+
+            let client = new_client(TestConfig::default());
+            match client.get_recipient_destination(&to).await {
+                Err(e @ TransportError::NoRecipientDomain(_)) => assert!(matches!(
+                    e.severity(),
+                    TransportErrorSeverity::MailboxPermanent
+                )),
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(dest) => panic!("got a destination for a domainless recipient: {}", dest),
+            }
+
+            let domain = Hostname::parse(b"mail.example.org").unwrap().1.to_owned();
+            let client = new_client(TestConfig {
+                domainless_recipient_domain: Some(domain),
+                ..TestConfig::default()
+            });
+            let dest = client.get_recipient_destination(&to).await.unwrap();
+            assert_eq!(dest.to_string(), "mail.example.org");
+        })
+    }
+
+    #[test]
+    fn orders_addresses() {
+        let v4 = |i| IpAddr::V4(Ipv4Addr::new(192, 0, 2, i));
+        let v6 = |i| IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i));
+        let ips = vec![v6(1), v4(1), v6(2), v4(2)];
+        let order = |pref, use_ipv6| order_addresses(ips.clone(), pref, use_ipv6);
+        assert_eq!(order(IpVersionPreference::AsResolved, true), ips);
+        assert_eq!(
+            order(IpVersionPreference::Ipv4First, true),
+            vec![v4(1), v4(2), v6(1), v6(2)]
+        );
+        assert_eq!(
+            order(IpVersionPreference::Ipv6First, true),
+            vec![v6(1), v6(2), v4(1), v4(2)]
+        );
+        assert_eq!(order(IpVersionPreference::Ipv6First, false), vec![v4(1), v4(2)]);
+        let ips = vec![v4(1), v4(2), v4(3), v6(1)];
+        assert_eq!(
+            order_addresses(ips, IpVersionPreference::Interleaved, true),
+            vec![v6(1), v4(1), v4(2), v4(3)]
+        );
+
+        // A host with only AAAA records has no usable address without IPv6
+        let ipv6_only = vec![v6(1), v6(2)];
+        assert!(order_addresses(ipv6_only, IpVersionPreference::AsResolved, false).is_empty());
+    }
+
+    #[test]
+    fn classifies_resource_exhaustion_as_local() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let err = io::Error::from_raw_os_error(libc::EMFILE);
+        let err = TransportError::Connecting(ip, 25, err);
+        assert_eq!(err.severity(), TransportErrorSeverity::Local);
+        let err = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let err = TransportError::Connecting(ip, 25, err);
+        assert_eq!(err.severity(), TransportErrorSeverity::NetworkTransient);
+    }
+
+    #[test]
+    fn fails_on_ipv6_when_disabled() {
+        smol::block_on(async {
+            let client = new_client(TestConfig {
+                use_ipv6: false,
+                ..TestConfig::default()
+            });
+            let host = Hostname::parse(b"[IPv6:2001:db8::1]").unwrap().1.to_owned();
+            let dest = client.get_destination(&host).await.unwrap();
+            let res = smol::future::or(async { Some(client.connect(&dest).await) }, async {
+                smol::Timer::after(std::time::Duration::from_secs(5)).await;
+                None
+            })
+            .await;
+            match res {
+                Some(Err(e @ TransportError::Ipv6Disabled(_))) => assert!(matches!(
+                    e.severity(),
+                    TransportErrorSeverity::MailSystemTransient
+                )),
+                Some(Err(e)) => panic!("unexpected error: {:?}", e),
+                Some(Ok(_)) => panic!("unexpectedly connected over IPv6"),
+                None => panic!("timed out instead of failing"),
+            }
+        })
+    }
+
+    #[test]
+    fn sends_single_bdat_chunk() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 CHUNKING\r\n").await;
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+
+                // Read the BDAT command line, then exactly the declared size
+                let size = read_line(&mut io)
+                    .await
+                    .strip_prefix("BDAT ")
+                    .and_then(|l| l.strip_suffix(" LAST\r\n"))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                let mut data = vec![0; size];
+                io.read_exact(&mut data).await.unwrap();
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                data
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\n..dotted\r\nbody\r\n.\r\n";
+                sender.send(None, &to, mail, Some(30)).await
+            };
+
+            let (res, data) = futures::join!(client, server);
+            res.unwrap();
+            assert_eq!(data, b"Subject: hi\r\n\r\n.dotted\r\nbody\r\n");
+        })
+    }
+
+    #[test]
+    fn announces_size_of_bdat_chunk() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) = accept(
+                    &listener,
+                    b"250-test.example.org\r\n250-SIZE 1000000\r\n250 CHUNKING\r\n",
+                )
+                .await;
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<> SIZE=30\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "BDAT 30 LAST\r\n");
+                let mut data = vec![0; 30];
+                io.read_exact(&mut data).await.unwrap();
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                data
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\n..dotted\r\nbody\r\n.\r\n";
+                sender.send(None, &to, mail, Some(30)).await
+            };
+
+            let (res, data) = futures::join!(client, server);
+            res.unwrap();
+            assert_eq!(data, b"Subject: hi\r\n\r\n.dotted\r\nbody\r\n");
+        })
+    }
+
+    #[test]
+    fn sends_mails_of_unknown_size_in_chunks() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 CHUNKING\r\n").await;
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+
+                // Read the chunks until the empty last one
+                let mut sizes = Vec::new();
+                let mut data = Vec::new();
+                loop {
+                    let line = read_line(&mut io).await;
+                    if line == "BDAT 0 LAST\r\n" {
+                        break;
+                    }
+                    let size = line
+                        .strip_prefix("BDAT ")
+                        .and_then(|l| l.strip_suffix("\r\n"))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    let start = data.len();
+                    data.resize(start + size, 0);
+                    io.read_exact(&mut data[start..]).await.unwrap();
+                    io.write_all(b"250 2.0.0 Chunk okay\r\n").await.unwrap();
+                    sizes.push(size);
+                }
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                (sizes, data)
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let mut mail = b"Subject: hi\r\n\r\n".to_vec();
+                for i in 0..10_000 {
+                    mail.extend_from_slice(format!("..line {}\r\n", i).as_bytes());
+                }
+                mail.extend_from_slice(b".\r\n");
+                sender.send(None, &to, &mail[..], None).await
+            };
+
+            let (res, (sizes, data)) = futures::join!(client, server);
+            res.unwrap();
+            let mut expected = b"Subject: hi\r\n\r\n".to_vec();
+            for i in 0..10_000 {
+                expected.extend_from_slice(format!(".line {}\r\n", i).as_bytes());
+            }
+            assert_eq!(sizes, vec![BDAT_CHUNK_SIZE, expected.len() - BDAT_CHUNK_SIZE]);
+            assert!(data == expected, "unexpected chunked data");
+        })
+    }
+
+    #[test]
+    fn reuses_idle_sender_within_ttl() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(read_line(&mut io).await, "NOOP \r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "QUIT\r\n");
+                io.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+                let mut rest = Vec::new();
+                io.read_to_end(&mut rest).await.unwrap();
+                assert_eq!(rest, b"");
+            };
+            let client = async {
+                let sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let idle = sender.into_idle(std::time::Duration::from_secs(3600));
+                let sender = idle.check_out().await.expect("checking out within the ttl");
+                let idle = sender.into_idle(std::time::Duration::from_millis(10));
+                smol::Timer::after(std::time::Duration::from_millis(20)).await;
+                assert!(idle.check_out().await.is_none());
+            };
+            futures::join!(client, server);
+        })
+    }
+
+    #[test]
+    fn reuses_pooled_sender_after_rset() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            // Both mails go through a single connection
+            let server = async {
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                for _ in 0..2 {
+                    assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                    io.write_all(b"250 2.1.5 Okay\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                    io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    let mut data = String::new();
+                    while !data.ends_with("\r\n.\r\n") {
+                        data.push_str(&read_line(&mut io).await);
+                    }
+                    io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "NOOP \r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                }
+                assert_eq!(read_line(&mut io).await, "QUIT\r\n");
+                io.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+            };
+            let client = async {
+                let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+                let dest = client.get_destination(&host).await.unwrap().with_port(port);
+                let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                for _ in 0..2 {
+                    let mut sender = client.connect(&dest).await.expect("connecting");
+                    sender.send(None, &to, mail, None).await.expect("sending");
+                    client.release(&dest, sender).await;
+                }
+                let sender = client.connect(&dest).await.expect("connecting");
+                assert_eq!(sender.reuses, 2);
+                sender.quit().await;
+            };
+            futures::join!(client, server);
+        })
+    }
+
+    #[test]
+    fn evicts_pooled_sender_failing_noop() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                // The server went away while the connection was idle
+                assert_eq!(read_line(&mut io).await, "NOOP \r\n");
+                io.write_all(b"421 4.4.2 Idle for too long\r\n")
+                    .await
+                    .unwrap();
+                std::mem::drop(io);
+
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(read_line(&mut io).await, "QUIT\r\n");
+                io.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+            };
+            let client = async {
+                let host = Hostname::parse(b"[127.0.0.1]").unwrap().1.to_owned();
+                let dest = client.get_destination(&host).await.unwrap().with_port(port);
+                let sender = client.connect(&dest).await.expect("connecting");
+                client.release(&dest, sender).await;
+                let sender = client.connect(&dest).await.expect("reconnecting");
+                assert_eq!(sender.reuses, 0);
+                sender.quit().await;
+                assert!(client.pool.lock().unwrap()[&dest].is_empty());
+            };
+            futures::join!(client, server);
+        })
+    }
+
+    #[test]
+    fn sends_data_after_rejected_mailbox() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (inp_pipe_r, inp_pipe_w) = piper::pipe(1024 * 1024);
+            let (out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+            let server = async {
+                let mut io = duplexify::Duplex::new(out_pipe_r, inp_pipe_w);
+                greet(&mut io, b"250 test.example.org\r\n").await;
+
+                // The first recipient does not exist
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<bar@example.org>\r\n");
+                io.write_all(b"550 5.1.1 No such user\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+
+                // The second one gets the mail, as it was escaped by the caller
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<baz@example.org>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.1.5 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                let mut data = String::new();
+                while !data.ends_with("\r\n.\r\n") {
+                    data.push_str(&read_line(&mut io).await);
+                }
+                io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+                data
+            };
+            let client = async {
+                let io = duplexify::Duplex::new(
+                    Box::pin(inp_pipe_r) as Pin<Box<dyn Send + AsyncRead>>,
+                    Box::pin(out_pipe_w) as Pin<Box<dyn Send + AsyncWrite>>,
+                );
+                let mut sender = client.connect_to_stream(io).await.unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\n..dotted\r\nbody\r\n.\r\n";
+                let bar = Email::parse_bracketed(b"<bar@example.org>").unwrap();
+                match sender.send(None, &bar, mail, None).await {
+                    Err(e @ TransportError::PermanentMailbox(_)) => assert!(matches!(
+                        e.severity(),
+                        TransportErrorSeverity::MailboxPermanent
+                    )),
+                    res => panic!("unexpected result: {:?}", res),
+                }
+                sender.reset().await.unwrap();
+                let from = Email::parse_bracketed(b"<baz@example.org>").unwrap();
+                let foo = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                sender.send(Some(&from), &foo, mail, None).await
+            };
+
+            let (res, data) = futures::join!(client, server);
+            res.unwrap();
+            assert_eq!(data, "Subject: hi\r\n\r\n..dotted\r\nbody\r\n.\r\n");
+        })
+    }
+
+    /// Writer that records each of the writes done through it
+    struct RecordingWriter(smol::net::TcpStream, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let res = Pin::new(&mut self.0).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = res {
+                self.1.lock().unwrap().push(buf[..n].to_vec());
+            }
+            res
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_close(cx)
+        }
+    }
+
+    #[test]
+    fn pipelines_commands() {
+        smol::block_on(async {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let writes = Arc::new(Mutex::new(Vec::new()));
+            let server = async {
+                let (mut io, _) =
+                    accept(&listener, b"250-test.example.org\r\n250 PIPELINING\r\n").await;
+
+                // All the commands are received before any reply is sent
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<bar@example.org>\r\n");
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(
+                    b"250 2.0.0 Okay\r\n250 2.1.5 Okay\r\n550 5.1.1 No such user\r\n\
+                      354 Go ahead\r\n",
+                )
+                .await
+                .unwrap();
+                while read_line(&mut io).await != ".\r\n" {}
+                io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+
+                // Replying 354 to MAIL FROM gets the replies out of sync
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(b"354 Go ahead\r\n250 2.1.5 Okay\r\n354 Go ahead\r\n")
+                    .await
+                    .unwrap();
+                assert_eq!(read_line(&mut io).await, ".\r\n");
+                io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RSET\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+
+                // So the next mail is sent without pipelining
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.1.5 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                while read_line(&mut io).await != ".\r\n" {}
+                io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+            };
+            let client = async {
+                let io = smol::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                    .await
+                    .unwrap();
+                let writer = RecordingWriter(io.clone(), writes.clone());
+                let io = duplexify::Duplex::new(
+                    Box::pin(io) as Pin<Box<dyn Send + AsyncRead>>,
+                    Box::pin(writer) as Pin<Box<dyn Send + AsyncWrite>>,
+                );
+                let mut sender = client.connect_to_stream(io).await.unwrap();
+                let foo = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                let bar = Email::parse_bracketed(b"<bar@example.org>").unwrap();
+                let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                let res = sender
+                    .send_batch(None, &[foo.clone(), bar], mail, None)
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    res[..],
+                    [Ok(()), Err(TransportError::PermanentMailbox(_))]
+                ));
+                let res = sender.send(None, &foo, mail, None).await;
+                assert!(matches!(res, Err(TransportError::UnexpectedReplyCode(_))));
+                sender.reset().await.unwrap();
+                sender.send(None, &foo, mail, None).await
+            };
+
+            let (res, ()) = futures::join!(client, server);
+            res.unwrap();
+            let burst: &[u8] = b"MAIL FROM:<>\r\nRCPT TO:<foo@example.org>\r\n\
+                                 RCPT TO:<bar@example.org>\r\nDATA\r\n";
+            let writes = writes.lock().unwrap();
+            assert_eq!(writes.iter().filter(|w| &w[..] == burst).count(), 1);
+            let rcpt: &[u8] = b"RCPT TO:<foo@example.org>\r\n";
+            assert_eq!(writes.iter().filter(|w| &w[..] == rcpt).count(), 1);
+        })
+    }
+
+    #[test]
+    fn handles_long_lines() {
+        let send = |policy, body_line_len: usize| {
+            smol::block_on(async move {
+                let client = new_client(TestConfig {
+                    long_line_policy: policy,
+                    ..TestConfig::default()
+                });
+                let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+                let server = async {
+                    let (mut io, _) =
+                        accept(&listener, b"250-test.example.org\r\n250 CHUNKING\r\n").await;
+                    if policy == LongLinePolicy::Reject || body_line_len > MAX_LINE_LENGTH {
+                        // The client must give up before starting the transaction
+                        let mut rest = Vec::new();
+                        io.read_to_end(&mut rest).await.unwrap();
+                        return rest;
+                    }
+                    assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    let size = read_line(&mut io)
+                        .await
+                        .strip_prefix("BDAT ")
+                        .and_then(|l| l.strip_suffix(" LAST\r\n"))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    let mut data = vec![0; size];
+                    io.read_exact(&mut data).await.unwrap();
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    data
+                };
+                let client = async {
+                    let mut sender = client
+                        .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                        .await
+                        .unwrap();
+                    let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                    let mut mail = b"Subject: ".to_vec();
+                    mail.extend_from_slice(&[b'a'; 2000]);
+                    mail.extend_from_slice(b"\r\n\r\n");
+                    mail.extend_from_slice(&vec![b'b'; body_line_len]);
+                    mail.extend_from_slice(b"\r\n.\r\n");
+                    let size = mail.len() as u64 - 3;
+                    sender.send(None, &to, &mail[..], Some(size)).await
+                };
+                futures::join!(client, server)
+            })
+        };
+
+        let (res, data) = send(LongLinePolicy::Reject, 10);
+        match res {
+            Err(e @ TransportError::LineTooLong) => assert!(matches!(
+                e.severity(),
+                TransportErrorSeverity::MailPermanent
+            )),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(data, b"");
+
+        // Only the headers are wrapped
+        let (res, data) = send(LongLinePolicy::Wrap, 10);
+        res.unwrap();
+        let mut expected = b"Subject: ".to_vec();
+        expected.extend_from_slice(&[b'a'; 989]);
+        expected.extend_from_slice(b"\r\n ");
+        expected.extend_from_slice(&[b'a'; 997]);
+        expected.extend_from_slice(b"\r\n ");
+        expected.extend_from_slice(&[b'a'; 14]);
+        expected.extend_from_slice(b"\r\n\r\n");
+        expected.extend_from_slice(&[b'b'; 10]);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(data, expected);
+
+        let (res, data) = send(LongLinePolicy::Wrap, 2000);
+        assert!(matches!(res, Err(TransportError::LineTooLong)));
+        assert_eq!(data, b"");
+    }
+
+    #[test]
+    fn folds_long_headers() {
+        let subject = (0..200)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let unbreakable = "a".repeat(1000);
+        let body = "b ".repeat(600);
+        let mail = format!(
+            "Subject: {}\r\nX-Long:{}\r\n\r\n{}\r\n.\r\n",
+            subject, unbreakable, body
+        );
+        let folded = String::from_utf8(fold_long_headers(mail.as_bytes())).unwrap();
+
+        let lines = folded.split("\r\n").collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Subject: word0 "));
+        assert!(lines[0].len() <= MAX_LINE_LENGTH);
+        assert!(lines[1].starts_with(' '));
+        assert!(lines[1].len() <= MAX_LINE_LENGTH);
+        // The header value is unchanged once unfolded
+        let unfolded = lines[..2].concat();
+        assert_eq!(unfolded, format!("Subject: {}", subject));
+        // Lines without whitespace and body lines are left to long_line_policy
+        assert_eq!(lines[2], format!("X-Long:{}", unbreakable));
+        assert_eq!(&lines[3..], &["", &body, ".", ""]);
+        assert_eq!(folded.len(), mail.len() + 2);
+    }
+
+    #[test]
+    fn negotiates_smtputf8() {
+        let send = |ehlo: &'static [u8], from: &'static [u8], to: &'static [u8]| {
+            smol::block_on(async move {
+                let client = new_client(TestConfig::default());
+                let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+                let server = async {
+                    let (mut io, _) = accept(&listener, ehlo).await;
+                    // Returns the commands of the transaction, if it was started
+                    let mut cmds = Vec::new();
+                    let mut c = [0];
+                    if io.peek(&mut c).await.unwrap() == 0 {
+                        return cmds;
+                    }
+                    for _ in 0..2 {
+                        cmds.push(read_line(&mut io).await);
+                        io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    }
+                    assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                    io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    while read_line(&mut io).await != ".\r\n" {}
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    cmds
+                };
+                let client = async {
+                    let mut sender = client
+                        .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                        .await
+                        .unwrap();
+                    let from = Email::parse_bracketed(from).unwrap();
+                    let to = Email::parse_bracketed(to).unwrap();
+                    let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                    sender.send(Some(&from), &to, mail, None).await
+                    // The sender is dropped here, closing the connection
+                };
+                futures::join!(client, server)
+            })
+        };
+        let with_smtputf8 = b"250-test.example.org\r\n250 SMTPUTF8\r\n";
+        let without_smtputf8 = b"250 test.example.org\r\n";
+
+        let (res, cmds) = send(
+            with_smtputf8,
+            "<pelé@exämple.org>".as_bytes(),
+            b"<foo@example.org>",
+        );
+        res.unwrap();
+        assert_eq!(
+            cmds,
+            vec![
+                "MAIL FROM:<pelé@exämple.org> SMTPUTF8\r\n",
+                "RCPT TO:<foo@example.org>\r\n",
+            ]
+        );
+
+        let (res, cmds) = send(
+            without_smtputf8,
+            b"<foo@example.org>",
+            "<bar@exämple.org>".as_bytes(),
+        );
+        res.unwrap();
+        assert_eq!(
+            cmds,
+            vec![
+                "MAIL FROM:<foo@example.org>\r\n",
+                "RCPT TO:<bar@xn--exmple-cua.org>\r\n",
+            ]
+        );
+
+        let (res, cmds) = send(
+            without_smtputf8,
+            b"<foo@example.org>",
+            "<pelé@example.org>".as_bytes(),
+        );
+        assert!(matches!(res, Err(TransportError::Smtputf8Unsupported)));
+        assert!(cmds.is_empty());
+    }
+
+    #[test]
+    fn handles_8bit_mails() {
+        let send = |policy| {
+            smol::block_on(async move {
+                let client = new_client(TestConfig {
+                    on_8bit_to_7bit_only: policy,
+                    ..TestConfig::default()
+                });
+                let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+                let server = async {
+                    // No 8BITMIME
+                    let (mut io, _) =
+                        accept(&listener, b"250-test.example.org\r\n250 CHUNKING\r\n").await;
+                    if policy == EightBitPolicy::Reject {
+                        // The client must give up before starting the transaction
+                        let mut rest = Vec::new();
+                        io.read_to_end(&mut rest).await.unwrap();
+                        return rest;
+                    }
+                    assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    let size = read_line(&mut io)
+                        .await
+                        .strip_prefix("BDAT ")
+                        .and_then(|l| l.strip_suffix(" LAST\r\n"))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    let mut data = vec![0; size];
+                    io.read_exact(&mut data).await.unwrap();
+                    io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                    data
+                };
+                let client = async {
+                    let mut sender = client
+                        .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                        .await
+                        .unwrap();
+                    let to = Email::parse_bracketed(b"<foo@example.org>").unwrap();
+                    let mail: &[u8] = b"Subject: hi\r\n\
+                                        Content-Transfer-Encoding: 8bit\r\n\
+                                        \r\n\
+                                        caf\xc3\xa9 = coffee \r\n\
+                                        ..dotted\r\n\
+                                        .\r\n";
+                    let size = mail.len() as u64 - 4;
+                    sender.send(None, &to, mail, Some(size)).await
+                };
+                futures::join!(client, server)
+            })
+        };
+
+        let (res, data) = send(EightBitPolicy::SendAnyway);
+        res.unwrap();
+        assert_eq!(
+            data,
+            &b"Subject: hi\r\n\
+               Content-Transfer-Encoding: 8bit\r\n\
+               \r\n\
+               caf\xc3\xa9 = coffee \r\n\
+               .dotted\r\n"[..]
+        );
+
+        let (res, data) = send(EightBitPolicy::Reject);
+        match res {
+            Err(e @ TransportError::EightBitUnsupported) => assert!(matches!(
+                e.severity(),
+                TransportErrorSeverity::MailPermanent
+            )),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(data, b"");
+
+        let (res, data) = send(EightBitPolicy::Downgrade);
+        res.unwrap();
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "Subject: hi\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\
+             \r\n\
+             caf=C3=A9 =3D coffee=20\r\n\
+             =2Edotted\r\n"
+        );
+
+        // Multipart mails would need each of their parts to be downgraded
+        assert_eq!(
+            downgrade_8bit(
+                b"Content-Type:\r\n Multipart/Mixed; boundary=x\r\n\r\n\xc3\xa9\r\n.\r\n"
+            ),
+            None
+        );
+    }
+
+    /// Sends a mail to foo, bar and baz, bar being rejected by RCPT TO, and
+    /// the server giving `final_replies` after the mail contents
+    fn send_to_three(lmtp: bool, final_replies: &'static [u8]) -> Delivery {
+        smol::block_on(async move {
+            let client = new_client(TestConfig {
+                use_lmtp: lmtp,
+                ..TestConfig::default()
+            });
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, hello) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert!(hello.starts_with(if lmtp { "LHLO " } else { "EHLO " }));
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<foo@example.org>\r\n");
+                io.write_all(b"250 2.1.5 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<bar@example.org>\r\n");
+                io.write_all(b"550 5.1.1 No such user\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "RCPT TO:<baz@example.org>\r\n");
+                io.write_all(b"250 2.1.5 Okay\r\n").await.unwrap();
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                while read_line(&mut io).await != ".\r\n" {}
+                io.write_all(final_replies).await.unwrap();
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let to = [
+                    "<foo@example.org>",
+                    "<bar@example.org>",
+                    "<baz@example.org>",
+                ]
+                .iter()
+                .map(|to| Email::parse_bracketed(to.as_bytes()).unwrap())
+                .collect::<Vec<_>>();
+                let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                sender.send_many(None, &to, mail, None).await
+            };
+            futures::join!(client, server).0.unwrap()
+        })
+    }
+
+    #[test]
+    fn returns_shared_final_reply() {
+        let delivery = send_to_three(false, b"250 2.0.0 Queued as 1234\r\n");
+        assert!(matches!(
+            delivery.rejected[..],
+            [None, Some(TransportError::PermanentMailbox(_)), None]
+        ));
+        assert!(matches!(delivery.final_replies, FinalReplies::Shared(_)));
+        for i in [0, 2] {
+            assert_eq!(
+                delivery.final_reply(i).unwrap().to_string(),
+                "250 2.0.0 Queued as 1234\r\n"
+            );
+        }
+        assert!(delivery.final_reply(1).is_none());
+    }
+
+    #[test]
+    fn returns_per_recipient_final_replies() {
+        let delivery = send_to_three(
+            true,
+            b"250 2.0.0 Delivered to foo\r\n452 4.2.2 Mailbox of baz is full\r\n",
+        );
+        assert!(matches!(
+            delivery.final_replies,
+            FinalReplies::PerRecipient(ref r) if r.len() == 2
+        ));
+        assert_eq!(
+            delivery.final_reply(0).unwrap().to_string(),
+            "250 2.0.0 Delivered to foo\r\n"
+        );
+        assert!(delivery.final_reply(1).is_none());
+        assert_eq!(
+            delivery.final_reply(2).unwrap().to_string(),
+            "452 4.2.2 Mailbox of baz is full\r\n"
+        );
+    }
+
+    /// Sends a mail to foo, bar and baz with `send_batch`, the server giving
+    /// them the replies of `rcpt_replies` to RCPT TO
+    fn batch_to_three(rcpt_replies: [&'static [u8]; 3]) -> Vec<Result<(), TransportError>> {
+        smol::block_on(async move {
+            let client = new_client(TestConfig::default());
+            let (listener, port) = listen(Ipv4Addr::LOCALHOST).await;
+            let server = async {
+                let (mut io, _) = accept(&listener, b"250 test.example.org\r\n").await;
+                assert_eq!(read_line(&mut io).await, "MAIL FROM:<>\r\n");
+                io.write_all(b"250 2.0.0 Okay\r\n").await.unwrap();
+                for (to, reply) in ["foo", "bar", "baz"].iter().zip(rcpt_replies.iter()) {
+                    let rcpt = format!("RCPT TO:<{}@example.org>\r\n", to);
+                    assert_eq!(read_line(&mut io).await, rcpt);
+                    io.write_all(reply).await.unwrap();
+                }
+                if rcpt_replies.iter().all(|r| !r.starts_with(b"250")) {
+                    // The client must not send the mail contents at all
+                    let mut rest = Vec::new();
+                    io.read_to_end(&mut rest).await.unwrap();
+                    assert_eq!(rest, b"");
+                    return;
+                }
+                assert_eq!(read_line(&mut io).await, "DATA\r\n");
+                io.write_all(b"354 Go ahead\r\n").await.unwrap();
+                let mut data = String::new();
+                while !data.ends_with("\r\n.\r\n") {
+                    data.push_str(&read_line(&mut io).await);
+                }
+                assert_eq!(data, "Subject: hi\r\n\r\nbody\r\n.\r\n");
+                io.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+            };
+            let client = async {
+                let mut sender = client
+                    .connect_to_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                    .await
+                    .unwrap();
+                let to = [
+                    "<foo@example.org>",
+                    "<bar@example.org>",
+                    "<baz@example.org>",
+                ]
+                .iter()
+                .map(|to| Email::parse_bracketed(to.as_bytes()).unwrap())
+                .collect::<Vec<_>>();
+                let mail: &[u8] = b"Subject: hi\r\n\r\nbody\r\n.\r\n";
+                sender.send_batch(None, &to, mail, None).await
+            };
+            futures::join!(client, server).0.unwrap()
+        })
+    }
+
+    #[test]
+    fn sends_batch_to_all_recipients() {
+        let res = batch_to_three([b"250 2.1.5 Okay\r\n"; 3]);
+        assert!(matches!(res[..], [Ok(()), Ok(()), Ok(())]));
+    }
+
+    #[test]
+    fn sends_batch_to_accepted_recipients() {
+        let res = batch_to_three([
+            b"250 2.1.5 Okay\r\n",
+            b"550 5.1.1 No such user\r\n",
+            b"450 4.2.1 Try again later\r\n",
+        ]);
+        assert!(matches!(
+            res[..],
+            [
+                Ok(()),
+                Err(TransportError::PermanentMailbox(_)),
+                Err(TransportError::TransientMailbox(_))
+            ]
+        ));
+    }
+
+    #[test]
+    fn reports_batch_rejected_by_all_recipients() {
+        let res = batch_to_three([
+            b"550 5.1.1 No such user\r\n",
+            b"550 5.1.1 No such user\r\n",
+            b"452 4.5.3 Too many recipients\r\n",
+        ]);
+        assert!(matches!(
+            res[..],
+            [
+                Err(TransportError::PermanentMailbox(_)),
+                Err(TransportError::PermanentMailbox(_)),
+                Err(TransportError::TransientMailbox(_))
+            ]
+        ));
+    }
+}
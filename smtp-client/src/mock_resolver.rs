@@ -0,0 +1,163 @@
+//! Resolver answering from scripted records, for testing the MX selection
+//! logic without any real DNS server.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{future, stream};
+use trust_dns_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfig, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+    },
+    error::ResolveError,
+    proto::{
+        op::{Message, MessageType, OpCode, ResponseCode},
+        rr::{
+            rdata::{MX, TLSA},
+            RData, Record, RecordType,
+        },
+        xfer::{DnsHandle, DnsRequest, DnsResponse},
+        Time,
+    },
+    AsyncResolver, ConnectionProvider, Name,
+};
+
+type Answers = HashMap<(String, RecordType), Result<Vec<RData>, ResponseCode>>;
+
+/// Names that have no answer configured resolve to no records
+#[derive(Default)]
+pub struct MockDns {
+    answers: Answers,
+}
+
+impl MockDns {
+    pub fn with_mx(mut self, name: &str, preference: u16, exchange: &str) -> MockDns {
+        let exchange = Name::from_ascii(exchange).unwrap();
+        self.push(name, RData::MX(MX::new(preference, exchange)));
+        self
+    }
+
+    /// Adds an A or AAAA record, depending on `ip`
+    pub fn with_ip(mut self, name: &str, ip: IpAddr) -> MockDns {
+        self.push(
+            name,
+            match ip {
+                IpAddr::V4(ip) => RData::A(ip),
+                IpAddr::V6(ip) => RData::AAAA(ip),
+            },
+        );
+        self
+    }
+
+    pub fn with_tlsa(mut self, name: &str, tlsa: TLSA) -> MockDns {
+        self.push(name, RData::TLSA(tlsa));
+        self
+    }
+
+    /// Makes the queries for `rtype` records of `name` fail with `code`
+    pub fn with_error(mut self, name: &str, rtype: RecordType, code: ResponseCode) -> MockDns {
+        self.answers.insert((key(name), rtype), Err(code));
+        self
+    }
+
+    fn push(&mut self, name: &str, rdata: RData) {
+        self.answers
+            .entry((key(name), rdata.to_record_type()))
+            .or_insert_with(|| Ok(Vec::new()))
+            .as_mut()
+            .expect("mixing records and errors for the same query")
+            .push(rdata);
+    }
+
+    /// The resolver looks up both the A and AAAA records of a name, like the
+    /// ones passed to `Client::new` should
+    pub fn resolver(self) -> AsyncResolver<MockConnection, MockConnectionProvider> {
+        // The name server is never contacted, but the resolver needs one
+        let name_servers =
+            NameServerConfigGroup::from_ips_clear(&[IpAddr::V4(Ipv4Addr::LOCALHOST)], 53, true);
+        let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        let provider = MockConnectionProvider(Arc::new(self.answers));
+        AsyncResolver::new_with_conn(config, opts, provider).unwrap()
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Clone)]
+pub struct MockConnectionProvider(Arc<Answers>);
+
+impl ConnectionProvider for MockConnectionProvider {
+    type Conn = MockConnection;
+    type FutureConn = future::Ready<Result<MockConnection, ResolveError>>;
+    type Time = SmolTime;
+
+    fn new_connection(&self, _: &NameServerConfig, _: &ResolverOpts) -> Self::FutureConn {
+        future::ready(Ok(MockConnection(self.0.clone())))
+    }
+}
+
+#[derive(Clone)]
+pub struct MockConnection(Arc<Answers>);
+
+impl DnsHandle for MockConnection {
+    type Error = ResolveError;
+    type Response = stream::Once<future::Ready<Result<DnsResponse, ResolveError>>>;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&mut self, request: R) -> Self::Response {
+        let request = request.into();
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_recursion_available(true);
+        for query in request.queries() {
+            response.add_query(query.clone());
+            let name = query.name().to_ascii();
+            match self.0.get(&(key(&name), query.query_type())) {
+                None => (),
+                Some(Err(code)) => {
+                    response.set_response_code(*code);
+                }
+                Some(Ok(answers)) => {
+                    response.add_answers(answers.iter().map(|rdata| {
+                        Record::from_rdata(query.name().clone(), 60, rdata.clone())
+                    }));
+                }
+            }
+        }
+        stream::once(future::ready(Ok(DnsResponse::from(response))))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SmolTime;
+
+#[async_trait]
+impl Time for SmolTime {
+    async fn delay_for(duration: Duration) {
+        smol::Timer::after(duration).await;
+    }
+
+    async fn timeout<F: 'static + Future + Send>(
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, io::Error> {
+        smol::future::or(async { Ok(future.await) }, async {
+            smol::Timer::after(duration).await;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        })
+        .await
+    }
+}
@@ -192,6 +192,11 @@ pub enum Command<S> {
 
     /// VRFY <name> <CRLF>
     Vrfy { name: MaybeUtf8<S> },
+
+    /// XCLIENT <attribute-name>=<attribute-value> [...] <CRLF>
+    ///
+    /// Note that the attribute values are left xtext-encoded
+    Xclient { attrs: Parameters<S> },
 }
 
 impl<S> Command<S> {
@@ -343,6 +348,15 @@ impl<S> Command<S> {
                     })
                 },
             ),
+            map(
+                tuple((
+                    tag_no_case(b"XCLIENT"),
+                    Parameters::parse_until(b" \t\r"),
+                    opt(is_a(" \t")),
+                    tag(b"\r\n"),
+                )),
+                |(_, attrs, _, _)| Command::Xclient { attrs },
+            ),
         ))(buf)
     }
 }
@@ -429,6 +443,10 @@ where
             Command::Vrfy { name } => iter::once(IoSlice::new(b"VRFY "))
                 .chain(name.as_io_slices())
                 .chain(iter::once(IoSlice::new(b"\r\n"))),
+
+            Command::Xclient { attrs } => iter::once(IoSlice::new(b"XCLIENT"))
+                .chain(attrs.as_io_slices())
+                .chain(iter::once(IoSlice::new(b"\r\n"))),
         }
     }
 }
@@ -640,6 +658,21 @@ mod tests {
             (b"VrFY \t hello.world \t \r\n", Command::Vrfy {
                 name: MaybeUtf8::Ascii("\t hello.world \t "),
             }),
+            (
+                b"XCLIENT ADDR=IPV6:2001:db8::1 HELO=client.example.org\r\n",
+                Command::Xclient {
+                    attrs: Parameters(vec![
+                        (
+                            ParameterName::Other("ADDR"),
+                            Some(MaybeUtf8::Ascii("IPV6:2001:db8::1")),
+                        ),
+                        (
+                            ParameterName::Other("HELO"),
+                            Some(MaybeUtf8::Ascii("client.example.org")),
+                        ),
+                    ]),
+                },
+            ),
         ];
         for (inp, out) in tests {
             println!("Test: {:?}", show_bytes(inp));
@@ -808,6 +841,15 @@ mod tests {
                 },
                 b"VRFY postmaster\r\n",
             ),
+            (
+                Command::Xclient {
+                    attrs: Parameters(vec![(
+                        ParameterName::Other("LOGIN"),
+                        Some(MaybeUtf8::Ascii("foo")),
+                    )]),
+                },
+                b"XCLIENT LOGIN=foo\r\n",
+            ),
         ];
         for (inp, out) in tests {
             println!("Test: {:?}", inp);
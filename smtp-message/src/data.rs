@@ -18,10 +18,31 @@ enum EscapedDataReaderState {
     CrLf,
     CrLfDot,
     CrLfDotCr,
+    // Bare LF states, only used when accepting bare LF end-of-data markers.
+    // The bytes since the bare LF are not output until it is known whether they
+    // are part of the end-of-data marker or not.
+    Lf,
+    LfDot,
+    LfDotCr,
+    // A bare LF end-of-data marker was found, and the canonical marker is being
+    // output in its stead
+    BareLfEnd,
     End,
     Completed,
 }
 
+impl EscapedDataReaderState {
+    /// Bytes that were read but not output yet in this state
+    fn held_back(self) -> &'static [u8] {
+        match self {
+            EscapedDataReaderState::Lf => b"\n",
+            EscapedDataReaderState::LfDot => b"\n.",
+            EscapedDataReaderState::LfDotCr => b"\n.\r",
+            _ => b"",
+        }
+    }
+}
+
 /// `AsyncRead` instance that returns an unescaped `DATA` stream.
 ///
 /// Note that:
@@ -29,6 +50,10 @@ enum EscapedDataReaderState {
 ///    "escaping" dot that is not part of the actual contents of the line.
 ///  - If a line is exactly b".\r\n", it is the last line of the stream this
 ///    stream will give. It is not part of the actual contents of the message.
+///
+/// Some non-compliant clients end the data with b"\n.\n" instead. This is
+/// not recognized as the end of the data unless
+/// [`with_bare_lf_end`](EscapedDataReader::with_bare_lf_end) is used.
 #[pin_project]
 pub struct EscapedDataReader<'a, R> {
     buf: &'a mut [u8],
@@ -39,6 +64,10 @@ pub struct EscapedDataReader<'a, R> {
 
     state: EscapedDataReaderState,
 
+    accept_bare_lf_end: bool,
+    ended_with_bare_lf: bool,
+    pending_end: &'static [u8],
+
     #[pin]
     read: R,
 }
@@ -53,10 +82,38 @@ where
             buf,
             unhandled,
             state: EscapedDataReaderState::CrLf,
+            accept_bare_lf_end: false,
+            ended_with_bare_lf: false,
+            pending_end: b"",
             read,
         }
     }
 
+    /// Also recognize end-of-data markers using bare LF line endings (ie.
+    /// b"\n.\n", b"\r\n.\n" or b"\n.\r\n") if `accept` is `true`.
+    ///
+    /// This is disabled by default, as the server and the next hops would not
+    /// agree on where the mail ends, which allows SMTP smuggling. When such a
+    /// marker is found, this reader returns the canonical b"\r\n.\r\n"
+    /// marker in its stead, and
+    /// [`.ended_with_bare_lf()`](EscapedDataReader::ended_with_bare_lf) will
+    /// return `true`.
+    ///
+    /// Note that in this mode, reads must be done with buffers of at least 4
+    /// bytes.
+    #[inline]
+    pub fn with_bare_lf_end(mut self, accept: bool) -> Self {
+        self.accept_bare_lf_end = accept;
+        self
+    }
+
+    /// Returns `true` iff the end-of-data marker was found with bare LF line
+    /// endings, and was thus normalized by this reader
+    #[inline]
+    pub fn ended_with_bare_lf(&self) -> bool {
+        self.ended_with_bare_lf
+    }
+
     /// Returns `true` iff the message has been successfully streamed
     /// to completion
     #[inline]
@@ -114,6 +171,14 @@ where
             return Poll::Ready(Ok(0));
         }
 
+        if self.accept_bare_lf_end {
+            let buf = match bufs.iter_mut().find(|b| !b.is_empty()) {
+                Some(buf) => &mut **buf,
+                None => return Poll::Ready(Ok(0)),
+            };
+            return self.poll_read_bare_lf(cx, buf);
+        }
+
         let this = self.project();
 
         // First, fill the bufs with incoming data
@@ -194,6 +259,113 @@ where
     }
 }
 
+impl<'a, R> EscapedDataReader<'a, R>
+where
+    R: AsyncRead,
+{
+    /// Reading function used when accepting bare LF end-of-data markers.
+    ///
+    /// As the output may be longer than the input, the data is always read
+    /// into `self.buf` first, and then copied to `out` while looking for the
+    /// end.
+    fn poll_read_bare_lf(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        use EscapedDataReaderState::*;
+
+        let mut this = self.project();
+        let mut size = 0;
+        loop {
+            // If the end was already found, output the canonical marker
+            if *this.state == BareLfEnd {
+                let len = cmp::min(out.len() - size, this.pending_end.len());
+                out[size..size + len].copy_from_slice(&this.pending_end[..len]);
+                *this.pending_end = &this.pending_end[len..];
+                if this.pending_end.is_empty() {
+                    *this.state = End;
+                }
+                return Poll::Ready(Ok(size + len));
+            }
+
+            // Then, fill self.buf with incoming data if required
+            if this.unhandled.start == this.unhandled.end {
+                if size > 0 {
+                    return Poll::Ready(Ok(size));
+                }
+                match this.read.as_mut().poll_read(cx, this.buf) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "connection aborted without finishing the data stream",
+                        )))
+                    }
+                    Poll::Ready(Ok(s)) => *this.unhandled = 0..s,
+                    other => return other,
+                }
+            }
+
+            // And copy it to out, looking for the end
+            while this.unhandled.start < this.unhandled.end {
+                let c = this.buf[this.unhandled.start];
+                let state = match (*this.state, c) {
+                    (CrLfDot, b'\n') => {
+                        *this.pending_end = b"\r\n";
+                        BareLfEnd
+                    }
+                    (LfDot, b'\n') | (LfDotCr, b'\n') => {
+                        *this.pending_end = b"\r\n.\r\n";
+                        BareLfEnd
+                    }
+                    (CrLfDotCr, b'\n') => End,
+                    (Cr, b'\n') => CrLf,
+                    (_, b'\n') => Lf,
+                    (Lf, b'.') => LfDot,
+                    (LfDot, b'\r') => LfDotCr,
+                    (CrLf, b'.') => CrLfDot,
+                    (CrLfDot, b'\r') => CrLfDotCr,
+                    (_, b'\r') => Cr,
+                    _ => Start,
+                };
+
+                if state == BareLfEnd {
+                    // The held back bytes and c are replaced by the canonical marker
+                    *this.ended_with_bare_lf = true;
+                } else {
+                    // Output the held back bytes followed by c, except for what is
+                    // still held back in the new state
+                    let held_back = this.state.held_back();
+                    let len = held_back.len() + 1 - state.held_back().len();
+                    if out.len() - size < len {
+                        if size == 0 {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "buffer too small for reading a data stream with bare LF ends",
+                            )));
+                        }
+                        return Poll::Ready(Ok(size));
+                    }
+                    let from_held_back = cmp::min(len, held_back.len());
+                    out[size..size + from_held_back].copy_from_slice(&held_back[..from_held_back]);
+                    if len > held_back.len() {
+                        out[size + from_held_back] = c;
+                    }
+                    size += len;
+                }
+
+                this.unhandled.start += 1;
+                *this.state = state;
+                if state == End {
+                    return Poll::Ready(Ok(size));
+                } else if state == BareLfEnd {
+                    break;
+                }
+            }
+        }
+    }
+}
+
 pub struct DataUnescapeRes {
     pub written: usize,
     pub unhandled_idx: usize,
@@ -573,6 +745,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn escaped_data_reader_bare_lf() {
+        let tests: &[(&[&[u8]], &[u8], &[u8], bool)] = &[
+            (&[b"foo\n.\n"], b"foo\r\n.\r\n", b"", true),
+            (
+                &[b"foo\r\n.\nMAIL FROM"],
+                b"foo\r\n.\r\n",
+                b"MAIL FROM",
+                true,
+            ),
+            (&[b"foo\n", b".", b"\r\n"], b"foo\r\n.\r\n", b"", true),
+            (&[b"foo\n.bar\n", b".\n"], b"foo\n.bar\r\n.\r\n", b"", true),
+            (&[b".\n", b"QUIT\r\n"], b".\r\n", b"QUIT\r\n", true),
+            (&[b"a\n\n.\n"], b"a\n\r\n.\r\n", b"", true),
+            (
+                &[b"foo\n.\r", b"bar\r\n.\r\n"],
+                b"foo\n.\rbar\r\n.\r\n",
+                b"",
+                false,
+            ),
+            (&[b"foo\r\n", b".\r\nbar"], b"foo\r\n.\r\n", b"bar", false),
+        ];
+        let mut surrounding_buf: [u8; 16] = [0; 16];
+        let mut enclosed_buf: [u8; 4] = [0; 4];
+        for &(inp, out, rem, bare_lf) in tests {
+            println!("Trying to parse {:?}", inp);
+            let mut reader = inp.iter().map(Cursor::new).fold(
+                Box::pin(futures::io::empty()) as Pin<Box<dyn 'static + AsyncRead>>,
+                |a, b| Box::pin(AsyncReadExt::chain(a, b)),
+            );
+            let mut data_reader =
+                EscapedDataReader::new(&mut surrounding_buf, 0..0, reader.as_mut())
+                    .with_bare_lf_end(true);
+
+            let mut res_out = Vec::<u8>::new();
+            loop {
+                let r = executor::block_on(data_reader.read(&mut enclosed_buf)).unwrap();
+                if r == 0 {
+                    break;
+                }
+                res_out.extend_from_slice(&enclosed_buf[..r]);
+            }
+            data_reader.complete();
+            assert_eq!(show_bytes(&res_out), show_bytes(out));
+            assert_eq!(data_reader.ended_with_bare_lf(), bare_lf);
+
+            let unhandled = data_reader.get_unhandled().unwrap();
+            let mut res_rem = surrounding_buf[unhandled].to_vec();
+            executor::block_on(reader.read_to_end(&mut res_rem)).unwrap();
+            assert_eq!(show_bytes(&res_rem), show_bytes(rem));
+        }
+    }
+
+    #[test]
+    fn escaped_data_reader_strict_ignores_bare_lf() {
+        let mut surrounding_buf: [u8; 16] = [0; 16];
+        let inp = b"foo\n.\nbar";
+        surrounding_buf[..inp.len()].copy_from_slice(inp);
+        let mut data_reader =
+            EscapedDataReader::new(&mut surrounding_buf, 0..inp.len(), futures::io::empty());
+        let mut res_out = Vec::new();
+        let err = executor::block_on(data_reader.read_to_end(&mut res_out)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(res_out, inp);
+        assert!(!data_reader.is_finished());
+    }
+
     #[test]
     fn data_unescaper() {
         let tests: &[(&[&[u8]], &[u8])] = &[
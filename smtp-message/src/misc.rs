@@ -367,6 +367,22 @@ where
             },
         }
     }
+
+    /// Returns this hostname with its domain lowercased, as domains are
+    /// case-insensitive. Address literals are returned as-is.
+    #[inline]
+    pub fn to_lowercase(&self) -> Hostname<String> {
+        match self {
+            Hostname::Utf8Domain { raw, punycode } => Hostname::Utf8Domain {
+                raw: raw.as_ref().to_lowercase(),
+                punycode: punycode.to_ascii_lowercase(),
+            },
+            Hostname::AsciiDomain { raw } => Hostname::AsciiDomain {
+                raw: raw.as_ref().to_ascii_lowercase(),
+            },
+            Hostname::Ipv4 { .. } | Hostname::Ipv6 { .. } => self.to_ref().into_owned(),
+        }
+    }
 }
 
 impl<S: AsRef<str>> fmt::Display for Hostname<S> {
@@ -896,6 +912,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn hostname_to_lowercase() {
+        let tests: &[(&[u8], Hostname<&str>)] = &[
+            (b"Foo.EXAMPLE.org", Hostname::AsciiDomain {
+                raw: "foo.example.org",
+            }),
+            ("Élégance.FR".as_bytes(), Hostname::Utf8Domain {
+                raw: "élégance.fr",
+                punycode: "xn--lgance-9uab.fr".into(),
+            }),
+            (b"[IPv6:0::FFFF:8.7.6.5]", Hostname::Ipv6 {
+                raw: "[IPv6:0::FFFF:8.7.6.5]",
+                ip: "0::ffff:8.7.6.5".parse().unwrap(),
+            }),
+        ];
+        for (inp, out) in tests {
+            let (_, host) = Hostname::<&str>::parse(inp).unwrap();
+            assert!(host.to_lowercase().deep_equal(&out.clone().into_owned()));
+        }
+    }
+
     #[test]
     fn hostname_incomplete() {
         let tests: &[&[u8]] = &[b"[1.2", b"[IPv6:0::"];
@@ -1,15 +1,20 @@
+pub mod maildir;
+
 use std::{
-    io,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io::{self, Read},
     marker::PhantomData,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures::{io::IoSlice, prelude::*};
-use openat::Dir;
+use openat::{Dir, SimpleType};
 use smol::unblock;
 use smtp_queue::{MailMetadata, QueueId, ScheduleInfo};
 use uuid::Uuid;
@@ -19,6 +24,7 @@ pub const DATA_DIR: &str = "data";
 pub const QUEUE_DIR: &str = "queue";
 pub const INFLIGHT_DIR: &str = "inflight";
 pub const CLEANUP_DIR: &str = "cleanup";
+pub const DEADLETTER_DIR: &str = "deadletter";
 
 pub const DATA_DIR_FROM_OTHER_QUEUE: &str = "../data";
 
@@ -31,14 +37,52 @@ pub const TMP_SCHEDULE_FILE_PREFIX: &str = "schedule.";
 const ONLY_USER_RW: u32 = 0o600;
 const ONLY_USER_RWX: u32 = 0o700;
 
-// TODO: auto-detect orphan files (pointed to by nowhere in the queue)
+/// Number of times the schedule or metadata of a mail can fail to parse before
+/// it is moved to the dead-letter folder
+pub const DEFAULT_MAX_READ_FAILURES: usize = 5;
+
+/// Makes the files and folders of a mail durable before the mail is exposed in
+/// the queue, so that a crash cannot leave a queued mail pointing to missing or
+/// truncated files
+pub trait Syncer: 'static + Send + Sync {
+    /// Blocking function! Called on the contents file once it is fully
+    /// written, and on the metadata and schedule files of each destination
+    fn sync_file(&self, file: &std::fs::File) -> io::Result<()> {
+        file.sync_all()
+    }
 
-#[derive(Debug)]
+    /// Blocking function! Called on the folders holding these files
+    fn sync_dir(&self, dir: &Dir) -> io::Result<()> {
+        dir.open_file(".")?.sync_all()
+    }
+}
+
+/// Syncs everything with `fsync`, which is the default
+pub struct Fsync;
+
+impl Syncer for Fsync {}
+
+/// Does not sync anything, eg. for tests or for queues that need not survive
+/// a power loss
+pub struct NoSync;
+
+impl Syncer for NoSync {
+    fn sync_file(&self, _file: &std::fs::File) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Dir) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum QueueType {
     Data,
     Queue,
     Inflight,
     Cleanup,
+    Deadletter,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -122,6 +166,9 @@ pub enum Error {
     #[error("Mail symlink ‘{0}’ in {1:?} queue points to ‘{2}’ which is not in the Data queue")]
     SymlinkDoesNotPointToDataQueue(Arc<String>, QueueType, PathBuf),
 
+    #[error("Mail symlink ‘{0}’ in {1:?} queue points to ‘{2}’ which does not exist")]
+    SymlinkPointsToMissingDestination(Arc<String>, QueueType, PathBuf),
+
     #[error("Removing folder ‘{0}’ from {1:?} queue")]
     RemovingFolderFromQueue(PathBuf, QueueType, #[source] io::Error),
 
@@ -131,12 +178,18 @@ pub enum Error {
     #[error("Listing folder ‘{0}’ in {1:?} queue")]
     ListingFolderInQueue(PathBuf, QueueType, #[source] io::Error),
 
+    #[error("Refusing to remove ‘{0}’, which is not a mail or destination of the Data queue")]
+    NotInDataFolder(PathBuf),
+
     #[error("Removing file ‘{0}’ from {1:?} queue")]
     RemovingFileFromQueue(Arc<String>, QueueType, #[source] io::Error),
 
     #[error("Flushing the changes to file ‘{0}’ of mail ‘{1}’ in {2:?} queue")]
     FlushingMailContents(&'static str, String, QueueType, #[source] io::Error),
 
+    #[error("Syncing folder ‘{0}’ in {1:?} queue")]
+    SyncingFolderInQueue(PathBuf, QueueType, #[source] io::Error),
+
     #[error("Creating folder ‘{0}’ in mail ‘{1}’ of {2:?} queue")]
     CreatingFolderInMail(String, String, QueueType, #[source] io::Error),
 
@@ -145,6 +198,38 @@ pub enum Error {
 
     #[error("Symlinking into file ‘{0}’ of {1:?} queue with destination ‘{2}’")]
     SymlinkingIntoQueue(String, QueueType, PathBuf, #[source] io::Error),
+
+    #[error("Reading file ‘{0}’ in mail ‘{1}’ of {2:?} queue")]
+    ReadingFileInMail(&'static str, Arc<String>, QueueType, #[source] io::Error),
+
+    #[error("Writing the queue archive")]
+    WritingArchive(#[source] io::Error),
+
+    #[error("Reading the queue archive")]
+    ReadingArchive(#[source] io::Error),
+
+    #[error("Parsing JSON from file ‘{0}’ of the queue archive")]
+    ParsingJsonInArchive(PathBuf, #[source] serde_json::Error),
+
+    #[error("Unexpected file ‘{0}’ in the queue archive")]
+    UnexpectedFileInArchive(PathBuf),
+
+    #[error("Found contents ‘{0}’ in the queue archive without its metadata and schedule")]
+    IncompleteMailInArchive(PathBuf),
+
+    #[error("Copying contents ‘{0}’ from the queue archive into mail ‘{1}’")]
+    CopyingContentsFromArchive(PathBuf, String, #[source] io::Error),
+
+    #[error("Committing the mail to destination number {0}")]
+    CommittingDestination(usize, #[source] Box<Error>),
+
+    #[error(
+        "Moved mail ‘{0}’ from {1:?} queue to the dead-letter folder after repeated failures"
+    )]
+    DeadLettered(Arc<String>, QueueType, #[source] Box<Error>),
+
+    #[error("Reading the delivery statistics from ‘{0}’")]
+    ReadingDeliveryStats(PathBuf, #[source] io::Error),
 }
 
 pub struct FsStorage<U> {
@@ -153,9 +238,107 @@ pub struct FsStorage<U> {
     queue: Arc<Dir>,
     inflight: Arc<Dir>,
     cleanup: Arc<Dir>,
+    read_failures: Arc<ReadFailures>,
+    stats: Option<Arc<StatsStore>>,
+    syncer: Arc<dyn Syncer>,
     phantom: PhantomData<U>,
 }
 
+/// Number of times each mail failed to be read due to an unparseable schedule
+/// or metadata file, which no amount of retrying will fix
+struct ReadFailures {
+    max: usize,
+    counts: Mutex<HashMap<Arc<String>, usize>>,
+    deadletter: Arc<Dir>,
+}
+
+impl ReadFailures {
+    /// Records that reading mail `id` from `dir` failed with `err`, returning
+    /// the error to report. After `max` such failures, the mail is moved to
+    /// the dead-letter folder, where it is left for operators to inspect.
+    fn record(&self, dir: &Dir, queue: QueueType, id: &Arc<String>, err: Error) -> Error {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(id.clone()).or_insert(0);
+        *count += 1;
+        if *count < self.max {
+            return err;
+        }
+        match openat::rename(dir, &**id, &self.deadletter, &**id) {
+            Ok(()) => {
+                counts.remove(id);
+                Error::DeadLettered(id.clone(), queue, Box::new(err))
+            }
+            // Try again on the next failure
+            Err(_) => err,
+        }
+    }
+
+    fn forget(&self, id: &Arc<String>) {
+        self.counts.lock().unwrap().remove(id);
+    }
+}
+
+/// Number of mails to a destination domain that were delivered, deferred for
+/// a later attempt, and dropped from the queue without being delivered
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct DeliveryStats {
+    pub delivered: u64,
+    pub deferred: u64,
+    pub bounced: u64,
+}
+
+/// Delivery statistics of each recipient domain, written back to a JSON file
+/// after each change so that they survive restarts
+struct StatsStore {
+    path: PathBuf,
+    stats: Mutex<HashMap<String, DeliveryStats>>,
+}
+
+impl StatsStore {
+    /// Blocking function!
+    fn open(path: PathBuf) -> Result<StatsStore, Error> {
+        let stats = match std::fs::File::open(&path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map_err(|e| Error::ParsingJson(path.clone(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(Error::ReadingDeliveryStats(path, e)),
+        };
+        Ok(StatsStore {
+            path,
+            stats: Mutex::new(stats),
+        })
+    }
+
+    /// Blocking function! Failing to persist the statistics is only logged, as
+    /// the operation they are about did happen anyway.
+    fn record(&self, domain: Option<String>, update: fn(&mut DeliveryStats)) {
+        let domain = match domain {
+            Some(domain) => domain,
+            None => return,
+        };
+        let mut stats = self.stats.lock().unwrap();
+        update(stats.entry(domain).or_default());
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let res = std::fs::File::create(&tmp_path)
+            .and_then(|file| serde_json::to_writer(file, &*stats).map_err(io::Error::from))
+            .and_then(|()| std::fs::rename(&tmp_path, &self.path));
+        if let Err(e) = res {
+            tracing::warn!(error = ?e, path = %self.path.display(), "Failed saving delivery stats");
+        }
+    }
+}
+
+/// Blocking function! Returns the recipient domain of mail `id` of `dir`, or
+/// `None` if it cannot be read or the recipient has no domain.
+fn recipient_domain(dir: &Dir, id: &str) -> Option<String> {
+    let dest = dir.read_link(id).ok()?;
+    let metadata_file = dir.sub_dir(&dest).ok()?.open_file(METADATA_FILE).ok()?;
+    let metadata: MailMetadata<serde::de::IgnoredAny> =
+        serde_json::from_reader(io::BufReader::new(metadata_file)).ok()?;
+    Some(metadata.to.hostname?.to_string().to_ascii_lowercase())
+}
+
 impl<U> FsStorage<U> {
     pub async fn new(path: Arc<PathBuf>) -> Result<FsStorage<U>, Error> {
         macro_rules! maybe_create_and_open_generic {
@@ -198,6 +381,7 @@ impl<U> FsStorage<U> {
         let queue = maybe_create_and_open!(QUEUE_DIR);
         let inflight = maybe_create_and_open!(INFLIGHT_DIR);
         let cleanup = maybe_create_and_open!(CLEANUP_DIR);
+        let deadletter = maybe_create_and_open!(DEADLETTER_DIR);
 
         Ok(FsStorage {
             path,
@@ -205,9 +389,743 @@ impl<U> FsStorage<U> {
             queue,
             inflight,
             cleanup,
+            read_failures: Arc::new(ReadFailures {
+                max: DEFAULT_MAX_READ_FAILURES,
+                counts: Mutex::new(HashMap::new()),
+                deadletter,
+            }),
+            stats: None,
+            syncer: Arc::new(Fsync),
             phantom: PhantomData,
         })
     }
+
+    /// Keeps per-recipient-domain counts of the mails delivered, rescheduled
+    /// and dropped in the JSON file at `path`, starting from the counts it
+    /// already holds if it exists
+    pub async fn with_delivery_stats(mut self, path: PathBuf) -> Result<FsStorage<U>, Error> {
+        self.stats = Some(Arc::new(unblock(move || StatsStore::open(path)).await?));
+        Ok(self)
+    }
+
+    /// Uses `syncer` to make the mails durable before exposing them in the
+    /// queue, instead of `Fsync`, eg. `NoSync` when durability does not matter
+    pub fn with_syncer<Sy: Syncer>(mut self, syncer: Sy) -> FsStorage<U> {
+        self.syncer = Arc::new(syncer);
+        self
+    }
+
+    /// Delivery statistics of each recipient domain, which are only kept if
+    /// the storage was built `with_delivery_stats`
+    pub fn delivery_stats(&self) -> HashMap<String, DeliveryStats> {
+        match self.stats {
+            Some(ref stats) => stats.stats.lock().unwrap().clone(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Number of times the schedule or metadata of a mail can fail to parse
+    /// before the mail is moved to the `deadletter` folder and no longer
+    /// retried, instead of `DEFAULT_MAX_READ_FAILURES`
+    pub fn with_max_read_failures(mut self, max: usize) -> FsStorage<U> {
+        let failures = Arc::get_mut(&mut self.read_failures)
+            .expect("called with_max_read_failures after starting to use the storage");
+        failures.max = max;
+        self
+    }
+
+    /// Checks that each mail of the queue, inflight and cleanup folders is a
+    /// symlink to a destination subfolder of the data folder, and moves the
+    /// mails whose symlink is broken to the dead-letter folder, as they would
+    /// otherwise fail each time they are read. This is meant to be called at
+    /// startup, before starting to use the storage.
+    ///
+    /// Mails pending cleanup whose destination subfolder no longer exists are
+    /// left in place, as cleaning them up only requires removing the symlink.
+    ///
+    /// Returns the errors encountered, including one `DeadLettered` error for
+    /// each mail that was moved.
+    pub async fn check_symlinks(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for (dir, queue, subfolder) in &[
+            (self.queue.clone(), QueueType::Queue, QUEUE_DIR),
+            (self.inflight.clone(), QueueType::Inflight, INFLIGHT_DIR),
+            (self.cleanup.clone(), QueueType::Cleanup, CLEANUP_DIR),
+        ] {
+            let ids = scan_folder(self.path.join(subfolder))
+                .await
+                .collect::<Vec<_>>()
+                .await;
+            let dir = dir.clone();
+            let queue = *queue;
+            let deadletter = self.read_failures.deadletter.clone();
+            errors.extend(
+                unblock(move || {
+                    let mut errors = Vec::new();
+                    for id in ids {
+                        let id = match id {
+                            Ok(id) => id.0,
+                            Err((e, _)) => {
+                                errors.push(e);
+                                continue;
+                            }
+                        };
+                        let broken = match check_symlink(&dir, queue, &id) {
+                            Ok(Some(broken)) => broken,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                errors.push(e);
+                                continue;
+                            }
+                        };
+                        errors.push(match openat::rename(&dir, &*id, &deadletter, &*id) {
+                            Ok(()) => Error::DeadLettered(id, queue, Box::new(broken)),
+                            Err(e) => {
+                                Error::MovingMailBetweenQueues(id, queue, QueueType::Deadletter, e)
+                            }
+                        });
+                    }
+                    errors
+                })
+                .await,
+            );
+        }
+        errors
+    }
+
+    /// Removes the mails of the data folder that no symlink of the queue,
+    /// inflight, cleanup or dead-letter folders points to, along with the
+    /// destination subfolders that no symlink points to. These are left over
+    /// by enqueues that were interrupted, eg. by a crash, before or while
+    /// committing, and are never scanned as nothing points to them. This is
+    /// meant to be called at startup, before starting to use the storage, as
+    /// the mails being enqueued would otherwise be removed too.
+    ///
+    /// Returns the number of mails and destination subfolders removed, along
+    /// with the errors encountered. If some symlinks could not be read,
+    /// nothing is removed, as the mails they point to could not be told apart
+    /// from the orphans.
+    pub async fn cleanup_orphans(&self) -> (usize, Vec<Error>) {
+        let mut removed = 0;
+        let mut errors = Vec::new();
+        let orphans = self.find_orphans().await.collect::<Vec<_>>().await;
+        for orphan in orphans {
+            match orphan {
+                Ok(path) => match self.remove_orphan(&path).await {
+                    Ok(()) => removed += 1,
+                    Err(e) => errors.push(e),
+                },
+                Err(e) => errors.push(e),
+            }
+        }
+        (removed, errors)
+    }
+
+    /// Lists the mails of the data folder that no symlink of the queue,
+    /// inflight, cleanup or dead-letter folders points to, along with the
+    /// destination subfolders of the other mails that no symlink points to,
+    /// without removing anything. The paths returned can be passed to
+    /// `remove_orphan`. Like `cleanup_orphans`, this only makes sense while
+    /// no mail is being enqueued.
+    ///
+    /// If some symlinks could not be read, only the errors are returned, as
+    /// the mails they point to could not be told apart from the orphans.
+    pub async fn find_orphans(&self) -> DynStreamOf<Result<PathBuf, Error>> {
+        let orphans = match self.referenced_mails().await {
+            Ok(referenced) => {
+                let data = self.data.clone();
+                let data_path = self.path.join(DATA_DIR);
+                unblock(move || find_orphans(&data, &data_path, &referenced)).await
+            }
+            Err(errors) => errors.into_iter().map(Err).collect(),
+        };
+        Box::pin(smol::stream::iter(orphans))
+    }
+
+    /// Removes an orphan found by `find_orphans`. This refuses to touch
+    /// anything that is not a mail or a destination subfolder of the data
+    /// folder, but does not check again that nothing points to it.
+    pub async fn remove_orphan(&self, path: &Path) -> Result<(), Error> {
+        let data_path = self.path.join(DATA_DIR);
+        let not_in_data = || Error::NotInDataFolder(path.to_owned());
+        let components = path
+            .strip_prefix(&data_path)
+            .map_err(|_| not_in_data())?
+            .components()
+            .collect::<Vec<_>>();
+        let (mail, dest) = match &components[..] {
+            [Component::Normal(mail)] => (PathBuf::from(mail), None),
+            [Component::Normal(mail), Component::Normal(dest)] => {
+                (PathBuf::from(mail), Some(PathBuf::from(dest)))
+            }
+            _ => return Err(not_in_data()),
+        };
+        let path = path.to_owned();
+        unblock(move || {
+            std::fs::remove_dir_all(path).map_err(|e| match dest {
+                None => Error::RemovingFolderFromQueue(mail, QueueType::Data, e),
+                Some(dest) => Error::RemovingFolderFromMail(dest, mail, QueueType::Data, e),
+            })
+        })
+        .await
+    }
+
+    /// Destinations pointed to by the symlinks of the queue, inflight, cleanup
+    /// and dead-letter folders, by mail of the data folder, or None if a
+    /// symlink points to something else in the mail, in which case the whole
+    /// mail is referenced
+    async fn referenced_mails(
+        &self,
+    ) -> Result<HashMap<OsString, Option<HashSet<OsString>>>, Vec<Error>> {
+        let mut errors = Vec::new();
+        let mut targets = Vec::new();
+        for (dir, queue, subfolder) in &[
+            (self.queue.clone(), QueueType::Queue, QUEUE_DIR),
+            (self.inflight.clone(), QueueType::Inflight, INFLIGHT_DIR),
+            (self.cleanup.clone(), QueueType::Cleanup, CLEANUP_DIR),
+            (
+                self.read_failures.deadletter.clone(),
+                QueueType::Deadletter,
+                DEADLETTER_DIR,
+            ),
+        ] {
+            let ids = scan_folder(self.path.join(subfolder))
+                .await
+                .collect::<Vec<_>>()
+                .await;
+            let dir = dir.clone();
+            let queue = *queue;
+            let (found, errs) = unblock(move || {
+                let mut found = Vec::new();
+                let mut errors = Vec::new();
+                for id in ids {
+                    let id = match id {
+                        Ok(id) => id.0,
+                        Err((e, _)) => {
+                            errors.push(e);
+                            continue;
+                        }
+                    };
+                    match dir.read_link(&*id) {
+                        Ok(dest) => found.push(dest),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => errors.push(Error::ReadingLinkInQueue(id, queue, e)),
+                    }
+                }
+                (found, errors)
+            })
+            .await;
+            targets.extend(found);
+            errors.extend(errs);
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut referenced = HashMap::<OsString, Option<HashSet<OsString>>>::new();
+        for target in &targets {
+            let target = match target.strip_prefix(DATA_DIR_FROM_OTHER_QUEUE) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            let components = target.components().collect::<Vec<_>>();
+            match &components[..] {
+                [Component::Normal(mail), Component::Normal(dest)] => {
+                    let dests = referenced
+                        .entry(mail.to_os_string())
+                        .or_insert_with(|| Some(HashSet::new()));
+                    if let Some(dests) = dests {
+                        dests.insert(dest.to_os_string());
+                    }
+                }
+                [Component::Normal(mail), ..] => {
+                    referenced.insert(mail.to_os_string(), None);
+                }
+                _ => (),
+            }
+        }
+        Ok(referenced)
+    }
+
+    /// Number of mails waiting in the cleanup folder
+    pub async fn pending_cleanup_count(&self) -> Result<usize, Error> {
+        count_folder(self.path.join(CLEANUP_DIR)).await
+    }
+
+    /// Number of mails waiting in the queue.
+    ///
+    /// This only enumerates the symlinks of the queue folder, without reading
+    /// the schedules like `list_queue` does, so it stays cheap even with a
+    /// large queue.
+    pub async fn count_queue(&self) -> Result<usize, Error> {
+        count_folder(self.path.join(QUEUE_DIR)).await
+    }
+
+    /// Lists the mails of the queue that were enqueued or attempted at or
+    /// after `since`, eg. for tools that poll the queue for changes.
+    ///
+    /// This only reads the schedules, using the last attempt of each mail, or
+    /// its scheduled time if it was never attempted. Errors are returned
+    /// whatever the mail.
+    pub async fn list_queue_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> DynStreamOf<Result<FsQueuedMail, (Error, Option<QueueId>)>> {
+        Box::pin(
+            scan_queue(
+                self.path.join(QUEUE_DIR),
+                self.queue.clone(),
+                QueueType::Queue,
+                self.read_failures.clone(),
+            )
+            .await
+            .filter(move |r| {
+                future::ready(match r {
+                    Ok(m) => m.schedule.last_attempt.unwrap_or(m.schedule.at) >= since,
+                    Err(_) => true,
+                })
+            })
+            .map(|r| r.map(FsQueuedMail::found)),
+        )
+    }
+}
+
+/// Returns `Some` with the reason why symlink `id` of `queue` does not point to
+/// a destination subfolder of the data folder, and `None` if it does or if it
+/// vanished in-between
+fn check_symlink(dir: &Dir, queue: QueueType, id: &Arc<String>) -> Result<Option<Error>, Error> {
+    let dest = match dir.read_link(&**id) {
+        Ok(dest) => dest,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::ReadingLinkInQueue(id.clone(), queue, e)),
+    };
+    let dest_path = match dest.strip_prefix(DATA_DIR_FROM_OTHER_QUEUE) {
+        Ok(p) => p,
+        Err(_) => return Ok(Some(Error::SymlinkDoesNotPointToDataQueue(id.clone(), queue, dest))),
+    };
+    // The symlink must be of the form ../data/<mail>/<destination>
+    let components = dest_path.components().collect::<Vec<_>>();
+    if components.len() != 2 || !components.iter().all(|c| matches!(c, Component::Normal(_))) {
+        return Ok(Some(Error::SymlinkPointsToNonDestinationSubfolder(
+            id.clone(),
+            queue,
+            dest,
+        )));
+    }
+    if let QueueType::Cleanup = queue {
+        return Ok(None);
+    }
+    match dir.sub_dir(&dest) {
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some(
+            Error::SymlinkPointsToMissingDestination(id.clone(), queue, dest),
+        )),
+        Err(e) => Err(Error::OpeningFolderInQueue(PathBuf::from(&**id), queue, e)),
+    }
+}
+
+/// Blocking function! Lists the mails and destination subfolders of `data`
+/// that are not in `referenced`, as built by `FsStorage::referenced_mails`
+fn find_orphans(
+    data: &Dir,
+    data_path: &Path,
+    referenced: &HashMap<OsString, Option<HashSet<OsString>>>,
+) -> Vec<Result<PathBuf, Error>> {
+    let mut orphans = Vec::new();
+    let mails = match data.list_dir(".") {
+        Ok(l) => l,
+        Err(e) => {
+            let e = Error::ListingFolderInQueue(PathBuf::from(DATA_DIR), QueueType::Data, e);
+            return vec![Err(e)];
+        }
+    };
+    for mail in mails {
+        let mail = match mail {
+            Ok(m) if matches!(m.simple_type(), Some(SimpleType::Dir)) => {
+                m.file_name().to_os_string()
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                let path = PathBuf::from(DATA_DIR);
+                orphans.push(Err(Error::ListingFolderInQueue(path, QueueType::Data, e)));
+                continue;
+            }
+        };
+        let mail_path = data_path.join(&mail);
+        let dests = match referenced.get(&mail) {
+            None => {
+                orphans.push(Ok(mail_path));
+                continue;
+            }
+            Some(None) => continue,
+            Some(Some(dests)) => dests,
+        };
+        let entries = match data.list_dir(&mail) {
+            Ok(l) => l,
+            Err(e) => {
+                let path = PathBuf::from(mail);
+                orphans.push(Err(Error::ListingFolderInQueue(path, QueueType::Data, e)));
+                continue;
+            }
+        };
+        for entry in entries {
+            let dest = match entry {
+                Ok(d) if matches!(d.simple_type(), Some(SimpleType::Dir)) => {
+                    d.file_name().to_os_string()
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    let path = PathBuf::from(&mail);
+                    orphans.push(Err(Error::ListingFolderInQueue(path, QueueType::Data, e)));
+                    continue;
+                }
+            };
+            if !dests.contains(&dest) {
+                orphans.push(Ok(mail_path.join(dest)));
+            }
+        }
+    }
+    orphans
+}
+
+impl<U> FsStorage<U>
+where
+    U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
+{
+    /// Cleans up all the mails of the cleanup folder right away, instead of
+    /// waiting for the queue to get to them, eg. after cleanup fell behind due
+    /// to IO errors.
+    ///
+    /// Returns the number of mails cleaned up, along with the errors
+    /// encountered for the others, which are left in the cleanup folder.
+    pub async fn cleanup_all(&self) -> (usize, Vec<Error>) {
+        let mut cleaned_up = 0;
+        let mut errors = Vec::new();
+        let mails = smtp_queue::Storage::find_pending_cleanup(self)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        for mail in mails {
+            let mail = match mail {
+                Ok(mail) => mail,
+                Err((e, _)) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            match smtp_queue::Storage::cleanup(self, mail).await {
+                Ok(_) => cleaned_up += 1,
+                Err((_, e)) => errors.push(e),
+            }
+        }
+        (cleaned_up, errors)
+    }
+
+    /// Export all the mails currently waiting in the queue as a tar archive,
+    /// that can be imported back with `import`, eg. on another host.
+    ///
+    /// Each queued mail is exported as three files, `<n>/metadata`,
+    /// `<n>/schedule` and `<n>/contents`. Inflight mails are not exported, as
+    /// they are being sent right now: they will either be sent or come back
+    /// to the queue, where a later export will find them. Similarly, mails
+    /// that leave the queue while the export is running are skipped.
+    pub async fn export<W>(&self, writer: W) -> Result<W, Error>
+    where
+        W: 'static + Send + io::Write,
+    {
+        let ids = scan_folder(self.path.join(QUEUE_DIR))
+            .await
+            .map_err(|(e, _)| e)
+            .try_collect::<Vec<QueueId>>()
+            .await?;
+        let queue = self.queue.clone();
+
+        unblock(move || {
+            let mut archive = tar::Builder::new(writer);
+            let mut exported = 0;
+            for id in ids {
+                let mail = match read_queued_mail(&queue, &id.0)? {
+                    Some(m) => m,
+                    None => continue,
+                };
+                let contents_len = mail
+                    .contents
+                    .metadata()
+                    .map_err(|e| {
+                        Error::ReadingFileInMail(CONTENTS_FILE, id.0.clone(), QueueType::Queue, e)
+                    })?
+                    .len();
+                let metadata_len = mail.metadata.len() as u64;
+                let schedule_len = mail.schedule.len() as u64;
+                append_to_archive(
+                    &mut archive,
+                    exported,
+                    METADATA_FILE,
+                    metadata_len,
+                    &mail.metadata[..],
+                )?;
+                append_to_archive(
+                    &mut archive,
+                    exported,
+                    SCHEDULE_FILE,
+                    schedule_len,
+                    &mail.schedule[..],
+                )?;
+                append_to_archive(
+                    &mut archive,
+                    exported,
+                    CONTENTS_FILE,
+                    contents_len,
+                    mail.contents,
+                )?;
+                exported += 1;
+            }
+            archive.into_inner().map_err(Error::WritingArchive)
+        })
+        .await
+    }
+
+    /// Import all the mails from an archive generated by `export` into this
+    /// queue, giving them fresh queue ids.
+    ///
+    /// The mails are only added to the queue once the whole archive has been
+    /// read, so that an invalid archive imports nothing: on error, all the
+    /// mails already read from it are removed.
+    ///
+    /// Note that a `Queue` already running on this storage will not notice
+    /// the imported mails until it is restarted, so this should usually be
+    /// done before starting it.
+    pub async fn import<R>(&self, reader: R) -> Result<Vec<FsQueuedMail>, Error>
+    where
+        R: 'static + Send + io::Read,
+    {
+        let data = self.data.clone();
+        let queue = self.queue.clone();
+        let syncer = self.syncer.clone();
+
+        unblock(move || {
+            let mut staged = Vec::new();
+            if let Err(e) = stage_archive::<U, _>(&data, &*syncer, reader, &mut staged) {
+                for mail in staged {
+                    mail.cleanup(&data);
+                }
+                return Err(e);
+            }
+
+            let mut queued_mails = Vec::with_capacity(staged.len());
+            for mail in &staged {
+                match link_into_queue(&queue, &mail.mail_uuid, &mail.dest_id, &mail.schedule) {
+                    Ok(queued_mail) => queued_mails.push(queued_mail),
+                    Err(e) => {
+                        for queued_mail in queued_mails {
+                            // TODO: consider logging IO errors on cleanups that follow an IO error
+                            let _ = queue.remove_file(&*queued_mail.id.0);
+                        }
+                        for mail in staged {
+                            mail.cleanup(&data);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(queued_mails)
+        })
+        .await
+    }
+}
+
+struct ExportedMail {
+    metadata: Vec<u8>,
+    schedule: Vec<u8>,
+    // Having the contents open means they are still readable even if the mail
+    // gets cleaned up in-between
+    contents: std::fs::File,
+}
+
+/// Blocking function!
+///
+/// Returns `None` if the mail vanished from the queue in-between
+fn read_queued_mail(queue: &Dir, id: &Arc<String>) -> Result<Option<ExportedMail>, Error> {
+    macro_rules! or_vanished {
+        ($e:expr, $err:expr) => {
+            match $e {
+                Ok(r) => r,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err($err(e)),
+            }
+        };
+    }
+
+    let dest_path = or_vanished!(queue.read_link(&**id), |e| {
+        Error::ReadingLinkInQueue(id.clone(), QueueType::Queue, e)
+    });
+    let dest_dir = or_vanished!(queue.sub_dir(&dest_path), |e| {
+        Error::OpeningFolderInQueue(PathBuf::from(&**id), QueueType::Queue, e)
+    });
+
+    let read_file = |file: &'static str| -> Result<Option<Vec<u8>>, Error> {
+        let mut res = Vec::new();
+        let mut f = or_vanished!(dest_dir.open_file(file), |e| {
+            Error::OpeningFileInMail(file, id.clone(), QueueType::Queue, e)
+        });
+        f.read_to_end(&mut res)
+            .map_err(|e| Error::ReadingFileInMail(file, id.clone(), QueueType::Queue, e))?;
+        Ok(Some(res))
+    };
+    let metadata = match read_file(METADATA_FILE)? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    let schedule = match read_file(SCHEDULE_FILE)? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let mail_dir = or_vanished!(dest_dir.sub_dir(".."), |e| {
+        Error::OpeningParentFromMail(id.clone(), e)
+    });
+    let contents = or_vanished!(mail_dir.open_file(CONTENTS_FILE), |e| {
+        Error::OpeningFileInMailParent(id.clone(), e)
+    });
+
+    Ok(Some(ExportedMail {
+        metadata,
+        schedule,
+        contents,
+    }))
+}
+
+/// Blocking function!
+fn append_to_archive<W, R>(
+    archive: &mut tar::Builder<W>,
+    mail: usize,
+    file: &'static str,
+    len: u64,
+    data: R,
+) -> Result<(), Error>
+where
+    W: io::Write,
+    R: io::Read,
+{
+    // Mode and size are the only metadata that matter for the import
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(ONLY_USER_RW);
+    header.set_size(len);
+    archive
+        .append_data(&mut header, Path::new(&mail.to_string()).join(file), data)
+        .map_err(Error::WritingArchive)
+}
+
+/// A mail read from an archive, that is fully written to the data folder but
+/// not yet linked into the queue
+struct StagedMail {
+    mail_uuid: String,
+    mail_dir: Dir,
+    dest_id: String,
+    schedule: ScheduleInfo,
+}
+
+impl StagedMail {
+    /// Blocking function!
+    fn cleanup(self, data: &Dir) {
+        cleanup_dest_dir(&self.mail_dir, &self.dest_id);
+        cleanup_contents_dir(data, self.mail_uuid, &self.mail_dir);
+    }
+}
+
+/// Blocking function!
+///
+/// Stages all the mails of the archive read from `reader` into `staged`,
+/// which on error holds the mails staged so far.
+fn stage_archive<U, R>(
+    data: &Dir,
+    syncer: &dyn Syncer,
+    reader: R,
+    staged: &mut Vec<StagedMail>,
+) -> Result<(), Error>
+where
+    U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
+    R: io::Read,
+{
+    let mut archive = tar::Archive::new(reader);
+    let mut metadata: Option<MailMetadata<U>> = None;
+    let mut schedule: Option<ScheduleInfo> = None;
+    for entry in archive.entries().map_err(Error::ReadingArchive)? {
+        let entry = entry.map_err(Error::ReadingArchive)?;
+        let path = entry.path().map_err(Error::ReadingArchive)?.into_owned();
+        match path.file_name().and_then(|f| f.to_str()) {
+            Some(METADATA_FILE) => {
+                metadata = Some(
+                    serde_json::from_reader(entry)
+                        .map_err(|e| Error::ParsingJsonInArchive(path, e))?,
+                )
+            }
+            Some(SCHEDULE_FILE) => {
+                schedule = Some(
+                    serde_json::from_reader(entry)
+                        .map_err(|e| Error::ParsingJsonInArchive(path, e))?,
+                )
+            }
+            Some(CONTENTS_FILE) => match (metadata.take(), schedule.take()) {
+                (Some(metadata), Some(schedule)) => {
+                    staged.push(stage_mail(data, syncer, &path, entry, &metadata, schedule)?)
+                }
+                _ => return Err(Error::IncompleteMailInArchive(path)),
+            },
+            _ => return Err(Error::UnexpectedFileInArchive(path)),
+        }
+    }
+    Ok(())
+}
+
+/// Blocking function!
+fn stage_mail<U, R>(
+    data: &Dir,
+    syncer: &dyn Syncer,
+    path: &Path,
+    mut contents: R,
+    metadata: &MailMetadata<U>,
+    schedule: ScheduleInfo,
+) -> Result<StagedMail, Error>
+where
+    U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
+    R: io::Read,
+{
+    let (mail_uuid, mail_dir, mut contents_file) = make_mail_dir(data)?;
+    if let Err(e) = io::copy(&mut contents, &mut contents_file) {
+        cleanup_contents_dir(data, mail_uuid.clone(), &mail_dir);
+        return Err(Error::CopyingContentsFromArchive(
+            path.to_owned(),
+            mail_uuid,
+            e,
+        ));
+    }
+    if let Err(e) = sync_contents(syncer, data, &mail_uuid, &mail_dir) {
+        cleanup_contents_dir(data, mail_uuid, &mail_dir);
+        return Err(e);
+    }
+
+    let dest_id = Uuid::new_v4().as_hyphenated().to_string();
+    let mail = StagedMail {
+        mail_uuid,
+        mail_dir,
+        dest_id,
+        schedule,
+    };
+    match make_dest_dir(
+        syncer,
+        &mail.mail_uuid,
+        &mail.mail_dir,
+        &mail.dest_id,
+        metadata,
+        &mail.schedule,
+    ) {
+        Ok(()) => Ok(mail),
+        Err(e) => {
+            mail.cleanup(data);
+            Err(e)
+        }
+    }
 }
 
 type DynStreamOf<T> = Pin<Box<dyn Send + Stream<Item = T>>>;
@@ -231,8 +1149,13 @@ where
         &self,
     ) -> Pin<Box<dyn Send + Stream<Item = Result<FsQueuedMail, (Error, Option<QueueId>)>>>> {
         Box::pin(
-            scan_queue(self.path.join(QUEUE_DIR), self.queue.clone())
-                .await
+            scan_queue(
+                self.path.join(QUEUE_DIR),
+                self.queue.clone(),
+                QueueType::Queue,
+                self.read_failures.clone(),
+            )
+            .await
                 .map(|r| r.map(FsQueuedMail::found)),
         )
     }
@@ -241,8 +1164,13 @@ where
         &self,
     ) -> Pin<Box<dyn Send + Stream<Item = Result<FsInflightMail, (Error, Option<QueueId>)>>>> {
         Box::pin(
-            scan_queue(self.path.join(INFLIGHT_DIR), self.inflight.clone())
-                .await
+            scan_queue(
+                self.path.join(INFLIGHT_DIR),
+                self.inflight.clone(),
+                QueueType::Inflight,
+                self.read_failures.clone(),
+            )
+            .await
                 .map(|r| r.map(FsInflightMail::found)),
         )
     }
@@ -258,34 +1186,82 @@ where
         )
     }
 
-    async fn read_inflight(
+    async fn read_queued_metadata(
         &self,
-        mail: &FsInflightMail,
-    ) -> Result<(MailMetadata<U>, Self::Reader), Error> {
-        let inflight = self.inflight.clone();
+        mail: &FsQueuedMail,
+    ) -> Result<Option<MailMetadata<U>>, Error> {
+        let queue = self.queue.clone();
+        let read_failures = self.read_failures.clone();
         let mail = mail.id.0.clone();
 
         unblock(move || {
-            let dest_path_from_inflight = inflight
-                .read_link(&*mail)
-                .map_err(|e| Error::ReadingLinkInQueue(mail.clone(), QueueType::Inflight, e))?;
+            let dest_path_from_queue = match queue.read_link(&*mail) {
+                Ok(p) => p,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Error::ReadingLinkInQueue(mail.clone(), QueueType::Queue, e)),
+            };
 
-            let dest_dir = inflight.sub_dir(&dest_path_from_inflight).map_err(|e| {
-                Error::OpeningFolderInQueue(PathBuf::from(&*mail), QueueType::Inflight, e)
+            let dest_dir = queue.sub_dir(&dest_path_from_queue).map_err(|e| {
+                Error::OpeningFolderInQueue(PathBuf::from(&*mail), QueueType::Queue, e)
             })?;
             let metadata_file = dest_dir.open_file(METADATA_FILE).map_err(|e| {
-                Error::OpeningFileInMail(METADATA_FILE, mail.clone(), QueueType::Inflight, e)
+                Error::OpeningFileInMail(METADATA_FILE, mail.clone(), QueueType::Queue, e)
             })?;
             let metadata = serde_json::from_reader(metadata_file).map_err(|e| {
-                Error::ParsingJsonFileInMail(METADATA_FILE, mail.clone(), QueueType::Inflight, e)
+                let err =
+                    Error::ParsingJsonFileInMail(METADATA_FILE, mail.clone(), QueueType::Queue, e);
+                read_failures.record(&queue, QueueType::Queue, &mail, err)
             })?;
-            let contents_file = dest_dir
-                .sub_dir("..")
-                .map_err(|e| Error::OpeningParentFromMail(mail.clone(), e))?
+            read_failures.forget(&mail);
+            Ok(Some(metadata))
+        })
+        .await
+    }
+
+    async fn read_inflight(
+        &self,
+        mail: &FsInflightMail,
+    ) -> Result<Option<(MailMetadata<U>, Self::Reader)>, Error> {
+        let inflight = self.inflight.clone();
+        let read_failures = self.read_failures.clone();
+        let mail = mail.id.0.clone();
+
+        unblock(move || {
+            let dest_path_from_inflight = match inflight.read_link(&*mail) {
+                Ok(p) => p,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => {
+                    return Err(Error::ReadingLinkInQueue(
+                        mail.clone(),
+                        QueueType::Inflight,
+                        e,
+                    ))
+                }
+            };
+
+            let dest_dir = inflight.sub_dir(&dest_path_from_inflight).map_err(|e| {
+                Error::OpeningFolderInQueue(PathBuf::from(&*mail), QueueType::Inflight, e)
+            })?;
+            let metadata_file = dest_dir.open_file(METADATA_FILE).map_err(|e| {
+                Error::OpeningFileInMail(METADATA_FILE, mail.clone(), QueueType::Inflight, e)
+            })?;
+            let metadata = serde_json::from_reader(metadata_file).map_err(|e| {
+                let err = Error::ParsingJsonFileInMail(
+                    METADATA_FILE,
+                    mail.clone(),
+                    QueueType::Inflight,
+                    e,
+                );
+                read_failures.record(&inflight, QueueType::Inflight, &mail, err)
+            })?;
+            read_failures.forget(&mail);
+            let contents_file = dest_dir
+                .sub_dir("..")
+                .map_err(|e| Error::OpeningParentFromMail(mail.clone(), e))?
                 .open_file(CONTENTS_FILE)
                 .map_err(|e| Error::OpeningFileInMailParent(mail, e))?;
             let reader = Box::pin(smol::Unblock::new(contents_file)) as _;
-            Ok((metadata, reader))
+            Ok(Some((metadata, reader)))
         })
         .await
     }
@@ -293,32 +1269,17 @@ where
     async fn enqueue(&self) -> Result<FsEnqueuer<U>, Error> {
         let data = self.data.clone();
         let queue = self.queue.clone();
+        let syncer = self.syncer.clone();
 
         unblock(move || {
-            let mut uuid_buf: [u8; 45] = Uuid::encode_buffer();
-            let mail_uuid = Uuid::new_v4().as_hyphenated().encode_lower(&mut uuid_buf);
-
-            data.create_dir(&*mail_uuid, ONLY_USER_RWX).map_err(|e| {
-                Error::CreatingFolderInQueue(mail_uuid.to_string(), QueueType::Data, e)
-            })?;
-            let mail_dir = data.sub_dir(&*mail_uuid).map_err(|e| {
-                Error::OpeningFolderInQueue(PathBuf::from(&*mail_uuid), QueueType::Data, e)
-            })?;
-            let contents_file = mail_dir
-                .new_file(CONTENTS_FILE, ONLY_USER_RW)
-                .map_err(|e| {
-                    Error::CreatingFileInMail(
-                        CONTENTS_FILE.to_string(),
-                        PathBuf::from(&*mail_uuid),
-                        QueueType::Data,
-                        e,
-                    )
-                })?;
+            let (mail_uuid, mail_dir, contents_file) = make_mail_dir(&data)?;
 
             Ok(FsEnqueuer {
-                mail_uuid: mail_uuid.to_string(),
+                mail_uuid,
                 mail_dir,
+                data,
                 queue,
+                syncer,
                 writer: Box::pin(smol::Unblock::new(contents_file)),
                 phantom: PhantomData,
             })
@@ -336,6 +1297,7 @@ where
         mail.schedule = schedule;
 
         let queue = self.queue.clone();
+        let stats = self.stats.clone();
         let id = mail.id.0.clone();
 
         unblock(move || {
@@ -377,12 +1339,15 @@ where
                     Error::RenamingFileInMail(
                         tmp_sched_file.to_string(),
                         SCHEDULE_FILE,
-                        id,
+                        id.clone(),
                         QueueType::Queue,
                         e,
                     )
                 })?;
 
+            if let Some(stats) = stats {
+                stats.record(recipient_domain(&queue, &id), |s| s.deferred += 1);
+            }
             Ok(())
         })
         .await?;
@@ -423,9 +1388,18 @@ where
     ) -> Result<Option<FsPendingCleanupMail>, (FsInflightMail, Error)> {
         let inflight = self.inflight.clone();
         let cleanup = self.cleanup.clone();
-        unblock(
-            move || match openat::rename(&*inflight, &*mail.id.0, &*cleanup, &*mail.id.0) {
-                Ok(()) => Ok(Some(mail.into_pending_cleanup())),
+        let stats = self.stats.clone();
+        unblock(move || {
+            let domain = stats
+                .as_ref()
+                .and_then(|_| recipient_domain(&inflight, &mail.id.0));
+            match openat::rename(&*inflight, &*mail.id.0, &*cleanup, &*mail.id.0) {
+                Ok(()) => {
+                    if let Some(stats) = stats {
+                        stats.record(domain, |s| s.delivered += 1);
+                    }
+                    Ok(Some(mail.into_pending_cleanup()))
+                }
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => {
                     let id = mail.id.0.clone();
@@ -439,8 +1413,8 @@ where
                         ),
                     ))
                 }
-            },
-        )
+            }
+        })
         .await
     }
 
@@ -477,9 +1451,18 @@ where
     ) -> Result<Option<FsPendingCleanupMail>, (FsQueuedMail, Error)> {
         let queue = self.queue.clone();
         let cleanup = self.cleanup.clone();
-        unblock(
-            move || match openat::rename(&*queue, &*mail.id.0, &*cleanup, &*mail.id.0) {
-                Ok(()) => Ok(Some(mail.into_pending_cleanup())),
+        let stats = self.stats.clone();
+        unblock(move || {
+            let domain = stats
+                .as_ref()
+                .and_then(|_| recipient_domain(&queue, &mail.id.0));
+            match openat::rename(&*queue, &*mail.id.0, &*cleanup, &*mail.id.0) {
+                Ok(()) => {
+                    if let Some(stats) = stats {
+                        stats.record(domain, |s| s.bounced += 1);
+                    }
+                    Ok(Some(mail.into_pending_cleanup()))
+                }
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => {
                     let id = mail.id.0.clone();
@@ -488,8 +1471,8 @@ where
                         Error::MovingMailBetweenQueues(id, QueueType::Queue, QueueType::Cleanup, e),
                     ))
                 }
-            },
-        )
+            }
+        })
         .await
     }
 
@@ -746,9 +1729,24 @@ where
         .filter_map(|r| async move { r.transpose() })
 }
 
+async fn count_folder<P>(path: P) -> Result<usize, Error>
+where
+    P: 'static + Send + AsRef<Path>,
+{
+    let ids = scan_folder(path).await.collect::<Vec<_>>().await;
+    let mut count = 0;
+    for id in ids {
+        id.map_err(|(e, _)| e)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
 async fn scan_queue<P>(
     path: P,
     dir: Arc<Dir>,
+    queue: QueueType,
+    read_failures: Arc<ReadFailures>,
 ) -> impl 'static + Send + Stream<Item = Result<FoundMail, (Error, Option<QueueId>)>>
 where
     P: 'static + Send + AsRef<Path>,
@@ -757,8 +1755,10 @@ where
     scan_folder(path).await.then(move |id| {
         let dir = dir.clone();
         let root_path = root_path.clone();
+        let read_failures = read_failures.clone();
         async move {
             let id = id?;
+            let mail = id.0.clone();
             let schedule_path = Path::new(&*id.0).join(SCHEDULE_FILE);
             let schedule = unblock(move || {
                 let schedule_file = dir.open_file(&schedule_path).map_err(|e| {
@@ -766,7 +1766,7 @@ where
                 })?;
                 serde_json::from_reader(schedule_file).map_err(|e| {
                     let file_path = root_path.join(schedule_path);
-                    Error::ParsingJson(file_path, e)
+                    read_failures.record(&dir, queue, &mail, Error::ParsingJson(file_path, e))
                 })
             })
             .await
@@ -842,6 +1842,10 @@ impl smtp_queue::InflightMail for FsInflightMail {
     fn id(&self) -> QueueId {
         self.id.clone()
     }
+
+    fn schedule(&self) -> ScheduleInfo {
+        self.schedule
+    }
 }
 
 #[derive(Debug)]
@@ -864,7 +1868,9 @@ impl smtp_queue::PendingCleanupMail for FsPendingCleanupMail {
 pub struct FsEnqueuer<U> {
     mail_uuid: String,
     mail_dir: Dir,
+    data: Arc<Dir>,
     queue: Arc<Dir>,
+    syncer: Arc<dyn Syncer>,
     writer: Pin<Box<dyn 'static + Send + AsyncWrite>>,
     // FsEnqueuer needs the U type parameter just so as to be able to take it as a parameter later
     // on
@@ -872,14 +1878,67 @@ pub struct FsEnqueuer<U> {
 }
 
 /// Blocking function!
+fn make_mail_dir(data: &Dir) -> Result<(String, Dir, std::fs::File), Error> {
+    let mut uuid_buf: [u8; 45] = Uuid::encode_buffer();
+    let mail_uuid = Uuid::new_v4().as_hyphenated().encode_lower(&mut uuid_buf);
+
+    data.create_dir(&*mail_uuid, ONLY_USER_RWX)
+        .map_err(|e| Error::CreatingFolderInQueue(mail_uuid.to_string(), QueueType::Data, e))?;
+    let mail_dir = data
+        .sub_dir(&*mail_uuid)
+        .map_err(|e| Error::OpeningFolderInQueue(PathBuf::from(&*mail_uuid), QueueType::Data, e))?;
+    let contents_file = mail_dir
+        .new_file(CONTENTS_FILE, ONLY_USER_RW)
+        .map_err(|e| {
+            Error::CreatingFileInMail(
+                CONTENTS_FILE.to_string(),
+                PathBuf::from(&*mail_uuid),
+                QueueType::Data,
+                e,
+            )
+        })?;
+
+    Ok((mail_uuid.to_string(), mail_dir, contents_file))
+}
+
+/// Blocking function! Makes the contents of a mail durable, along with the
+/// folders that lead to them
+fn sync_contents(
+    syncer: &dyn Syncer,
+    data: &Dir,
+    mail_uuid: &str,
+    mail_dir: &Dir,
+) -> Result<(), Error> {
+    let contents_file = mail_dir.open_file(CONTENTS_FILE).map_err(|e| {
+        Error::OpeningFileInMail(
+            CONTENTS_FILE,
+            Arc::new(mail_uuid.to_string()),
+            QueueType::Data,
+            e,
+        )
+    })?;
+    syncer.sync_file(&contents_file).map_err(|e| {
+        Error::FlushingMailContents(CONTENTS_FILE, mail_uuid.to_string(), QueueType::Data, e)
+    })?;
+    syncer
+        .sync_dir(mail_dir)
+        .map_err(|e| Error::SyncingFolderInQueue(PathBuf::from(mail_uuid), QueueType::Data, e))?;
+    syncer
+        .sync_dir(data)
+        .map_err(|e| Error::SyncingFolderInQueue(PathBuf::from("."), QueueType::Data, e))
+}
+
+/// Blocking function! Writes the metadata and schedule of destination
+/// `dest_id` and makes them durable, but the contents must already be. The
+/// destination is only exposed in the queue by `link_into_queue`.
 fn make_dest_dir<U>(
-    queue: &Dir,
+    syncer: &dyn Syncer,
     mail_uuid: &str,
     mail_dir: &Dir,
     dest_id: &str,
     metadata: &MailMetadata<U>,
     schedule: &ScheduleInfo,
-) -> Result<FsQueuedMail, Error>
+) -> Result<(), Error>
 where
     U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
 {
@@ -908,7 +1967,7 @@ where
         .map_err(|e| {
             Error::CreatingFileInMail(SCHEDULE_FILE.to_string(), dest_path(), QueueType::Data, e)
         })?;
-    serde_json::to_writer(schedule_file, &schedule).map_err(|e| {
+    serde_json::to_writer(&schedule_file, &schedule).map_err(|e| {
         Error::WritingJsonFileInMail(SCHEDULE_FILE.to_string(), dest_path(), QueueType::Data, e)
     })?;
 
@@ -917,10 +1976,38 @@ where
         .map_err(|e| {
             Error::CreatingFileInMail(METADATA_FILE.to_string(), dest_path(), QueueType::Data, e)
         })?;
-    serde_json::to_writer(metadata_file, &metadata).map_err(|e| {
+    serde_json::to_writer(&metadata_file, &metadata).map_err(|e| {
         Error::WritingJsonFileInMail(METADATA_FILE.to_string(), dest_path(), QueueType::Data, e)
     })?;
 
+    for (file, name) in &[
+        (schedule_file, SCHEDULE_FILE),
+        (metadata_file, METADATA_FILE),
+    ] {
+        syncer.sync_file(file).map_err(|e| {
+            let dest = dest_path().display().to_string();
+            Error::FlushingMailContents(name, dest, QueueType::Data, e)
+        })?;
+    }
+    syncer
+        .sync_dir(&dest_dir)
+        .map_err(|e| Error::SyncingFolderInQueue(dest_path(), QueueType::Data, e))?;
+    syncer
+        .sync_dir(mail_dir)
+        .map_err(|e| Error::SyncingFolderInQueue(PathBuf::from(mail_uuid), QueueType::Data, e))?;
+    Ok(())
+}
+
+/// Blocking function!
+///
+/// Makes the destination `dest_id` of mail `mail_uuid`, as written by
+/// `make_dest_dir`, visible in the queue
+fn link_into_queue(
+    queue: &Dir,
+    mail_uuid: &str,
+    dest_id: &str,
+    schedule: &ScheduleInfo,
+) -> Result<FsQueuedMail, Error> {
     let mut dest_uuid_buf: [u8; 45] = Uuid::encode_buffer();
     let dest_uuid = Uuid::new_v4()
         .as_hyphenated()
@@ -957,26 +2044,40 @@ fn cleanup_dest_dir(mail_dir: &Dir, dest_id: &str) {
 /// Blocking function!
 // TODO: factor out with FsStorage::cleanup? This will require
 // thinking of a way to handle errors properly
-fn cleanup_contents_dir(queue: &Dir, mail_uuid: String, mail_dir: &Dir) {
+fn cleanup_contents_dir(data: &Dir, mail_uuid: String, mail_dir: &Dir) {
     // TODO: consider logging IO errors on cleanups that follow an IO error
     let _ = mail_dir.remove_file(CONTENTS_FILE);
-    let _ = queue.remove_dir(mail_uuid);
+    let _ = data.remove_dir(mail_uuid);
 }
 
-#[async_trait]
-impl<U> smtp_queue::StorageEnqueuer<U, FsStorage<U>, FsQueuedMail> for FsEnqueuer<U>
+impl<U> FsEnqueuer<U>
 where
     U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
 {
-    async fn commit(
+    /// Commit the mail to all the destinations that can be committed, instead
+    /// of failing the whole mail as soon as one destination fails like
+    /// `commit` does.
+    ///
+    /// Returns the queued mails along with the index in `destinations` and
+    /// the error of each destination that could not be committed. If no
+    /// destination could be committed, the mail contents are removed.
+    pub async fn commit_partial(
+        self,
+        destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
+    ) -> Result<(Vec<FsQueuedMail>, Vec<(usize, Error)>), Error> {
+        self.do_commit(destinations, true).await
+    }
+
+    async fn do_commit(
         mut self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
-    ) -> Result<Vec<FsQueuedMail>, Error> {
+        partial: bool,
+    ) -> Result<(Vec<FsQueuedMail>, Vec<(usize, Error)>), Error> {
         match self.flush().await {
             Ok(()) => (),
             Err(e) => {
                 let mail_uuid = self.mail_uuid.clone();
-                unblock(move || cleanup_contents_dir(&self.queue, self.mail_uuid, &self.mail_dir))
+                unblock(move || cleanup_contents_dir(&self.data, self.mail_uuid, &self.mail_dir))
                     .await;
                 return Err(Error::FlushingMailContents(
                     CONTENTS_FILE,
@@ -995,29 +2096,94 @@ where
             })
             .collect::<Vec<_>>();
         unblock(move || {
+            if let Err(e) =
+                sync_contents(&*self.syncer, &self.data, &self.mail_uuid, &self.mail_dir)
+            {
+                cleanup_contents_dir(&self.data, self.mail_uuid, &self.mail_dir);
+                return Err(e);
+            }
+
             let mut queued_mails = Vec::with_capacity(destinations.len());
+            let mut failed = Vec::new();
 
-            for d in 0..destinations.len() {
-                match make_dest_dir(
-                    &self.queue,
+            for (d, dest) in destinations.iter().enumerate() {
+                let queued_mail = make_dest_dir(
+                    &*self.syncer,
                     &self.mail_uuid,
                     &self.mail_dir,
-                    &destinations[d].0,
-                    &destinations[d].1,
-                    &destinations[d].2,
-                ) {
-                    Ok(queued_mail) => queued_mails.push(queued_mail),
+                    &dest.0,
+                    &dest.1,
+                    &dest.2,
+                )
+                .and_then(|()| link_into_queue(&self.queue, &self.mail_uuid, &dest.0, &dest.2));
+                match queued_mail {
+                    Ok(queued_mail) => queued_mails.push((d, queued_mail)),
                     Err(e) => {
-                        for dest in &destinations[0..d] {
-                            cleanup_dest_dir(&self.mail_dir, &dest.0);
+                        cleanup_dest_dir(&self.mail_dir, &dest.0);
+                        failed.push((d, e));
+                        if !partial {
+                            break;
                         }
-                        cleanup_contents_dir(&self.queue, self.mail_uuid, &self.mail_dir);
-                        return Err(e);
                     }
                 }
             }
 
-            Ok(queued_mails)
+            if queued_mails.is_empty() || (!partial && !failed.is_empty()) {
+                for (d, mail) in queued_mails {
+                    // TODO: consider logging IO errors on cleanups that follow an IO error
+                    let _ = self.queue.remove_file(&*mail.id.0);
+                    cleanup_dest_dir(&self.mail_dir, &destinations[d].0);
+                }
+                cleanup_contents_dir(&self.data, self.mail_uuid, &self.mail_dir);
+                if !partial {
+                    if let Some((d, e)) = failed.pop() {
+                        return Err(Error::CommittingDestination(d, Box::new(e)));
+                    }
+                }
+                return Ok((Vec::new(), failed));
+            }
+
+            Ok((queued_mails.into_iter().map(|(_, m)| m).collect(), failed))
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<U> smtp_queue::StorageEnqueuer<U, FsStorage<U>, FsQueuedMail> for FsEnqueuer<U>
+where
+    U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
+{
+    async fn commit(
+        self,
+        destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
+    ) -> Result<Vec<FsQueuedMail>, Error> {
+        // Without partial commits, any failure is returned as an error
+        let (queued_mails, _) = self.do_commit(destinations, false).await?;
+        Ok(queued_mails)
+    }
+
+    async fn abort(self) -> Result<(), Error> {
+        let FsEnqueuer {
+            mail_uuid,
+            mail_dir,
+            data,
+            writer,
+            ..
+        } = self;
+        std::mem::drop(writer);
+        unblock(move || {
+            mail_dir.remove_file(CONTENTS_FILE).map_err(|e| {
+                Error::RemovingFileFromMail(
+                    CONTENTS_FILE,
+                    PathBuf::from(&*mail_uuid),
+                    QueueType::Data,
+                    e,
+                )
+            })?;
+            data.remove_dir(&*mail_uuid).map_err(|e| {
+                Error::RemovingFolderFromQueue(PathBuf::from(&*mail_uuid), QueueType::Data, e)
+            })
         })
         .await
     }
@@ -1049,11 +2215,20 @@ impl<U> AsyncWrite for FsEnqueuer<U> {
 mod tests {
     use super::*;
 
-    use std::io::BufRead;
+    use std::{
+        io::BufRead,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
 
+    use chrono::{TimeZone, Utc};
     use tempdir::TempDir;
 
-    use smtp_queue::Storage;
+    use smtp_message::{Email, Hostname};
+    use smtp_queue::{
+        BounceContents, InflightMail, MailState, QueuedMail, Storage, StorageEnqueuer,
+        TransportFailure,
+    };
 
     fn sleep_for_debug() {
         if let Ok(_) = std::env::var("DEBUGGING") {
@@ -1143,4 +2318,2138 @@ mod tests {
         });
         confirm(path, "res/cleanup-broken-link/after");
     }
+
+    async fn enqueue(stor: &FsStorage<()>, contents: &[u8], to: &[&str]) -> Vec<FsQueuedMail> {
+        let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+        enqueuer
+            .write_all(contents)
+            .await
+            .expect("writing contents");
+        let destinations = to
+            .iter()
+            .map(|to| {
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+                    metadata: (),
+                    first_seen: None,
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc.timestamp(1_600_000_000, 0),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                (meta, schedule)
+            })
+            .collect();
+        enqueuer.commit(destinations).await.expect("committing")
+    }
+
+    /// Returns (id, destination, contents) for all the mails in the queue
+    async fn dump_queue(stor: &FsStorage<()>) -> Vec<(String, String, Vec<u8>)> {
+        let mut res = Vec::new();
+        let mut queue = stor.list_queue().await;
+        while let Some(mail) = queue.next().await {
+            let mail = mail.expect("listing queue");
+            assert_eq!(mail.schedule().at, Utc.timestamp(1_600_000_000, 0));
+            let inflight = stor
+                .send_start(mail)
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+            let (meta, mut reader) = stor
+                .read_inflight(&inflight)
+                .await
+                .expect("reading")
+                .expect("mail vanished");
+            let mut contents = Vec::new();
+            reader
+                .read_to_end(&mut contents)
+                .await
+                .expect("reading contents");
+            res.push(((*inflight.id().0).clone(), meta.to.to_string(), contents));
+            stor.send_cancel(inflight)
+                .await
+                .expect("cancelling send")
+                .expect("mail vanished");
+        }
+        res.sort_by(|a, b| (&a.1, &a.2).cmp(&(&b.1, &b.2)));
+        res
+    }
+
+    #[test]
+    fn export_import_roundtrip() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(dir.path().join("from")))
+                .await
+                .expect("creating storage");
+            enqueue(
+                &stor,
+                b"Hello\r\n",
+                &["<foo@example.org>", "<bar@example.org>"],
+            )
+            .await;
+            enqueue(&stor, b"World\r\n", &["<baz@example.org>"]).await;
+            let inflight = enqueue(&stor, b"Inflight\r\n", &["<qux@example.org>"])
+                .await
+                .pop()
+                .unwrap();
+            stor.send_start(inflight)
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+
+            let archive = stor.export(Vec::new()).await.expect("exporting");
+
+            let imported = FsStorage::<()>::new(Arc::new(dir.path().join("to")))
+                .await
+                .expect("creating storage");
+            let imported_mails = imported
+                .import(io::Cursor::new(archive))
+                .await
+                .expect("importing");
+            assert_eq!(imported_mails.len(), 3);
+
+            let expected = dump_queue(&stor).await;
+            let got = dump_queue(&imported).await;
+            assert_eq!(expected.len(), 3);
+            assert_eq!(got.len(), 3);
+            for (e, g) in expected.iter().zip(got.iter()) {
+                assert_ne!(e.0, g.0, "imported mail kept its queue id");
+                assert_eq!((&e.1, &e.2), (&g.1, &g.2));
+            }
+        });
+    }
+
+    #[test]
+    fn failed_import_queues_nothing() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(dir.path().join("from")))
+                .await
+                .expect("creating storage");
+            enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+            let archive = stor.export(Vec::new()).await.expect("exporting");
+
+            // A valid mail, followed by garbage
+            let mut builder = tar::Builder::new(Vec::new());
+            for entry in tar::Archive::new(&archive[..]).entries().unwrap() {
+                let entry = entry.unwrap();
+                builder.append(&entry.header().clone(), entry).unwrap();
+            }
+            append_to_archive(&mut builder, 1, "garbage", 3, &b"foo"[..]).unwrap();
+            let archive = builder.into_inner().unwrap();
+
+            let imported = FsStorage::<()>::new(Arc::new(dir.path().join("to")))
+                .await
+                .expect("creating storage");
+            let res = imported.import(io::Cursor::new(archive)).await;
+            assert!(matches!(res, Err(Error::UnexpectedFileInArchive(_))));
+            assert!(dump_queue(&imported).await.is_empty());
+            let data = std::fs::read_dir(dir.path().join("to").join(DATA_DIR))
+                .expect("listing data")
+                .count();
+            assert_eq!(data, 0);
+        });
+    }
+
+    #[test]
+    fn requeue_copies_contents() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                .await
+                .expect("creating storage");
+            // Bigger than any buffer used for copying
+            let contents = (0..100_000u32)
+                .flat_map(|i| i.to_le_bytes())
+                .collect::<Vec<u8>>();
+            let mail = enqueue(&stor, &contents, &["<foo@example.org>"])
+                .await
+                .pop()
+                .unwrap();
+            let inflight = stor
+                .send_start(mail)
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+
+            let requeued = smtp_queue::requeue(&stor, &inflight, |meta| {
+                assert_eq!(meta.to.to_string(), "<foo@example.org>");
+                let meta = MailMetadata {
+                    from: meta.from,
+                    to: Email::parse_bracketed(b"<bar@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: meta.first_seen,
+                };
+                vec![(
+                    meta,
+                    ScheduleInfo {
+                        at: Utc.timestamp(1_600_000_000, 0),
+                        last_attempt: None,
+                        priority: 0,
+                    },
+                )]
+            })
+            .await
+            .expect("requeuing");
+            assert_eq!(requeued.len(), 1);
+            stor.send_done(inflight)
+                .await
+                .expect("finishing send")
+                .expect("mail vanished");
+
+            let queue = dump_queue(&stor).await;
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue[0].0, *requeued[0].id().0);
+            assert_eq!(queue[0].1, "<bar@example.org>");
+            assert!(queue[0].2 == contents, "requeued contents differ");
+        });
+    }
+
+    #[test]
+    fn bounce_reads_only_headers() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                .await
+                .expect("creating storage");
+            let headers = b"From: foo@example.org\r\nSubject: Hello\r\n\r\n";
+            // Bigger than any buffer used for reading
+            let mut contents = headers.to_vec();
+            contents.resize(1_000_000, b'a');
+            contents.extend_from_slice(b"\r\n.\r\n");
+            let mail = enqueue(&stor, &contents, &["<foo@example.org>"])
+                .await
+                .pop()
+                .unwrap();
+            let inflight = stor
+                .send_start(mail)
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+
+            let (meta, mut reader) =
+                smtp_queue::read_bounce_contents(&stor, &inflight, BounceContents::Headers)
+                    .await
+                    .expect("reading bounce contents")
+                    .expect("mail vanished");
+            assert_eq!(meta.to.to_string(), "<foo@example.org>");
+            let mut read = Vec::new();
+            let mut buf = [0; 16];
+            loop {
+                match reader.read(&mut buf).await.expect("reading headers") {
+                    0 => break,
+                    n => read.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(read, headers);
+
+            // Whatever was not handed out is still in the storage reader
+            let mut rest = Vec::new();
+            reader
+                .into_inner()
+                .read_to_end(&mut rest)
+                .await
+                .expect("reading the rest");
+            assert!(rest.len() + headers.len() + buf.len() >= contents.len());
+            assert!(contents.ends_with(&rest));
+        });
+    }
+
+    #[test]
+    fn persists_delivery_stats() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        let stats_path = dir.path().join("stats.json");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage")
+                .with_delivery_stats(stats_path.clone())
+                .await
+                .expect("opening delivery stats");
+            let to = &[
+                "<foo@one.example>",
+                "<bar@one.example>",
+                "<baz@Two.example>",
+                "<postmaster>",
+            ];
+            let mut mails = enqueue(&stor, b"Hello\r\n", to).await;
+            let mut bar = mails.remove(1);
+            for mail in mails {
+                let inflight = stor
+                    .send_start(mail)
+                    .await
+                    .expect("starting send")
+                    .expect("mail vanished");
+                stor.send_done(inflight)
+                    .await
+                    .expect("finishing send")
+                    .expect("mail vanished");
+            }
+            let schedule = ScheduleInfo {
+                at: Utc::now(),
+                last_attempt: Some(Utc::now()),
+                priority: 0,
+            };
+            stor.reschedule(&mut bar, schedule)
+                .await
+                .expect("rescheduling");
+            stor.drop(bar).await.expect("dropping").expect("mail vanished");
+        });
+
+        let stats = smol::block_on(async {
+            FsStorage::<()>::new(path)
+                .await
+                .expect("reopening storage")
+                .with_delivery_stats(stats_path)
+                .await
+                .expect("reopening delivery stats")
+                .delivery_stats()
+        });
+        let mut expected = HashMap::new();
+        expected.insert(String::from("one.example"), DeliveryStats {
+            delivered: 1,
+            deferred: 1,
+            bounced: 1,
+        });
+        expected.insert(String::from("two.example"), DeliveryStats {
+            delivered: 1,
+            deferred: 0,
+            bounced: 0,
+        });
+        assert_eq!(stats, expected);
+    }
+
+    #[test]
+    fn dead_letters_unparseable_mails() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage")
+                .with_max_read_failures(3);
+            let bad_meta = enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+            let bad_meta = bad_meta[0].id().0;
+            let bad_sched = enqueue(&stor, b"World\r\n", &["<bar@example.org>"]).await;
+            let bad_sched = bad_sched[0].id().0;
+            let corrupt = |id: &str, file| {
+                std::fs::write(path.join(QUEUE_DIR).join(id).join(file), b"{").unwrap()
+            };
+            corrupt(&bad_meta, METADATA_FILE);
+            corrupt(&bad_sched, SCHEDULE_FILE);
+
+            for attempt in 1..=3 {
+                let mut queue = stor.list_queue().await;
+                while let Some(mail) = queue.next().await {
+                    let mail = match mail {
+                        Ok(mail) => mail,
+                        Err((Error::DeadLettered(id, _, _), _)) => {
+                            assert_eq!((attempt, id), (3, bad_sched.clone()));
+                            continue;
+                        }
+                        Err((e, id)) => {
+                            assert_eq!(id.unwrap().0, bad_sched);
+                            assert!(matches!(e, Error::ParsingJson(_, _)));
+                            continue;
+                        }
+                    };
+                    let inflight = stor
+                        .send_start(mail)
+                        .await
+                        .expect("starting send")
+                        .expect("mail vanished");
+                    match stor.read_inflight(&inflight).await {
+                        Err(Error::DeadLettered(id, _, _)) => {
+                            assert_eq!((attempt, id), (3, bad_meta.clone()));
+                            assert!(stor
+                                .read_inflight(&inflight)
+                                .await
+                                .expect("reading")
+                                .is_none());
+                            assert!(stor.send_cancel(inflight).await.unwrap().is_none());
+                        }
+                        Err(Error::ParsingJsonFileInMail(..)) => {
+                            stor.send_cancel(inflight)
+                                .await
+                                .expect("cancelling send")
+                                .expect("mail vanished");
+                        }
+                        r => panic!("unexpected read result: {:?}", r.map(|r| r.is_some())),
+                    }
+                }
+            }
+
+            // Both mails are now left alone in the dead-letter folder
+            assert!(stor.list_queue().await.next().await.is_none());
+            let mut deadletter = std::fs::read_dir(path.join(DEADLETTER_DIR))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            deadletter.sort();
+            let mut expected = vec![(*bad_meta).clone(), (*bad_sched).clone()];
+            expected.sort();
+            assert_eq!(deadletter, expected);
+            let meta = path.join(DEADLETTER_DIR).join(&*bad_meta).join(METADATA_FILE);
+            assert_eq!(std::fs::read(meta).unwrap(), b"{");
+        });
+    }
+
+    #[test]
+    fn dead_letters_broken_symlinks() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let good = enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+            let good = good[0].id().0;
+            let symlink = |queue: &str, id: &str, dest: &str| {
+                std::os::unix::fs::symlink(dest, path.join(queue).join(id)).unwrap()
+            };
+            symlink(QUEUE_DIR, "missing", "../data/not-a-mail/not-a-destination");
+            symlink(INFLIGHT_DIR, "outside", "/tmp");
+            symlink(QUEUE_DIR, "not-a-destination", &format!("../data/{}", good));
+            // Pending cleanup mails are only checked for a malformed symlink
+            symlink(CLEANUP_DIR, "vanished", "../data/not-a-mail/not-a-destination");
+
+            let mut errors = stor
+                .check_symlinks()
+                .await
+                .into_iter()
+                .map(|e| match e {
+                    Error::DeadLettered(id, _, e) => match *e {
+                        Error::SymlinkPointsToMissingDestination(..) => (id, "missing"),
+                        Error::SymlinkDoesNotPointToDataQueue(..) => (id, "outside"),
+                        Error::SymlinkPointsToNonDestinationSubfolder(..) => (id, "malformed"),
+                        e => panic!("unexpected reason: {}", e),
+                    },
+                    e => panic!("unexpected error: {}", e),
+                })
+                .collect::<Vec<_>>();
+            errors.sort();
+            assert_eq!(
+                errors,
+                vec![
+                    (Arc::new("missing".to_string()), "missing"),
+                    (Arc::new("not-a-destination".to_string()), "malformed"),
+                    (Arc::new("outside".to_string()), "outside"),
+                ]
+            );
+
+            let mut deadletter = std::fs::read_dir(path.join(DEADLETTER_DIR))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            deadletter.sort();
+            assert_eq!(deadletter, vec!["missing", "not-a-destination", "outside"]);
+            assert!(path.join(CLEANUP_DIR).join("vanished").symlink_metadata().is_ok());
+            assert_eq!(
+                dump_queue(&stor).await,
+                vec![(
+                    (*good).clone(),
+                    "<foo@example.org>".to_string(),
+                    b"Hello\r\n".to_vec()
+                )]
+            );
+            assert!(stor.check_symlinks().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn cleans_up_whole_backlog() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = dir.path().join("queue");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(path.clone()))
+                .await
+                .expect("creating storage");
+            let mut mails = enqueue(
+                &stor,
+                b"Hello\r\n",
+                &["<foo@example.org>", "<bar@example.org>"],
+            )
+            .await;
+            mails.extend(enqueue(&stor, b"World\r\n", &["<baz@example.org>"]).await);
+            mails.extend(enqueue(&stor, b"Again\r\n", &["<qux@example.org>"]).await);
+            for mail in mails {
+                let inflight = stor
+                    .send_start(mail)
+                    .await
+                    .expect("starting send")
+                    .expect("mail vanished");
+                stor.send_done(inflight)
+                    .await
+                    .expect("finishing send")
+                    .expect("mail vanished");
+            }
+            assert_eq!(stor.pending_cleanup_count().await.unwrap(), 4);
+
+            let (cleaned_up, errors) = stor.cleanup_all().await;
+            assert_eq!(cleaned_up, 4);
+            assert!(errors.is_empty(), "cleanup errors: {:?}", errors);
+            assert_eq!(stor.pending_cleanup_count().await.unwrap(), 0);
+        });
+        for subfolder in &[CLEANUP_DIR, DATA_DIR] {
+            let left = std::fs::read_dir(path.join(subfolder)).unwrap().count();
+            assert_eq!(left, 0, "{} folder is not empty", subfolder);
+        }
+    }
+
+    #[test]
+    fn cleans_up_orphans() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let good = enqueue(
+                &stor,
+                b"Hello\r\n",
+                &["<foo@example.org>", "<bar@example.org>"],
+            )
+            .await;
+            let mut good_ids = good.iter().map(|m| (*m.id().0).clone()).collect::<Vec<_>>();
+            good_ids.sort();
+            let sent = enqueue(&stor, b"World\r\n", &["<baz@example.org>"]).await;
+            let sent = sent.into_iter().next().unwrap();
+            let sent_id = sent.id();
+            stor.send_start(sent)
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+
+            // An enqueue interrupted before its commit
+            let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+            enqueuer
+                .write_all(b"Partial contents")
+                .await
+                .expect("writing contents");
+            drop(enqueuer);
+
+            // And one interrupted while committing its second destination
+            let dest = std::fs::read_link(path.join(QUEUE_DIR).join(&good_ids[0])).unwrap();
+            let good_mail = path.join(QUEUE_DIR).join(dest).join("..");
+            std::fs::create_dir(good_mail.join("partial")).unwrap();
+            std::fs::write(good_mail.join("partial").join(SCHEDULE_FILE), b"{}").unwrap();
+
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 3);
+            let (removed, errors) = stor.cleanup_orphans().await;
+            assert!(errors.is_empty(), "cleanup errors: {:?}", errors);
+            assert_eq!(removed, 2);
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 2);
+            assert!(!good_mail.join("partial").exists());
+
+            let queued = dump_queue(&stor).await;
+            let mut queued_ids = queued.iter().map(|(id, _, _)| id.clone()).collect::<Vec<_>>();
+            queued_ids.sort();
+            assert_eq!(queued_ids, good_ids);
+            assert!(queued.iter().all(|(_, _, c)| c == b"Hello\r\n"));
+            let inflight = stor.find_inflight().await.collect::<Vec<_>>().await;
+            assert_eq!(inflight.len(), 1);
+            assert_eq!(inflight[0].as_ref().unwrap().id().0, sent_id.0);
+
+            assert_eq!(stor.cleanup_orphans().await.0, 0);
+        });
+    }
+
+    #[test]
+    fn finds_orphans() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let good = enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+            let dead = enqueue(&stor, b"World\r\n", &["<bar@example.org>"]).await;
+            let dead_id = (*dead[0].id().0).clone();
+            std::fs::rename(
+                path.join(QUEUE_DIR).join(&dead_id),
+                path.join(DEADLETTER_DIR).join(&dead_id),
+            )
+            .unwrap();
+            let orphan = path.join(DATA_DIR).join("orphan");
+            std::fs::create_dir_all(orphan.join("dest")).unwrap();
+            std::fs::write(orphan.join(CONTENTS_FILE), b"Lost\r\n").unwrap();
+
+            let orphans = stor.find_orphans().await.collect::<Vec<_>>().await;
+            let orphans = orphans.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+            assert_eq!(orphans, vec![orphan.clone()]);
+            assert!(orphan.exists());
+
+            for outside in &[
+                path.join(QUEUE_DIR).join(&*good[0].id().0),
+                path.join(DATA_DIR),
+                path.join(DATA_DIR).join("..").join(QUEUE_DIR),
+                orphan.join("dest").join("..").join(".."),
+            ] {
+                let res = stor.remove_orphan(outside).await;
+                assert!(
+                    matches!(res, Err(Error::NotInDataFolder(_))),
+                    "removed {:?}",
+                    outside
+                );
+            }
+            stor.remove_orphan(&orphan).await.expect("removing orphan");
+            assert!(!orphan.exists());
+            assert!(stor.find_orphans().await.next().await.is_none());
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 2);
+        });
+    }
+
+    /// Size of each file synced, or None for folders, along with the number of
+    /// mails that were in the queue at that point
+    type Synced = Arc<Mutex<Vec<(Option<u64>, usize)>>>;
+
+    struct RecordingSyncer(PathBuf, Synced);
+
+    impl Syncer for RecordingSyncer {
+        fn sync_file(&self, file: &std::fs::File) -> io::Result<()> {
+            let queued = std::fs::read_dir(&self.0)?.count();
+            self.1
+                .lock()
+                .unwrap()
+                .push((Some(file.metadata()?.len()), queued));
+            file.sync_all()
+        }
+
+        fn sync_dir(&self, dir: &Dir) -> io::Result<()> {
+            let queued = std::fs::read_dir(&self.0)?.count();
+            self.1.lock().unwrap().push((None, queued));
+            Fsync.sync_dir(dir)
+        }
+    }
+
+    #[test]
+    fn syncs_mails_before_queueing_them() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        let synced = Synced::default();
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage")
+                .with_syncer(RecordingSyncer(path.join(QUEUE_DIR), synced.clone()));
+            enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+        });
+        assert_eq!(std::fs::read_dir(path.join(QUEUE_DIR)).unwrap().count(), 1);
+
+        let synced = synced.lock().unwrap();
+        // The contents and the folders leading to them, then the schedule, the
+        // metadata and the folders leading to them
+        let files = synced.iter().map(|(f, _)| f.is_some()).collect::<Vec<_>>();
+        assert_eq!(files, vec![true, false, false, true, true, false, false]);
+        assert_eq!(synced[0].0, Some(7));
+        assert!(synced.iter().all(|(f, _)| f != &Some(0)));
+        assert!(synced.iter().all(|(_, queued)| *queued == 0));
+    }
+
+    #[test]
+    fn counts_queue_without_reading_schedules() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = dir.path().join("queue");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(path.clone()))
+                .await
+                .expect("creating storage");
+            assert_eq!(stor.count_queue().await.unwrap(), 0);
+            let mut mails = enqueue(
+                &stor,
+                b"Hello\r\n",
+                &["<foo@example.org>", "<bar@example.org>"],
+            )
+            .await;
+            mails.extend(enqueue(&stor, b"World\r\n", &["<baz@example.org>"]).await);
+
+            // Counting must not need the schedules, so removing them is fine
+            for mail in &mails {
+                let schedule = path.join(QUEUE_DIR).join(&*mail.id().0).join(SCHEDULE_FILE);
+                std::fs::remove_file(schedule).expect("removing schedule");
+            }
+            assert_eq!(stor.count_queue().await.unwrap(), 3);
+            assert_eq!(stor.pending_cleanup_count().await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn lists_queue_since() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                .await
+                .expect("creating storage");
+            let mut ids = Vec::new();
+            for (to, at, last_attempt) in &[
+                ("<old@example.org>", 1_600_000_000, None),
+                ("<stale@example.org>", 1_600_000_900, Some(1_600_000_000)),
+                ("<new@example.org>", 1_600_000_600, None),
+                ("<retried@example.org>", 1_600_000_900, Some(1_600_000_600)),
+            ] {
+                let mut mail = enqueue(&stor, b"Hello\r\n", &[to]).await.pop().unwrap();
+                let schedule = ScheduleInfo {
+                    at: Utc.timestamp(*at, 0),
+                    last_attempt: last_attempt.map(|t| Utc.timestamp(t, 0)),
+                    priority: 0,
+                };
+                stor.reschedule(&mut mail, schedule)
+                    .await
+                    .expect("rescheduling");
+                ids.push((to, mail.id().0));
+            }
+
+            let listed = stor
+                .list_queue_since(Utc.timestamp(1_600_000_500, 0))
+                .await
+                .map(|m| m.expect("listing queue").id().0)
+                .collect::<Vec<_>>()
+                .await;
+            let mut listed = listed
+                .iter()
+                .map(|id| ids.iter().find(|(_, i)| i == id).unwrap().0)
+                .collect::<Vec<_>>();
+            listed.sort();
+            assert_eq!(listed, vec![&"<new@example.org>", &"<retried@example.org>"]);
+        });
+    }
+
+    #[test]
+    fn round_trips_structured_metadata() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<serde_json::Value>::new(Arc::new(dir.path().join("queue")))
+                .await
+                .expect("creating storage");
+            let metadata = serde_json::json!({
+                "auth_identity": "client.example.org",
+                "spam_score": 1.5,
+                "received_chain": ["from client.example.org by mx.example.org"],
+            });
+            let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+            enqueuer
+                .write_all(b"Hello\r\n")
+                .await
+                .expect("writing contents");
+            let meta = MailMetadata {
+                from: None,
+                to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                metadata: metadata.clone(),
+                first_seen: None,
+            };
+            let schedule = ScheduleInfo {
+                at: Utc.timestamp(1_600_000_000, 0),
+                last_attempt: None,
+                priority: 0,
+            };
+            let mut mails = enqueuer
+                .commit(vec![(meta, schedule)])
+                .await
+                .expect("committing");
+
+            let inflight = stor
+                .send_start(mails.pop().unwrap())
+                .await
+                .expect("starting send")
+                .expect("mail vanished");
+            let (meta, _) = stor
+                .read_inflight(&inflight)
+                .await
+                .expect("reading")
+                .expect("mail vanished");
+            assert_eq!(meta.metadata, metadata);
+        });
+    }
+
+    #[test]
+    fn abort_removes_contents() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+            enqueuer
+                .write_all(b"Partial contents")
+                .await
+                .expect("writing contents");
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 1);
+            enqueuer.abort().await.expect("aborting enqueue");
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 0);
+            assert!(dump_queue(&stor).await.is_empty());
+        });
+    }
+
+    // serde_json refuses to serialize maps with non-string keys, so a non-empty
+    // map is a metadata that fails at commit time
+    type FailingMeta = std::collections::BTreeMap<Vec<u8>, ()>;
+
+    async fn enqueue_failing_second(stor: &FsStorage<FailingMeta>) -> FsEnqueuer<FailingMeta> {
+        let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+        enqueuer
+            .write_all(b"Hello\r\n")
+            .await
+            .expect("writing contents");
+        enqueuer
+    }
+
+    fn failing_second_destinations() -> Vec<(MailMetadata<FailingMeta>, ScheduleInfo)> {
+        (0..3)
+            .map(|i| {
+                let mut metadata = FailingMeta::new();
+                if i == 1 {
+                    metadata.insert(b"fail".to_vec(), ());
+                }
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(format!("<foo{}@example.org>", i).as_bytes())
+                        .unwrap(),
+                    metadata,
+                    first_seen: None,
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc.timestamp(1_600_000_000, 0),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                (meta, schedule)
+            })
+            .collect()
+    }
+
+    async fn queue_len(stor: &FsStorage<FailingMeta>) -> usize {
+        stor.list_queue().await.count().await
+    }
+
+    #[test]
+    fn commit_reports_failed_destination() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        smol::block_on(async {
+            let stor = FsStorage::<FailingMeta>::new(Arc::new(dir.path().join("queue")))
+                .await
+                .expect("creating storage");
+
+            // By default, the whole mail is rolled back
+            let enqueuer = enqueue_failing_second(&stor).await;
+            match enqueuer.commit(failing_second_destinations()).await {
+                Err(Error::CommittingDestination(1, e)) => {
+                    assert!(matches!(*e, Error::WritingJsonFileInMail(..)))
+                }
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("unexpectedly committed the failing destination"),
+            }
+            assert_eq!(queue_len(&stor).await, 0);
+
+            // With partial commits, only the failing destination is dropped
+            let enqueuer = enqueue_failing_second(&stor).await;
+            let (queued, failed) = enqueuer
+                .commit_partial(failing_second_destinations())
+                .await
+                .expect("committing");
+            assert_eq!(queued.len(), 2);
+            assert_eq!(failed.len(), 1);
+            assert_eq!(failed[0].0, 1);
+            assert!(matches!(failed[0].1, Error::WritingJsonFileInMail(..)));
+            assert_eq!(queue_len(&stor).await, 2);
+        });
+    }
+
+    /// Records the state transitions of all the mails
+    struct RateLimitedConfig(Arc<Mutex<Vec<(MailState, MailState)>>>);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for RateLimitedConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn log_state_transition(&self, _id: QueueId, from: MailState, to: MailState) {
+            self.0.lock().unwrap().push((from, to));
+        }
+
+        fn max_rate_per_destination(&self, domain: &Hostname) -> Option<(u32, Duration)> {
+            match domain.to_string().as_str() {
+                "limited.example.org" => Some((1, Duration::from_millis(300))),
+                _ => None,
+            }
+        }
+    }
+
+    struct TickConfig(Duration);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for TickConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn scheduler_tick_interval(&self) -> Option<Duration> {
+            Some(self.0)
+        }
+    }
+
+    /// Id, previous state and new state of each state transition
+    type Transitions = Arc<Mutex<Vec<(Arc<String>, MailState, MailState)>>>;
+
+    /// Records the state transitions of all the mails
+    struct TransitionsConfig(Transitions);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for TransitionsConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn log_state_transition(&self, id: QueueId, from: MailState, to: MailState) {
+            self.0.lock().unwrap().push((id.0, from, to));
+        }
+    }
+
+    /// Records the delivery latency of all the mails
+    struct LatencyConfig(Arc<Mutex<Vec<Duration>>>);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for LatencyConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn log_delivery_latency(&self, _id: QueueId, latency: Duration) {
+            self.0.lock().unwrap().push(latency);
+        }
+    }
+
+    /// Logs the state transitions of all the mails as tracing events
+    struct TracingConfig;
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for TracingConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn log_state_transition(&self, _id: QueueId, from: MailState, to: MailState) {
+            tracing::info!(?from, ?to, "State transition");
+        }
+    }
+
+    /// For each event, the id and the `queue_id` field of the span it was
+    /// emitted in, if any
+    type SpannedEvents = Arc<Mutex<Vec<Option<(u64, String)>>>>;
+
+    /// Subscriber recording the span each event was emitted in
+    #[derive(Default)]
+    struct SpanRecorder {
+        /// The `queue_id` field of each span, indexed by span id minus one
+        spans: Mutex<Vec<String>>,
+        entered: Mutex<Vec<u64>>,
+        events: SpannedEvents,
+    }
+
+    struct QueueIdVisitor<'a>(&'a mut String);
+
+    impl tracing::field::Visit for QueueIdVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "queue_id" {
+                *self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut queue_id = String::new();
+            span.record(&mut QueueIdVisitor(&mut queue_id));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(queue_id);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {
+            let span = self.entered.lock().unwrap().last().copied();
+            let span = span.map(|id| (id, self.spans.lock().unwrap()[id as usize - 1].clone()));
+            self.events.lock().unwrap().push(span);
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    /// Waits before recovering an inflight mail until 500ms after the attempt
+    /// that left it inflight started
+    struct InflightDelayConfig;
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for InflightDelayConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn found_inflight_check_delay(&self, schedule: ScheduleInfo) -> Duration {
+            let elapsed = (Utc::now() - schedule.at).to_std().unwrap_or_default();
+            Duration::from_millis(500).saturating_sub(elapsed)
+        }
+    }
+
+    /// Recovers in the background, blocking on each inflight mail found until
+    /// the sender side of the channel is dropped
+    struct BlockedRecoveryConfig(smol::channel::Receiver<()>);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for BlockedRecoveryConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {
+            let _ = self.0.recv().await;
+        }
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn recover_in_background(&self) -> bool {
+            true
+        }
+    }
+
+    /// Records the time of each delivery, along with its recipient
+    #[derive(Clone)]
+    struct RecordingTransport(Arc<Mutex<Vec<(Instant, String)>>>);
+
+    #[async_trait]
+    impl smtp_queue::Transport<()> for RecordingTransport {
+        type Destination = ();
+        type Sender = RecordingTransport;
+
+        async fn destination(&self, _meta: &MailMetadata<()>) -> Result<(), TransportFailure> {
+            Ok(())
+        }
+
+        async fn connect(&self, _dest: &()) -> Result<RecordingTransport, TransportFailure> {
+            Ok(self.clone())
+        }
+    }
+
+    #[async_trait]
+    impl smtp_queue::TransportSender<()> for RecordingTransport {
+        async fn send<Reader>(
+            &mut self,
+            meta: &MailMetadata<()>,
+            mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            let mut contents = Vec::new();
+            Box::pin(mail)
+                .read_to_end(&mut contents)
+                .await
+                .expect("reading mail");
+            self.0
+                .lock()
+                .unwrap()
+                .push((Instant::now(), meta.to.to_string()));
+            Ok(())
+        }
+    }
+
+    /// Bounces the mails with more than 3 Received headers
+    struct LoopConfig;
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for LoopConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn max_received_headers(&self) -> Option<usize> {
+            Some(3)
+        }
+    }
+
+    /// Gives the deliveries 100ms to complete upon shutdown
+    struct ShutdownConfig;
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for ShutdownConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn shutdown_grace_period(&self) -> Duration {
+            Duration::from_millis(100)
+        }
+    }
+
+    /// Notifies the start of each sending, that then never completes
+    #[derive(Clone)]
+    struct StallingTransport(smol::channel::Sender<()>);
+
+    #[async_trait]
+    impl smtp_queue::Transport<()> for StallingTransport {
+        type Destination = ();
+        type Sender = StallingTransport;
+
+        async fn destination(&self, _meta: &MailMetadata<()>) -> Result<(), TransportFailure> {
+            Ok(())
+        }
+
+        async fn connect(&self, _dest: &()) -> Result<StallingTransport, TransportFailure> {
+            Ok(self.clone())
+        }
+    }
+
+    #[async_trait]
+    impl smtp_queue::TransportSender<()> for StallingTransport {
+        async fn send<Reader>(
+            &mut self,
+            _meta: &MailMetadata<()>,
+            _mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            self.0.send(()).await.expect("notifying the sending");
+            future::pending().await
+        }
+    }
+
+    /// Records the number of mails inflight, along with the maximum reached
+    struct SerializingConfig(Arc<Mutex<(usize, usize)>>);
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for SerializingConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn log_state_transition(&self, _id: QueueId, from: MailState, to: MailState) {
+            let mut inflight = self.0.lock().unwrap();
+            if let MailState::Inflight = to {
+                inflight.0 += 1;
+                inflight.1 = inflight.1.max(inflight.0);
+            } else if let MailState::Inflight = from {
+                inflight.0 -= 1;
+            }
+        }
+
+        fn serialize_per_recipient(&self) -> bool {
+            true
+        }
+    }
+
+    /// Sends a single mail at a time
+    struct SingleSendConfig;
+
+    #[async_trait]
+    impl smtp_queue::Config<(), Error> for SingleSendConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            panic!("no delivery is supposed to fail")
+        }
+
+        async fn log_storage_error(&self, err: Error, id: Option<QueueId>) {
+            panic!("storage error for {:?}: {}", id, err)
+        }
+        async fn log_found_inflight(&self, _inflight: QueueId) {}
+        async fn log_found_pending_cleanup(&self, _pcm: QueueId) {}
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        fn max_concurrent_sends(&self) -> usize {
+            1
+        }
+    }
+
+    /// Records the recipient of each mail sent, taking 100ms to send each
+    #[derive(Clone, Default)]
+    struct SlowTransport(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl smtp_queue::Transport<()> for SlowTransport {
+        type Destination = ();
+        type Sender = SlowTransport;
+
+        async fn destination(&self, _meta: &MailMetadata<()>) -> Result<(), TransportFailure> {
+            Ok(())
+        }
+
+        async fn connect(&self, _dest: &()) -> Result<SlowTransport, TransportFailure> {
+            Ok(self.clone())
+        }
+    }
+
+    #[async_trait]
+    impl smtp_queue::TransportSender<()> for SlowTransport {
+        async fn send<Reader>(
+            &mut self,
+            meta: &MailMetadata<()>,
+            _mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            self.0.lock().unwrap().push(meta.to.to_string());
+            Ok(())
+        }
+    }
+
+    /// Number of mails being sent to each recipient, along with the maximum
+    /// number reached and the number of mails sent
+    type Concurrency = Arc<Mutex<HashMap<String, (usize, usize, usize)>>>;
+
+    #[derive(Clone, Default)]
+    struct ConcurrencyTransport(Concurrency);
+
+    #[async_trait]
+    impl smtp_queue::Transport<()> for ConcurrencyTransport {
+        type Destination = ();
+        type Sender = ConcurrencyTransport;
+
+        async fn destination(&self, _meta: &MailMetadata<()>) -> Result<(), TransportFailure> {
+            Ok(())
+        }
+
+        async fn connect(&self, _dest: &()) -> Result<ConcurrencyTransport, TransportFailure> {
+            Ok(self.clone())
+        }
+    }
+
+    #[async_trait]
+    impl smtp_queue::TransportSender<()> for ConcurrencyTransport {
+        async fn send<Reader>(
+            &mut self,
+            meta: &MailMetadata<()>,
+            _mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            let to = meta.to.to_string();
+            {
+                let mut counts = self.0.lock().unwrap();
+                let (current, max, _) = counts.entry(to.clone()).or_default();
+                *current += 1;
+                *max = (*max).max(*current);
+            }
+            smol::Timer::after(Duration::from_millis(100)).await;
+            let mut counts = self.0.lock().unwrap();
+            let (current, _, sent) = counts.get_mut(&to).unwrap();
+            *current -= 1;
+            *sent += 1;
+            Ok(())
+        }
+    }
+
+    /// Recipient and contents of each mail sent
+    type SentMails = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// Sends to the recipient domain, failing for `failing_domain`, and
+    /// records the connections made and closed and the mails sent
+    #[derive(Clone, Default)]
+    struct FanOutTransport {
+        failing_domain: Option<&'static str>,
+        connections: Arc<Mutex<Vec<String>>>,
+        quits: Arc<Mutex<usize>>,
+        sent: SentMails,
+    }
+
+    #[async_trait]
+    impl smtp_queue::Transport<()> for FanOutTransport {
+        type Destination = String;
+        type Sender = FanOutTransport;
+
+        async fn destination(&self, meta: &MailMetadata<()>) -> Result<String, TransportFailure> {
+            Ok(meta.to.hostname.as_ref().unwrap().to_string())
+        }
+
+        async fn connect(&self, dest: &String) -> Result<FanOutTransport, TransportFailure> {
+            self.connections.lock().unwrap().push(dest.clone());
+            match self.failing_domain {
+                Some(d) if d == dest => Err(TransportFailure::NetworkTransient),
+                _ => Ok(self.clone()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl smtp_queue::TransportSender<()> for FanOutTransport {
+        async fn send<Reader>(
+            &mut self,
+            meta: &MailMetadata<()>,
+            mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            let mut contents = Vec::new();
+            Box::pin(mail)
+                .read_to_end(&mut contents)
+                .await
+                .expect("reading mail");
+            self.sent
+                .lock()
+                .unwrap()
+                .push((meta.to.to_string(), contents));
+            Ok(())
+        }
+
+        async fn quit(self) {
+            *self.quits.lock().unwrap() += 1;
+        }
+    }
+
+    async fn send_start_all(stor: &FsStorage<()>, mails: Vec<FsQueuedMail>) -> Vec<FsInflightMail> {
+        let mut res = Vec::new();
+        for mail in mails {
+            res.push(
+                stor.send_start(mail)
+                    .await
+                    .expect("starting send")
+                    .expect("mail vanished"),
+            );
+        }
+        res
+    }
+
+    #[test]
+    fn fan_out_cleans_up_shared_contents_once() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let contents = b"Subject: Hello\r\n\r\nWorld\r\n";
+            let mails = enqueue(
+                &stor,
+                contents,
+                &["<foo@one.example>", "<bar@one.example>", "<baz@two.example>"],
+            )
+            .await;
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 1);
+
+            // Delivery to two.example fails, so the contents must stay around
+            let transport = FanOutTransport {
+                failing_domain: Some("two.example"),
+                ..FanOutTransport::default()
+            };
+            let inflight = send_start_all(&stor, mails).await;
+            let requeued = smtp_queue::send_fan_out(&stor, &transport, inflight)
+                .await
+                .expect("fanning out");
+            let mut connections = transport.connections.lock().unwrap().clone();
+            connections.sort();
+            assert_eq!(connections, vec!["one.example", "two.example"]);
+            assert_eq!(*transport.quits.lock().unwrap(), 1);
+            let mut sent = transport.sent.lock().unwrap().clone();
+            sent.sort();
+            assert_eq!(sent, vec![
+                ("<bar@one.example>".to_string(), contents.to_vec()),
+                ("<foo@one.example>".to_string(), contents.to_vec()),
+            ]);
+            assert_eq!(requeued.len(), 1);
+            let queue = dump_queue(&stor).await;
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue[0].1, "<baz@two.example>");
+            assert!(queue[0].2 == contents);
+
+            // Once the last destination is delivered, the contents are removed
+            let transport = FanOutTransport::default();
+            let inflight = send_start_all(&stor, requeued).await;
+            let requeued = smtp_queue::send_fan_out(&stor, &transport, inflight)
+                .await
+                .expect("fanning out");
+            assert!(requeued.is_empty());
+            assert_eq!(transport.sent.lock().unwrap().len(), 1);
+            assert_eq!(*transport.quits.lock().unwrap(), 1);
+            assert!(dump_queue(&stor).await.is_empty());
+            assert_eq!(std::fs::read_dir(path.join(DATA_DIR)).unwrap().count(), 0);
+        });
+    }
+
+    #[test]
+    fn rate_limited_deliveries_are_spaced() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            let transitions = transitions.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                enqueue(
+                    &stor,
+                    b"Hello\r\n",
+                    &[
+                        "<foo@limited.example.org>",
+                        "<bar@limited.example.org>",
+                        "<baz@limited.example.org>",
+                        "<foo@example.org>",
+                    ],
+                )
+                .await;
+                let _queue = smtp_queue::Queue::new(
+                    executor,
+                    RateLimitedConfig(transitions),
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+                while deliveries.lock().unwrap().len() < 4 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+        }));
+
+        let deliveries = deliveries.lock().unwrap();
+        let start = deliveries.iter().map(|d| d.0).min().unwrap();
+        let mut limited = deliveries
+            .iter()
+            .filter(|d| d.1.ends_with("@limited.example.org>"))
+            .map(|d| d.0)
+            .collect::<Vec<_>>();
+        limited.sort();
+        assert_eq!(limited.len(), 3);
+        for w in limited.windows(2) {
+            assert!(
+                w[1] - w[0] >= Duration::from_millis(280),
+                "deliveries spaced by only {:?}",
+                w[1] - w[0]
+            );
+        }
+        // Mail to other domains is not held back by the rate limit
+        let unlimited = deliveries
+            .iter()
+            .find(|d| d.1 == "<foo@example.org>")
+            .unwrap()
+            .0;
+        assert!(unlimited - start < Duration::from_millis(280));
+        // Mails over the rate limit are deferred before leaving the queue
+        let transitions = transitions.lock().unwrap();
+        assert!(!transitions.contains(&(MailState::Inflight, MailState::Queued)));
+    }
+
+    async fn wait_for_deliveries(deliveries: &Mutex<Vec<(Instant, String)>>, n: usize) {
+        while deliveries.lock().unwrap().len() < n {
+            smol::Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn scheduler_ticks_at_configured_interval() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(path.clone())
+                    .await
+                    .expect("creating storage");
+                let _queue = smtp_queue::Queue::new(
+                    executor,
+                    TickConfig(Duration::from_millis(200)),
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+
+                // Mails enqueued by another process are picked up by the next scan
+                let other = FsStorage::<()>::new(path)
+                    .await
+                    .expect("creating storage");
+                for (i, to) in ["<foo@example.org>", "<bar@example.org>"].iter().enumerate() {
+                    let start = Instant::now();
+                    enqueue(&other, b"Hello\r\n", &[to]).await;
+                    wait_for_deliveries(&deliveries, i + 1).await;
+                    let latency = deliveries.lock().unwrap()[i].0 - start;
+                    assert!(
+                        latency < Duration::from_millis(350),
+                        "picked up only after {:?}",
+                        latency
+                    );
+                }
+            }
+        }));
+        assert_eq!(deliveries.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn enqueue_does_not_wait_for_tick() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    TickConfig(Duration::from_secs(3600)),
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+
+                let start = Instant::now();
+                let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: None,
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc::now(),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                enqueuer
+                    .commit(vec![(meta, schedule)])
+                    .await
+                    .expect("committing");
+                wait_for_deliveries(&deliveries, 1).await;
+                assert!(start.elapsed() < Duration::from_millis(500));
+            }
+        }));
+    }
+
+    #[test]
+    fn submits_local_mails() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    TickConfig(Duration::from_secs(3600)),
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: None,
+                };
+                let ids = queue
+                    .submit(meta, b"Subject: Autoreply\r\n\r\nAway\r\n.\r\n")
+                    .await
+                    .expect("submitting");
+                assert_eq!(ids.len(), 1);
+                wait_for_deliveries(&deliveries, 1).await;
+                assert_eq!(deliveries.lock().unwrap()[0].1, "<foo@example.org>");
+            }
+        }));
+    }
+
+    #[test]
+    fn reports_state_transitions() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let transitions = transitions.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+                let id = stor.list_queue().await.next().await.unwrap().unwrap().id().0;
+                let _queue = smtp_queue::Queue::new(
+                    executor,
+                    TransitionsConfig(transitions.clone()),
+                    stor,
+                    RecordingTransport(Arc::new(Mutex::new(Vec::new()))),
+                )
+                .await;
+                while transitions.lock().unwrap().len() < 3 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+                assert_eq!(
+                    *transitions.lock().unwrap(),
+                    vec![
+                        (id.clone(), MailState::Queued, MailState::Inflight),
+                        (id.clone(), MailState::Inflight, MailState::PendingCleanup),
+                        (id, MailState::PendingCleanup, MailState::CleanedUp),
+                    ]
+                );
+            }
+        }));
+    }
+
+    #[test]
+    fn serializes_deliveries_per_recipient() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let transport = ConcurrencyTransport::default();
+        let inflight = Arc::new(Mutex::new((0, 0)));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let transport = transport.clone();
+            let inflight = inflight.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                for _ in 0..3 {
+                    enqueue(&stor, b"Hello\r\n", &["<foo@example.org>"]).await;
+                }
+                enqueue(&stor, b"Hello\r\n", &["<bar@example.org>"]).await;
+                let cfg = SerializingConfig(inflight);
+                let _queue = smtp_queue::Queue::new(executor, cfg, stor, transport.clone()).await;
+                while transport.0.lock().unwrap().values().map(|c| c.2).sum::<usize>() < 4 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+        }));
+
+        let counts = transport.0.lock().unwrap();
+        assert_eq!(counts["<foo@example.org>"], (0, 1, 3));
+        assert_eq!(counts["<bar@example.org>"], (0, 1, 1));
+        // The mails waiting for another one to the same recipient stay queued
+        assert_eq!(inflight.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn sends_high_priority_mails_first() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let transport = SlowTransport::default();
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let transport = transport.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let queue =
+                    smtp_queue::Queue::new(executor, SingleSendConfig, stor, transport.clone())
+                        .await;
+
+                // The first mail is sent right away, the others wait for it
+                let mails = &[
+                    ("<bulk1@example.org>", 0),
+                    ("<bulk2@example.org>", 0),
+                    ("<bulk3@example.org>", 0),
+                    ("<otp@example.org>", 10),
+                ];
+                for &(to, priority) in mails {
+                    let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                    enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                    let meta = MailMetadata {
+                        from: None,
+                        to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+                        metadata: (),
+                        first_seen: None,
+                    };
+                    let schedule = ScheduleInfo {
+                        at: Utc::now(),
+                        last_attempt: None,
+                        priority,
+                    };
+                    enqueuer
+                        .commit(vec![(meta, schedule)])
+                        .await
+                        .expect("committing");
+                }
+                while transport.0.lock().unwrap().len() < 4 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+        }));
+
+        // The high-priority mail was enqueued last, but went first among the
+        // waiting ones
+        let sent = transport.0.lock().unwrap();
+        assert_eq!(sent[..2], ["<bulk1@example.org>", "<otp@example.org>"]);
+    }
+
+    #[test]
+    fn bounces_looping_mails() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    LoopConfig,
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+
+                let mails: &[(usize, &str)] = &[(4, "<foo@example.org>"), (3, "<bar@example.org>")];
+                for &(hops, to) in mails {
+                    let mut contents = Vec::new();
+                    for i in 0..hops {
+                        contents.extend_from_slice(
+                            format!("Received: from mx{}.example.org\r\n", i).as_bytes(),
+                        );
+                    }
+                    contents.extend_from_slice(b"Subject: Hello\r\n\r\nHello\r\n.\r\n");
+                    let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                    enqueuer.write_all(&contents).await.expect("writing");
+                    let meta = MailMetadata {
+                        from: Some(Email::parse_bracketed(b"<sender@example.org>").unwrap()),
+                        to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+                        metadata: (),
+                        first_seen: None,
+                    };
+                    let schedule = ScheduleInfo {
+                        at: Utc::now(),
+                        last_attempt: None,
+                        priority: 0,
+                    };
+                    enqueuer
+                        .commit(vec![(meta, schedule)])
+                        .await
+                        .expect("committing");
+                }
+                wait_for_deliveries(&deliveries, 2).await;
+            }
+        }));
+
+        // The looping mail was not delivered, but bounced to its sender
+        let mut delivered = deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, to)| to.clone())
+            .collect::<Vec<_>>();
+        delivered.sort();
+        assert_eq!(delivered, vec!["<bar@example.org>", "<sender@example.org>"]);
+    }
+
+    #[test]
+    fn abandons_deliveries_on_shutdown() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let path = Arc::new(dir.path().join("queue"));
+        let executor = Arc::new(smol::Executor::new());
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let path = path.clone();
+            async move {
+                let stor = FsStorage::<()>::new(path.clone())
+                    .await
+                    .expect("creating storage");
+                let (started_send, started) = smol::channel::unbounded();
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    ShutdownConfig,
+                    stor,
+                    StallingTransport(started_send),
+                )
+                .await;
+
+                let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                enqueuer
+                    .write_all(b"Subject: Hello\r\n\r\nHello\r\n.\r\n")
+                    .await
+                    .expect("writing");
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: None,
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc::now(),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                enqueuer
+                    .commit(vec![(meta, schedule)])
+                    .await
+                    .expect("committing");
+                started.recv().await.expect("waiting for the sending");
+
+                let start = Instant::now();
+                queue.shutdown().await;
+                assert!(start.elapsed() >= Duration::from_millis(100));
+
+                // The mail was left inflight, for the next startup to recover
+                let stor = FsStorage::<()>::new(path).await.expect("reopening storage");
+                assert!(stor.list_queue().await.next().await.is_none());
+                let inflight = stor.find_inflight().await.collect::<Vec<_>>().await;
+                assert_eq!(inflight.len(), 1);
+                let inflight = inflight.into_iter().next().unwrap().expect("finding inflight");
+                assert!(stor.send_cancel(inflight).await.unwrap().is_some());
+            }
+        }));
+    }
+
+    #[test]
+    fn reports_delivery_latency() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let latencies = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let latencies = latencies.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    LatencyConfig(latencies.clone()),
+                    stor,
+                    RecordingTransport(Arc::new(Mutex::new(Vec::new()))),
+                )
+                .await;
+
+                let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: Some(Utc::now() - chrono::Duration::seconds(2)),
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc::now(),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                enqueuer
+                    .commit(vec![(meta, schedule)])
+                    .await
+                    .expect("committing");
+                while latencies.lock().unwrap().is_empty() {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+        }));
+
+        let latencies = latencies.lock().unwrap();
+        assert_eq!(latencies.len(), 1);
+        assert!(
+            latencies[0] >= Duration::from_secs(2) && latencies[0] < Duration::from_secs(5),
+            "unexpected latency {:?}",
+            latencies[0]
+        );
+    }
+
+    #[test]
+    fn accepts_mail_during_background_recovery() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        let (release, recovery) = smol::channel::unbounded();
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let to = (0..20)
+                    .map(|i| format!("<user{}@example.org>", i))
+                    .collect::<Vec<_>>();
+                let to = to.iter().map(|t| t as &str).collect::<Vec<_>>();
+                let mails = enqueue(&stor, b"Hello\r\n", &to).await;
+                send_start_all(&stor, mails).await;
+
+                // The recovery of the inflight mails never completes before
+                // release is dropped, yet the queue must already take mails
+                let queue = smtp_queue::Queue::new(
+                    executor,
+                    BlockedRecoveryConfig(recovery),
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+                let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                let meta = MailMetadata {
+                    from: None,
+                    to: Email::parse_bracketed(b"<foo@example.org>").unwrap(),
+                    metadata: (),
+                    first_seen: None,
+                };
+                let schedule = ScheduleInfo {
+                    at: Utc::now(),
+                    last_attempt: None,
+                    priority: 0,
+                };
+                enqueuer
+                    .commit(vec![(meta, schedule)])
+                    .await
+                    .expect("committing");
+                wait_for_deliveries(&deliveries, 1).await;
+                assert_eq!(deliveries.lock().unwrap()[0].1, "<foo@example.org>");
+                std::mem::drop(release);
+            }
+        }));
+    }
+
+    #[test]
+    fn recovers_stale_inflight_mails_first() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        smol::block_on(executor.run({
+            let executor = executor.clone();
+            let deliveries = deliveries.clone();
+            async move {
+                let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                    .await
+                    .expect("creating storage");
+                let mut enqueuer = stor.enqueue().await.expect("starting enqueue");
+                enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                let destinations = [
+                    ("<recent@example.org>", Utc::now()),
+                    ("<stale@example.org>", Utc::now() - chrono::Duration::hours(1)),
+                ]
+                .iter()
+                .map(|(to, at)| {
+                    let meta = MailMetadata {
+                        from: None,
+                        to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+                        metadata: (),
+                        first_seen: None,
+                    };
+                    let schedule = ScheduleInfo {
+                        at: *at,
+                        last_attempt: None,
+                        priority: 0,
+                    };
+                    (meta, schedule)
+                })
+                .collect();
+                let mails = enqueuer.commit(destinations).await.expect("committing");
+                send_start_all(&stor, mails).await;
+
+                let start = Instant::now();
+                let _queue = smtp_queue::Queue::new(
+                    executor,
+                    InflightDelayConfig,
+                    stor,
+                    RecordingTransport(deliveries.clone()),
+                )
+                .await;
+                wait_for_deliveries(&deliveries, 2).await;
+
+                let deliveries = deliveries.lock().unwrap();
+                assert_eq!(deliveries[0].1, "<stale@example.org>");
+                assert_eq!(deliveries[1].1, "<recent@example.org>");
+                assert!(deliveries[0].0 - start < Duration::from_millis(300));
+                assert!(deliveries[1].0 - start >= Duration::from_millis(400));
+            }
+        }));
+    }
+
+    #[test]
+    fn traces_each_mail_in_its_own_span() {
+        let dir = TempDir::new("smtp-queue-fs-test").expect("creating tempdir");
+        let executor = Arc::new(smol::Executor::new());
+        let events = SpannedEvents::default();
+        let recorder = SpanRecorder {
+            events: events.clone(),
+            ..SpanRecorder::default()
+        };
+        let ids = tracing::subscriber::with_default(recorder, || {
+            smol::block_on(executor.run({
+                let executor = executor.clone();
+                let events = events.clone();
+                async move {
+                    let stor = FsStorage::<()>::new(Arc::new(dir.path().join("queue")))
+                        .await
+                        .expect("creating storage");
+                    let queue = smtp_queue::Queue::new(
+                        executor,
+                        TracingConfig,
+                        stor,
+                        RecordingTransport(Arc::new(Mutex::new(Vec::new()))),
+                    )
+                    .await;
+                    let mut enqueuer = queue.enqueue().await.expect("starting enqueue");
+                    enqueuer.write_all(b"Hello\r\n").await.expect("writing");
+                    let destinations = ["<foo@example.org>", "<bar@example.org>"]
+                        .iter()
+                        .map(|to| {
+                            let meta = MailMetadata {
+                                from: None,
+                                to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+                                metadata: (),
+                                first_seen: None,
+                            };
+                            let schedule = ScheduleInfo {
+                                at: Utc::now(),
+                                last_attempt: None,
+                                priority: 0,
+                            };
+                            (meta, schedule)
+                        })
+                        .collect();
+                    let ids = enqueuer.commit(destinations).await.expect("committing");
+                    // Each mail goes through three state transitions
+                    while events.lock().unwrap().len() < 6 {
+                        smol::Timer::after(Duration::from_millis(10)).await;
+                    }
+                    ids
+                }
+            }))
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 6);
+        let events = events
+            .iter()
+            .map(|e| e.clone().expect("event emitted outside of any span"))
+            .collect::<Vec<_>>();
+        assert_eq!(ids.len(), 2);
+        for id in ids {
+            let spans = events
+                .iter()
+                .filter(|(_, queue_id)| *queue_id == *id.0)
+                .map(|(span, _)| *span)
+                .collect::<Vec<_>>();
+            assert_eq!(spans.len(), 3, "events of {} not in its span", id.0);
+            assert!(spans.iter().all(|s| *s == spans[0]));
+        }
+    }
 }
@@ -0,0 +1,198 @@
+//! Local delivery of the queued mails to per-user maildirs, as a transport
+//! for smtp-queue.
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::prelude::*;
+use smtp_message::DataUnescaper;
+use smtp_queue::{MailMetadata, TransportFailure};
+use tracing::warn;
+use uuid::Uuid;
+
+const MAILDIR_SUBDIRS: [&str; 3] = ["tmp", "new", "cur"];
+const BUF_SIZE: usize = 16 * 1024;
+
+#[derive(Clone)]
+pub struct MaildirTransport {
+    root: Arc<PathBuf>,
+    hostname: Arc<String>,
+}
+
+impl MaildirTransport {
+    /// Mails to `user@domain` are delivered to the maildir `root/user`, which
+    /// must already exist: mails to users without a maildir are refused, so
+    /// that senders cannot create folders at will. Deciding which domains are
+    /// local is left to the caller. `hostname` is used for naming the
+    /// delivered files.
+    pub fn new(root: PathBuf, hostname: String) -> MaildirTransport {
+        MaildirTransport {
+            root: Arc::new(root),
+            hostname: Arc::new(hostname),
+        }
+    }
+
+    /// Returns the maildir of `user`, if it is a valid user name and the
+    /// maildir exists
+    async fn maildir(&self, user: &str) -> Option<PathBuf> {
+        if user.is_empty() || user.starts_with('.') || user.contains(&['/', '\0'][..]) {
+            return None;
+        }
+        let maildir = self.root.join(user);
+        for subdir in MAILDIR_SUBDIRS.iter() {
+            match smol::fs::metadata(maildir.join(subdir)).await {
+                Ok(m) if m.is_dir() => (),
+                _ => return None,
+            }
+        }
+        Some(maildir)
+    }
+
+    /// Unique file name for a new mail, as per the maildir specification
+    fn file_name(&self) -> String {
+        let hostname = self.hostname.replace('/', "\\057").replace(':', "\\072");
+        format!("{}.{}.{}", Utc::now().timestamp(), Uuid::new_v4(), hostname)
+    }
+}
+
+#[async_trait]
+impl<U: 'static + Send + Sync> smtp_queue::Transport<U> for MaildirTransport {
+    type Destination = ();
+    type Sender = MaildirTransport;
+
+    async fn destination(&self, _meta: &MailMetadata<U>) -> Result<(), TransportFailure> {
+        Ok(())
+    }
+
+    async fn connect(&self, _dest: &()) -> Result<MaildirTransport, TransportFailure> {
+        Ok(self.clone())
+    }
+}
+
+#[async_trait]
+impl<U: 'static + Send + Sync> smtp_queue::TransportSender<U> for MaildirTransport {
+    /// Writes the mail to the `tmp` folder of the maildir, then moves it to
+    /// `new` once it is complete, so that mail readers never see a partial
+    /// mail. The mail is unescaped, and a `Return-Path` header is prepended.
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        let user = meta.to.localpart.unquote();
+        let maildir = self
+            .maildir(user.as_str())
+            .await
+            .ok_or(TransportFailure::MailboxPermanent)?;
+        let file_name = self.file_name();
+        let tmp_path = maildir.join("tmp").join(&file_name);
+        let new_path = maildir.join("new").join(&file_name);
+        let res: io::Result<()> = async {
+            let mut file = smol::fs::File::create(&tmp_path).await?;
+            let return_path = match meta.from {
+                Some(ref from) => from.to_string(),
+                None => String::from("<>"),
+            };
+            file.write_all(format!("Return-Path: {}\r\n", return_path).as_bytes())
+                .await?;
+
+            futures::pin_mut!(mail);
+            let mut buf = vec![0; BUF_SIZE];
+            let mut unhandled = 0;
+            let mut unescaper = DataUnescaper::new(true);
+            loop {
+                let read = mail.read(&mut buf[unhandled..]).await?;
+                if read == 0 {
+                    // Anything left over is the end-of-data marker
+                    break;
+                }
+                let end = unhandled + read;
+                let res = unescaper.unescape(&mut buf[..end]);
+                file.write_all(&buf[..res.written]).await?;
+                buf.copy_within(res.unhandled_idx..end, 0);
+                unhandled = end - res.unhandled_idx;
+            }
+            file.sync_all().await?;
+            smol::fs::rename(&tmp_path, &new_path).await
+        }
+        .await;
+        res.map_err(|e| {
+            warn!(error = ?e, maildir = %maildir.display(), "Failed delivering to maildir");
+            let _ = std::fs::remove_file(&tmp_path);
+            TransportFailure::Local
+        })
+    }
+
+    async fn reset(&mut self) -> Result<(), TransportFailure> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smtp_message::Email;
+    use smtp_queue::{Transport, TransportSender};
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn meta(to: &str) -> MailMetadata<()> {
+        MailMetadata {
+            from: Some(Email::parse_bracketed(b"<sender@example.org>").unwrap()),
+            to: Email::parse_bracketed(to.as_bytes()).unwrap(),
+            metadata: (),
+            first_seen: None,
+        }
+    }
+
+    fn list(dir: PathBuf) -> Vec<PathBuf> {
+        match std::fs::read_dir(dir) {
+            Ok(d) => d.map(|e| e.unwrap().path()).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => panic!("listing directory: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn delivers_to_maildir() {
+        let dir = TempDir::new("smtp-queue-fs-maildir").expect("creating tempdir");
+        let transport = MaildirTransport::new(dir.path().to_owned(), "mx.example.org".into());
+        let maildir = dir.path().join("foo");
+        for subdir in MAILDIR_SUBDIRS.iter() {
+            std::fs::create_dir_all(maildir.join(subdir)).unwrap();
+        }
+        let mail: &[u8] = b"Subject: hi\r\n\r\n..dotted\r\nbody\r\n.\r\n";
+        smol::block_on(async {
+            let mut sender = Transport::<()>::connect(&transport, &())
+                .await
+                .ok()
+                .unwrap();
+            let res = sender.send(&meta("<foo@example.org>"), mail).await;
+            assert!(res.is_ok());
+            let res = sender.send(&meta("<\"a/b\"@example.org>"), mail).await;
+            assert!(matches!(res, Err(TransportFailure::MailboxPermanent)));
+            let res = sender.send(&meta("<bar@example.org>"), mail).await;
+            assert!(matches!(res, Err(TransportFailure::MailboxPermanent)));
+        });
+
+        assert_eq!(list(maildir.join("tmp")), Vec::<PathBuf>::new());
+        assert_eq!(list(maildir.join("cur")), Vec::<PathBuf>::new());
+        let new = list(maildir.join("new"));
+        assert_eq!(new.len(), 1);
+        assert!(new[0]
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with(".mx.example.org"));
+        assert_eq!(
+            std::fs::read(&new[0]).unwrap(),
+            &b"Return-Path: <sender@example.org>\r\nSubject: hi\r\n\r\n.dotted\r\nbody\r\n"[..]
+        );
+        assert_eq!(list(dir.path().to_owned()), vec![maildir]);
+    }
+}
@@ -6,6 +6,10 @@ use chrono::{DateTime, Utc};
 pub struct ScheduleInfo {
     pub at: DateTime<Utc>,
     pub last_attempt: Option<DateTime<Utc>>,
+    /// Among the mails that are due, the ones with a higher priority are
+    /// sent first
+    #[serde(default)]
+    pub priority: u8,
 }
 
 impl ScheduleInfo {
@@ -1,17 +1,23 @@
 use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
     hash::Hash,
     io::IoSlice,
     marker::PhantomData,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
-use chrono::Utc;
-use futures::{io, join, pin_mut, AsyncRead, AsyncWrite, Stream, StreamExt, TryFutureExt};
-use smtp_message::Email;
+use chrono::{DateTime, Utc};
+use futures::{
+    io, join, pin_mut, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, Stream,
+    StreamExt, TryFutureExt,
+};
+use smtp_message::{Email, Hostname};
+use tracing::Instrument;
 
 // TODO:
 //  - Record SendFailLevel (Server/Mailbox/Email)
@@ -51,10 +57,29 @@ pub struct MailMetadata<U> {
     pub from: Option<Email>,
     pub to: Email,
     pub metadata: U,
+    /// When the mail was first received, if known
+    #[serde(default)]
+    pub first_seen: Option<DateTime<Utc>>,
 }
 
 pub use smtp_queue_types::{QueueId, ScheduleInfo};
 
+/// Span in which everything that happens to mail `id` is done, from its
+/// enqueuing to its cleanup, so that all the log lines about a single mail
+/// can be correlated
+pub fn mail_span(id: &QueueId) -> tracing::Span {
+    tracing::info_span!("mail", queue_id = %id.0)
+}
+
+/// State of a mail in the queue storage
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MailState {
+    Queued,
+    Inflight,
+    PendingCleanup,
+    CleanedUp,
+}
+
 #[async_trait]
 pub trait Config<U, StorageError>: 'static + Send + Sync {
     // Returning None means dropping the email from the queue. If it does so, this
@@ -69,10 +94,25 @@ pub trait Config<U, StorageError>: 'static + Send + Sync {
     async fn log_pending_cleanup_mail_vanished(&self, id: QueueId);
     async fn log_too_big_duration(&self, id: QueueId, too_big: Duration, new: Duration);
 
+    // Called each time the queue moves a mail from one state to another, eg.
+    // to keep an audit log of what happened to each mail. Transitions that
+    // did not happen because the mail vanished are not reported.
+    #[allow(unused_variables)]
+    async fn log_state_transition(&self, id: QueueId, from: MailState, to: MailState) {}
+
+    // Called each time a mail is successfully delivered, with the time
+    // elapsed since it was first enqueued, eg. to build a histogram of the
+    // delivery latencies. Mails whose first_seen is unknown are not reported.
+    #[allow(unused_variables)]
+    async fn log_delivery_latency(&self, id: QueueId, latency: Duration) {}
+
     // The important thing is it must be longer than the time between
     // switching a mail to inflight and either completing it or
-    // returning it to the queue
-    fn found_inflight_check_delay(&self) -> Duration {
+    // returning it to the queue. schedule is the one of the inflight mail:
+    // the attempt that left it inflight started at about schedule.at, so a
+    // mail that was just picked up may need waiting longer than a stale one.
+    #[allow(unused_variables)]
+    fn found_inflight_check_delay(&self, schedule: ScheduleInfo) -> Duration {
         Duration::from_secs(3600)
     }
 
@@ -83,6 +123,76 @@ pub trait Config<U, StorageError>: 'static + Send + Sync {
             d.mul_f64(2.0)
         }
     }
+
+    // Returning Some(d) means that the queue storage is scanned again every d,
+    // to pick up mails that were added to it from outside this queue (eg. by
+    // another process or by hand). Mails enqueued through this queue are
+    // always scheduled right away, without waiting for the next scan.
+    fn scheduler_tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    // Returning Some((n, d)) means that at most n mails can be sent to the
+    // recipient domain over any period of d. Mails exceeding this rate are
+    // rescheduled for when they can be sent, without counting as an attempt.
+    #[allow(unused_variables)]
+    fn max_rate_per_destination(&self, domain: &Hostname) -> Option<(u32, Duration)> {
+        None
+    }
+
+    // Returning true means that mails to the same recipient are never sent
+    // concurrently: each waits for the mails to this recipient that are
+    // currently being sent before being sent itself.
+    fn serialize_per_recipient(&self) -> bool {
+        false
+    }
+
+    // Maximum number of mails being sent at the same time, 0 meaning no limit.
+    // The mails that become due while this many are being sent wait for one
+    // to complete, and are then sent by decreasing priority, then by
+    // increasing schedule time.
+    fn max_concurrent_sends(&self) -> usize {
+        0
+    }
+
+    // Returning true means that Queue::new returns right away, the inflight
+    // and pending-cleanup mails left over by a previous run being recovered
+    // in the background. Otherwise, Queue::new only returns once they have
+    // all been found, so a server waiting on it does not accept mail before.
+    fn recover_in_background(&self) -> bool {
+        false
+    }
+
+    // Returning Some(n) means that mails carrying more than n Received
+    // headers are considered to be looping: they are bounced as per
+    // loop_bounce instead of being delivered.
+    fn max_received_headers(&self) -> Option<usize> {
+        None
+    }
+
+    // Returns the metadata of the bounce to send about a looping mail, or None
+    // to drop the mail without bouncing it. The default bounces to the sender,
+    // unless it is the null sender, eg. for bounces.
+    fn loop_bounce(&self, meta: MailMetadata<U>) -> Option<MailMetadata<U>> {
+        let to = meta.from?;
+        Some(MailMetadata {
+            from: None,
+            to,
+            metadata: meta.metadata,
+            first_seen: None,
+        })
+    }
+
+    // Value of the From header of the bounces generated by the queue
+    fn bounce_from(&self) -> String {
+        String::from("Mail Delivery System <MAILER-DAEMON@localhost>")
+    }
+
+    // Upon Queue::shutdown, how long the mails that are already being sent
+    // are given to complete, before being abandoned inflight
+    fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 #[async_trait]
@@ -99,17 +209,27 @@ pub trait Storage<U>: 'static + Send + Sync {
     type PendingCleanupLister: Send
         + Stream<Item = Result<Self::PendingCleanupMail, (Self::Error, Option<QueueId>)>>;
 
-    type Enqueuer: StorageEnqueuer<U, Self, Self::QueuedMail>;
+    type Enqueuer: Unpin + StorageEnqueuer<U, Self, Self::QueuedMail>;
     type Reader: Send + AsyncRead;
 
     async fn list_queue(&self) -> Self::QueueLister;
     async fn find_inflight(&self) -> Self::InflightLister;
     async fn find_pending_cleanup(&self) -> Self::PendingCleanupLister;
 
+    /// Returns the metadata of a mail that is still queued, eg. to check its
+    /// recipient before starting to send it. Returns `None` if the mail
+    /// vanished, like `read_inflight`.
+    async fn read_queued_metadata(
+        &self,
+        mail: &Self::QueuedMail,
+    ) -> Result<Option<MailMetadata<U>>, Self::Error>;
+
+    /// Returns `None` if the mail vanished, eg. because the storage gave up on
+    /// it after failing to read it too many times
     async fn read_inflight(
         &self,
         mail: &Self::InflightMail,
-    ) -> Result<(MailMetadata<U>, Self::Reader), Self::Error>;
+    ) -> Result<Option<(MailMetadata<U>, Self::Reader)>, Self::Error>;
 
     async fn enqueue(&self) -> Result<Self::Enqueuer, Self::Error>;
 
@@ -156,6 +276,7 @@ pub trait QueuedMail: Send + Sync {
 
 pub trait InflightMail: Send + Sync {
     fn id(&self) -> QueueId;
+    fn schedule(&self) -> ScheduleInfo;
 }
 
 pub trait PendingCleanupMail: Send + Sync {
@@ -171,6 +292,235 @@ where
         self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
     ) -> Result<Vec<QueuedMail>, S::Error>;
+
+    /// Drops the contents written so far, without enqueuing anything
+    async fn abort(self) -> Result<(), S::Error>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequeueError<E> {
+    #[error("Storage error while requeuing")]
+    Storage(#[source] E),
+
+    #[error("Copying the mail contents to the new enqueue")]
+    CopyingContents(#[source] io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubmitError<E> {
+    #[error("Storage error while submitting")]
+    Storage(#[source] E),
+
+    #[error("Writing the mail contents to the enqueue")]
+    WritingContents(#[source] io::Error),
+}
+
+/// Enqueues the contents of `mail` again, with the destinations returned by
+/// `destinations` when given the metadata of `mail`. This allows re-injecting
+/// or forwarding a mail, eg. after its metadata was modified.
+///
+/// The contents are streamed from `read_inflight` to the new enqueuer, so they
+/// are never fully held in memory. `mail` itself is left untouched. If `mail`
+/// vanished, nothing is enqueued.
+pub async fn requeue<U, S, F>(
+    storage: &S,
+    mail: &S::InflightMail,
+    destinations: F,
+) -> Result<Vec<S::QueuedMail>, RequeueError<S::Error>>
+where
+    S: Storage<U>,
+    F: FnOnce(MailMetadata<U>) -> Vec<(MailMetadata<U>, ScheduleInfo)>,
+{
+    let (meta, reader) = match storage
+        .read_inflight(mail)
+        .await
+        .map_err(RequeueError::Storage)?
+    {
+        Some(r) => r,
+        None => return Ok(Vec::new()),
+    };
+    let destinations = destinations(meta);
+    let mut enqueuer = storage.enqueue().await.map_err(RequeueError::Storage)?;
+    io::copy(Box::pin(reader), &mut enqueuer)
+        .await
+        .map_err(RequeueError::CopyingContents)?;
+    enqueuer
+        .commit(destinations)
+        .await
+        .map_err(RequeueError::Storage)
+}
+
+/// Sends `mails`, which are destinations sharing the contents of a single
+/// mail. Each contents reader is opened along with its metadata, and a single
+/// connection is made to each transport destination for all the mails going
+/// there.
+///
+/// Successfully sent mails are marked as done and cleaned up only once all the
+/// mails have been attempted, so that the shared contents are never removed
+/// while some destination may still need to read them. The mails that could
+/// not be sent are moved back to the queue and returned, while the mails that
+/// vanished are skipped.
+pub async fn send_fan_out<U, S, T>(
+    storage: &S,
+    transport: &T,
+    mails: Vec<S::InflightMail>,
+) -> Result<Vec<S::QueuedMail>, S::Error>
+where
+    S: Storage<U>,
+    T: Transport<U>,
+{
+    let mut failed = Vec::new();
+    let mut by_destination = HashMap::<_, Vec<_>>::new();
+    for mail in mails {
+        let (meta, reader) = match storage.read_inflight(&mail).await? {
+            Some(r) => r,
+            None => continue,
+        };
+        match transport.destination(&meta).await {
+            Ok(dest) => by_destination
+                .entry(dest)
+                .or_default()
+                .push((mail, meta, reader)),
+            Err(_) => failed.push(mail),
+        }
+    }
+
+    let mut sent = Vec::new();
+    for (dest, mails) in by_destination {
+        let mut sender = None;
+        for (mail, meta, reader) in mails {
+            if sender.is_none() {
+                sender = transport.connect(&dest).await.ok();
+            }
+            let res = match sender {
+                Some(ref mut s) => s.send(&meta, reader).await,
+                None => Err(TransportFailure::NetworkTransient),
+            };
+            match res {
+                Ok(()) => sent.push(mail),
+                Err(_) => {
+                    failed.push(mail);
+                    if let Some(ref mut s) = sender {
+                        if s.reset().await.is_err() {
+                            // Do not reuse a connection whose state is unknown
+                            sender = None;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(s) = sender {
+            s.quit().await;
+        }
+    }
+
+    for mail in sent {
+        if let Some(pcm) = storage.send_done(mail).await.map_err(|(_, e)| e)? {
+            storage.cleanup(pcm).await.map_err(|(_, e)| e)?;
+        }
+    }
+    let mut queued = Vec::with_capacity(failed.len());
+    for mail in failed {
+        if let Some(mail) = storage.send_cancel(mail).await.map_err(|(_, e)| e)? {
+            queued.push(mail);
+        }
+    }
+    Ok(queued)
+}
+
+/// Which part of the original mail a bounce includes, as per the RET parameter
+/// of RFC 3461
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BounceContents {
+    Headers,
+    Full,
+}
+
+/// Reads the contents of `mail` to include in a bounce about it.
+///
+/// With `BounceContents::Headers`, the returned reader ends with the empty
+/// line that terminates the header block, and the body is never read from the
+/// storage reader. The contents are returned as stored, ie. still dot-stuffed.
+/// Returns `None` if the mail vanished.
+pub async fn read_bounce_contents<U, S>(
+    storage: &S,
+    mail: &S::InflightMail,
+    contents: BounceContents,
+) -> Result<Option<(MailMetadata<U>, BounceContentsReader<S::Reader>)>, S::Error>
+where
+    S: Storage<U>,
+{
+    Ok(storage
+        .read_inflight(mail)
+        .await?
+        .map(|(meta, reader)| (meta, BounceContentsReader::new(reader, contents))))
+}
+
+pub struct BounceContentsReader<R> {
+    reader: Pin<Box<R>>,
+    headers_only: bool,
+    // Number of bytes of the CRLFCRLF header block terminator seen so far. It
+    // starts at 2 so that a mail without any header ends right away.
+    terminator_seen: usize,
+    done: bool,
+}
+
+impl<R> BounceContentsReader<R> {
+    pub fn new(reader: R, contents: BounceContents) -> BounceContentsReader<R> {
+        BounceContentsReader {
+            reader: Box::pin(reader),
+            headers_only: contents == BounceContents::Headers,
+            terminator_seen: 2,
+            done: false,
+        }
+    }
+
+    /// Returns the storage reader. With `BounceContents::Headers`, it was read
+    /// from at most one buffer further than the header block.
+    pub fn into_inner(self) -> Pin<Box<R>> {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for BounceContentsReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.done {
+            return Poll::Ready(Ok(0));
+        }
+        let read = match self.reader.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(read)) => read,
+            r => return r,
+        };
+        if !self.headers_only {
+            return Poll::Ready(Ok(read));
+        }
+        for (i, &c) in buf[..read].iter().enumerate() {
+            let expected = b"\r\n\r\n"[self.terminator_seen];
+            self.terminator_seen = match (c == expected, c == b'\r') {
+                (true, _) => self.terminator_seen + 1,
+                (false, true) => 1,
+                (false, false) => 0,
+            };
+            if self.terminator_seen == 4 {
+                self.done = true;
+                return Poll::Ready(Ok(i + 1));
+            }
+        }
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// Counts the Received header fields of a header block
+pub fn count_received_headers(headers: &[u8]) -> usize {
+    headers
+        .split(|&c| c == b'\n')
+        .take_while(|l| *l != b"\r" && !l.is_empty())
+        .filter(|l| l.len() >= 9 && l[..9].eq_ignore_ascii_case(b"received:"))
+        .count()
 }
 
 pub enum TransportFailure {
@@ -208,6 +558,23 @@ pub trait TransportSender<U>: 'static + Send {
     ) -> Result<(), TransportFailure>
     where
         Reader: Send + AsyncRead;
+
+    // Called after a failed `send`, so as to reuse the sender for sending
+    // another mail. Returning an error, which is the default, means that the
+    // sender cannot be reused and must be discarded.
+    async fn reset(&mut self) -> Result<(), TransportFailure> {
+        Err(TransportFailure::Local)
+    }
+
+    // Called once the sender is no longer needed, ie. after trying to send the
+    // mails, whether it succeeded or not, or instead of sending them when the
+    // queue is shutting down, so as to politely close the connection. The
+    // default just drops the sender.
+    async fn quit(self)
+    where
+        Self: Sized,
+    {
+    }
 }
 
 // Interval used when the duration doesn't match (ie. only in error conditions)
@@ -218,6 +585,157 @@ struct QueueImpl<C, S, T> {
     config: C,
     storage: S,
     transport: T,
+    rate_limits: Mutex<HashMap<Hostname, TokenBucket>>,
+    // Locks held while sending to each recipient, if serialize_per_recipient
+    recipient_locks: Mutex<HashMap<String, Arc<smol::lock::Mutex<()>>>>,
+    // Mails that currently have a task scheduled to send them
+    scheduled: Mutex<HashSet<Arc<String>>>,
+    // Slots that the due mails need to hold while being sent
+    dispatcher: Arc<Dispatcher>,
+    // Closed once the queue is shutting down
+    stop: smol::channel::Sender<()>,
+    stopped: smol::channel::Receiver<()>,
+    // Held for reading by each delivery in progress, so that shutting down
+    // can wait for them all
+    deliveries: smol::lock::RwLock<()>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Returns how long to wait before a token is available if there is none
+    fn take(&mut self, (n, period): (u32, Duration), now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(n);
+        let per_sec = capacity / period.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = capacity.min(self.tokens + elapsed.as_secs_f64() * per_sec);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+}
+
+struct Dispatcher {
+    state: Mutex<DispatcherState>,
+}
+
+struct DispatcherState {
+    free: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<DueMail>,
+}
+
+// Ordered by priority, then by schedule time, then by the order in which the
+// mails started waiting, so that the heap pops the next mail to send
+struct DueMail {
+    key: (u8, Reverse<DateTime<Utc>>, Reverse<u64>),
+    slot: smol::channel::Sender<DispatchSlot>,
+}
+
+impl PartialEq for DueMail {
+    fn eq(&self, other: &DueMail) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for DueMail {}
+
+impl PartialOrd for DueMail {
+    fn partial_cmp(&self, other: &DueMail) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DueMail {
+    fn cmp(&self, other: &DueMail) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+// Hands the slot over to the next waiting mail when dropped
+struct DispatchSlot(Option<Arc<Dispatcher>>);
+
+impl Drop for DispatchSlot {
+    fn drop(&mut self) {
+        if let Some(dispatcher) = self.0.take() {
+            dispatcher.release();
+        }
+    }
+}
+
+impl Dispatcher {
+    fn new(max: usize) -> Dispatcher {
+        Dispatcher {
+            state: Mutex::new(DispatcherState {
+                free: if max == 0 { usize::MAX } else { max },
+                next_seq: 0,
+                waiting: BinaryHeap::new(),
+            }),
+        }
+    }
+
+    async fn acquire(self: &Arc<Self>, schedule: &ScheduleInfo) -> DispatchSlot {
+        let (sender, receiver) = smol::channel::bounded(1);
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.free > 0 {
+                state.free -= 1;
+                return DispatchSlot(Some(self.clone()));
+            }
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(DueMail {
+                key: (schedule.priority, Reverse(schedule.at), Reverse(seq)),
+                slot: sender,
+            });
+        }
+        receiver
+            .recv()
+            .await
+            .expect("dispatcher dropped a waiting mail without handing it a slot")
+    }
+
+    fn release(self: &Arc<Self>) {
+        loop {
+            // The lock must not be held when handing the slot over, as
+            // dropping it releases it again
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                match state.waiting.pop() {
+                    Some(next) => next,
+                    None => {
+                        state.free += 1;
+                        return;
+                    }
+                }
+            };
+            match next.slot.try_send(DispatchSlot(Some(self.clone()))) {
+                Ok(()) => return,
+                // The mail stopped waiting, eg. because the queue is shutting
+                // down, so the slot goes to the next one
+                Err(e) => {
+                    e.into_inner().0.take();
+                }
+            }
+        }
+    }
+}
+
+enum SendFailure<M> {
+    // The attempt failed, and the mail should be rescheduled as per
+    // next_interval
+    Failed(M),
+
+    // The mail was not sent due to rate limiting, and should be retried at
+    // the given time
+    Deferred(M, DateTime<Utc>),
 }
 
 pub struct Queue<U, C, S, T> {
@@ -273,7 +791,12 @@ where
     async fn cleanup(&self, pcm: S::PendingCleanupMail) {
         let id = pcm.id();
         let cleanup_successful = io_retry_loop!(self, pcm, |p| self.q.storage.cleanup(p).await);
-        if !cleanup_successful {
+        if cleanup_successful {
+            self.q
+                .config
+                .log_state_transition(id, MailState::PendingCleanup, MailState::CleanedUp)
+                .await;
+        } else {
             self.q.config.log_pending_cleanup_mail_vanished(id).await;
         }
     }
@@ -292,22 +815,49 @@ where
         storage: S,
         transport: T,
     ) -> Queue<U, C, S, T> {
+        let (stop, stopped) = smol::channel::bounded(1);
+        let dispatcher = Arc::new(Dispatcher::new(config.max_concurrent_sends()));
         let this = Queue {
             q: Arc::new(QueueImpl {
                 executor,
                 config,
                 storage,
                 transport,
+                rate_limits: Mutex::new(HashMap::new()),
+                recipient_locks: Mutex::new(HashMap::new()),
+                scheduled: Mutex::new(HashSet::new()),
+                dispatcher,
+                stop,
+                stopped,
+                deliveries: smol::lock::RwLock::new(()),
             }),
             phantom: PhantomData,
         };
 
-        join!(this.scan_inflight(), this.scan_pending_cleanup());
+        if this.q.config.recover_in_background() {
+            let this2 = this.clone();
+            this.q
+                .executor
+                .spawn(async move {
+                    join!(this2.scan_inflight(), this2.scan_pending_cleanup());
+                })
+                .detach();
+        } else {
+            join!(this.scan_inflight(), this.scan_pending_cleanup());
+        }
 
         let this2 = this.clone();
         this.q
             .executor
-            .spawn(async move { this2.scan_queue().await })
+            .spawn(async move {
+                this2.scan_queue().await;
+                if let Some(tick) = this2.q.config.scheduler_tick_interval() {
+                    loop {
+                        smol::Timer::after(tick).await;
+                        this2.scan_queue().await;
+                    }
+                }
+            })
             .detach();
 
         this
@@ -320,6 +870,52 @@ where
         })
     }
 
+    /// Enqueues a locally generated mail, eg. a bounce or an autoreply, for it
+    /// to be sent right away. `contents` must be escaped and end with the
+    /// end-of-data marker, like what is written to an `Enqueuer`. Returns the
+    /// ids of the queued mails, like `Enqueuer::commit`.
+    pub async fn submit(
+        &self,
+        meta: MailMetadata<U>,
+        contents: &[u8],
+    ) -> Result<Vec<QueueId>, SubmitError<S::Error>> {
+        let schedule = ScheduleInfo {
+            at: Utc::now(),
+            last_attempt: None,
+            priority: 0,
+        };
+        let mut enqueuer = self.enqueue().await.map_err(SubmitError::Storage)?;
+        if let Err(e) = enqueuer.write_all(contents).await {
+            // Failing to abort only leaves garbage in the storage, so the
+            // write error is the one worth reporting
+            let _ = enqueuer.abort().await;
+            return Err(SubmitError::WritingContents(e));
+        }
+        enqueuer
+            .commit(vec![(meta, schedule)])
+            .await
+            .map_err(SubmitError::Storage)
+    }
+
+    /// Stops sending mails, and returns once the deliveries in progress are
+    /// over. Deliveries that did not start sending their mail yet are
+    /// abandoned right away, while the others are given
+    /// `Config::shutdown_grace_period` to complete. Abandoned mails are left
+    /// inflight, to be recovered upon the next startup.
+    pub async fn shutdown(&self) {
+        self.q.stop.close();
+        let _ = self.q.deliveries.write().await;
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.q.stop.is_closed()
+    }
+
+    // Resolves once the queue is shutting down
+    async fn stopped(&self) {
+        let _ = self.q.stopped.recv().await;
+    }
+
     async fn scan_inflight(&self) {
         let found_inflight_stream = self.q.storage.find_inflight().await;
         pin_mut!(found_inflight_stream);
@@ -327,27 +923,47 @@ where
             match inflight {
                 Err((e, id)) => self.q.config.log_storage_error(e, id).await,
                 Ok(inflight) => {
-                    self.q.config.log_found_inflight(inflight.id()).await;
+                    let span = mail_span(&inflight.id());
+                    self.q
+                        .config
+                        .log_found_inflight(inflight.id())
+                        .instrument(span.clone())
+                        .await;
+                    let delay = self
+                        .q
+                        .config
+                        .found_inflight_check_delay(inflight.schedule());
                     let this = self.clone();
                     self.q
                         .executor
-                        .spawn(async move {
-                            smol::Timer::after(this.q.config.found_inflight_check_delay()).await;
-                            let queued = io_retry_loop!(this, inflight, |i| this
-                                .q
-                                .storage
-                                .send_cancel(i)
-                                .await);
-                            if let Some(queued) = queued {
-                                // Mail is still waiting, probably was
-                                // inflight during a crash
-                                this.send(queued).await
-                            } else {
-                                // Mail is no longer waiting, probably
-                                // was inflight because another
-                                // process was currently sending it
+                        .spawn(
+                            async move {
+                                smol::Timer::after(delay).await;
+                                let queued = io_retry_loop!(this, inflight, |i| this
+                                    .q
+                                    .storage
+                                    .send_cancel(i)
+                                    .await);
+                                if let Some(queued) = queued {
+                                    // Mail is still waiting, probably was
+                                    // inflight during a crash
+                                    this.q
+                                        .config
+                                        .log_state_transition(
+                                            queued.id(),
+                                            MailState::Inflight,
+                                            MailState::Queued,
+                                        )
+                                        .await;
+                                    this.send(queued).await
+                                } else {
+                                    // Mail is no longer waiting, probably
+                                    // was inflight because another
+                                    // process was currently sending it
+                                }
                             }
-                        })
+                            .instrument(span),
+                        )
                         .detach();
                 }
             }
@@ -361,12 +977,16 @@ where
             match queued {
                 Err((e, id)) => self.q.config.log_storage_error(e, id).await,
                 Ok(queued) => {
+                    let span = mail_span(&queued.id());
                     let this = self.clone();
                     self.q
                         .executor
-                        .spawn(async move {
-                            this.send(queued).await;
-                        })
+                        .spawn(
+                            async move {
+                                this.send(queued).await;
+                            }
+                            .instrument(span),
+                        )
                         .detach();
                 }
             }
@@ -380,13 +1000,21 @@ where
             match pcm {
                 Err((e, id)) => self.q.config.log_storage_error(e, id).await,
                 Ok(pcm) => {
-                    self.q.config.log_found_pending_cleanup(pcm.id()).await;
+                    let span = mail_span(&pcm.id());
+                    self.q
+                        .config
+                        .log_found_pending_cleanup(pcm.id())
+                        .instrument(span.clone())
+                        .await;
                     let this = self.clone();
                     self.q
                         .executor
-                        .spawn(async move {
-                            this.cleanup(pcm).await;
-                        })
+                        .spawn(
+                            async move {
+                                this.cleanup(pcm).await;
+                            }
+                            .instrument(span),
+                        )
                         .detach();
                 }
             }
@@ -394,6 +1022,16 @@ where
     }
 
     async fn send(&self, mail: S::QueuedMail) {
+        // Rescanning the queue finds again the mails that are already scheduled
+        let id = mail.id().0;
+        if !self.q.scheduled.lock().unwrap().insert(id.clone()) {
+            return;
+        }
+        self.send_scheduled(mail).await;
+        self.q.scheduled.lock().unwrap().remove(&id);
+    }
+
+    async fn send_scheduled(&self, mail: S::QueuedMail) {
         let mut mail = mail;
         loop {
             // TODO: this should be smol::Timer::at, but I can't find how to convert from
@@ -402,10 +1040,38 @@ where
             let wait_time = (mail.schedule().at - Utc::now())
                 .to_std()
                 .unwrap_or(ZERO_DURATION);
-            smol::Timer::after(wait_time).await;
-            match self.try_send(mail).await {
+            let waited = async {
+                smol::Timer::after(wait_time).await;
+                true
+            };
+            if !smol::future::or(waited, self.stopped().map(|()| false)).await {
+                return;
+            }
+            let schedule = mail.schedule();
+            let slot = self.q.dispatcher.acquire(&schedule).map(Some);
+            let slot = match smol::future::or(slot, self.stopped().map(|()| None)).await {
+                Some(slot) => slot,
+                None => return,
+            };
+            let res = self.try_send(mail).await;
+            std::mem::drop(slot);
+            match res {
                 Ok(()) => return,
-                Err(m) => mail = m,
+                Err(SendFailure::Failed(m)) => mail = m,
+                Err(SendFailure::Deferred(m, at)) => {
+                    mail = m;
+                    let schedule = ScheduleInfo {
+                        at,
+                        last_attempt: mail.schedule().last_attempt,
+                        priority: mail.schedule().priority,
+                    };
+                    io_retry_loop_raw!(
+                        self,
+                        mail.id(),
+                        self.q.storage.reschedule(&mut mail, schedule).await
+                    );
+                    continue;
+                }
             }
             let this_attempt = Utc::now();
             match self.q.config.next_interval(mail.schedule()).await {
@@ -425,6 +1091,7 @@ where
                     let schedule = ScheduleInfo {
                         at: next_attempt,
                         last_attempt: Some(this_attempt),
+                        priority: mail.schedule().priority,
                     };
                     io_retry_loop_raw!(
                         self,
@@ -442,6 +1109,10 @@ where
                             return;
                         }
                     };
+                    self.q
+                        .config
+                        .log_state_transition(id, MailState::Queued, MailState::PendingCleanup)
+                        .await;
 
                     self.cleanup(pcm).await;
                     return;
@@ -450,8 +1121,88 @@ where
         }
     }
 
-    async fn try_send(&self, mail: S::QueuedMail) -> Result<(), S::QueuedMail> {
+    // Returns how long to wait before sending to this recipient, if the rate limit
+    // of its domain is currently exceeded
+    fn take_rate_token(&self, to: &Email) -> Result<(), Duration> {
+        let domain = match &to.hostname {
+            Some(domain) => domain,
+            None => return Ok(()),
+        };
+        let rate = match self.q.config.max_rate_per_destination(domain) {
+            Some((n, period)) if n > 0 && period > Duration::from_secs(0) => (n, period),
+            _ => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut rate_limits = self.q.rate_limits.lock().unwrap();
+        rate_limits
+            .entry(domain.clone())
+            .or_insert(TokenBucket {
+                tokens: f64::from(rate.0),
+                last_refill: now,
+            })
+            .take(rate, now)
+    }
+
+    // Returns the lock to hold while sending to this recipient, if deliveries
+    // to a single recipient are serialized
+    fn recipient_lock(&self, to: &Email) -> Option<Arc<smol::lock::Mutex<()>>> {
+        if !self.q.config.serialize_per_recipient() {
+            return None;
+        }
+        let mut recipient_locks = self.q.recipient_locks.lock().unwrap();
+        // Forget the locks of the recipients no mail is being sent to
+        recipient_locks.retain(|_, l| Arc::strong_count(l) > 1);
+        Some(
+            recipient_locks
+                .entry(to.to_string())
+                .or_insert_with(|| Arc::new(smol::lock::Mutex::new(())))
+                .clone(),
+        )
+    }
+
+    async fn try_send(&self, mail: S::QueuedMail) -> Result<(), SendFailure<S::QueuedMail>> {
+        if self.is_stopped() {
+            // The mail stays queued, for the next startup to send it
+            return Ok(());
+        }
         let id = mail.id();
+        let read = io_retry_loop!(self, mail, |m| match self
+            .q
+            .storage
+            .read_queued_metadata(&m)
+            .await
+        {
+            Ok(Some(meta)) => Ok(Some((m, meta))),
+            Ok(None) => Ok(None),
+            Err(e) => Err((m, e)),
+        });
+        let (mail, to) = match read {
+            Some((mail, meta)) => (mail, meta.to),
+            None => {
+                self.q.config.log_queued_mail_vanished(id).await;
+                return Ok(());
+            }
+        };
+
+        // Wait for the other mails to this recipient while this one is still
+        // queued, and without holding up a shutdown
+        let recipient_lock = self.recipient_lock(&to);
+        let _recipient_guard = match recipient_lock {
+            Some(ref l) => Some(l.lock().await),
+            None => None,
+        };
+        let _delivery = self.q.deliveries.read().await;
+        if self.is_stopped() {
+            return Ok(());
+        }
+
+        if let Err(wait) = self.take_rate_token(&to) {
+            let wait = chrono::Duration::from_std(wait).unwrap_or_else(|_| {
+                chrono::Duration::from_std(INTERVAL_ON_TOO_BIG_DURATION).unwrap()
+            });
+            return Err(SendFailure::Deferred(mail, Utc::now() + wait));
+        }
+
         let inflight = io_retry_loop!(self, mail, |m| self.q.storage.send_start(m).await);
         let inflight = match inflight {
             Some(inflight) => inflight,
@@ -460,36 +1211,96 @@ where
                 return Ok(());
             }
         };
+        self.q
+            .config
+            .log_state_transition(id.clone(), MailState::Queued, MailState::Inflight)
+            .await;
 
-        let (inflight, meta, reader) = io_retry_loop!(self, inflight, |i| match self
+        let inflight = match self.q.config.max_received_headers() {
+            Some(max) => match self.check_loop(inflight, max).await {
+                Some(inflight) => inflight,
+                None => return Ok(()),
+            },
+            None => inflight,
+        };
+
+        let read = io_retry_loop!(self, inflight, |i| match self
             .q
             .storage
             .read_inflight(&i)
             .await
         {
-            Ok((m, r)) => Ok((i, m, r)),
+            Ok(Some((m, r))) => Ok(Some((i, m, r))),
+            Ok(None) => Ok(None),
             Err(e) => Err((i, e)),
         });
+        let (inflight, meta, reader) = match read {
+            Some(read) => read,
+            None => {
+                self.q.config.log_inflight_mail_vanished(id).await;
+                return Ok(());
+            }
+        };
 
         // TODO: connect only once for all mails towards a single destination
         // Note that this will probably mean having to refactor smtp-client, as
         // Destination currently does not remember for how long the DNS reply was valid
         // Also, we will have to consider how to properly handle the case here multiple
         // hostnames have the same top-prio MX IP but not the same lower-prio MX IPs
-        let meta_ref = &meta;
-        let send_attempt = self
+        let connect = self
             .q
             .transport
             .destination(&meta)
             .and_then(|dest| async move { self.q.transport.connect(&dest).await })
-            .and_then(|mut sender| async move { sender.send(meta_ref, reader).await })
-            .await;
+            .map(Some);
+        let send_attempt = match smol::future::or(connect, self.stopped().map(|()| None)).await {
+            None => return self.abandon(inflight),
+            Some(Ok(sender)) if self.is_stopped() => {
+                sender.quit().await;
+                return self.abandon(inflight);
+            }
+            Some(Ok(mut sender)) => {
+                // The remote may already have received part of the mail, so
+                // interrupting the sending right away could lead to it being
+                // delivered twice
+                let send = sender.send(&meta, reader).map(Some);
+                let grace = self.q.config.shutdown_grace_period();
+                let grace = self
+                    .stopped()
+                    .then(|()| smol::Timer::after(grace))
+                    .map(|_| None);
+                match smol::future::or(send, grace).await {
+                    Some(Ok(())) => {
+                        sender.quit().await;
+                        Ok(())
+                    }
+                    Some(Err(e)) => {
+                        sender.quit().await;
+                        Err(e)
+                    }
+                    None => return self.abandon(inflight),
+                }
+            }
+            Some(Err(e)) => Err(e),
+        };
 
         match send_attempt {
             Ok(()) => {
                 let pcm = io_retry_loop!(self, inflight, |i| self.q.storage.send_done(i).await);
                 match pcm {
                     Some(pcm) => {
+                        self.q
+                            .config
+                            .log_state_transition(
+                                id.clone(),
+                                MailState::Inflight,
+                                MailState::PendingCleanup,
+                            )
+                            .await;
+                        if let Some(first_seen) = meta.first_seen {
+                            let latency = (Utc::now() - first_seen).to_std().unwrap_or_default();
+                            self.q.config.log_delivery_latency(id, latency).await;
+                        }
                         self.cleanup(pcm).await;
                     }
                     None => {
@@ -504,16 +1315,148 @@ where
             }
         }
         // The above match falls through only in cases where we ought to retry
+        self.send_failed(inflight).await
+    }
+
+    // Leaves the mail inflight, for it to be recovered upon the next startup
+    fn abandon(&self, inflight: S::InflightMail) -> Result<(), SendFailure<S::QueuedMail>> {
+        tracing::info!(id = ?inflight.id(), "Abandoning the delivery to shut down");
+        Ok(())
+    }
+
+    async fn send_failed(
+        &self,
+        inflight: S::InflightMail,
+    ) -> Result<(), SendFailure<S::QueuedMail>> {
         let id = inflight.id();
         let queued = io_retry_loop!(self, inflight, |i| self.q.storage.send_cancel(i).await);
         match queued {
-            Some(queued) => Err(queued),
+            Some(queued) => {
+                self.q
+                    .config
+                    .log_state_transition(id, MailState::Inflight, MailState::Queued)
+                    .await;
+                Err(SendFailure::Failed(queued))
+            }
             None => {
                 self.q.config.log_inflight_mail_vanished(id).await;
                 Ok(())
             }
         }
     }
+
+    // Reads the header block of the mail, and bounces it if it has more than
+    // max Received headers. Returns the mail if it is to be delivered, and
+    // None if it was bounced or vanished.
+    async fn check_loop(&self, inflight: S::InflightMail, max: usize) -> Option<S::InflightMail> {
+        let id = inflight.id();
+        let read = io_retry_loop!(self, inflight, |i| match read_bounce_contents(
+            &self.q.storage,
+            &i,
+            BounceContents::Headers
+        )
+        .await
+        {
+            Ok(Some((m, r))) => Ok(Some((i, m, r))),
+            Ok(None) => Ok(None),
+            Err(e) => Err((i, e)),
+        });
+        let (inflight, meta, mut reader) = match read {
+            Some(read) => read,
+            None => {
+                self.q.config.log_inflight_mail_vanished(id).await;
+                return None;
+            }
+        };
+        let mut headers = Vec::new();
+        // Failing to read the mail here means failing to deliver it too, so
+        // the delivery attempt reports it
+        let _ = reader.read_to_end(&mut headers).await;
+        std::mem::drop(reader);
+        let received = count_received_headers(&headers);
+        if received <= max {
+            return Some(inflight);
+        }
+
+        tracing::warn!(received, "Bouncing looping mail");
+        if let Some(bounce) = self.q.config.loop_bounce(meta) {
+            if !self.enqueue_loop_bounce(bounce, headers, received).await {
+                // Retry later, rather than losing the mail without a bounce
+                let _ = self.send_failed(inflight).await;
+                return None;
+            }
+        }
+        let pcm = io_retry_loop!(self, inflight, |i| self.q.storage.send_done(i).await);
+        match pcm {
+            Some(pcm) => {
+                self.q
+                    .config
+                    .log_state_transition(id, MailState::Inflight, MailState::PendingCleanup)
+                    .await;
+                self.cleanup(pcm).await;
+            }
+            None => self.q.config.log_inflight_mail_vanished(id).await,
+        }
+        None
+    }
+
+    // Enqueues a bounce quoting the header block of a looping mail, and
+    // schedules it. Returns false if it could not be enqueued.
+    async fn enqueue_loop_bounce(
+        &self,
+        meta: MailMetadata<U>,
+        headers: Vec<u8>,
+        received: usize,
+    ) -> bool {
+        let mut contents = format!(
+            "From: {}\r\n\
+             To: {}\r\n\
+             Date: {}\r\n\
+             Subject: Undelivered Mail Returned to Sender\r\n\
+             Auto-Submitted: auto-replied\r\n\
+             \r\n\
+             Your mail could not be delivered, as it went through too many mail\r\n\
+             servers ({} Received headers), which means that it is probably\r\n\
+             looping. Its headers follow.\r\n\
+             \r\n",
+            self.q.config.bounce_from(),
+            meta.to,
+            Utc::now().to_rfc2822(),
+            received,
+        )
+        .into_bytes();
+        // The header block of a mail without body also holds the end-of-data
+        // marker
+        let headers = match headers.strip_suffix(b"\r\n.\r\n") {
+            Some(h) => h,
+            None => headers.strip_suffix(b"\r\n").unwrap_or(&headers),
+        };
+        contents.extend_from_slice(headers);
+        contents.extend_from_slice(b"\r\n.\r\n");
+
+        match self.submit(meta, &contents).await {
+            Ok(ids) => !ids.is_empty(),
+            Err(SubmitError::Storage(e)) => {
+                self.q.config.log_storage_error(e, None).await;
+                false
+            }
+            Err(SubmitError::WritingContents(e)) => {
+                tracing::warn!(error = ?e, "Failed writing loop bounce");
+                false
+            }
+        }
+    }
+
+    // This is not an async fn, so that it can be called from the sending of a
+    // mail without making the type of its future recursive
+    fn spawn_send(&self, mail: S::QueuedMail) {
+        let id = mail.id();
+        let q = self.clone();
+        self.q
+            .executor
+            .spawn(async move { q.send(mail).await }.instrument(mail_span(&id)))
+            .detach();
+    }
 }
 
 // This cannot be a #[derive] due to the absence of bounds on U,C,S,T
@@ -534,6 +1477,9 @@ where
     enqueuer: Option<S::Enqueuer>,
 }
 
+// The only field ever accessed through a pin is `enqueuer`, which is Unpin
+impl<U, C, S, T> Unpin for Enqueuer<U, C, S, T> where S: Storage<U> {}
+
 impl<U, C, S, T> Enqueuer<U, C, S, T>
 where
     U: 'static + Send + Sync,
@@ -541,21 +1487,25 @@ where
     S: Storage<U>,
     T: Transport<U>,
 {
+    /// Returns the ids of the mails that were queued, one per destination, so
+    /// that the caller can log in their [`mail_span`]
     pub async fn commit(
         self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
-    ) -> Result<(), S::Error> {
+    ) -> Result<Vec<QueueId>, S::Error> {
         let mut this = self;
         let mails = this.enqueuer.take().unwrap().commit(destinations).await?;
+        let mut ids = Vec::with_capacity(mails.len());
         for mail in mails {
-            let q = this.queue.clone();
-            this.queue
-                .q
-                .executor
-                .spawn(async move { q.send(mail).await })
-                .detach();
+            ids.push(mail.id());
+            this.queue.spawn_send(mail);
         }
-        Ok(())
+        Ok(ids)
+    }
+
+    pub async fn abort(self) -> Result<(), S::Error> {
+        let mut this = self;
+        this.enqueuer.take().unwrap().abort().await
     }
 }
 
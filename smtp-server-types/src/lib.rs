@@ -1,4 +1,4 @@
-use std::io;
+use std::{fmt, io, net::IpAddr};
 
 use smtp_message::{Email, Hostname, Reply};
 
@@ -57,6 +57,8 @@ pub struct MailMetadata<U> {
     pub user: U,
     pub from: Option<Email>,
     pub to: Vec<Email>,
+    /// Result of the SPF check of `from`, if one happened
+    pub spf: Option<SpfResult>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -67,9 +69,74 @@ pub struct HelloInfo {
     pub hostname: Hostname,
 }
 
+/// Information about the original client, relayed by a trusted front-end with
+/// the XCLIENT command
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct XclientInfo {
+    pub addr: Option<IpAddr>,
+    /// Hostname given by the original client, which replaces the one the
+    /// front-end gives in its next HELO or EHLO
+    pub helo: Option<Hostname>,
+    /// Name the original client authenticated as
+    pub login: Option<String>,
+}
+
+/// Identity of the certificate the client presented during the TLS handshake
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct TlsPeerIdentity {
+    /// Common name of the subject of the certificate
+    pub subject_cn: Option<String>,
+    /// DNS names, email addresses, URIs and IP addresses listed in the subject
+    /// alternative names of the certificate
+    pub sans: Vec<String>,
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ConnectionMetadata<U> {
     pub user: U,
+    /// Address the connection comes from, if known. When the client relays
+    /// through a front-end, this is the address of the front-end, while
+    /// `xclient` holds the one of the original client.
+    pub peer_addr: Option<IpAddr>,
     pub hello: Option<HelloInfo>,
     pub is_encrypted: bool,
+    pub xclient: Option<XclientInfo>,
+    /// Set by `tls_accept` if the client presented a certificate
+    pub tls_peer: Option<TlsPeerIdentity>,
+}
+
+/// Outcome of checking the HELO hostname of a client against its reverse DNS
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum HelloVerification {
+    Pass,
+    /// Mismatch that is refused with a transient reply, so that a
+    /// misconfigured sender can still fix it and retry
+    SoftFail,
+    Fail,
+}
+
+/// Result of an SPF check, as per RFC 7208 section 2.6
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        })
+    }
 }
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use smtp_message::{EnhancedReplyCode, MaybeUtf8, Reply, ReplyCode};
 
 #[inline]
@@ -132,8 +134,8 @@ pub fn okay_quit() -> Reply<&'static str> {
 }
 
 /// Usual value for returning “Okay” from `already_did_hello`,
-/// `mail_before_hello`, `already_in_mail`, `rcpt_before_mail`,
-/// `data_before_rcpt` and `data_before_mail`
+/// `already_in_mail`, `rcpt_before_mail`, `data_before_rcpt` and
+/// `data_before_mail`
 #[inline]
 pub fn bad_sequence() -> Reply<&'static str> {
     Reply {
@@ -143,6 +145,56 @@ pub fn bad_sequence() -> Reply<&'static str> {
     }
 }
 
+/// Usual value for `mail_before_hello`
+#[inline]
+pub fn hello_required() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::BAD_SEQUENCE,
+        ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
+        text: vec![MaybeUtf8::Ascii("Send HELO/EHLO first")],
+    }
+}
+
+/// Usual value for returning “Reject” from `filter_spf`
+#[inline]
+pub fn spf_fail() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::POLICY_REASON,
+        ecode: Some(EnhancedReplyCode::PERMANENT_SPF_VALIDATION_FAILED),
+        text: vec![MaybeUtf8::Ascii("SPF validation failed")],
+    }
+}
+
+/// Usual value for rejecting a mail from a content filter
+#[inline]
+pub fn content_rejected() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_DELIVERY_NOT_AUTHORIZED),
+        text: vec![MaybeUtf8::Ascii("Message content rejected")],
+    }
+}
+
+/// Usual value for `hello_softfail`
+#[inline]
+pub fn hello_softfail() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::MAILBOX_TEMPORARILY_UNAVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("HELO hostname does not match reverse DNS")],
+    }
+}
+
+/// Usual value for `hello_rejected`
+#[inline]
+pub fn hello_rejected() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::POLICY_REASON,
+        ecode: Some(EnhancedReplyCode::PERMANENT_REVERSE_DNS_VALIDATION_FAILED),
+        text: vec![MaybeUtf8::Ascii("HELO hostname does not match reverse DNS")],
+    }
+}
+
 #[inline]
 pub fn command_unimplemented() -> Reply<&'static str> {
     Reply {
@@ -170,6 +222,37 @@ pub fn command_not_supported() -> Reply<&'static str> {
     }
 }
 
+/// Usual value for `data_without_tls`
+#[inline]
+pub fn starttls_required() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::MUST_ISSUE_STARTTLS,
+        ecode: Some(EnhancedReplyCode::PERMANENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Must issue STARTTLS first")],
+    }
+}
+
+/// Reply for `filter_to` to defer a single recipient, eg. for greylisting,
+/// while the transaction goes on with the other recipients
+#[inline]
+pub fn rcpt_deferred() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::LOCAL_ERROR,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Recipient deferred, try again later")],
+    }
+}
+
+/// Usual value for `too_many_rejected_rcpts`
+#[inline]
+pub fn too_many_rejected_rcpts() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::LOCAL_ERROR,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Too many rejected recipients, try again later")],
+    }
+}
+
 #[inline]
 pub fn pipeline_forbidden_after_starttls() -> Reply<&'static str> {
     Reply {
@@ -179,6 +262,24 @@ pub fn pipeline_forbidden_after_starttls() -> Reply<&'static str> {
     }
 }
 
+#[inline]
+pub fn unadvertised_pipelining() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
+        text: vec![MaybeUtf8::Ascii("Improper command pipelining")],
+    }
+}
+
+#[inline]
+pub fn too_many_pipelined_commands() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Too many pipelined commands")],
+    }
+}
+
 #[inline]
 pub fn line_too_long() -> Reply<&'static str> {
     Reply {
@@ -197,6 +298,69 @@ pub fn internal_server_error() -> Reply<&'static str> {
     }
 }
 
+#[inline]
+pub fn xclient_forbidden() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::POLICY_REASON,
+        ecode: Some(EnhancedReplyCode::PERMANENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Not authorized to use XCLIENT")],
+    }
+}
+
+#[inline]
+pub fn xclient_invalid() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SYNTAX_ERROR,
+        ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND_ARGUMENTS),
+        text: vec![MaybeUtf8::Ascii("Invalid XCLIENT attribute")],
+    }
+}
+
+#[inline]
+pub fn session_too_long() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_BAD_CONNECTION),
+        text: vec![MaybeUtf8::Ascii("Session lasted for too long")],
+    }
+}
+
+#[inline]
+pub fn shutting_down() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_NOT_ACCEPTING_MESSAGES),
+        text: vec![MaybeUtf8::Ascii("Server shutting down")],
+    }
+}
+
+#[inline]
+pub fn no_mail_route() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::MAILBOX_UNAVAILABLE,
+        ecode: Some(EnhancedReplyCode::PERMANENT_BAD_DEST_SYSTEM),
+        text: vec![MaybeUtf8::Ascii("Recipient domain cannot receive mail")],
+    }
+}
+
+#[inline]
+pub fn null_mx() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::DOMAIN_DOES_NOT_ACCEPT_MAIL,
+        ecode: Some(EnhancedReplyCode::PERMANENT_RECIPIENT_ADDRESS_HAS_NULL_MX),
+        text: vec![MaybeUtf8::Ascii("Recipient domain does not accept mail")],
+    }
+}
+
+#[inline]
+pub fn too_many_connections() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Too many connections from your address")],
+    }
+}
+
 #[inline]
 pub fn handle_mail_did_not_call_complete() -> Reply<&'static str> {
     Reply {
@@ -205,3 +369,33 @@ pub fn handle_mail_did_not_call_complete() -> Reply<&'static str> {
         text: vec![MaybeUtf8::Ascii("System incorrectly configured")],
     }
 }
+
+/// Function of this module building a reply that takes no parameters
+pub type BuiltinReply = fn() -> Reply<&'static str>;
+
+/// Translations of the text of the built-in replies, keyed by their id, ie.
+/// the name of the function of this module building them, with one string per
+/// line of the reply. The reply codes are never translated.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct ReplyCatalog(pub HashMap<String, Vec<String>>);
+
+impl ReplyCatalog {
+    /// Returns `reply`, the built-in reply `id`, with its text translated if
+    /// this catalog has a translation for it
+    pub fn translate(&self, id: &str, reply: Reply) -> Reply {
+        match self.0.get(id) {
+            None => reply,
+            Some(text) => Reply {
+                text: text
+                    .iter()
+                    .map(|l| match l.is_ascii() {
+                        true => MaybeUtf8::Ascii(l.clone()),
+                        false => MaybeUtf8::Utf8(l.clone()),
+                    })
+                    .collect(),
+                ..reply
+            },
+        }
+    }
+}
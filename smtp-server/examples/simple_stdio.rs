@@ -127,5 +127,5 @@ fn main() -> io::Result<()> {
     let reader = io::AllowStdIo::new(std::io::stdin());
     let writer = io::AllowStdIo::new(std::io::stdout());
     let io = Duplex::new(reader, writer);
-    executor::block_on(interact(io, IsAlreadyTls::No, (), Arc::new(SimpleConfig)))
+    executor::block_on(interact(io, None, IsAlreadyTls::No, (), Arc::new(SimpleConfig)))
 }
@@ -150,5 +150,5 @@ fuzz_target!(|data: &[u8]| {
     let writer = io::sink();
     let io = Duplex::new(reader, writer);
     let _ignore_errors =
-        executor::block_on(interact(io, IsAlreadyTls::No, (), Arc::new(FuzzConfig)));
+        executor::block_on(interact(io, None, IsAlreadyTls::No, (), Arc::new(FuzzConfig)));
 });
@@ -0,0 +1,288 @@
+//! Streaming of the mail contents through a content filter, eg. a virus
+//! scanner, on their way from the client to the storage.
+
+use std::io;
+
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use smtp_message::{EscapedDataReader, Reply};
+
+use crate::{header_block_len, RDBUF_SIZE};
+
+#[derive(Debug)]
+pub enum FilterVerdict {
+    Accept,
+    /// The caller must discard everything that was already written out, eg.
+    /// by aborting the enqueuer, and answer the mail with this reply
+    Reject(Reply),
+}
+
+#[async_trait]
+pub trait ContentFilter: Send {
+    /// Called on each chunk of the mail contents, in order. The bytes pushed
+    /// to `out` replace the chunk, so a filter that does not modify the mail
+    /// just copies `chunk` into `out`.
+    ///
+    /// Note that the chunks are still dot-escaped, as read from the
+    /// `EscapedDataReader`, and that the output must be escaped likewise.
+    async fn chunk(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Called once all the chunks went through `chunk`. The bytes pushed to
+    /// `out` are appended to the mail if it is accepted.
+    async fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<FilterVerdict>;
+}
+
+/// Content filter that prepends header fields to the mail, above its original
+/// header block, before passing it on to the wrapped filter
+pub struct PrependHeaders<'a, F: ?Sized> {
+    headers: Vec<String>,
+    /// Beginning of the mail, held back until its header block is complete
+    buf: Vec<u8>,
+    prepended: bool,
+    inner: &'a mut F,
+}
+
+impl<'a, F: ?Sized + ContentFilter> PrependHeaders<'a, F> {
+    /// Each of `headers` is a whole header field, including its final CRLF,
+    /// eg. as built by
+    /// [`spf::received_spf_header`](crate::spf::received_spf_header). They
+    /// are prepended in order, skipping the ones that are already in the
+    /// original header block, or earlier in `headers`.
+    ///
+    /// The original header block is only looked at up to
+    /// [`RDBUF_SIZE`](RDBUF_SIZE) bytes.
+    pub fn new(headers: Vec<String>, inner: &'a mut F) -> PrependHeaders<'a, F> {
+        PrependHeaders {
+            headers,
+            buf: Vec::new(),
+            prepended: false,
+            inner,
+        }
+    }
+
+    async fn prepend(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let block_len = header_block_len(&self.buf).unwrap_or(self.buf.len());
+        let mut existing = header_fields(&self.buf[..block_len]);
+        let mut data = Vec::with_capacity(self.buf.len());
+        for header in &self.headers {
+            if !existing.contains(&header.as_bytes()) {
+                existing.push(header.as_bytes());
+                data.extend_from_slice(header.as_bytes());
+            }
+        }
+        data.extend_from_slice(&self.buf);
+        self.prepended = true;
+        self.buf = Vec::new();
+        self.inner.chunk(&data, out).await
+    }
+}
+
+#[async_trait]
+impl<F: ?Sized + ContentFilter> ContentFilter for PrependHeaders<'_, F> {
+    async fn chunk(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if self.prepended {
+            return self.inner.chunk(chunk, out).await;
+        }
+        self.buf.extend_from_slice(chunk);
+        if header_block_len(&self.buf).is_some() || self.buf.len() >= RDBUF_SIZE {
+            self.prepend(out).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<FilterVerdict> {
+        if !self.prepended {
+            self.prepend(out).await?;
+        }
+        self.inner.finish(out).await
+    }
+}
+
+/// Splits `block` into its header fields, each including its continuation
+/// lines and final CRLF
+fn header_fields(block: &[u8]) -> Vec<&[u8]> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    for i in 0..block.len() {
+        let is_field_end = block[i] == b'\n'
+            && i > 0
+            && block[i - 1] == b'\r'
+            && !matches!(block.get(i + 1), Some(b' ') | Some(b'\t'));
+        if is_field_end {
+            fields.push(&block[start..=i]);
+            start = i + 1;
+        }
+    }
+    fields
+}
+
+/// Reads the mail contents from `reader`, and writes them to `out` chunk by
+/// chunk, after they went through `filter`.
+///
+/// This returns an error if reading, filtering or writing failed, or if the
+/// data stream stopped without an end-of-data marker. In this case, the rest
+/// of the mail contents can be read with [`drain_data`](crate::drain_data).
+/// A client closing the connection mid-DATA is reported by an error of kind
+/// `ConnectionAborted`, with the reader not finished. Otherwise, the reader
+/// is finished but not completed, so the caller still has to call `complete`.
+pub async fn apply_content_filter<R, F, W>(
+    reader: &mut EscapedDataReader<'_, R>,
+    filter: &mut F,
+    out: &mut W,
+) -> io::Result<FilterVerdict>
+where
+    R: Unpin + AsyncRead,
+    F: ?Sized + ContentFilter,
+    W: Unpin + AsyncWrite,
+{
+    let mut buf = [0; RDBUF_SIZE];
+    let mut filtered = Vec::with_capacity(RDBUF_SIZE);
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        filtered.clear();
+        filter.chunk(&buf[..read], &mut filtered).await?;
+        out.write_all(&filtered).await?;
+    }
+    if !reader.is_finished() {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "data stream stopped before the end-of-data marker",
+        ));
+    }
+    filtered.clear();
+    let verdict = filter.finish(&mut filtered).await?;
+    if let FilterVerdict::Accept = verdict {
+        out.write_all(&filtered).await?;
+    }
+    Ok(verdict)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor;
+
+    use super::*;
+    use crate::reply;
+
+    /// Rejects the mails containing `SIGNATURE`, even across chunks
+    #[derive(Default)]
+    struct SignatureFilter {
+        tail: Vec<u8>,
+        found: bool,
+    }
+
+    const SIGNATURE: &[u8] = b"X5O!P%@AP";
+
+    #[async_trait]
+    impl ContentFilter for SignatureFilter {
+        async fn chunk(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+            self.tail.extend_from_slice(chunk);
+            self.found |= self.tail.windows(SIGNATURE.len()).any(|w| w == SIGNATURE);
+            let keep = self.tail.len().saturating_sub(SIGNATURE.len() - 1);
+            self.tail.drain(..keep);
+            out.extend_from_slice(chunk);
+            Ok(())
+        }
+
+        async fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<FilterVerdict> {
+            if self.found {
+                Ok(FilterVerdict::Reject(reply::content_rejected().convert()))
+            } else {
+                Ok(FilterVerdict::Accept)
+            }
+        }
+    }
+
+    fn run(input: &[u8]) -> (io::Result<FilterVerdict>, Vec<u8>, bool) {
+        let mut buf = [0; RDBUF_SIZE];
+        let mut reader = EscapedDataReader::new(&mut buf, 0..0, input);
+        let mut out = Vec::new();
+        let res = executor::block_on(apply_content_filter(
+            &mut reader,
+            &mut SignatureFilter::default(),
+            &mut out,
+        ));
+        (res, out, reader.is_finished())
+    }
+
+    #[test]
+    fn passes_clean_mail_through() {
+        let mut input = b"Subject: hello\r\n\r\n".to_vec();
+        for i in 0..10_000 {
+            input.extend_from_slice(format!("line {}\r\n", i).as_bytes());
+        }
+        input.extend_from_slice(b".\r\n");
+        let contents = input.clone();
+        input.extend_from_slice(b"QUIT\r\n");
+        let (res, out, finished) = run(&input);
+        assert!(matches!(res, Ok(FilterVerdict::Accept)));
+        assert!(finished);
+        assert_eq!(out, contents);
+    }
+
+    #[test]
+    fn rejects_mail_with_signature() {
+        let mut input = vec![b'a'; RDBUF_SIZE - 4];
+        input.extend_from_slice(b"X5O!P%@AP\r\n.\r\n");
+        match run(&input) {
+            (Ok(FilterVerdict::Reject(r)), _, true) => {
+                assert_eq!(r, reply::content_rejected().convert())
+            }
+            (res, _, finished) => panic!("unexpected result {:?}, finished: {}", res, finished),
+        }
+        let (res, _, _) = run(b"X5O!P%@A P\r\n.\r\n");
+        assert!(matches!(res, Ok(FilterVerdict::Accept)));
+    }
+
+    #[test]
+    fn prepends_headers() {
+        let run = |input: &[u8]| {
+            let mut buf = [0; RDBUF_SIZE];
+            let mut reader = EscapedDataReader::new(&mut buf, 0..0, input);
+            let mut inner = SignatureFilter::default();
+            let mut filter = PrependHeaders::new(
+                vec![
+                    "Authentication-Results: mx.example.org; spf=pass\r\n".into(),
+                    "X-Seen: yes\r\n".into(),
+                    "X-Seen: yes\r\n".into(),
+                ],
+                &mut inner,
+            );
+            let mut out = Vec::new();
+            let res = executor::block_on(apply_content_filter(&mut reader, &mut filter, &mut out));
+            assert!(matches!(res, Ok(FilterVerdict::Accept)));
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            run(b"Subject: hello\r\nX-Seen:\r\n yes\r\n\r\nX-Seen: yes\r\n.\r\n"),
+            "Authentication-Results: mx.example.org; spf=pass\r\nX-Seen: yes\r\n\
+             Subject: hello\r\nX-Seen:\r\n yes\r\n\r\nX-Seen: yes\r\n.\r\n"
+        );
+        assert_eq!(
+            run(b"X-Seen: yes\r\nSubject: hello\r\n.\r\n"),
+            "Authentication-Results: mx.example.org; spf=pass\r\n\
+             X-Seen: yes\r\nSubject: hello\r\n.\r\n"
+        );
+
+        // The header block is held back across chunks
+        let mut input = b"Subject: hello\r\n".to_vec();
+        input.extend_from_slice(&vec![b'a'; RDBUF_SIZE]);
+        input.extend_from_slice(b"\r\n\r\nbody\r\n.\r\n");
+        let mut expected =
+            b"Authentication-Results: mx.example.org; spf=pass\r\nX-Seen: yes\r\n".to_vec();
+        expected.extend_from_slice(&input);
+        assert_eq!(run(&input).as_bytes(), &expected[..]);
+    }
+
+    #[test]
+    fn fails_on_interrupted_mail() {
+        let (res, _, finished) = run(b"Subject: hello\r\n\r\nhel");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert!(!finished);
+    }
+}
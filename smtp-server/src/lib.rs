@@ -1,9 +1,21 @@
 #![cfg_attr(test, feature(negative_impls))]
 #![type_length_limit = "200000000"]
 
+pub mod filter;
 pub mod protocol;
-
-use std::{cmp, io, ops::Range, pin::Pin, sync::Arc};
+pub mod route;
+pub mod spf;
+pub mod tls;
+
+use std::{
+    cmp,
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
 use chrono::Utc;
@@ -13,12 +25,17 @@ use futures::{
 };
 use smol::future::FutureExt;
 use smtp_message::{
-    next_crlf, nom, Command, Email, EscapedDataReader, Hostname, MaybeUtf8, NextCrLfState, Reply,
+    next_crlf, nom, Command, DataUnescaper, Email, EscapedDataReader, Hostname, MaybeUtf8,
+    NextCrLfState, ParameterName, Parameters, Reply, ReplyCodeKind,
 };
 
-pub use smtp_server_types::{reply, ConnectionMetadata, Decision, HelloInfo, MailMetadata};
+pub use smtp_server_types::{
+    reply, ConnectionMetadata, Decision, HelloInfo, HelloVerification, MailMetadata, SpfResult,
+    TlsPeerIdentity, XclientInfo,
+};
 
 pub use protocol::{Protocol, ProtocolName};
+pub use route::MailRoute;
 
 pub const RDBUF_SIZE: usize = 16 * 1024;
 const MINIMUM_FREE_BUFSPACE: usize = 128;
@@ -38,6 +55,10 @@ pub trait Config: Send + Sync {
     /// Note: this function is only ever used for the default implementations of
     /// other functions in this trait. As such, it is OK to leave it
     /// `unimplemented!()` if other functions are implemented.
+    ///
+    /// The banner is sent before any command, so `conn_meta` only knows about
+    /// the listener: `is_encrypted` is set for implicit TLS listeners, and the
+    /// user metadata can say which listener accepted the connection.
     #[allow(unused_variables)]
     fn welcome_banner(&self, conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>) -> &str {
         "Service ready"
@@ -50,6 +71,48 @@ pub trait Config: Send + Sync {
         reply::welcome_banner(self.hostname(conn_meta), self.welcome_banner(conn_meta))
     }
 
+    /// Returning true makes new connections receive `shutting_down_reply`
+    /// instead of the welcome banner, before being closed. This is meant to
+    /// be set during a graceful shutdown, for connections accepted before the
+    /// listener gets closed.
+    fn is_shutting_down(&self) -> bool {
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn shutting_down_reply(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("shutting_down", reply::shutting_down)
+    }
+
+    /// Maximum number of connections open at the same time from a single
+    /// `client_ip`, over which new connections receive
+    /// `too_many_connections_reply` instead of the welcome banner, before
+    /// being closed. If this returns 0, which is the default, there is no
+    /// limit.
+    ///
+    /// Note: the connections are counted in `open_connections`, so the limit
+    /// is only enforced if it returns a tracker shared by all the connections
+    /// of the server. Also, `client_ip` is called before any XCLIENT command,
+    /// so its default implementation counts the connections by peer address.
+    fn max_connections_per_ip(&self) -> usize {
+        0
+    }
+
+    fn open_connections(&self) -> Option<&OpenConnections> {
+        None
+    }
+
+    #[allow(unused_variables)]
+    fn too_many_connections_reply(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("too_many_connections", reply::too_many_connections)
+    }
+
     /// Note: this function is only ever used for the default implementations of
     /// other functions in this trait. As such, it is OK to leave it
     /// `unimplemented!()` if other functions are implemented.
@@ -70,14 +133,27 @@ pub trait Config: Send + Sync {
             is_extended,
             hostname: hostname.clone(),
         });
+        let mut reply = reply::okay_hello(
+            is_extended,
+            self.hostname(conn_meta),
+            self.hello_banner(conn_meta),
+            self.can_do_tls(conn_meta),
+        );
+        if is_extended {
+            let is_trusted = matches!(
+                conn_meta.peer_addr,
+                Some(addr) if self.is_trusted_frontend(addr)
+            );
+            if is_trusted && self.xclient_allowed(conn_meta) {
+                reply
+                    .text
+                    .push(MaybeUtf8::Ascii("XCLIENT ADDR HELO LOGIN".into()));
+            }
+            let keywords = reply.text.split_off(1);
+            reply.text.extend(self.ehlo_keywords(keywords, conn_meta));
+        }
         Decision::Accept {
-            reply: reply::okay_hello(
-                is_extended,
-                self.hostname(conn_meta),
-                self.hello_banner(conn_meta),
-                self.can_do_tls(conn_meta),
-            )
-            .convert(),
+            reply: reply.convert(),
             res: HelloInfo {
                 is_extended,
                 hostname,
@@ -85,6 +161,41 @@ pub trait Config: Send + Sync {
         }
     }
 
+    /// Called by the default `filter_hello` on the keywords advertised in reply
+    /// to EHLO, in order, to return the lines that actually get sent after the
+    /// banner, eg. to reorder or recase them to look like another MTA.
+    #[allow(unused_variables)]
+    fn ehlo_keywords(
+        &self,
+        keywords: Vec<MaybeUtf8<String>>,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Vec<MaybeUtf8<String>> {
+        keywords
+    }
+
+    /// Called before `filter_hello` if `client_ip` returned an address, to
+    /// check the HELO hostname against the reverse DNS of the client.
+    /// Implementations will usually resolve the PTR records of `ip`, and on
+    /// mismatch return either `SoftFail`, answered with `hello_softfail`, or
+    /// `Fail`, answered with `hello_rejected`.
+    #[allow(unused_variables)]
+    async fn verify_hello(
+        &self,
+        ip: IpAddr,
+        hostname: &Hostname,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> HelloVerification {
+        HelloVerification::Pass
+    }
+
+    /// Whether `tls_accept` can succeed at all, eg. whether the certificate
+    /// and key could be loaded. When this returns `false`, STARTTLS is
+    /// neither advertised in the EHLO reply nor accepted, whatever
+    /// `can_do_tls` and `filter_hello` say
+    fn tls_configured(&self) -> bool {
+        true
+    }
+
     #[allow(unused_variables)]
     fn can_do_tls(&self, conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>) -> bool {
         !conn_meta.is_encrypted
@@ -103,6 +214,10 @@ pub trait Config: Send + Sync {
     /// `can_do_tls` to return `false` so that STARTTLS is not advertized. This
     /// being said, returning an error here should have the same result in
     /// practice, except clients will try STARTTLS and fail
+    ///
+    /// If the client presented a certificate, implementations should set
+    /// `conn_meta.tls_peer` to its identity, eg. with `tls::peer_identity`,
+    /// so that the later hooks can authorize based on it
     async fn tls_accept<IO>(
         &self,
         io: IO,
@@ -125,6 +240,80 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<Option<Email>>;
 
+    /// Whether `peer_addr`, the address a connection comes from, is a trusted
+    /// front-end, allowed to relay the information of the original client
+    /// with XCLIENT. Connections with an unknown peer address are never
+    /// trusted.
+    #[allow(unused_variables)]
+    fn is_trusted_frontend(&self, peer_addr: IpAddr) -> bool {
+        false
+    }
+
+    /// Whether a connection from a trusted front-end, as per
+    /// `is_trusted_frontend`, can currently use XCLIENT. Note that after a
+    /// successful XCLIENT, `conn_meta.xclient` is set, so that implementations
+    /// can decide whether to allow further XCLIENT commands. By default, a
+    /// single one is allowed.
+    fn xclient_allowed(&self, conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>) -> bool {
+        conn_meta.xclient.is_none()
+    }
+
+    /// IP address of the client, used for checking the SPF policy of the
+    /// sender domain. If this returns `None`, no SPF check is done.
+    ///
+    /// By default, this is the address relayed with XCLIENT, if any, and the
+    /// peer address otherwise.
+    fn client_ip(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Option<IpAddr> {
+        conn_meta
+            .xclient
+            .as_ref()
+            .and_then(|x| x.addr)
+            .or(conn_meta.peer_addr)
+    }
+
+    /// Called after `filter_from` accepted a sender with a domain, if
+    /// `client_ip` returned an address. Implementations will usually call
+    /// [`spf::check_host`](spf::check_host) with their resolver.
+    #[allow(unused_variables)]
+    async fn spf_check(
+        &self,
+        ip: IpAddr,
+        domain: &str,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> SpfResult {
+        SpfResult::None
+    }
+
+    /// Decides what to do with the result of `spf_check`. If this returns
+    /// `Accept`, its reply is ignored and the reply of `filter_from` is sent.
+    ///
+    /// The result is also recorded in `meta.spf`, so that `handle_mail` can
+    /// prepend a `Received-SPF` header built by
+    /// [`spf::received_spf_header`](spf::received_spf_header).
+    #[allow(unused_variables)]
+    async fn filter_spf(
+        &self,
+        result: SpfResult,
+        meta: &mut MailMetadata<Self::MailUserMeta>,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Decision<()> {
+        match result {
+            SpfResult::Fail => Decision::Reject {
+                reply: self.reply("spf_fail", reply::spf_fail),
+            },
+            _ => Decision::Accept {
+                reply: self.reply("okay_from", reply::okay_from),
+                res: (),
+            },
+        }
+    }
+
+    /// Rejecting a recipient with a transient reply, eg. with
+    /// `reply::rcpt_deferred`, defers only this recipient, while the
+    /// transaction goes on with the accepted ones
     async fn filter_to(
         &self,
         to: Email,
@@ -132,6 +321,106 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<Email>;
 
+    /// Whether to check, after `filter_to` accepted a recipient, that its
+    /// domain can receive mail with `recipient_route`. Recipients whose
+    /// domain has no mail route are then rejected with `no_route_reply`.
+    #[allow(unused_variables)]
+    fn verify_recipient_routability(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        false
+    }
+
+    /// Called for the domain of each recipient accepted by `filter_to`, if
+    /// `verify_recipient_routability` returned true. Implementations will
+    /// usually call [`route::RouteCache::mail_route`](route::RouteCache::mail_route)
+    /// with their resolver.
+    #[allow(unused_variables)]
+    async fn recipient_route(
+        &self,
+        domain: &str,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> MailRoute {
+        MailRoute::Unknown
+    }
+
+    /// Reply to the recipients whose `recipient_route` is `Missing` or
+    /// `NullMx`
+    #[allow(unused_variables)]
+    fn no_route_reply(
+        &self,
+        route: MailRoute,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        match route {
+            MailRoute::NullMx => self.reply("null_mx", reply::null_mx),
+            _ => self.reply("no_mail_route", reply::no_mail_route),
+        }
+    }
+
+    /// Whether to canonicalize the recipients accepted by `filter_to`, or as
+    /// the postmaster, before storing them in `meta.to`. Their domain is then
+    /// lowercased, while their localpart is kept as-is, as it is
+    /// case-sensitive per RFC 5321. Source routes are never stored anyway.
+    #[allow(unused_variables)]
+    fn canonicalize_recipients(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        false
+    }
+
+    /// If this returns true, which is the default, `RCPT` commands for the
+    /// postmaster, as recognized by `is_postmaster`, are accepted without
+    /// calling `filter_to`: RFC 5321 section 4.5.1 requires accepting them.
+    #[allow(unused_variables)]
+    fn always_accept_postmaster(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        true
+    }
+
+    /// Returns whether `email` is the postmaster of this server, that is
+    /// `<postmaster>` or postmaster at the domain returned by `hostname`. The
+    /// local part is case-insensitive.
+    fn is_postmaster(
+        &self,
+        email: &Email,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        email
+            .localpart
+            .unquote()
+            .as_str()
+            .eq_ignore_ascii_case("postmaster")
+            && match email.hostname {
+                None => true,
+                Some(ref h) => h.raw().eq_ignore_ascii_case(self.hostname(conn_meta)),
+            }
+    }
+
+    /// Number of recipients permanently rejected by `filter_to`, or for
+    /// lacking a mail route, in a single mail transaction after which all the
+    /// further `RCPT` of this transaction are refused with
+    /// `too_many_rejected_rcpts`, without calling `filter_to`. This slows
+    /// down address harvesting by probing recipients. Deferred recipients do
+    /// not count. If this returns 0, which is the default, there is no limit.
+    #[allow(unused_variables)]
+    fn max_rejected_rcpts(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> usize {
+        0
+    }
+
+    /// Called upon DATA, before sending the `354` reply, which makes it the
+    /// last chance to reject the mail before receiving its contents.
+    ///
+    /// If this returns `Reject`, the reply is sent instead of the `354` and
+    /// the transaction is kept as-is, so that the client can retry the DATA
+    /// command later on or RSET it.
     #[allow(unused_variables)]
     async fn filter_data(
         &self,
@@ -139,11 +428,107 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Accept {
-            reply: reply::okay_data().convert(),
+            reply: self.reply("okay_data", reply::okay_data),
+            res: (),
+        }
+    }
+
+    /// Whether to refuse MAIL with `mail_before_hello`, as well as RCPT and
+    /// DATA with the `hello_required` reply, until the client sent HELO, EHLO
+    /// or LHLO. This gates the whole transaction.
+    #[allow(unused_variables)]
+    fn require_helo_before_mail(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        true
+    }
+
+    /// Whether to refuse DATA with `data_without_tls` until the connection
+    /// is encrypted, so that mail contents are never sent in plaintext
+    #[allow(unused_variables)]
+    fn require_tls_for_data(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        false
+    }
+
+    /// Whether to also accept a bare LF end-of-data marker (b"\n.\n"), as
+    /// sent by some non-compliant clients, instead of only b"\r\n.\r\n".
+    ///
+    /// This is off by default, as it allows SMTP smuggling when the next hops
+    /// do not agree on where the mail ends. When accepted, such markers are
+    /// normalized into b"\r\n.\r\n" before reaching `handle_mail`, and
+    /// `log_bare_lf_data_end` is called.
+    #[allow(unused_variables)]
+    fn accept_bare_lf_data_end(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        false
+    }
+
+    /// Maximum size of the header block buffered for `filter_headers`, which
+    /// is capped to [`RDBUF_SIZE`](RDBUF_SIZE). If this returns 0, which is the
+    /// default, `filter_headers` is not called.
+    #[allow(unused_variables)]
+    fn max_headers_size(&self, conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>) -> usize {
+        0
+    }
+
+    /// Called after the `354` reply to DATA, with the unescaped header block
+    /// of the mail, including the CRLF of its last line but not the empty line
+    /// that follows. If the header block is longer than `max_headers_size`,
+    /// only its beginning is given.
+    ///
+    /// If this returns `Reject`, the mail contents are read and dropped, and
+    /// the reply is sent instead of calling `handle_mail`. If it returns
+    /// `Accept`, its reply is ignored and `handle_mail` is called, its reader
+    /// returning the whole mail, including the already buffered header block.
+    #[allow(unused_variables)]
+    async fn filter_headers(
+        &self,
+        headers: &[u8],
+        meta: &mut MailMetadata<Self::MailUserMeta>,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Decision<()> {
+        Decision::Accept {
+            reply: self.reply("okay_data", reply::okay_data),
             res: (),
         }
     }
 
+    /// Called after `handle_mail` for each mail whose end-of-data marker used
+    /// bare LF line endings and was normalized
+    #[allow(unused_variables)]
+    async fn log_bare_lf_data_end(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) {
+    }
+
+    /// Called when `tls_accept` failed after STARTTLS was accepted, right
+    /// before closing the connection: the client may already have sent data
+    /// meant for the TLS session, so nothing it sends can be trusted anymore
+    #[allow(unused_variables)]
+    async fn log_tls_handshake_failure(
+        &self,
+        error: &io::Error,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) {
+    }
+
+    /// Called when the client closed the connection before the end of the
+    /// mail contents, right before `interact` returns a `ConnectionAborted`
+    /// error. This usually is a client giving up rather than a server failure.
+    #[allow(unused_variables)]
+    async fn log_interrupted_data(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) {
+    }
+
     /// `handle_mail` is an async function that returns either a single decision
     /// in the case of the SMTP protocol, or an async stream of decisions in the
     /// case of the LMTP protocol.
@@ -184,7 +569,7 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Accept {
-            reply: reply::okay_rset().convert(),
+            reply: self.reply("okay_rset", reply::okay_rset),
             res: (),
         }
     }
@@ -196,12 +581,12 @@ pub trait Config: Send + Sync {
     ) -> Decision<()> {
         if self.can_do_tls(conn_meta) {
             Decision::Accept {
-                reply: reply::okay_starttls().convert(),
+                reply: self.reply("okay_starttls", reply::okay_starttls),
                 res: (),
             }
         } else {
             Decision::Reject {
-                reply: reply::command_not_supported().convert(),
+                reply: self.reply("command_not_supported", reply::command_not_supported),
             }
         }
     }
@@ -213,7 +598,7 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Reject {
-            reply: reply::command_unimplemented().convert(),
+            reply: self.reply("command_unimplemented", reply::command_unimplemented),
         }
     }
 
@@ -224,7 +609,7 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Accept {
-            reply: reply::ignore_vrfy().convert(),
+            reply: self.reply("ignore_vrfy", reply::ignore_vrfy),
             res: (),
         }
     }
@@ -236,7 +621,7 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Accept {
-            reply: reply::ignore_help().convert(),
+            reply: self.reply("ignore_help", reply::ignore_help),
             res: (),
         }
     }
@@ -248,7 +633,7 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Accept {
-            reply: reply::okay_noop().convert(),
+            reply: self.reply("okay_noop", reply::okay_noop),
             res: (),
         }
     }
@@ -259,17 +644,48 @@ pub trait Config: Send + Sync {
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Decision<()> {
         Decision::Kill {
-            reply: Some(reply::okay_quit().convert()),
+            reply: Some(self.reply("okay_quit", reply::okay_quit)),
             res: Ok(()),
         }
     }
 
+    /// Called on the lines that are not a known command, without their
+    /// trailing CRLF, eg. to implement custom verbs. Returning `None` answers
+    /// them with `command_unrecognized`, like lines too long to be a command.
+    #[allow(unused_variables)]
+    async fn unknown_command(
+        &self,
+        line: &[u8],
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Option<Decision<()>> {
+        None
+    }
+
+    // The methods below return the replies to the commands that the server
+    // itself refuses, all of which can thus be customized
+
     #[allow(unused_variables)]
     fn already_did_hello(
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("bad_sequence", reply::bad_sequence)
+    }
+
+    #[allow(unused_variables)]
+    fn hello_softfail(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("hello_softfail", reply::hello_softfail)
+    }
+
+    #[allow(unused_variables)]
+    fn hello_rejected(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("hello_rejected", reply::hello_rejected)
     }
 
     #[allow(unused_variables)]
@@ -277,7 +693,7 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("hello_required", reply::hello_required)
     }
 
     #[allow(unused_variables)]
@@ -285,7 +701,7 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("bad_sequence", reply::bad_sequence)
     }
 
     #[allow(unused_variables)]
@@ -293,7 +709,15 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("bad_sequence", reply::bad_sequence)
+    }
+
+    #[allow(unused_variables)]
+    fn too_many_rejected_rcpts(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("too_many_rejected_rcpts", reply::too_many_rejected_rcpts)
     }
 
     #[allow(unused_variables)]
@@ -301,7 +725,7 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("bad_sequence", reply::bad_sequence)
     }
 
     #[allow(unused_variables)]
@@ -309,7 +733,15 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::bad_sequence().convert()
+        self.reply("bad_sequence", reply::bad_sequence)
+    }
+
+    #[allow(unused_variables)]
+    fn data_without_tls(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("starttls_required", reply::starttls_required)
     }
 
     #[allow(unused_variables)]
@@ -317,7 +749,7 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::command_not_supported().convert()
+        self.reply("command_not_supported", reply::command_not_supported)
     }
 
     #[allow(unused_variables)]
@@ -325,7 +757,23 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::command_unrecognized().convert()
+        self.reply("command_unrecognized", reply::command_unrecognized)
+    }
+
+    #[allow(unused_variables)]
+    fn xclient_forbidden(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("xclient_forbidden", reply::xclient_forbidden)
+    }
+
+    #[allow(unused_variables)]
+    fn xclient_invalid(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("xclient_invalid", reply::xclient_invalid)
     }
 
     #[allow(unused_variables)]
@@ -333,12 +781,63 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::pipeline_forbidden_after_starttls().convert()
+        self.reply(
+            "pipeline_forbidden_after_starttls",
+            reply::pipeline_forbidden_after_starttls,
+        )
+    }
+
+    /// Called when the client sent a command before getting the reply to the
+    /// previous one while PIPELINING was not advertised, eg. to log it. The
+    /// commands are processed in order all the same, unless this returns
+    /// `true`, in which case the connection is closed with
+    /// `unadvertised_pipelining_reply`.
+    #[allow(unused_variables)]
+    fn disconnect_on_unadvertised_pipelining(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> bool {
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn unadvertised_pipelining_reply(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("unadvertised_pipelining", reply::unadvertised_pipelining)
+    }
+
+    /// Maximum number of commands that a client can send within a single mail
+    /// transaction, whether it pipelines them or waits for each reply. The
+    /// count restarts at each `MAIL FROM`, and also covers the commands sent
+    /// outside of any transaction. Past that, the connection is closed with
+    /// `too_many_pipelined_commands`, so that a client flooding commands,
+    /// eg. NOOP or RSET, cannot keep the server busy. The default is well
+    /// above realistic recipient limits, so that no legitimate transaction is
+    /// cut off. Setting this to 0 disables the limit.
+    #[allow(unused_variables)]
+    fn max_pipelined_commands(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> usize {
+        10_000
+    }
+
+    #[allow(unused_variables)]
+    fn too_many_pipelined_commands(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply(
+            "too_many_pipelined_commands",
+            reply::too_many_pipelined_commands,
+        )
     }
 
     #[allow(unused_variables)]
     fn line_too_long(&self, conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>) -> Reply {
-        reply::line_too_long().convert()
+        self.reply("line_too_long", reply::line_too_long)
     }
 
     #[allow(unused_variables)]
@@ -346,7 +845,35 @@ pub trait Config: Send + Sync {
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
     ) -> Reply {
-        reply::handle_mail_did_not_call_complete().convert()
+        self.reply(
+            "handle_mail_did_not_call_complete",
+            reply::handle_mail_did_not_call_complete,
+        )
+    }
+
+    #[allow(unused_variables)]
+    fn session_too_long(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        self.reply("session_too_long", reply::session_too_long)
+    }
+
+    /// Translations of the built-in replies, applied by `reply` so that they
+    /// can be localized without overriding each of the methods above.
+    fn reply_catalog(&self) -> Option<&reply::ReplyCatalog> {
+        None
+    }
+
+    /// Builds the built-in reply `id` with `builtin`, translated with
+    /// `reply_catalog`. The default replies of this trait are all built this
+    /// way, while replies built otherwise are sent as is.
+    fn reply(&self, id: &str, builtin: reply::BuiltinReply) -> Reply {
+        let reply = builtin().convert();
+        match self.reply_catalog() {
+            Some(catalog) => catalog.translate(id, reply),
+            None => reply,
+        }
     }
 
     fn reply_write_timeout(&self) -> chrono::Duration {
@@ -356,6 +883,20 @@ pub trait Config: Send + Sync {
     fn command_read_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(5)
     }
+
+    /// Maximum time to wait for each read of the mail contents, for use by
+    /// `handle_mail`, including when it drops the contents with
+    /// [`drain_data`](drain_data)
+    fn data_read_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(3)
+    }
+
+    /// Maximum duration of a whole session, from the connection to its
+    /// closing. When it is exceeded, the `session_too_long` reply is sent and
+    /// the connection is closed, even in the middle of `handle_mail`.
+    fn max_session_duration(&self) -> chrono::Duration {
+        chrono::Duration::hours(1)
+    }
 }
 
 async fn advance_until_crlf<R>(
@@ -384,32 +925,225 @@ where
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
-pub enum IsAlreadyTls {
-    Yes,
-    No,
+/// Reads into `buf` until the line starting at `unhandled` is complete, and
+/// returns its range, CRLF included. Returns `None` without reading the rest
+/// of the line if it does not fit in `buf`.
+async fn read_full_line<R>(
+    r: &mut R,
+    buf: &mut [u8],
+    unhandled: &mut Range<usize>,
+) -> io::Result<Option<Range<usize>>>
+where
+    R: Unpin + AsyncRead,
+{
+    let mut state = NextCrLfState::Start;
+    let mut checked = unhandled.start;
+    loop {
+        if let Some(p) = next_crlf(&buf[checked..unhandled.end], &mut state) {
+            return Ok(Some(unhandled.start..checked + p + 1));
+        }
+        if unhandled.end == buf.len() {
+            if unhandled.start == 0 {
+                return Ok(None);
+            }
+            buf.copy_within(unhandled.clone(), 0);
+            *unhandled = 0..unhandled.len();
+        }
+        checked = unhandled.end;
+        let read = r.read(&mut buf[unhandled.end..]).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection shutdown with partial command",
+            ));
+        }
+        unhandled.end += read;
+    }
 }
 
-pub async fn interact<IO, Cfg>(
-    io: IO,
-    is_already_tls: IsAlreadyTls,
-    metadata: Cfg::ConnectionUserMeta,
-    cfg: Arc<Cfg>,
+/// Reads and drops the rest of the mail contents, eg. after `handle_mail`
+/// failed to store them, so that the session can go on.
+///
+/// Each read may take at most `timeout`, after which this returns a `TimedOut`
+/// error. The reader is not completed, so the caller still has to check
+/// `is_finished` and call `complete`.
+pub async fn drain_data<R>(
+    reader: &mut EscapedDataReader<'_, R>,
+    timeout: chrono::Duration,
 ) -> io::Result<()>
 where
-    IO: 'static + Send + AsyncRead + AsyncWrite,
-    Cfg: Config,
+    R: Unpin + AsyncRead,
 {
-    let (io_r, io_w) = io.split();
-    let mut io = duplexify::Duplex::new(
-        Box::pin(io_r) as Pin<Box<dyn Send + AsyncRead>>,
-        Box::pin(io_w) as Pin<Box<dyn Send + AsyncWrite>>,
-    );
-
-    let rdbuf = &mut [0; RDBUF_SIZE];
-    let mut unhandled = 0..0;
-    // TODO: should have a wrslices: Vec<IoSlice> here, so that we don't allocate
-    // for each write, but it looks like the API for reusing a Vec's backing
+    let timeout = timeout
+        .to_std()
+        .unwrap_or(std::time::Duration::from_secs(0));
+    let mut buf = [0; 1024];
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .or(async {
+                smol::Timer::after(timeout).await;
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out reading the mail contents",
+                ))
+            })
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn xtext_decode(s: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'+' {
+            let hex = [bytes.next()?, bytes.next()?];
+            res.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            res.push(b);
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+/// Applies the XCLIENT attributes to `info`, returning `None` if one of them
+/// is invalid. Unsupported attributes are ignored.
+fn apply_xclient(attrs: &Parameters<&str>, mut info: XclientInfo) -> Option<XclientInfo> {
+    for (ParameterName::Other(name), value) in &attrs.0 {
+        let value = xtext_decode(value.as_ref()?.as_str())?;
+        let unavailable = value == "[UNAVAILABLE]" || value == "[TEMPUNAVAIL]";
+        if name.eq_ignore_ascii_case("ADDR") {
+            info.addr = match value.get(..5) {
+                _ if unavailable => None,
+                Some(p) if p.eq_ignore_ascii_case("IPV6:") => {
+                    Some(IpAddr::V6(value[5..].parse::<Ipv6Addr>().ok()?))
+                }
+                _ => Some(IpAddr::V4(value.parse().ok()?)),
+            };
+        } else if name.eq_ignore_ascii_case("HELO") {
+            info.helo = if unavailable {
+                None
+            } else {
+                match Hostname::<&str>::parse(value.as_bytes()) {
+                    Ok((&[], hostname)) => Some(hostname.into_owned()),
+                    _ => return None,
+                }
+            };
+        } else if name.eq_ignore_ascii_case("LOGIN") {
+            info.login = Some(value).filter(|_| !unavailable);
+        }
+    }
+    Some(info)
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum IsAlreadyTls {
+    Yes,
+    No,
+}
+
+/// Number of connections currently open from each client IP, to be shared by
+/// all the connections of a server for enforcing
+/// `Config::max_connections_per_ip`
+#[derive(Default)]
+pub struct OpenConnections(Mutex<HashMap<IpAddr, usize>>);
+
+impl OpenConnections {
+    pub fn new() -> OpenConnections {
+        OpenConnections::default()
+    }
+
+    /// Counts a new connection from `ip`, unless `max` of them are already
+    /// open. It stays counted until the returned guard is dropped.
+    fn open(&self, ip: IpAddr, max: usize) -> Option<OpenConnection<'_>> {
+        let mut conns = self.0.lock().unwrap();
+        let count = conns.entry(ip).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(OpenConnection { conns: self, ip })
+    }
+}
+
+struct OpenConnection<'a> {
+    conns: &'a OpenConnections,
+    ip: IpAddr,
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        let mut conns = self.conns.0.lock().unwrap();
+        if let Some(count) = conns.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Returns the length of the header block at the start of `data`, which is
+/// dot-escaped, if it is complete
+fn header_block_len(data: &[u8]) -> Option<usize> {
+    if data.starts_with(b"\r\n") || data.starts_with(b".\r\n") {
+        return Some(0);
+    }
+    // The header block ends with an empty line, or with the end of the data
+    data.windows(4)
+        .position(|w| w == b"\r\n\r\n" || w == b"\r\n.\r")
+        .map(|i| i + 2)
+}
+
+/// Returns `email` with its domain lowercased if `cfg` asks for canonical
+/// recipients, and as-is otherwise
+fn canonical_recipient<Cfg: Config>(
+    cfg: &Cfg,
+    email: Email,
+    conn_meta: &ConnectionMetadata<Cfg::ConnectionUserMeta>,
+) -> Email {
+    if !cfg.canonicalize_recipients(conn_meta) {
+        return email;
+    }
+    Email {
+        localpart: email.localpart,
+        hostname: email.hostname.map(|h| h.to_lowercase()),
+    }
+}
+
+fn unescape_headers(headers: &[u8]) -> Vec<u8> {
+    let mut res = headers.to_vec();
+    let unescaped = DataUnescaper::new(true).unescape(&mut res);
+    // What could not be handled yet is at most the final CRLF
+    res.copy_within(unescaped.unhandled_idx.., unescaped.written);
+    res.truncate(unescaped.written + headers.len() - unescaped.unhandled_idx);
+    res
+}
+
+pub async fn interact<IO, Cfg>(
+    io: IO,
+    peer_addr: Option<IpAddr>,
+    is_already_tls: IsAlreadyTls,
+    metadata: Cfg::ConnectionUserMeta,
+    cfg: Arc<Cfg>,
+) -> io::Result<()>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    let (io_r, io_w) = io.split();
+    let mut io = duplexify::Duplex::new(
+        Box::pin(io_r) as Pin<Box<dyn Send + AsyncRead>>,
+        Box::pin(io_w) as Pin<Box<dyn Send + AsyncWrite>>,
+    );
+
+    let rdbuf = &mut [0; RDBUF_SIZE];
+    let mut unhandled = 0..0;
+    // TODO: should have a wrslices: Vec<IoSlice> here, so that we don't allocate
+    // for each write, but it looks like the API for reusing a Vec's backing
     // allocation isn't ready yet and IoSlice's lifetime is going to make this
     // impossible. Maybe this would require writing a crate that allows such vec
     // storage recycling, as there doesn't appear to be any on crates.io. Having
@@ -417,10 +1151,20 @@ where
     // .collect() (present in `send_reply()`)
     let mut conn_meta = ConnectionMetadata {
         user: metadata,
+        peer_addr,
         hello: None,
         is_encrypted: is_already_tls == IsAlreadyTls::Yes,
+        xclient: None,
+        tls_peer: None,
     };
     let mut mail_meta = None;
+    // Number of recipients rejected in the current mail transaction
+    let mut rejected_rcpts = 0;
+    // Whether the latest reply to EHLO or LHLO advertised PIPELINING
+    let mut pipelining_advertised = false;
+    // Number of commands handled since the latest MAIL FROM, or since the
+    // beginning of the connection
+    let mut pipelined_cmds = 0;
 
     let mut waiting_for_command_since = Utc::now();
 
@@ -442,12 +1186,41 @@ where
         };
     }
 
+    // Reads and drops the rest of the mail contents, bailing out if the
+    // connection is closed before the end-of-data marker
+    macro_rules! skip_data {
+        ($reader:expr, $buf:expr) => {
+            let res = loop {
+                match read_for_command!($reader.read($buf)).await {
+                    Ok(0) if $reader.is_finished() => break Ok(()),
+                    Ok(0) => {
+                        break Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "connection shutdown during email reception",
+                        ));
+                    }
+                    Ok(_) => (),
+                    Err(e) => break Err(e),
+                }
+            };
+            if let Err(e) = res {
+                // The reader also fails with ConnectionAborted on a stream cut
+                // mid-connection
+                if e.kind() == io::ErrorKind::ConnectionAborted {
+                    cfg.log_interrupted_data(&mut conn_meta).await;
+                }
+                return Err(e);
+            }
+        };
+    }
+
     macro_rules! send_reply {
         ($writer:expr, $reply:expr) => {
             smol::future::or(
                 async {
+                    let reply: Reply = $reply;
                     $writer
-                        .write_all_vectored(&mut $reply.as_io_slices().collect::<Vec<_>>())
+                        .write_all_vectored(&mut reply.as_io_slices().collect::<Vec<_>>())
                         .await?;
                     waiting_for_command_since = Utc::now();
                     Ok(())
@@ -507,269 +1280,554 @@ where
         };
     }
 
-    send_reply!(io, cfg.welcome_banner_reply(&mut conn_meta)).await?;
+    // The whole session is raced against its maximum duration, so that a client
+    // cannot hold a connection forever by trickling valid commands
+    let session = async {
+        if cfg.is_shutting_down() {
+            send_reply!(io, cfg.shutting_down_reply(&mut conn_meta)).await?;
+            return Ok(());
+        }
+        let max_connections = cfg.max_connections_per_ip();
+        let _open_connection = match (cfg.open_connections(), cfg.client_ip(&conn_meta)) {
+            (Some(conns), Some(ip)) if max_connections > 0 => {
+                match conns.open(ip, max_connections) {
+                    Some(conn) => Some(conn),
+                    None => {
+                        send_reply!(io, cfg.too_many_connections_reply(&mut conn_meta)).await?;
+                        return Ok(());
+                    }
+                }
+            }
+            _ => None,
+        };
+        send_reply!(io, cfg.welcome_banner_reply(&mut conn_meta)).await?;
 
-    loop {
-        if unhandled.is_empty() {
-            unhandled = 0..read_for_command!(io.read(rdbuf)).await?;
+        loop {
             if unhandled.is_empty() {
-                return Ok(());
+                unhandled = 0..read_for_command!(io.read(rdbuf)).await?;
+                if unhandled.is_empty() {
+                    return Ok(());
+                }
             }
-        }
 
-        let cmd = match Command::<&str>::parse(&rdbuf[unhandled.clone()]) {
-            Err(nom::Err::Incomplete(n)) => {
-                // Don't have enough data to handle command, let's fetch more
-                if unhandled.start != 0 {
-                    // Do we have to copy the data to the beginning of the buffer?
-                    let missing = match n {
-                        nom::Needed::Unknown => MINIMUM_FREE_BUFSPACE,
-                        nom::Needed::Size(s) => cmp::max(MINIMUM_FREE_BUFSPACE, s.into()),
+            let cmd = match Command::<&str>::parse(&rdbuf[unhandled.clone()]) {
+                Err(nom::Err::Incomplete(n)) => {
+                    // Don't have enough data to handle command, let's fetch more
+                    if unhandled.start != 0 {
+                        // Do we have to copy the data to the beginning of the buffer?
+                        let missing = match n {
+                            nom::Needed::Unknown => MINIMUM_FREE_BUFSPACE,
+                            nom::Needed::Size(s) => cmp::max(MINIMUM_FREE_BUFSPACE, s.into()),
+                        };
+                        if missing > rdbuf.len() - unhandled.end {
+                            rdbuf.copy_within(unhandled.clone(), 0);
+                            unhandled.end = unhandled.len();
+                            unhandled.start = 0;
+                        }
+                    }
+                    if unhandled.end == rdbuf.len() {
+                        // If we reach here, it means that unhandled is already
+                        // basically the full buffer. Which means that we have to
+                        // error out that the line is too long.
+                        read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled))
+                            .await?;
+                        send_reply!(io, cfg.line_too_long(&mut conn_meta)).await?;
+                    } else {
+                        let read = read_for_command!(io.read(&mut rdbuf[unhandled.end..])).await?;
+                        if read == 0 {
+                            return Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "connection shutdown with partial command",
+                            ));
+                        }
+                        unhandled.end += read;
+                    }
+                    None
+                }
+                Err(_) => {
+                    // Syntax error, or a command that the configuration may know about
+                    let line =
+                        read_for_command!(read_full_line(&mut io, rdbuf, &mut unhandled)).await?;
+                    let decision = match line {
+                        Some(line) => {
+                            let text = &rdbuf[line.start..line.end - 2];
+                            let decision = cfg.unknown_command(text, &mut conn_meta).await;
+                            unhandled.start = line.end;
+                            decision
+                        }
+                        None => {
+                            read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled))
+                                .await?;
+                            None
+                        }
                     };
-                    if missing > rdbuf.len() - unhandled.end {
-                        rdbuf.copy_within(unhandled.clone(), 0);
-                        unhandled.end = unhandled.len();
-                        unhandled.start = 0;
+                    match decision {
+                        Some(decision) => simple_handler!(decision),
+                        None => {
+                            send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
+                        }
                     }
+                    None
                 }
-                if unhandled.end == rdbuf.len() {
-                    // If we reach here, it means that unhandled is already
-                    // basically the full buffer. Which means that we have to
-                    // error out that the line is too long.
-                    read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled)).await?;
-                    send_reply!(io, cfg.line_too_long(&mut conn_meta)).await?;
-                } else {
-                    let read = read_for_command!(io.read(&mut rdbuf[unhandled.end..])).await?;
-                    if read == 0 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "connection shutdown with partial command",
-                        ));
+                Ok((rem, cmd)) => {
+                    // Got a command
+                    unhandled.start = unhandled.end - rem.len();
+                    if !rem.is_empty()
+                        && !pipelining_advertised
+                        && cfg.disconnect_on_unadvertised_pipelining(&mut conn_meta)
+                    {
+                        send_reply!(io, cfg.unadvertised_pipelining_reply(&mut conn_meta)).await?;
+                        return Ok(());
+                    }
+                    if let Command::Mail { .. } = cmd {
+                        pipelined_cmds = 0;
+                    }
+                    pipelined_cmds += 1;
+                    let max_pipelined_cmds = cfg.max_pipelined_commands(&conn_meta);
+                    if max_pipelined_cmds > 0 && pipelined_cmds > max_pipelined_cmds {
+                        send_reply!(io, cfg.too_many_pipelined_commands(&mut conn_meta)).await?;
+                        return Ok(());
                     }
-                    unhandled.end += read;
+                    Some(cmd)
                 }
-                None
-            }
-            Err(_) => {
-                // Syntax error
-                read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled)).await?;
-                send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
-                None
-            }
-            Ok((rem, cmd)) => {
-                // Got a command
-                unhandled.start = unhandled.end - rem.len();
-                Some(cmd)
-            }
-        };
-
-        // This match is really just to avoid too much rightwards drift, otherwise it
-        // could have been included directly in the Ok((rem, cmd)) branch above.
-        // Unfortunately we can't make it a function, because `cmd` borrows `rdbuf`, and
-        // we need to use `rdbuf` in the `Command::Data` branch here
-        match cmd {
-            None => (),
-
-            Some(cmd @ (Command::Ehlo { .. } | Command::Helo { .. } | Command::Lhlo { .. })) => {
-                let (cmd_proto, is_extended, hostname) = match cmd {
-                    Command::Ehlo { hostname } => (ProtocolName::Smtp, true, hostname),
-                    Command::Helo { hostname } => (ProtocolName::Smtp, false, hostname),
-                    Command::Lhlo { hostname } => (ProtocolName::Lmtp, true, hostname),
-                    _ => unreachable!(),
-                };
-                if cmd_proto != <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
-                    send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
-                } else {
-                    match conn_meta.hello {
-                        Some(_) => {
-                            send_reply!(io, cfg.already_did_hello(&mut conn_meta)).await?;
+            };
+
+            // This match is really just to avoid too much rightwards drift, otherwise it
+            // could have been included directly in the Ok((rem, cmd)) branch above.
+            // Unfortunately we can't make it a function, because `cmd` borrows `rdbuf`, and
+            // we need to use `rdbuf` in the `Command::Data` branch here
+            match cmd {
+                None => (),
+
+                Some(
+                    cmd @ (Command::Ehlo { .. } | Command::Helo { .. } | Command::Lhlo { .. }),
+                ) => {
+                    let (cmd_proto, is_extended, hostname) = match cmd {
+                        Command::Ehlo { hostname } => (ProtocolName::Smtp, true, hostname),
+                        Command::Helo { hostname } => (ProtocolName::Smtp, false, hostname),
+                        Command::Lhlo { hostname } => (ProtocolName::Lmtp, true, hostname),
+                        _ => unreachable!(),
+                    };
+                    if cmd_proto != <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
+                        send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
+                    } else {
+                        match conn_meta.hello {
+                            Some(_) => {
+                                send_reply!(io, cfg.already_did_hello(&mut conn_meta)).await?;
+                            }
+                            None => dispatch_decision! {
+                                {
+                                    // The original client's hostname, if relayed by a front-end
+                                    let hostname = match conn_meta.xclient.as_ref().and_then(|x| x.helo.clone()) {
+                                        Some(h) => h,
+                                        None => hostname.into_owned(),
+                                    };
+                                    let verification = match cfg.client_ip(&conn_meta) {
+                                        Some(ip) => {
+                                            cfg.verify_hello(ip, &hostname, &mut conn_meta).await
+                                        }
+                                        None => HelloVerification::Pass,
+                                    };
+                                    match verification {
+                                        HelloVerification::Pass => {
+                                            cfg.filter_hello(is_extended, hostname, &mut conn_meta)
+                                                .await
+                                        }
+                                        HelloVerification::SoftFail => Decision::Reject {
+                                            reply: cfg.hello_softfail(&mut conn_meta),
+                                        },
+                                        HelloVerification::Fail => Decision::Reject {
+                                            reply: cfg.hello_rejected(&mut conn_meta),
+                                        },
+                                    }
+                                },
+                                Accept(mut reply, res) => {
+                                    if !cfg.tls_configured() {
+                                        reply.text.retain(|l| {
+                                            !l.as_str().eq_ignore_ascii_case("STARTTLS")
+                                        });
+                                    }
+                                    pipelining_advertised = reply.text.iter().any(|l| {
+                                        l.as_str().eq_ignore_ascii_case("PIPELINING")
+                                    });
+                                    conn_meta.hello = Some(res);
+                                    send_reply!(io, reply).await?;
+                                }
+                            },
                         }
-                        None => dispatch_decision! {
-                            cfg.filter_hello(is_extended, hostname.into_owned(), &mut conn_meta)
-                                .await,
-                            Accept(reply, res) => {
-                                conn_meta.hello = Some(res);
-                                send_reply!(io, reply).await?;
+                    }
+                }
+
+                Some(Command::Mail {
+                    path: _path,
+                    email,
+                    params: _params,
+                }) => {
+                    if conn_meta.hello.is_none() && cfg.require_helo_before_mail(&conn_meta) {
+                        send_reply!(io, cfg.mail_before_hello(&mut conn_meta)).await?;
+                    } else {
+                        match mail_meta {
+                            Some(_) => {
+                                // Both postfix and OpenSMTPD just return an error and ignore further
+                                // MAIL FROM when there is already a MAIL FROM running
+                                send_reply!(io, cfg.already_in_mail(&mut conn_meta)).await?;
                             }
-                        },
+                            None => {
+                                rejected_rcpts = 0;
+                                let mut mail_metadata = MailMetadata {
+                                    user: cfg.new_mail(&mut conn_meta).await,
+                                    from: None,
+                                    to: Vec::with_capacity(4),
+                                    spf: None,
+                                };
+                                dispatch_decision! {
+                                    cfg.filter_from(
+                                        email.as_ref().map(|e| e.clone().into_owned()),
+                                        &mut mail_metadata,
+                                        &mut conn_meta,
+                                    )
+                                    .await,
+                                    Accept(reply, res) => {
+                                        mail_metadata.from = res;
+                                        let ip = cfg.client_ip(&conn_meta);
+                                        let domain = spf::sender_domain(mail_metadata.from.as_ref());
+                                        let spf_result = match (ip, domain) {
+                                            (Some(ip), Some(domain)) => {
+                                                Some(cfg.spf_check(ip, domain, &mut conn_meta).await)
+                                            }
+                                            _ => None,
+                                        };
+                                        mail_metadata.spf = spf_result;
+                                        match spf_result {
+                                            None => {
+                                                mail_meta = Some(mail_metadata);
+                                                send_reply!(io, reply).await?;
+                                            }
+                                            Some(spf_result) => dispatch_decision! {
+                                                cfg.filter_spf(
+                                                    spf_result,
+                                                    &mut mail_metadata,
+                                                    &mut conn_meta,
+                                                )
+                                                .await,
+                                                Accept(_, ()) => {
+                                                    mail_meta = Some(mail_metadata);
+                                                    send_reply!(io, reply).await?;
+                                                }
+                                            },
+                                        }
+                                    }
+                                }
+                            }
+                        }
                     }
                 }
-            }
 
-            Some(Command::Mail {
-                path: _path,
-                email,
-                params: _params,
-            }) => {
-                if conn_meta.hello.is_none() {
-                    send_reply!(io, cfg.mail_before_hello(&mut conn_meta)).await?;
-                } else {
+                Some(Command::Rcpt {
+                    path: _path,
+                    email,
+                    params: _params,
+                }) => {
+                    let email = email.into_owned();
+                    let max = cfg.max_rejected_rcpts(&conn_meta);
                     match mail_meta {
-                        Some(_) => {
-                            // Both postfix and OpenSMTPD just return an error and ignore further
-                            // MAIL FROM when there is already a MAIL FROM running
-                            send_reply!(io, cfg.already_in_mail(&mut conn_meta)).await?;
+                        _ if conn_meta.hello.is_none()
+                            && cfg.require_helo_before_mail(&conn_meta) =>
+                        {
+                            send_reply!(io, cfg.reply("hello_required", reply::hello_required))
+                                .await?;
                         }
                         None => {
-                            let mut mail_metadata = MailMetadata {
-                                user: cfg.new_mail(&mut conn_meta).await,
-                                from: None,
-                                to: Vec::with_capacity(4),
-                            };
-                            dispatch_decision! {
-                                cfg.filter_from(
-                                    email.as_ref().map(|e| e.clone().into_owned()),
-                                    &mut mail_metadata,
-                                    &mut conn_meta,
-                                )
-                                .await,
-                                Accept(reply, res) => {
-                                    mail_metadata.from = res;
-                                    mail_meta = Some(mail_metadata);
-                                    send_reply!(io, reply).await?;
+                            send_reply!(io, cfg.rcpt_before_mail(&mut conn_meta)).await?;
+                        }
+                        Some(ref mut mail_meta_unw)
+                            if cfg.always_accept_postmaster(&conn_meta)
+                                && cfg.is_postmaster(&email, &conn_meta) =>
+                        {
+                            let email = canonical_recipient(&*cfg, email, &conn_meta);
+                            mail_meta_unw.to.push(email);
+                            send_reply!(io, cfg.reply("okay_to", reply::okay_to)).await?;
+                        }
+                        Some(_) if max > 0 && rejected_rcpts >= max => {
+                            send_reply!(io, cfg.too_many_rejected_rcpts(&mut conn_meta)).await?;
+                        }
+                        Some(ref mut mail_meta_unw) => dispatch_decision! {
+                            cfg.filter_to(email, mail_meta_unw, &mut conn_meta).await,
+                            Reject(reply) => {
+                                if reply.code.kind() == ReplyCodeKind::PermanentNegative {
+                                    rejected_rcpts += 1;
                                 }
+                                send_reply!(io, reply).await?;
                             }
-                        }
+                            Accept(reply, res) => {
+                                let route = match route::domain(&res) {
+                                    Some(domain)
+                                        if cfg.verify_recipient_routability(&conn_meta) =>
+                                    {
+                                        cfg.recipient_route(domain, &mut conn_meta).await
+                                    }
+                                    _ => MailRoute::Unknown,
+                                };
+                                match route {
+                                    MailRoute::Missing | MailRoute::NullMx => {
+                                        rejected_rcpts += 1;
+                                        let reply = cfg.no_route_reply(route, &mut conn_meta);
+                                        send_reply!(io, reply).await?;
+                                    }
+                                    MailRoute::Exists | MailRoute::Unknown => {
+                                        let res = canonical_recipient(&*cfg, res, &conn_meta);
+                                        mail_meta_unw.to.push(res);
+                                        send_reply!(io, reply).await?;
+                                    }
+                                }
+                            }
+                        },
                     }
                 }
-            }
 
-            Some(Command::Rcpt {
-                path: _path,
-                email,
-                params: _params,
-            }) => match mail_meta {
-                None => {
-                    send_reply!(io, cfg.rcpt_before_mail(&mut conn_meta)).await?;
-                }
-                Some(ref mut mail_meta_unw) => dispatch_decision! {
-                    cfg.filter_to(email.into_owned(), mail_meta_unw, &mut conn_meta).await,
-                    Accept(reply, res) => {
-                        mail_meta_unw.to.push(res);
+                Some(Command::Data) => match mail_meta.take() {
+                    taken
+                        if conn_meta.hello.is_none()
+                            && cfg.require_helo_before_mail(&conn_meta) =>
+                    {
+                        mail_meta = taken;
+                        send_reply!(io, cfg.reply("hello_required", reply::hello_required)).await?;
+                    }
+                    None => {
+                        send_reply!(io, cfg.data_before_mail(&mut conn_meta)).await?;
+                    }
+                    Some(ref mail_meta_unw) if mail_meta_unw.to.is_empty() => {
+                        send_reply!(io, cfg.data_before_rcpt(&mut conn_meta)).await?;
+                    }
+                    Some(mail_meta_unw)
+                        if !conn_meta.is_encrypted && cfg.require_tls_for_data(&conn_meta) =>
+                    {
+                        mail_meta = Some(mail_meta_unw);
+                        send_reply!(io, cfg.data_without_tls(&mut conn_meta)).await?;
+                    }
+                    Some(mut mail_meta_unw) => {
+                        dispatch_decision! {
+                            cfg.filter_data(&mut mail_meta_unw, &mut conn_meta).await,
+                            Reject(reply) => {
+                                mail_meta = Some(mail_meta_unw);
+                                send_reply!(io, reply).await?;
+                            }
+                            Accept(reply, ()) => {
+                                send_reply!(io, reply).await?;
+                                let expected_n_decisions = match <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
+                                    ProtocolName::Smtp => 1,
+                                    ProtocolName::Lmtp => mail_meta_unw.to.len(),
+                                };
+                                let headers_decision = match cmp::min(cfg.max_headers_size(&conn_meta), RDBUF_SIZE) {
+                                    0 => Decision::Accept {
+                                        reply: cfg.reply("okay_data", reply::okay_data),
+                                        res: (),
+                                    },
+                                    max_headers_size => {
+                                        // Buffer the header block in rdbuf, from which the reader will
+                                        // then replay it
+                                        rdbuf.copy_within(unhandled.clone(), 0);
+                                        unhandled = 0..unhandled.len();
+                                        while header_block_len(&rdbuf[unhandled.clone()]).is_none()
+                                            && unhandled.end < max_headers_size
+                                        {
+                                            match read_for_command!(io.read(&mut rdbuf[unhandled.end..max_headers_size])).await? {
+                                                0 => break,
+                                                n => unhandled.end += n,
+                                            }
+                                        }
+                                        let headers_len = header_block_len(&rdbuf[unhandled.clone()])
+                                            .unwrap_or(unhandled.end);
+                                        let headers = unescape_headers(&rdbuf[..cmp::min(headers_len, max_headers_size)]);
+                                        cfg.filter_headers(&headers, &mut mail_meta_unw, &mut conn_meta).await
+                                    }
+                                };
+                                dispatch_decision! {
+                                    headers_decision,
+                                    Reject(reply) => {
+                                        // Drop the contents, and reply once per expected decision
+                                        let mut reader =
+                                            EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io)
+                                                .with_bare_lf_end(cfg.accept_bare_lf_data_end(&conn_meta));
+                                        let ignore_buf = &mut [0u8; 128];
+                                        skip_data!(reader, ignore_buf);
+                                        reader.complete();
+                                        unhandled = reader.get_unhandled().unwrap();
+                                        let ended_with_bare_lf = reader.ended_with_bare_lf();
+                                        for _i in 0..expected_n_decisions {
+                                            send_reply!(io, reply.clone()).await?;
+                                        }
+                                        if ended_with_bare_lf {
+                                            cfg.log_bare_lf_data_end(&mut conn_meta).await;
+                                        }
+                                    }
+                                    Accept(_, ()) => {
+                                        let mut reader =
+                                            EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io)
+                                                .with_bare_lf_end(cfg.accept_bare_lf_data_end(&conn_meta));
+                                        let mut decision_stream = <Cfg::Protocol as Protocol<'_>>::handle_mail_return_type_as_stream(cfg
+                                            .handle_mail(&mut reader, mail_meta_unw, &mut conn_meta).await);
+                                        // This variable is a trick because otherwise rustc thinks the `reader`
+                                        // borrow is still alive across await points and makes `interact: !Send`
+                                        let reader_was_completed = if let Some(u) = reader.get_unhandled() {
+                                            unhandled = u;
+                                            true
+                                        } else {
+                                            false
+                                        };
+                                        let mut ended_with_bare_lf = reader.ended_with_bare_lf();
+                                        if reader_was_completed {
+                                            // Other mail systems (at least
+                                            // postfix, OpenSMTPD and gmail)
+                                            // appear to drop the state on an
+                                            // unsuccessful DATA command (eg. too
+                                            // long, non-RFC5322-compliant, etc.).
+                                            // Couldn't find the RFC reference
+                                            // anywhere, though.
+                                            let mut n_decisions = 0;
+                                            while let Some(decision) = decision_stream.next().await {
+                                                n_decisions += 1;
+                                                if n_decisions > expected_n_decisions {
+                                                    panic!("got more decisions in handle_mail return than the expected {}", expected_n_decisions);
+                                                }
+                                                simple_handler!(decision);
+                                            }
+                                            assert_eq!(n_decisions, expected_n_decisions, "got {} decisions in handle_mail return, expected {}", n_decisions, expected_n_decisions);
+                                            drop(decision_stream);
+                                        } else {
+                                            // handle_mail did not call complete, let's read until the end and
+                                            // then return an error
+                                            // TODO: rustc complains if we don't drop(decision_stream) here, why?
+                                            drop(decision_stream);
+                                            // TODO: 128 is probably too small?
+                                            let ignore_buf = &mut [0u8; 128];
+                                            // TODO: consider whether it would make sense to have a separate
+                                            // timeout here... giving as much time for sending the whole DATA
+                                            // message may be a bit too little? but then it only happens when
+                                            // handle_mail breaks anyway, so...
+                                            skip_data!(reader, ignore_buf);
+                                            reader.complete();
+                                            unhandled = reader.get_unhandled().unwrap();
+                                            ended_with_bare_lf = reader.ended_with_bare_lf();
+                                            for _i in 0..expected_n_decisions {
+                                                send_reply!(io, cfg.handle_mail_did_not_call_complete(&mut conn_meta)).await?;
+                                            }
+                                        };
+                                        if ended_with_bare_lf {
+                                            cfg.log_bare_lf_data_end(&mut conn_meta).await;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+
+                Some(Command::Rset) => dispatch_decision! {
+                    cfg.handle_rset(&mut mail_meta, &mut conn_meta).await,
+                    Accept(reply, ()) => {
+                        mail_meta = None;
                         send_reply!(io, reply).await?;
                     }
                 },
-            },
 
-            Some(Command::Data) => match mail_meta.take() {
-                None => {
-                    send_reply!(io, cfg.data_before_mail(&mut conn_meta)).await?;
-                }
-                Some(ref mail_meta_unw) if mail_meta_unw.to.is_empty() => {
-                    send_reply!(io, cfg.data_before_rcpt(&mut conn_meta)).await?;
-                }
-                Some(mut mail_meta_unw) => {
-                    dispatch_decision! {
-                        cfg.filter_data(&mut mail_meta_unw, &mut conn_meta).await,
-                        Reject(reply) => {
-                            mail_meta = Some(mail_meta_unw);
-                            send_reply!(io, reply).await?;
-                        }
-                        Accept(reply, ()) => {
-                            send_reply!(io, reply).await?;
-                            let mut reader =
-                                EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io);
-                            let expected_n_decisions = match <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
-                                ProtocolName::Smtp => 1,
-                                ProtocolName::Lmtp => mail_meta_unw.to.len(),
-                            };
-                            let mut decision_stream = <Cfg::Protocol as Protocol<'_>>::handle_mail_return_type_as_stream(cfg
-                                .handle_mail(&mut reader, mail_meta_unw, &mut conn_meta).await);
-                            // This variable is a trick because otherwise rustc thinks the `reader`
-                            // borrow is still alive across await points and makes `interact: !Send`
-                            let reader_was_completed = if let Some(u) = reader.get_unhandled() {
-                                unhandled = u;
-                                true
-                            } else {
-                                false
-                            };
-                            if reader_was_completed {
-                                // Other mail systems (at least
-                                // postfix, OpenSMTPD and gmail)
-                                // appear to drop the state on an
-                                // unsuccessful DATA command (eg. too
-                                // long, non-RFC5322-compliant, etc.).
-                                // Couldn't find the RFC reference
-                                // anywhere, though.
-                                let mut n_decisions = 0;
-                                while let Some(decision) = decision_stream.next().await {
-                                    n_decisions += 1;
-                                    if n_decisions > expected_n_decisions {
-                                        panic!("got more decisions in handle_mail return than the expected {}", expected_n_decisions);
+                Some(Command::Starttls) => {
+                    if !cfg.tls_configured() || !cfg.can_do_tls(&conn_meta) {
+                        send_reply!(io, cfg.starttls_unsupported(&mut conn_meta)).await?;
+                    } else if !unhandled.is_empty() {
+                        send_reply!(io, cfg.pipeline_forbidden_after_starttls(&mut conn_meta))
+                            .await?;
+                    } else {
+                        dispatch_decision! {
+                            cfg.handle_starttls(&mut conn_meta).await,
+                            Accept(reply, ()) => {
+                                send_reply!(io, reply).await?;
+                                // Leave a placeholder in `io` while TLS is being set up, so that the
+                                // session can still be cut off meanwhile
+                                let plain_io = std::mem::replace(
+                                    &mut io,
+                                    duplexify::Duplex::new(
+                                        Box::pin(futures::io::empty()),
+                                        Box::pin(futures::io::sink()),
+                                    ),
+                                );
+                                conn_meta.tls_peer = None;
+                                io = match cfg.tls_accept(plain_io, &mut conn_meta).await {
+                                    Ok(io) => io,
+                                    Err(e) => {
+                                        // Do not go back to reading plaintext
+                                        cfg.log_tls_handshake_failure(&e, &mut conn_meta).await;
+                                        return Ok(());
                                     }
-                                    simple_handler!(decision);
-                                }
-                                assert_eq!(n_decisions, expected_n_decisions, "got {} decisions in handle_mail return, expected {}", n_decisions, expected_n_decisions);
-                            } else {
-                                // handle_mail did not call complete, let's read until the end and
-                                // then return an error
-                                // TODO: 128 is probably too small?
-                                let ignore_buf = &mut [0u8; 128];
-                                // TODO: consider whether it would make sense to have a separate
-                                // timeout here... giving as much time for sending the whole DATA
-                                // message may be a bit too little? but then it only happens when
-                                // handle_mail breaks anyway, so...
-                                while read_for_command!(reader.read(ignore_buf)).await? != 0 {}
-                                if !reader.is_finished() {
-                                    // Stream cut mid-connection
-                                    return Err(io::Error::new(
-                                        io::ErrorKind::ConnectionAborted,
-                                        "connection shutdown during email reception",
-                                    ));
-                                }
-                                reader.complete();
-                                unhandled = reader.get_unhandled().unwrap();
-                                // TODO: rustc complains if we don't drop(decision_stream) here, why?
-                                drop(decision_stream);
-                                for _i in 0..expected_n_decisions {
-                                    send_reply!(io, cfg.handle_mail_did_not_call_complete(&mut conn_meta)).await?;
-                                }
-                            };
+                                };
+                                mail_meta = None;
+                                conn_meta.is_encrypted = true;
+                                conn_meta.hello = None;
+                                pipelining_advertised = false;
+                            }
                         }
                     }
                 }
-            },
 
-            Some(Command::Rset) => dispatch_decision! {
-                cfg.handle_rset(&mut mail_meta, &mut conn_meta).await,
-                Accept(reply, ()) => {
-                    mail_meta = None;
-                    send_reply!(io, reply).await?;
+                Some(Command::Expn { name }) => {
+                    simple_handler!(cfg.handle_expn(name, &mut conn_meta).await)
                 }
-            },
-
-            Some(Command::Starttls) => {
-                if !cfg.can_do_tls(&conn_meta) {
-                    send_reply!(io, cfg.starttls_unsupported(&mut conn_meta)).await?;
-                } else if !unhandled.is_empty() {
-                    send_reply!(io, cfg.pipeline_forbidden_after_starttls(&mut conn_meta)).await?;
-                } else {
-                    dispatch_decision! {
-                        cfg.handle_starttls(&mut conn_meta).await,
-                        Accept(reply, ()) => {
-                            send_reply!(io, reply).await?;
-                            io = cfg.tls_accept(io, &mut conn_meta).await?;
-                            mail_meta = None;
-                            conn_meta.is_encrypted = true;
-                            conn_meta.hello = None;
+                Some(Command::Vrfy { name }) => {
+                    simple_handler!(cfg.handle_vrfy(name, &mut conn_meta).await)
+                }
+                Some(Command::Help { subject }) => {
+                    simple_handler!(cfg.handle_help(subject, &mut conn_meta).await)
+                }
+                Some(Command::Noop { string }) => {
+                    simple_handler!(cfg.handle_noop(string, &mut conn_meta).await)
+                }
+                Some(Command::Quit) => simple_handler!(cfg.handle_quit(&mut conn_meta).await),
+
+                Some(Command::Xclient { attrs }) => {
+                    let is_trusted = matches!(
+                        conn_meta.peer_addr,
+                        Some(addr) if cfg.is_trusted_frontend(addr)
+                    );
+                    if !is_trusted || !cfg.xclient_allowed(&conn_meta) {
+                        send_reply!(io, cfg.xclient_forbidden(&mut conn_meta)).await?;
+                    } else if mail_meta.is_some() {
+                        send_reply!(io, cfg.already_in_mail(&mut conn_meta)).await?;
+                    } else {
+                        match apply_xclient(&attrs, conn_meta.xclient.clone().unwrap_or_default()) {
+                            None => {
+                                send_reply!(io, cfg.xclient_invalid(&mut conn_meta)).await?;
+                            }
+                            Some(xclient) => {
+                                // The front-end now speaks for the original client, which
+                                // starts over with a new greeting
+                                conn_meta.xclient = Some(xclient);
+                                conn_meta.hello = None;
+                                pipelining_advertised = false;
+                                send_reply!(io, cfg.welcome_banner_reply(&mut conn_meta)).await?;
+                            }
                         }
                     }
                 }
             }
-
-            Some(Command::Expn { name }) => {
-                simple_handler!(cfg.handle_expn(name, &mut conn_meta).await)
-            }
-            Some(Command::Vrfy { name }) => {
-                simple_handler!(cfg.handle_vrfy(name, &mut conn_meta).await)
-            }
-            Some(Command::Help { subject }) => {
-                simple_handler!(cfg.handle_help(subject, &mut conn_meta).await)
-            }
-            Some(Command::Noop { string }) => {
-                simple_handler!(cfg.handle_noop(string, &mut conn_meta).await)
-            }
-            Some(Command::Quit) => simple_handler!(cfg.handle_quit(&mut conn_meta).await),
+        }
+    };
+    let session_timeout = async {
+        smol::Timer::after(
+            cfg.max_session_duration()
+                .to_std()
+                .unwrap_or(std::time::Duration::from_secs(0)),
+        )
+        .await;
+        None
+    };
+    match async { Some(session.await) }.or(session_timeout).await {
+        Some(res) => res,
+        None => {
+            send_reply!(io, cfg.session_too_long(&mut conn_meta)).await?;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "session lasted for too long",
+            ))
         }
     }
 }
@@ -780,7 +1838,10 @@ mod tests {
 
     use std::{
         self, str,
-        sync::{Arc, Mutex},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
     };
 
     use async_trait::async_trait;
@@ -800,8 +1861,69 @@ mod tests {
         }
     }
 
+    /// Address all the test connections come from, unless specified otherwise
+    fn client_addr() -> Option<IpAddr> {
+        Some("192.0.2.1".parse().unwrap())
+    }
+
     struct TestConfig {
         mails: Arc<Mutex<Vec<(Option<Email>, Vec<Email>, Vec<u8>)>>>,
+        max_session_duration: chrono::Duration,
+        accept_bare_lf_data_end: bool,
+        bare_lf_data_ends: Arc<Mutex<usize>>,
+        interrupted_data: Arc<Mutex<usize>>,
+        custom_replies: bool,
+        max_rejected_rcpts: usize,
+        reject_all_rcpts: bool,
+        shutting_down: Arc<AtomicBool>,
+        require_tls_for_data: bool,
+        hello_mismatch: HelloVerification,
+        tls_configured: bool,
+        max_connections_per_ip: usize,
+        open_connections: OpenConnections,
+        route_cache: Option<route::RouteCache>,
+        reply_catalog: Option<reply::ReplyCatalog>,
+        canonicalize_recipients: bool,
+        frontend_addr: IpAddr,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> TestConfig {
+            TestConfig {
+                mails: Arc::new(Mutex::new(Vec::new())),
+                max_session_duration: chrono::Duration::hours(1),
+                accept_bare_lf_data_end: false,
+                bare_lf_data_ends: Arc::new(Mutex::new(0)),
+                interrupted_data: Arc::new(Mutex::new(0)),
+                custom_replies: false,
+                max_rejected_rcpts: 0,
+                reject_all_rcpts: false,
+                shutting_down: Arc::new(AtomicBool::new(false)),
+                require_tls_for_data: false,
+                hello_mismatch: HelloVerification::Pass,
+                tls_configured: true,
+                max_connections_per_ip: 0,
+                open_connections: OpenConnections::new(),
+                route_cache: None,
+                reply_catalog: None,
+                canonicalize_recipients: false,
+                frontend_addr: "192.0.2.1".parse().unwrap(),
+            }
+        }
+    }
+
+    impl TestConfig {
+        fn refusal(&self, default: Reply, text: &str) -> Reply {
+            if self.custom_replies {
+                Reply {
+                    code: ReplyCode::COMMAND_UNRECOGNIZED,
+                    ecode: None,
+                    text: vec![MaybeUtf8::Utf8(text.into())],
+                }
+            } else {
+                default
+            }
+        }
     }
 
     #[async_trait]
@@ -814,12 +1936,23 @@ mod tests {
             "test.example.org".into()
         }
 
+        fn welcome_banner(&self, conn_meta: &ConnectionMetadata<()>) -> &str {
+            match conn_meta.is_encrypted {
+                true => "Service ready over TLS",
+                false => "Service ready",
+            }
+        }
+
+        fn tls_configured(&self) -> bool {
+            self.tls_configured
+        }
+
         async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
 
         async fn tls_accept<IO>(
             &self,
             mut io: IO,
-            _conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+            conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
         ) -> io::Result<
             duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
         >
@@ -829,24 +1962,48 @@ mod tests {
             io.write_all(b"<tls server>").await?;
             let mut buf = [0; 12];
             io.read_exact(&mut buf).await?;
-            assert_eq!(
-                &buf,
-                b"<tls client>",
-                "got TLS handshake that is not <tls client>: {:?}",
-                show_bytes(&buf)
-            );
+            if &buf != b"<tls client>" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "got TLS handshake that is not <tls client>: {:?}",
+                        show_bytes(&buf)
+                    ),
+                ));
+            }
+            conn_meta.tls_peer = tls::peer_identity(include_bytes!("../res/tls-client.der"));
             let (r, w) = io.split();
             Ok(duplexify::Duplex::new(Box::pin(r), Box::pin(w)))
         }
 
+        fn max_session_duration(&self) -> chrono::Duration {
+            self.max_session_duration
+        }
+
+        fn is_shutting_down(&self) -> bool {
+            self.shutting_down.load(Ordering::SeqCst)
+        }
+
+        fn max_connections_per_ip(&self) -> usize {
+            self.max_connections_per_ip
+        }
+
+        fn open_connections(&self) -> Option<&OpenConnections> {
+            Some(&self.open_connections)
+        }
+
         async fn filter_from(
             &self,
             addr: Option<Email>,
             _meta: &mut MailMetadata<()>,
-            _conn_meta: &mut ConnectionMetadata<()>,
+            conn_meta: &mut ConnectionMetadata<()>,
         ) -> Decision<Option<Email>> {
             // TODO: have a helper function for the Email::parse_until that just works(tm)
             // for uses such as this one
+            let peer_cn = conn_meta
+                .tls_peer
+                .as_ref()
+                .and_then(|p| p.subject_cn.as_deref());
             if addr == Some(Email::parse_bracketed(b"<bad@quux.example.org>").unwrap()) {
                 Decision::Reject {
                     reply: Reply {
@@ -855,46 +2012,271 @@ mod tests {
                         text: vec!["User 'bad' banned".into()],
                     },
                 }
+            } else if addr == Some(Email::parse_bracketed(b"<tls@client.example.org>").unwrap())
+                && peer_cn != Some("client.example.org")
+            {
+                Decision::Reject {
+                    reply: Reply {
+                        code: ReplyCode::POLICY_REASON,
+                        ecode: None,
+                        text: vec!["Client certificate required".into()],
+                    },
+                }
             } else {
                 Decision::Accept {
-                    reply: reply::okay_from().convert(),
+                    reply: self.reply("okay_from", reply::okay_from),
                     res: addr,
                 }
             }
         }
 
-        async fn filter_to(
+        fn xclient_allowed(&self, conn_meta: &ConnectionMetadata<()>) -> bool {
+            let is_frontend = conn_meta
+                .hello
+                .as_ref()
+                .map(|h| h.hostname.to_string() == "frontend.example.org")
+                .unwrap_or(false);
+            is_frontend && conn_meta.xclient.is_none()
+        }
+
+        fn is_trusted_frontend(&self, peer_addr: IpAddr) -> bool {
+            peer_addr == self.frontend_addr
+        }
+
+        async fn verify_hello(
             &self,
-            email: Email,
-            _meta: &mut MailMetadata<()>,
+            ip: IpAddr,
+            hostname: &Hostname,
             _conn_meta: &mut ConnectionMetadata<()>,
-        ) -> Decision<Email> {
-            if email.localpart.raw() == "baz" {
-                Decision::Reject {
-                    reply: Reply {
-                        code: ReplyCode::MAILBOX_UNAVAILABLE,
-                        ecode: None,
-                        text: vec!["No user 'baz'".into()],
-                    },
-                }
+        ) -> HelloVerification {
+            // The only PTR record is client.example.org for 192.0.2.1
+            if ip == IpAddr::from([192, 0, 2, 1]) && hostname.to_string() == "client.example.org" {
+                HelloVerification::Pass
             } else {
-                Decision::Accept {
-                    reply: reply::okay_to().convert(),
-                    res: email,
-                }
+                self.hello_mismatch
             }
         }
 
-        async fn handle_mail<'resp, R>(
-            &'resp self,
-            reader: &mut EscapedDataReader<'_, R>,
-            meta: MailMetadata<()>,
-            _conn_meta: &'resp mut ConnectionMetadata<()>,
-        ) -> Decision<()>
-        where
-            R: Send + Unpin + AsyncRead,
-        {
-            let mut mail_text = Vec::new();
+        fn ehlo_keywords(
+            &self,
+            keywords: Vec<MaybeUtf8<String>>,
+            conn_meta: &ConnectionMetadata<()>,
+        ) -> Vec<MaybeUtf8<String>> {
+            // Clients greeting as mimic.example.org get another MTA's style
+            match conn_meta.hello {
+                Some(ref h) if h.hostname.to_string() == "mimic.example.org" => keywords
+                    .iter()
+                    .rev()
+                    .map(|k| MaybeUtf8::Ascii(k.as_str().to_ascii_lowercase()))
+                    .collect(),
+                _ => keywords,
+            }
+        }
+
+        async fn spf_check(
+            &self,
+            ip: IpAddr,
+            domain: &str,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> SpfResult {
+            spf::check_host(&spf::tests::mock_resolver(), ip, domain).await
+        }
+
+        fn disconnect_on_unadvertised_pipelining(
+            &self,
+            conn_meta: &mut ConnectionMetadata<()>,
+        ) -> bool {
+            // Clients greeting as impatient.example.org must wait for replies
+            matches!(
+                conn_meta.hello,
+                Some(ref h) if h.hostname.to_string() == "impatient.example.org"
+            )
+        }
+
+        fn reply_catalog(&self) -> Option<&reply::ReplyCatalog> {
+            self.reply_catalog.as_ref()
+        }
+
+        fn verify_recipient_routability(&self, _conn_meta: &ConnectionMetadata<()>) -> bool {
+            self.route_cache.is_some()
+        }
+
+        async fn recipient_route(
+            &self,
+            domain: &str,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> MailRoute {
+            let cache = self.route_cache.as_ref().unwrap();
+            cache.mail_route(&spf::tests::mock_resolver(), domain).await
+        }
+
+        async fn filter_to(
+            &self,
+            email: Email,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<Email> {
+            if self.reject_all_rcpts {
+                Decision::Reject {
+                    reply: Reply {
+                        code: ReplyCode::MAILBOX_UNAVAILABLE,
+                        ecode: None,
+                        text: vec!["No user at all".into()],
+                    },
+                }
+            } else if email.localpart.raw() == "baz" {
+                Decision::Reject {
+                    reply: Reply {
+                        code: ReplyCode::MAILBOX_UNAVAILABLE,
+                        ecode: None,
+                        text: vec!["No user 'baz'".into()],
+                    },
+                }
+            } else if email.localpart.raw() == "grey" {
+                Decision::Reject {
+                    reply: reply::rcpt_deferred().convert(),
+                }
+            } else {
+                Decision::Accept {
+                    reply: reply::okay_to().convert(),
+                    res: email,
+                }
+            }
+        }
+
+        fn max_rejected_rcpts(&self, _conn_meta: &ConnectionMetadata<()>) -> usize {
+            self.max_rejected_rcpts
+        }
+
+        fn canonicalize_recipients(&self, _conn_meta: &ConnectionMetadata<()>) -> bool {
+            self.canonicalize_recipients
+        }
+
+        async fn filter_data(
+            &self,
+            meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<()> {
+            if meta.to.iter().any(|to| to.localpart.raw() == "quota") {
+                Decision::Reject {
+                    reply: Reply {
+                        code: ReplyCode::INSUFFICIENT_STORAGE,
+                        ecode: None,
+                        text: vec!["User 'quota' is over quota".into()],
+                    },
+                }
+            } else {
+                Decision::Accept {
+                    reply: reply::okay_data().convert(),
+                    res: (),
+                }
+            }
+        }
+
+        fn accept_bare_lf_data_end(&self, _conn_meta: &ConnectionMetadata<()>) -> bool {
+            self.accept_bare_lf_data_end
+        }
+
+        fn require_tls_for_data(&self, _conn_meta: &ConnectionMetadata<()>) -> bool {
+            self.require_tls_for_data
+        }
+
+        async fn log_bare_lf_data_end(&self, _conn_meta: &mut ConnectionMetadata<()>) {
+            *self.bare_lf_data_ends.lock().unwrap() += 1;
+        }
+
+        async fn log_interrupted_data(&self, _conn_meta: &mut ConnectionMetadata<()>) {
+            *self.interrupted_data.lock().unwrap() += 1;
+        }
+
+        fn max_headers_size(&self, _conn_meta: &ConnectionMetadata<()>) -> usize {
+            RDBUF_SIZE
+        }
+
+        async fn filter_headers(
+            &self,
+            headers: &[u8],
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<()> {
+            let banned = b"\r\nSubject: banned\r\n";
+            let mut headers_crlf = b"\r\n".to_vec();
+            headers_crlf.extend_from_slice(headers);
+            if headers_crlf.windows(banned.len()).any(|w| w == banned) {
+                Decision::Reject {
+                    reply: Reply {
+                        code: ReplyCode::POLICY_REASON,
+                        ecode: None,
+                        text: vec!["Subject 'banned' is banned".into()],
+                    },
+                }
+            } else {
+                Decision::Accept {
+                    reply: reply::okay_data().convert(),
+                    res: (),
+                }
+            }
+        }
+
+        fn already_did_hello(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(reply::bad_sequence().convert(), "Custom already did hello")
+        }
+
+        fn mail_before_hello(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(
+                reply::hello_required().convert(),
+                "Custom mail before hello",
+            )
+        }
+
+        fn rcpt_before_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(reply::bad_sequence().convert(), "Custom rcpt before mail")
+        }
+
+        fn data_before_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(reply::bad_sequence().convert(), "Custom data before mail")
+        }
+
+        fn data_before_rcpt(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(reply::bad_sequence().convert(), "Custom data before rcpt")
+        }
+
+        fn command_unrecognized(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Reply {
+            self.refusal(
+                reply::command_unrecognized().convert(),
+                "Custom command unrecognized",
+            )
+        }
+
+        async fn unknown_command(
+            &self,
+            line: &[u8],
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Option<Decision<()>> {
+            if line.eq_ignore_ascii_case(b"XDEBUG") {
+                Some(Decision::Accept {
+                    reply: Reply {
+                        code: ReplyCode::OKAY,
+                        ecode: None,
+                        text: vec!["Debugging enabled".into()],
+                    },
+                    res: (),
+                })
+            } else {
+                None
+            }
+        }
+
+        async fn handle_mail<'resp, R>(
+            &'resp self,
+            reader: &mut EscapedDataReader<'_, R>,
+            meta: MailMetadata<()>,
+            _conn_meta: &'resp mut ConnectionMetadata<()>,
+        ) -> Decision<()>
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let mut mail_text = Vec::new();
             let res = reader.read_to_end(&mut mail_text).await;
             if !reader.is_finished() {
                 // Note: this is a stupid buggy implementation.
@@ -935,10 +2317,39 @@ mod tests {
         }
     }
 
+    /// Runs `interact` on `inp` with `cfg`, and returns all its replies. Its
+    /// result is not checked, as some sessions are expected to be cut off:
+    /// callers look at the replies and at what `cfg` recorded instead.
+    fn run_interact(cfg: Arc<TestConfig>, inp: &[u8]) -> Vec<u8> {
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        executor::block_on(async move {
+            inp_pipe_w
+                .write_all(inp)
+                .await
+                .expect("writing to input pipe");
+            std::mem::drop(inp_pipe_w);
+            let _ = interact(io, client_addr(), IsAlreadyTls::No, (), cfg).await;
+            let mut out = Vec::new();
+            out_pipe_r
+                .read_to_end(&mut out)
+                .await
+                .expect("reading from output pipe");
+            out
+        })
+    }
+
     #[test]
     fn interacts_ok() {
-        let tests: &[(&[&[u8]], &[u8], &[(Option<&[u8]>, &[&[u8]], &[u8])])] = &[
+        let tests: &[(
+            fn() -> TestConfig,
+            &[&[u8]],
+            &[u8],
+            &[(Option<&[u8]>, &[&[u8]], &[u8])],
+        )] = &[
             (
+                TestConfig::default,
                 &[b"EHLO test\r\n\
                     MAIL FROM:<>\r\n\
                     RCPT TO:<baz@quux.example.org>\r\n\
@@ -969,6 +2380,7 @@ mod tests {
                 )],
             ),
             (
+                TestConfig::default,
                 &[b"HELO test\r\n\
                     MAIL FROM:<test@example.org>\r\n\
                     RCPT TO:<foo@example.org>\r\n\
@@ -986,6 +2398,52 @@ mod tests {
                 &[],
             ),
             (
+                TestConfig::default,
+                &[
+                    b"HELO test\r\n\
+                      MAIL FROM:<test@example.org>\r\n\
+                      RCPT TO:<foo@example.org>\r\n\
+                      DATA\r\n\
+                      From: test@example.org\r\n\
+                      Subj",
+                    b"ect: banned\r\n\
+                      \r\n\
+                      Hello\r\n\
+                      .\r\n\
+                      MAIL FROM:<test@example.org>\r\n\
+                      RCPT TO:<foo@example.org>\r\n\
+                      DATA\r\n\
+                      From: test@example.org\r\n\
+                      Subj",
+                    b"ect: not banned\r\n\
+                      \r\n\
+                      ..Hello\r\n\
+                      .\r\n\
+                      QUIT\r\n",
+                ],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  550 Subject 'banned' is banned\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<test@example.org>"),
+                    &[b"<foo@example.org>"],
+                    b"From: test@example.org\r\n\
+                      Subject: not banned\r\n\
+                      \r\n\
+                      ..Hello\r\n\
+                      .\r\n",
+                )],
+            ),
+            (
+                TestConfig::default,
                 &[b"HELO test\r\n\
                     MAIL FROM:<bad@quux.example.org>\r\n\
                     MAIL FROM:<foo@bar.example.org>\r\n\
@@ -999,114 +2457,700 @@ mod tests {
                   250 test.example.org\r\n\
                   550 User 'bad' banned\r\n\
                   250 2.0.0 Okay\r\n\
-                  503 5.5.1 Bad sequence of commands\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<foo@bar.example.org>"),
+                    &[b"<foo2@bar.example.org>"],
+                    b"Hello\r\n.\r\n",
+                )],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RSET\r\n\
+                    MAIL FROM:<baz@quux.example.org>\r\n\
+                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<baz@quux.example.org>"),
+                    &[b"<foo2@bar.example.org>"],
+                    b"Hello\r\n.\r\n",
+                )],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<quota@bar.example.org>\r\n\
+                    DATA\r\n\
+                    MAIL FROM:<baz@bar.example.org>\r\n\
+                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    DATA\r\n\
+                    RSET\r\n\
+                    MAIL FROM:<baz@bar.example.org>\r\n\
+                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  452 User 'quota' is over quota\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  250 2.1.5 Okay\r\n\
+                  452 User 'quota' is over quota\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<baz@bar.example.org>"),
+                    &[b"<foo2@bar.example.org>"],
+                    b"Hello\r\n.\r\n",
+                )],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<quota@bar.example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  452 User 'quota' is over quota\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@spf-fail.example.org>\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n\
+                    MAIL FROM:<foo@spf-pass.example.org>\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  550 5.7.23 SPF validation failed\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<foo@spf-pass.example.org>"),
+                    &[b"<foo@bar.example.org>"],
+                    b"Hello\r\n.\r\n",
+                )],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n\
+                    DATA\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n\
+                    THISISNOTACOMMAND\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  250 2.1.5 Okay\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"MAIL FROM:<foo@test.example.com>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"RCPT TO:<foo@bar.example.org>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"DATA\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"MAIL FROM:<foo@test.example.com>\r\n\
+                    RCPT TO:<foo@bar.example.org>\r\n\
+                    DATA\r\n\
+                    EHLO test\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    EXPN foo\r\n\
+                    VRFY bar\r\n\
+                    HELP baz\r\n\
+                    NOOP\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  502 5.5.1 Command not implemented\r\n\
+                  252 2.1.5 Cannot VRFY user, but will accept message and attempt delivery\r\n\
+                  214 2.0.0 See https://tools.ietf.org/html/rfc5321\r\n\
+                  250 2.0.0 Okay\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"EHLO mimic.example.org\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-starttls\r\n\
+                  250-smtputf8\r\n\
+                  250-pipelining\r\n\
+                  250-enhancedstatuscodes\r\n\
+                  250 8bitmime\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[
+                    b"HELO test\r\n\
+                      XDEBUG\r\n\
+                      XDEB",
+                    b"ug\r\n\
+                      XDEBUG now\r\n\
+                      QUIT\r\n",
+                ],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 Debugging enabled\r\n\
+                  250 Debugging enabled\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    EXPN foo\r\n\
+                    QUIT\r\n\
+                    HELP baz\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  502 5.5.1 Command not implemented\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"EHLO frontend.example.org\r\n\
+                    XCLIENT ADDR=198.51.100.1 HELO=client.example.org LOGIN=foo\r\n\
+                    XCLIENT ADDR=192.0.2.1\r\n\
+                    EHLO frontend.example.org\r\n\
+                    MAIL FROM:<foo@spf-pass.example.org>\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250-STARTTLS\r\n\
+                  250 XCLIENT ADDR HELO LOGIN\r\n\
+                  220 test.example.org Service ready\r\n\
+                  550 5.7.0 Not authorized to use XCLIENT\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  550 5.7.23 SPF validation failed\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"EHLO test\r\n\
+                    XCLIENT ADDR=198.51.100.1\r\n\
+                    MAIL FROM:<foo@spf-pass.example.org>\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  550 5.7.0 Not authorized to use XCLIENT\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[
+                    b"EHLO test\r\n\
+                      STARTTLS\r\n",
+                    b"<tls client>",
+                    b"EHLO test2\r\n",
+                ],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  220 2.0.0 Ready to start TLS\r\n\
+                  <tls server>\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250 SMTPUTF8\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[
+                    b"EHLO test\r\n\
+                      STARTTLS\r\n",
+                    b"<bad client>\
+                      EHLO test2\r\n\
+                      MAIL FROM:<tls@client.example.org>\r\n",
+                ],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  220 2.0.0 Ready to start TLS\r\n\
+                  <tls server>",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[
+                    b"EHLO test\r\n\
+                      MAIL FROM:<tls@client.example.org>\r\n\
+                      STARTTLS\r\n",
+                    b"<tls client>",
+                    b"EHLO test2\r\n\
+                      MAIL FROM:<tls@client.example.org>\r\n",
+                ],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  550 Client certificate required\r\n\
+                  220 2.0.0 Ready to start TLS\r\n\
+                  <tls server>\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250 SMTPUTF8\r\n\
+                  250 2.0.0 Okay\r\n",
+                &[],
+            ),
+            // All the commands get their reply in order, even without PIPELINING
+            (
+                TestConfig::default,
+                &[b"HELO test\r\n\
+                    MAIL FROM:<>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    NOOP\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                TestConfig::default,
+                &[b"HELO impatient.example.org\r\n\
+                    MAIL FROM:<>\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  554 5.5.1 Improper command pipelining\r\n",
+                &[],
+            ),
+            // PIPELINING is advertised in reply to EHLO
+            (
+                TestConfig::default,
+                &[b"EHLO impatient.example.org\r\n\
+                    MAIL FROM:<>\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            // XCLIENT is only allowed from the trusted frontend's address
+            (
+                || TestConfig {
+                    frontend_addr: "203.0.113.1".parse().unwrap(),
+                    ..TestConfig::default()
+                },
+                &[b"EHLO frontend.example.org\r\n\
+                    XCLIENT ADDR=198.51.100.1\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  550 5.7.0 Not authorized to use XCLIENT\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            (
+                || TestConfig {
+                    custom_replies: true,
+                    ..TestConfig::default()
+                },
+                &[b"MAIL FROM:<foo@bar.example.org>\r\n\
+                    LHLO test\r\n\
+                    EHLO test\r\n\
+                    EHLO test\r\n\
+                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    DATA\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    DATA\r\n\
+                    FOO bar\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  500 Custom mail before hello\r\n\
+                  500 Custom command unrecognized\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  500 Custom already did hello\r\n\
+                  500 Custom rcpt before mail\r\n\
+                  500 Custom data before mail\r\n\
+                  250 2.0.0 Okay\r\n\
+                  500 Custom data before rcpt\r\n\
+                  500 Custom command unrecognized\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            // Deferred recipients do not count as rejected
+            (
+                || TestConfig {
+                    max_rejected_rcpts: 1,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    RCPT TO:<grey@example.org>\r\n\
+                    RCPT TO:<grey@example.net>\r\n\
+                    RCPT TO:<bar@example.org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  451 4.7.0 Recipient deferred, try again later\r\n\
+                  451 4.7.0 Recipient deferred, try again later\r\n\
                   250 2.1.5 Okay\r\n\
                   354 Start mail input; end with <CRLF>.<CRLF>\r\n\
                   250 2.0.0 Okay\r\n\
                   221 2.0.0 Bye\r\n",
                 &[(
                     Some(b"<foo@bar.example.org>"),
-                    &[b"<foo2@bar.example.org>"],
+                    &[b"<foo@example.org>", b"<bar@example.org>"],
                     b"Hello\r\n.\r\n",
                 )],
             ),
             (
-                &[b"HELO test\r\n\
+                || TestConfig {
+                    route_cache: Some(route::RouteCache::new(
+                        std::time::Duration::from_secs(3600),
+                        16,
+                    )),
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
                     MAIL FROM:<foo@bar.example.org>\r\n\
-                    RSET\r\n\
-                    MAIL FROM:<baz@quux.example.org>\r\n\
-                    RCPT TO:<foo2@bar.example.org>\r\n\
+                    RCPT TO:<foo@spf-fail.example.org>\r\n\
+                    RCPT TO:<foo@nowhere.example.org>\r\n\
+                    RCPT TO:<foo@null-mx.example.org>\r\n\
+                    RCPT TO:<bar@relay.example.org>\r\n\
                     DATA\r\n\
                     Hello\r\n\
                     .\r\n\
                     QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
-                  250 test.example.org\r\n\
-                  250 2.0.0 Okay\r\n\
-                  250 2.0.0 Okay\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
                   250 2.0.0 Okay\r\n\
                   250 2.1.5 Okay\r\n\
+                  550 5.1.2 Recipient domain cannot receive mail\r\n\
+                  556 5.1.10 Recipient domain does not accept mail\r\n\
+                  250 2.1.5 Okay\r\n\
                   354 Start mail input; end with <CRLF>.<CRLF>\r\n\
                   250 2.0.0 Okay\r\n\
                   221 2.0.0 Bye\r\n",
                 &[(
-                    Some(b"<baz@quux.example.org>"),
-                    &[b"<foo2@bar.example.org>"],
+                    Some(b"<foo@bar.example.org>"),
+                    &[b"<foo@spf-fail.example.org>", b"<bar@relay.example.org>"],
                     b"Hello\r\n.\r\n",
                 )],
             ),
             (
+                || {
+                    let catalog = vec![
+                        ("okay_from", "D'accord"),
+                        (
+                            "no_mail_route",
+                            "Le domaine du destinataire ne peut pas recevoir de courrier",
+                        ),
+                        ("okay_quit", "Au revoir"),
+                        // Not applied to the reply built by filter_to, as it is not a default one
+                        ("okay_to", "Destinataire accepté"),
+                    ];
+                    let catalog = catalog
+                        .into_iter()
+                        .map(|(id, text)| (id.to_string(), vec![text.to_string()]))
+                        .collect();
+                    TestConfig {
+                        route_cache: Some(route::RouteCache::new(
+                            std::time::Duration::from_secs(3600),
+                            16,
+                        )),
+                        reply_catalog: Some(reply::ReplyCatalog(catalog)),
+                        ..TestConfig::default()
+                    }
+                },
                 &[b"HELO test\r\n\
-                    MAIL FROM:<foo@test.example.com>\r\n\
-                    DATA\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo@nowhere.example.org>\r\n\
+                    RCPT TO:<foo@spf-fail.example.org>\r\n\
                     QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
                   250 test.example.org\r\n\
-                  250 2.0.0 Okay\r\n\
-                  503 5.5.1 Bad sequence of commands\r\n\
-                  221 2.0.0 Bye\r\n",
-                &[],
-            ),
-            (
-                &[b"HELO test\r\n\
-                    MAIL FROM:<foo@test.example.com>\r\n\
-                    RCPT TO:<foo@bar.example.org>\r\n"],
-                b"220 test.example.org Service ready\r\n\
-                  250 test.example.org\r\n\
-                  250 2.0.0 Okay\r\n\
-                  250 2.1.5 Okay\r\n",
+                  250 2.0.0 D'accord\r\n\
+                  550 5.1.2 Le domaine du destinataire ne peut pas recevoir de courrier\r\n\
+                  250 2.1.5 Okay\r\n\
+                  221 2.0.0 Au revoir\r\n",
                 &[],
             ),
             (
-                &[b"HELO test\r\n\
-                    MAIL FROM:<foo@test.example.com>\r\n\
-                    THISISNOTACOMMAND\r\n\
-                    RCPT TO:<foo@bar.example.org>\r\n"],
+                || TestConfig {
+                    require_tls_for_data: true,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    DATA\r\n\
+                    QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
-                  250 test.example.org\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
                   250 2.0.0 Okay\r\n\
-                  500 5.5.1 Command not recognized\r\n\
-                  250 2.1.5 Okay\r\n",
+                  250 2.1.5 Okay\r\n\
+                  530 5.7.0 Must issue STARTTLS first\r\n\
+                  221 2.0.0 Bye\r\n",
                 &[],
             ),
             (
-                &[b"MAIL FROM:<foo@test.example.com>\r\n"],
+                || TestConfig {
+                    tls_configured: false,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    STARTTLS\r\n\
+                    QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
-                  503 5.5.1 Bad sequence of commands\r\n",
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250 SMTPUTF8\r\n\
+                  502 5.5.1 Command not supported\r\n\
+                  221 2.0.0 Bye\r\n",
                 &[],
             ),
             (
-                &[b"HELO test\r\n\
-                    EXPN foo\r\n\
-                    VRFY bar\r\n\
-                    HELP baz\r\n\
-                    NOOP\r\n"],
+                || TestConfig {
+                    hello_mismatch: HelloVerification::SoftFail,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO mismatch.example.org\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    HELO client.example.org\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
+                  450 4.7.0 HELO hostname does not match reverse DNS\r\n\
+                  503 5.5.1 Send HELO/EHLO first\r\n\
                   250 test.example.org\r\n\
-                  502 5.5.1 Command not implemented\r\n\
-                  252 2.1.5 Cannot VRFY user, but will accept message and attempt delivery\r\n\
-                  214 2.0.0 See https://tools.ietf.org/html/rfc5321\r\n\
-                  250 2.0.0 Okay\r\n",
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
                 &[],
             ),
+            // Once the limit is reached, even valid recipients are refused, until
+            // the next transaction
             (
-                &[b"HELO test\r\n\
-                    EXPN foo\r\n\
-                    QUIT\r\n\
-                    HELP baz\r\n"],
+                || TestConfig {
+                    max_rejected_rcpts: 2,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<baz@example.org>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    RCPT TO:<baz@example.org>\r\n\
+                    RCPT TO:<baz@example.org>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    RSET\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo@example.org>\r\n\
+                    QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
-                  250 test.example.org\r\n\
-                  502 5.5.1 Command not implemented\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  250 2.0.0 Okay\r\n\
+                  550 No user 'baz'\r\n\
+                  250 2.1.5 Okay\r\n\
+                  550 No user 'baz'\r\n\
+                  451 4.7.0 Too many rejected recipients, try again later\r\n\
+                  451 4.7.0 Too many rejected recipients, try again later\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
                   221 2.0.0 Bye\r\n",
                 &[],
             ),
             (
-                &[
-                    b"EHLO test\r\n\
-                      STARTTLS\r\n",
-                    b"<tls client>",
-                    b"EHLO test2\r\n",
-                ],
+                || TestConfig {
+                    reject_all_rcpts: true,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<foo@bar.example.org>\r\n\
+                    RCPT TO:<foo@test.example.org>\r\n\
+                    RCPT TO:<postmaster>\r\n\
+                    RCPT TO:<postmaster@test.example.org>\r\n\
+                    RCPT TO:<PostMaster@Test.Example.Org>\r\n\
+                    RCPT TO:<postmaster@other.example.org>\r\n\
+                    QUIT\r\n"],
                 b"220 test.example.org Service ready\r\n\
                   250-test.example.org\r\n\
                   250-8BITMIME\r\n\
@@ -1114,17 +3158,50 @@ mod tests {
                   250-PIPELINING\r\n\
                   250-SMTPUTF8\r\n\
                   250 STARTTLS\r\n\
-                  220 2.0.0 Ready to start TLS\r\n\
-                  <tls server>\
+                  250 2.0.0 Okay\r\n\
+                  550 No user at all\r\n\
+                  250 2.1.5 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  550 No user at all\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[],
+            ),
+            // Only the recipients are canonicalized
+            (
+                || TestConfig {
+                    canonicalize_recipients: true,
+                    ..TestConfig::default()
+                },
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<Foo@Bar.Example.Org>\r\n\
+                    RCPT TO:<User@EXAMPLE.COM>\r\n\
+                    RCPT TO:<PostMaster@Test.Example.Org>\r\n\
+                    DATA\r\n\
+                    Hello\r\n\
+                    .\r\n\
+                    QUIT\r\n"],
+                b"220 test.example.org Service ready\r\n\
                   250-test.example.org\r\n\
                   250-8BITMIME\r\n\
                   250-ENHANCEDSTATUSCODES\r\n\
                   250-PIPELINING\r\n\
-                  250 SMTPUTF8\r\n",
-                &[],
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n",
+                &[(
+                    Some(b"<Foo@Bar.Example.Org>"),
+                    &[b"<User@example.com>", b"<PostMaster@test.example.org>"],
+                    b"Hello\r\n.\r\n",
+                )],
             ),
         ];
-        for &(inp, out, mail) in tests {
+        for &(cfg, inp, out, mail) in tests {
             println!(
                 "\nSending: {:?}",
                 inp.iter().map(|b| show_bytes(*b)).collect::<Vec<_>>()
@@ -1132,6 +3209,7 @@ mod tests {
             let resp_mail = Arc::new(Mutex::new(Vec::new()));
             let cfg = Arc::new(TestConfig {
                 mails: resp_mail.clone(),
+                ..cfg()
             });
             let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
             let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
@@ -1151,7 +3229,7 @@ mod tests {
                     }
                 },
                 async move {
-                    interact(io, IsAlreadyTls::No, (), cfg)
+                    interact(io, client_addr(), IsAlreadyTls::No, (), cfg)
                         .await
                         .expect("calling interact");
                     let mut resp = Vec::new();
@@ -1190,6 +3268,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn welcomes_depending_on_listener() {
+        let tests: &[(IsAlreadyTls, &[u8])] = &[
+            (
+                IsAlreadyTls::No,
+                b"220 test.example.org Service ready\r\n221 2.0.0 Bye\r\n",
+            ),
+            (
+                IsAlreadyTls::Yes,
+                b"220 test.example.org Service ready over TLS\r\n221 2.0.0 Bye\r\n",
+            ),
+        ];
+        for &(is_already_tls, out) in tests {
+            let cfg = Arc::new(TestConfig::default());
+            let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024);
+            let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024);
+            let io = Duplex::new(inp_pipe_r, out_pipe_w);
+            let resp = smol::block_on(async move {
+                inp_pipe_w
+                    .write_all(b"QUIT\r\n")
+                    .await
+                    .expect("writing to input pipe");
+                interact(io, client_addr(), is_already_tls, (), cfg)
+                    .await
+                    .expect("calling interact");
+                let mut resp = Vec::new();
+                out_pipe_r
+                    .read_to_end(&mut resp)
+                    .await
+                    .expect("reading from output pipe");
+                resp
+            });
+            assert_eq!(show_bytes(&resp), show_bytes(out));
+        }
+    }
+
     // Fuzzer-found
     #[test]
     fn interrupted_data() {
@@ -1197,9 +3311,7 @@ mod tests {
                            RCPT TO:bar\r\n\
                            DATA\r\n\
                            hello";
-        let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
-        });
+        let cfg = Arc::new(TestConfig::default());
         let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
         let (_out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
         let io = Duplex::new(inp_pipe_r, out_pipe_w);
@@ -1209,7 +3321,7 @@ mod tests {
                 .await
                 .expect("writing to input pipe");
             std::mem::drop(inp_pipe_w);
-            interact(io, IsAlreadyTls::No, (), cfg)
+            interact(io, client_addr(), IsAlreadyTls::No, (), cfg)
                 .await
                 .expect_err("calling interact")
                 .kind()
@@ -1217,6 +3329,314 @@ mod tests {
         assert_eq!(err_kind, io::ErrorKind::ConnectionAborted,);
     }
 
+    #[test]
+    fn logs_interrupted_data() {
+        let inp: &[u8] = b"EHLO test\r\n\
+                           MAIL FROM:<foo@example.org>\r\n\
+                           RCPT TO:<bar@example.org>\r\n\
+                           DATA\r\n\
+                           Subject: hello\r\n\r\nhel";
+        let cfg = Arc::new(TestConfig::default());
+        let out = run_interact(cfg.clone(), inp);
+        assert!(out.ends_with(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n"));
+        assert_eq!(*cfg.interrupted_data.lock().unwrap(), 1);
+        assert!(cfg.mails.lock().unwrap().is_empty());
+    }
+    #[test]
+    fn limits_pipelined_commands() {
+        let cfg = Arc::new(TestConfig::default());
+        // EHLO and 10100 NOOPs in a single batch, past the default limit of 10000
+        let mut inp = b"EHLO test\r\n".to_vec();
+        for _ in 0..10100 {
+            inp.extend_from_slice(b"NOOP\r\n");
+        }
+        let res = String::from_utf8(run_interact(cfg, &inp)).unwrap();
+        let noops = res.matches("250 2.0.0 Okay\r\n").count();
+        assert_eq!(noops, 9999);
+        assert!(res.ends_with("250 2.0.0 Okay\r\n421 4.7.0 Too many pipelined commands\r\n"));
+    }
+    #[test]
+    fn counts_pipelined_commands_per_transaction() {
+        let cfg = Arc::new(TestConfig::default());
+        // Two transactions of 6000 recipients each in a single batch, each
+        // below the default limit of 10000 while their total is above it
+        let mut inp = b"EHLO test\r\n".to_vec();
+        for _ in 0..2 {
+            inp.extend_from_slice(b"MAIL FROM:<foo@bar.example.org>\r\n");
+            for _ in 0..6000 {
+                inp.extend_from_slice(b"RCPT TO:<bazz@quux.example.org>\r\n");
+            }
+            inp.extend_from_slice(b"RSET\r\n");
+        }
+        let res = String::from_utf8(run_interact(cfg, &inp)).unwrap();
+        assert!(!res.contains("Too many pipelined commands"));
+        assert!(res.ends_with("250 2.0.0 Okay\r\n"));
+    }
+    #[test]
+    fn limits_commands_across_reads() {
+        let cfg = Arc::new(TestConfig::default());
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        let replies = executor::block_on(async move {
+            let server = async move {
+                interact(io, client_addr(), IsAlreadyTls::No, (), cfg)
+                    .await
+                    .expect("calling interact")
+            };
+            // Send each NOOP only once the previous one got its reply, so that
+            // every command is received in a read of its own
+            let client = async move {
+                let mut replies = Vec::new();
+                loop {
+                    let mut line = Vec::new();
+                    while !line.ends_with(b"\r\n") {
+                        let mut byte = [0];
+                        out_pipe_r
+                            .read_exact(&mut byte)
+                            .await
+                            .expect("reading a reply");
+                        line.push(byte[0]);
+                    }
+                    let line = show_bytes(&line);
+                    if line.starts_with("421 ") || replies.len() > 10100 {
+                        replies.push(line);
+                        break replies;
+                    }
+                    replies.push(line);
+                    inp_pipe_w.write_all(b"NOOP\r\n").await.unwrap();
+                }
+            };
+            futures::join!(server, client).1
+        });
+        // The banner, the replies to 10000 NOOPs, which is the default limit,
+        // and the refusal of the next one
+        assert_eq!(replies.len(), 10002);
+        assert_eq!(replies[10000], "250 2.0.0 Okay\r\n");
+        assert_eq!(replies[10001], "421 4.7.0 Too many pipelined commands\r\n");
+    }
+
+    #[test]
+    fn refuses_connections_when_shutting_down() {
+        let cfg = Arc::new(TestConfig::default());
+        let connect = |cfg| show_bytes(&run_interact(cfg, b"QUIT\r\n"));
+
+        assert_eq!(
+            connect(cfg.clone()),
+            "220 test.example.org Service ready\r\n\
+             221 2.0.0 Bye\r\n"
+        );
+        cfg.shutting_down.store(true, Ordering::SeqCst);
+        assert_eq!(connect(cfg.clone()), "421 4.3.2 Server shutting down\r\n");
+        cfg.shutting_down.store(false, Ordering::SeqCst);
+        assert_eq!(
+            connect(cfg),
+            "220 test.example.org Service ready\r\n\
+             221 2.0.0 Bye\r\n"
+        );
+    }
+    #[test]
+    fn refuses_connections_over_per_ip_limit() {
+        let cfg = Arc::new(TestConfig {
+            max_connections_per_ip: 2,
+            ..TestConfig::default()
+        });
+        // All the connections come from the same `client_ip`, 192.0.2.1
+        let connect = || {
+            let (inp_pipe_r, inp_pipe_w) = piper::pipe(1024 * 1024);
+            let (out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+            let io = Duplex::new(inp_pipe_r, out_pipe_w);
+            let task = smol::spawn(interact(
+                io,
+                client_addr(),
+                IsAlreadyTls::No,
+                (),
+                cfg.clone(),
+            ));
+            (inp_pipe_w, out_pipe_r, task)
+        };
+        let read_line = |mut out: piper::Reader| async move {
+            let mut line = Vec::new();
+            while !line.ends_with(b"\r\n") {
+                let mut byte = [0];
+                out.read_exact(&mut byte).await.expect("reading a reply");
+                line.push(byte[0]);
+            }
+            (out, show_bytes(&line))
+        };
+        type Conn = (piper::Writer, piper::Reader, smol::Task<io::Result<()>>);
+        let quit = |(mut inp, mut out, task): Conn| async move {
+            inp.write_all(b"QUIT\r\n")
+                .await
+                .expect("writing to input pipe");
+            std::mem::drop(inp);
+            task.await.expect("calling interact");
+            let mut rest = Vec::new();
+            out.read_to_end(&mut rest).await.unwrap();
+            show_bytes(&rest)
+        };
+
+        executor::block_on(async {
+            let mut held = Vec::new();
+            for _ in 0..2 {
+                let (inp, out, task) = connect();
+                let (out, banner) = read_line(out).await;
+                assert_eq!(banner, "220 test.example.org Service ready\r\n");
+                held.push((inp, out, task));
+            }
+
+            let (_inp, mut out, task) = connect();
+            task.await.expect("calling interact");
+            let mut refused = Vec::new();
+            out.read_to_end(&mut refused).await.unwrap();
+            assert_eq!(
+                show_bytes(&refused),
+                "421 4.7.0 Too many connections from your address\r\n"
+            );
+
+            // Closing a connection makes room for a new one
+            assert_eq!(quit(held.remove(0)).await, "221 2.0.0 Bye\r\n");
+            let (inp, out, task) = connect();
+            let (out, banner) = read_line(out).await;
+            assert_eq!(banner, "220 test.example.org Service ready\r\n");
+            held.push((inp, out, task));
+
+            for conn in held {
+                assert_eq!(quit(conn).await, "221 2.0.0 Bye\r\n");
+            }
+        });
+    }
+
+    #[test]
+    fn session_cut_off() {
+        let cfg = Arc::new(TestConfig {
+            max_session_duration: chrono::Duration::milliseconds(500),
+            ..TestConfig::default()
+        });
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        let (err_kind, sent) = executor::block_on(async move {
+            let server = async move {
+                interact(io, client_addr(), IsAlreadyTls::No, (), cfg)
+                    .await
+                    .expect_err("calling interact")
+                    .kind()
+            };
+            // Trickle valid commands for 5 seconds, each well within the
+            // command read timeout
+            let client = async move {
+                inp_pipe_w.write_all(b"EHLO test\r\n").await.unwrap();
+                let mut sent = 0;
+                for _ in 0..50 {
+                    smol::Timer::after(std::time::Duration::from_millis(100)).await;
+                    if inp_pipe_w.write_all(b"NOOP\r\n").await.is_err() {
+                        break;
+                    }
+                    sent += 1;
+                }
+                sent
+            };
+            futures::join!(server, client)
+        });
+        assert_eq!(err_kind, io::ErrorKind::TimedOut);
+        assert!(sent < 10, "the client could send {} commands", sent);
+        let mut out = Vec::new();
+        executor::block_on(out_pipe_r.read_to_end(&mut out)).unwrap();
+        assert!(out.ends_with(b"250 2.0.0 Okay\r\n421 4.4.2 Session lasted for too long\r\n"));
+    }
+
+    #[test]
+    fn bare_lf_data_end() {
+        let inp: &[u8] = b"EHLO test\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           RCPT TO:<foo3@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           Bare LF\n\
+                           .\n\
+                           QUIT\r\n";
+        for &accept in &[false, true] {
+            let cfg = Arc::new(TestConfig {
+                accept_bare_lf_data_end: accept,
+                ..TestConfig::default()
+            });
+            let out = run_interact(cfg.clone(), inp);
+            let mails = cfg.mails.lock().unwrap();
+            if accept {
+                // The end is recognized, and normalized before handle_mail
+                assert!(out.ends_with(
+                    b"354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                      250 2.0.0 Okay\r\n\
+                      221 2.0.0 Bye\r\n"
+                ));
+                assert_eq!(mails.len(), 1);
+                assert_eq!(show_bytes(&mails[0].2), "Hello\r\nBare LF\r\n.\r\n");
+                assert_eq!(*cfg.bare_lf_data_ends.lock().unwrap(), 1);
+            } else {
+                // The end is not recognized, so the QUIT is part of the data
+                assert!(out.ends_with(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n"));
+                assert!(mails.is_empty());
+                assert_eq!(*cfg.bare_lf_data_ends.lock().unwrap(), 0);
+            }
+        }
+    }
+    #[test]
+    fn xclient_attributes() {
+        let parse = |attrs: &[u8]| match Command::<&str>::parse(attrs) {
+            Ok((_, Command::Xclient { attrs })) => apply_xclient(&attrs, XclientInfo::default()),
+            r => panic!("unexpected parse result {:?}", r),
+        };
+        let info = parse(b"XCLIENT ADDR=IPV6:2001:db8::1 HELO=client.example.org LOGIN=f+3Do\r\n")
+            .unwrap();
+        assert_eq!(info.addr, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(info.helo.unwrap().to_string(), "client.example.org");
+        assert_eq!(info.login.as_deref(), Some("f=o"));
+        let info = parse(b"XCLIENT ADDR=[UNAVAILABLE] NAME=client.example.org\r\n").unwrap();
+        assert_eq!(info.addr, None);
+        assert!(parse(b"XCLIENT ADDR=not.an.ip\r\n").is_none());
+        assert!(parse(b"XCLIENT HELO\r\n").is_none());
+    }
+
+    #[test]
+    fn drain_data_times_out() {
+        for &stall in &[false, true] {
+            let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+            let (res, finished, elapsed) = executor::block_on(futures::future::join(
+                async move {
+                    inp_pipe_w.write_all(b"Hello\r\n").await.unwrap();
+                    if stall {
+                        // Slower than the timeout
+                        smol::Timer::after(std::time::Duration::from_millis(500)).await;
+                    }
+                    // The reader may already have given up
+                    let _ = inp_pipe_w.write_all(b"World\r\n.\r\n").await;
+                },
+                async move {
+                    let mut inp_pipe_r = inp_pipe_r;
+                    let mut rdbuf = [0; RDBUF_SIZE];
+                    let mut reader = EscapedDataReader::new(&mut rdbuf, 0..0, &mut inp_pipe_r);
+                    let start = std::time::Instant::now();
+                    let res = drain_data(&mut reader, chrono::Duration::milliseconds(100)).await;
+                    (res, reader.is_finished(), start.elapsed())
+                },
+            ))
+            .1;
+            if stall {
+                assert_eq!(
+                    res.expect_err("draining data").kind(),
+                    io::ErrorKind::TimedOut
+                );
+                assert!(!finished);
+                assert!(elapsed < std::time::Duration::from_millis(400));
+            } else {
+                res.expect("draining data");
+                assert!(finished);
+            }
+        }
+    }
+
     // Fuzzer-found
     #[test]
     fn no_stack_overflow() {
@@ -1272,9 +3692,7 @@ mod tests {
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\r\n";
-        let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
-        });
+        let cfg = Arc::new(TestConfig::default());
         let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
         let (_out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
         let io = Duplex::new(inp_pipe_r, out_pipe_w);
@@ -1284,7 +3702,7 @@ mod tests {
                 .await
                 .expect("writing to input pipe");
             std::mem::drop(inp_pipe_w);
-            interact(io, IsAlreadyTls::No, (), cfg)
+            interact(io, client_addr(), IsAlreadyTls::No, (), cfg)
                 .await
                 .expect("calling interact");
         });
@@ -1329,9 +3747,7 @@ mod tests {
 
     #[test]
     fn interact_is_send() {
-        let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
-        });
-        assert_send(interact(MinBoundsIo, IsAlreadyTls::No, (), cfg));
+        let cfg = Arc::new(TestConfig::default());
+        assert_send(interact(MinBoundsIo, None, IsAlreadyTls::No, (), cfg));
     }
 }
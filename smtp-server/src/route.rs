@@ -0,0 +1,145 @@
+//! Checking that the domain of a recipient can receive mail at all, so that
+//! mails that could never be delivered are refused upfront instead of being
+//! bounced later on.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use smtp_message::{Email, Hostname};
+
+use crate::spf::{SpfDnsError, SpfResolver};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MailRoute {
+    /// The domain has an MX record, or an address record to fall back to
+    Exists,
+    /// The domain has neither MX nor address records
+    Missing,
+    /// The domain explicitly does not accept mail, as per RFC 7505
+    NullMx,
+    /// The DNS lookups failed, or the route was not checked
+    Unknown,
+}
+
+/// Returns the domain of `email`, if it has a domain and not an address
+pub fn domain(email: &Email) -> Option<&str> {
+    match email.hostname.as_ref()? {
+        Hostname::AsciiDomain { raw } => Some(raw),
+        Hostname::Utf8Domain { punycode, .. } => Some(punycode),
+        Hostname::Ipv4 { .. } | Hostname::Ipv6 { .. } => None,
+    }
+}
+
+/// Looks up the MX records of `domain`, falling back to its address records
+/// as per RFC 5321 section 5.1
+pub async fn mail_route<R: SpfResolver>(resolver: &R, domain: &str) -> MailRoute {
+    match resolver.mx(domain).await {
+        Ok(mxs) if mxs.len() == 1 && mxs[0].trim_end_matches('.').is_empty() => MailRoute::NullMx,
+        Ok(mxs) if !mxs.is_empty() => MailRoute::Exists,
+        Ok(_) | Err(SpfDnsError::NotFound) => match resolver.ips(domain).await {
+            Ok(ips) if !ips.is_empty() => MailRoute::Exists,
+            Ok(_) | Err(SpfDnsError::NotFound) => MailRoute::Missing,
+            Err(SpfDnsError::Transient) => MailRoute::Unknown,
+        },
+        Err(SpfDnsError::Transient) => MailRoute::Unknown,
+    }
+}
+
+/// Cache of the results of `mail_route`, to be shared by all the connections
+/// of a server. `MailRoute::Unknown` results are not cached.
+pub struct RouteCache {
+    ttl: Duration,
+    capacity: usize,
+    routes: Mutex<HashMap<String, (MailRoute, Instant)>>,
+}
+
+impl RouteCache {
+    /// Results are kept for `ttl`, and at most `capacity` of them are kept at
+    /// once, so that probing many domains cannot exhaust the memory
+    pub fn new(ttl: Duration, capacity: usize) -> RouteCache {
+        RouteCache {
+            ttl,
+            capacity,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn mail_route<R: SpfResolver>(&self, resolver: &R, domain: &str) -> MailRoute {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let now = Instant::now();
+        if let Some(&(route, expires)) = self.routes.lock().unwrap().get(&domain) {
+            if expires > now {
+                return route;
+            }
+        }
+        let route = mail_route(resolver, &domain).await;
+        if route != MailRoute::Unknown {
+            let mut routes = self.routes.lock().unwrap();
+            if routes.len() >= self.capacity {
+                routes.retain(|_, &mut (_, expires)| expires > now);
+            }
+            if routes.len() < self.capacity {
+                routes.insert(domain, (route, now + self.ttl));
+            }
+        }
+        route
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::spf::tests::{mock_resolver, MockResolver};
+
+    /// Counts the MX lookups, that are done once per `mail_route` call
+    struct CountingResolver(MockResolver, AtomicUsize);
+
+    #[async_trait]
+    impl SpfResolver for CountingResolver {
+        async fn txt(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+            self.0.txt(name).await
+        }
+
+        async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, SpfDnsError> {
+            self.0.ips(name).await
+        }
+
+        async fn mx(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.mx(name).await
+        }
+    }
+
+    #[test]
+    fn caches_mail_routes() {
+        let resolver = CountingResolver(mock_resolver(), AtomicUsize::new(0));
+        let cache = RouteCache::new(Duration::from_secs(3600), 2);
+        let tests: &[(&str, MailRoute, usize)] = &[
+            ("spf-fail.example.org", MailRoute::Exists, 1),
+            ("SPF-fail.example.org.", MailRoute::Exists, 1),
+            ("relay.example.org", MailRoute::Exists, 2),
+            ("null-mx.example.org", MailRoute::NullMx, 3),
+            // The cache is full, so this one was not kept
+            ("null-mx.example.org", MailRoute::NullMx, 4),
+            ("relay.example.org", MailRoute::Exists, 4),
+        ];
+        for &(domain, expected, lookups) in tests {
+            let res = smol::block_on(cache.mail_route(&resolver, domain));
+            assert_eq!(res, expected, "checking {}", domain);
+            let done = resolver.1.load(Ordering::SeqCst);
+            assert_eq!(done, lookups, "counting lookups for {}", domain);
+        }
+        let res = smol::block_on(mail_route(&resolver, "nowhere.example.org"));
+        assert_eq!(res, MailRoute::Missing);
+    }
+}
@@ -0,0 +1,387 @@
+//! SPF evaluation of inbound mail, as per RFC 7208.
+//!
+//! Macros and the `ptr` mechanism are not supported: records using macros are
+//! evaluated as a `permerror`, and `ptr` never matches.
+
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    AsyncResolver,
+};
+
+use smtp_message::Email;
+use smtp_server_types::SpfResult;
+
+/// Maximum number of DNS-querying terms, as per RFC 7208 section 4.6.4
+const MAX_DNS_LOOKUPS: usize = 10;
+
+pub enum SpfDnsError {
+    NotFound,
+    Transient,
+}
+
+/// The DNS queries SPF evaluation requires, implemented for
+/// `trust_dns_resolver::AsyncResolver`
+#[async_trait]
+pub trait SpfResolver: Sync {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, SpfDnsError>;
+    async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, SpfDnsError>;
+    async fn mx(&self, name: &str) -> Result<Vec<String>, SpfDnsError>;
+}
+
+fn dns_error(e: ResolveError) -> SpfDnsError {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => SpfDnsError::NotFound,
+        _ => SpfDnsError::Transient,
+    }
+}
+
+#[async_trait]
+impl<C, P> SpfResolver for AsyncResolver<C, P>
+where
+    C: trust_dns_resolver::proto::DnsHandle<Error = ResolveError>,
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+{
+    async fn txt(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+        let lookup = self.txt_lookup(name).await.map_err(dns_error)?;
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|d| String::from_utf8_lossy(d))
+                    .collect::<String>()
+            })
+            .collect())
+    }
+
+    async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, SpfDnsError> {
+        let lookup = self.lookup_ip(name).await.map_err(dns_error)?;
+        Ok(lookup.iter().collect())
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+        let lookup = self.mx_lookup(name).await.map_err(dns_error)?;
+        Ok(lookup.iter().map(|mx| mx.exchange().to_string()).collect())
+    }
+}
+
+/// Returns the domain whose SPF policy applies to a mail from `from`, if any
+pub fn sender_domain(from: Option<&Email>) -> Option<&str> {
+    crate::route::domain(from?)
+}
+
+/// Builds the `Received-SPF` header line, including its final CRLF, that can
+/// be prepended to the mail to record the result of the check
+pub fn received_spf_header(
+    result: SpfResult,
+    receiver: &str,
+    ip: IpAddr,
+    from: Option<&Email>,
+) -> String {
+    let from = from.map(|f| f.to_string()).unwrap_or_default();
+    format!(
+        "Received-SPF: {} receiver={}; client-ip={}; envelope-from=\"{}\";\r\n",
+        result, receiver, ip, from
+    )
+}
+
+/// Evaluates the SPF policy of `domain` for a client connecting from `ip`
+pub async fn check_host<R: SpfResolver>(resolver: &R, ip: IpAddr, domain: &str) -> SpfResult {
+    let mut lookups = 0;
+    check_host_rec(resolver, ip, domain.to_owned(), &mut lookups).await
+}
+
+fn check_host_rec<'a, R: SpfResolver>(
+    resolver: &'a R,
+    ip: IpAddr,
+    domain: String,
+    lookups: &'a mut usize,
+) -> BoxFuture<'a, SpfResult> {
+    async move {
+        let records = match resolver.txt(&domain).await {
+            Ok(r) => r,
+            Err(SpfDnsError::NotFound) => return SpfResult::None,
+            Err(SpfDnsError::Transient) => return SpfResult::TempError,
+        };
+        let mut records = records.into_iter().filter(|r| {
+            let r = r.to_ascii_lowercase();
+            r == "v=spf1" || r.starts_with("v=spf1 ")
+        });
+        let record = match (records.next(), records.next()) {
+            (None, _) => return SpfResult::None,
+            (Some(r), None) => r,
+            (Some(_), Some(_)) => return SpfResult::PermError,
+        };
+
+        let mut redirect = None;
+        for term in record.split_ascii_whitespace().skip(1) {
+            if term.contains('%') {
+                // Macros are not supported
+                return SpfResult::PermError;
+            }
+
+            // Modifiers
+            if let Some((name, value)) = term.split_once('=') {
+                if name.eq_ignore_ascii_case("redirect") {
+                    redirect = Some(value.to_owned());
+                }
+                continue;
+            }
+
+            // Mechanisms
+            let (result, mechanism) = match term.as_bytes()[0] {
+                b'+' => (SpfResult::Pass, &term[1..]),
+                b'-' => (SpfResult::Fail, &term[1..]),
+                b'~' => (SpfResult::SoftFail, &term[1..]),
+                b'?' => (SpfResult::Neutral, &term[1..]),
+                _ => (SpfResult::Pass, term),
+            };
+            let (name, arg) = match mechanism.find([':', '/']) {
+                Some(i) => (&mechanism[..i], &mechanism[i..]),
+                None => (mechanism, ""),
+            };
+            let matches = match &*name.to_ascii_lowercase() {
+                "all" => true,
+                "ip4" | "ip6" => match parse_network(arg.trim_start_matches(':')) {
+                    Some((net, prefix)) => ip_in_network(ip, net, prefix),
+                    None => return SpfResult::PermError,
+                },
+                "a" | "mx" | "include" | "exists" | "ptr" => {
+                    *lookups += 1;
+                    if *lookups > MAX_DNS_LOOKUPS {
+                        return SpfResult::PermError;
+                    }
+                    let (target, prefix) = match parse_domain_spec(arg, &domain) {
+                        Some(t) => t,
+                        None => return SpfResult::PermError,
+                    };
+                    let r = match &*name.to_ascii_lowercase() {
+                        "a" => match_a(resolver, ip, &target, prefix).await,
+                        "mx" => match_mx(resolver, ip, &target, prefix).await,
+                        "exists" => match resolver.ips(&target).await {
+                            Ok(ips) => Ok(!ips.is_empty()),
+                            Err(SpfDnsError::NotFound) => Ok(false),
+                            Err(SpfDnsError::Transient) => Err(SpfResult::TempError),
+                        },
+                        "include" => {
+                            match check_host_rec(resolver, ip, target, &mut *lookups).await {
+                                SpfResult::Pass => Ok(true),
+                                SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => {
+                                    Ok(false)
+                                }
+                                SpfResult::TempError => Err(SpfResult::TempError),
+                                SpfResult::None | SpfResult::PermError => Err(SpfResult::PermError),
+                            }
+                        }
+                        // ptr is not supported, and should not be used anyway
+                        _ => Ok(false),
+                    };
+                    match r {
+                        Ok(m) => m,
+                        Err(res) => return res,
+                    }
+                }
+                _ => return SpfResult::PermError,
+            };
+            if matches {
+                return result;
+            }
+        }
+
+        match redirect {
+            None => SpfResult::Neutral,
+            Some(target) => {
+                *lookups += 1;
+                if *lookups > MAX_DNS_LOOKUPS {
+                    return SpfResult::PermError;
+                }
+                match check_host_rec(resolver, ip, target, lookups).await {
+                    SpfResult::None => SpfResult::PermError,
+                    r => r,
+                }
+            }
+        }
+    }
+    .boxed()
+}
+
+/// Parses the `[:domain][/prefix]` argument of a mechanism
+fn parse_domain_spec(arg: &str, current: &str) -> Option<(String, Option<u8>)> {
+    let (domain, prefix) = match arg.find('/') {
+        Some(i) => (
+            &arg[..i],
+            Some(arg[i + 1..].split('/').next()?.parse().ok()?),
+        ),
+        None => (arg, None),
+    };
+    let domain = match domain.strip_prefix(':') {
+        Some("") => return None,
+        Some(d) => d,
+        None if domain.is_empty() => current,
+        None => return None,
+    };
+    Some((domain.to_owned(), prefix))
+}
+
+/// Parses the `network[/prefix]` argument of an `ip4` or `ip6` mechanism
+fn parse_network(arg: &str) -> Option<(IpAddr, Option<u8>)> {
+    match arg.split_once('/') {
+        Some((net, prefix)) => Some((net.parse().ok()?, Some(prefix.parse().ok()?))),
+        None => Some((arg.parse().ok()?, None)),
+    }
+}
+
+fn ip_in_network(ip: IpAddr, net: IpAddr, prefix: Option<u8>) -> bool {
+    fn masked(bits: u128, len: u32, prefix: u8) -> Option<u128> {
+        let prefix = u32::from(prefix);
+        if prefix > len {
+            None
+        } else if prefix == 0 {
+            Some(0)
+        } else {
+            Some(bits >> (len - prefix))
+        }
+    }
+    let (ip, net, len) = match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => (u32::from(ip).into(), u32::from(net).into(), 32),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(net), 128),
+        _ => return false,
+    };
+    let prefix = prefix.unwrap_or(len as u8);
+    matches!(
+        (masked(ip, len, prefix), masked(net, len, prefix)),
+        (Some(a), Some(b)) if a == b
+    )
+}
+
+async fn match_a<R: SpfResolver>(
+    resolver: &R,
+    ip: IpAddr,
+    target: &str,
+    prefix: Option<u8>,
+) -> Result<bool, SpfResult> {
+    match resolver.ips(target).await {
+        Ok(ips) => Ok(ips.into_iter().any(|net| ip_in_network(ip, net, prefix))),
+        Err(SpfDnsError::NotFound) => Ok(false),
+        Err(SpfDnsError::Transient) => Err(SpfResult::TempError),
+    }
+}
+
+async fn match_mx<R: SpfResolver>(
+    resolver: &R,
+    ip: IpAddr,
+    target: &str,
+    prefix: Option<u8>,
+) -> Result<bool, SpfResult> {
+    let mxes = match resolver.mx(target).await {
+        Ok(mxes) => mxes,
+        Err(SpfDnsError::NotFound) => return Ok(false),
+        Err(SpfDnsError::Transient) => return Err(SpfResult::TempError),
+    };
+    // RFC 7208 section 4.6.4 limits the number of MX names to look up
+    if mxes.len() > MAX_DNS_LOOKUPS {
+        return Err(SpfResult::PermError);
+    }
+    for mx in mxes {
+        if match_a(resolver, ip, &mx, prefix).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Resolver answering from fixed zone data, names being given without
+    /// their trailing dot
+    #[derive(Default)]
+    pub(crate) struct MockResolver {
+        pub txt: HashMap<&'static str, Vec<&'static str>>,
+        pub ips: HashMap<&'static str, Vec<IpAddr>>,
+        pub mx: HashMap<&'static str, Vec<&'static str>>,
+    }
+
+    #[async_trait]
+    impl SpfResolver for MockResolver {
+        async fn txt(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+            match self.txt.get(name.trim_end_matches('.')) {
+                Some(r) => Ok(r.iter().map(|r| r.to_string()).collect()),
+                None => Err(SpfDnsError::NotFound),
+            }
+        }
+
+        async fn ips(&self, name: &str) -> Result<Vec<IpAddr>, SpfDnsError> {
+            self.ips
+                .get(name.trim_end_matches('.'))
+                .cloned()
+                .ok_or(SpfDnsError::NotFound)
+        }
+
+        async fn mx(&self, name: &str) -> Result<Vec<String>, SpfDnsError> {
+            match self.mx.get(name.trim_end_matches('.')) {
+                Some(r) => Ok(r.iter().map(|r| r.to_string()).collect()),
+                None => Err(SpfDnsError::NotFound),
+            }
+        }
+    }
+
+    pub(crate) fn mock_resolver() -> MockResolver {
+        let mut r = MockResolver::default();
+        r.txt.insert(
+            "spf-pass.example.org",
+            vec!["some unrelated record", "v=spf1 ip4:192.0.2.0/24 -all"],
+        );
+        r.txt.insert(
+            "spf-fail.example.org",
+            vec!["v=spf1 mx a:relay.example.org -all"],
+        );
+        r.txt.insert(
+            "spf-include.example.org",
+            vec!["v=spf1 include:spf-pass.example.org ~all"],
+        );
+        r.txt.insert(
+            "spf-redirect.example.org",
+            vec!["v=spf1 redirect=spf-fail.example.org"],
+        );
+        r.txt
+            .insert("spf-twice.example.org", vec!["v=spf1 +all", "v=spf1 -all"]);
+        r.mx.insert("spf-fail.example.org", vec!["mx.spf-fail.example.org."]);
+        r.mx.insert("null-mx.example.org", vec!["."]);
+        r.ips.insert(
+            "mx.spf-fail.example.org",
+            vec!["198.51.100.1".parse().unwrap()],
+        );
+        r.ips
+            .insert("relay.example.org", vec!["2001:db8::1".parse().unwrap()]);
+        r
+    }
+
+    #[test]
+    fn check_host_results() {
+        let resolver = mock_resolver();
+        let tests: &[(&str, &str, SpfResult)] = &[
+            ("192.0.2.1", "spf-pass.example.org", SpfResult::Pass),
+            ("192.0.3.1", "spf-pass.example.org", SpfResult::Fail),
+            ("198.51.100.1", "spf-fail.example.org", SpfResult::Pass),
+            ("2001:db8::1", "spf-fail.example.org", SpfResult::Pass),
+            ("192.0.2.1", "spf-fail.example.org", SpfResult::Fail),
+            ("192.0.2.1", "spf-include.example.org", SpfResult::Pass),
+            ("192.0.3.1", "spf-include.example.org", SpfResult::SoftFail),
+            ("192.0.2.1", "spf-redirect.example.org", SpfResult::Fail),
+            ("192.0.2.1", "spf-twice.example.org", SpfResult::PermError),
+            ("192.0.2.1", "no-spf.example.org", SpfResult::None),
+        ];
+        for &(ip, domain, expected) in tests {
+            let res = smol::block_on(check_host(&resolver, ip.parse().unwrap(), domain));
+            assert_eq!(res, expected, "checking {} for {}", ip, domain);
+        }
+    }
+}
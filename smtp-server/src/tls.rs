@@ -0,0 +1,162 @@
+//! Extraction of the identity of the certificate presented by a TLS client.
+//!
+//! Only the fields needed for authorization are parsed out of the DER-encoded
+//! X.509 certificate. This does not check the certificate in any way: it must
+//! have been verified during the TLS handshake.
+
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use smtp_server_types::TlsPeerIdentity;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+// GeneralName choices, as per RFC 5280 section 4.2.1.6
+const RFC822_NAME: u8 = 0x81;
+const DNS_NAME: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+/// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Splits the first DER element of `data` into its tag, its contents and the
+/// data following it
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&len, mut data) = data.split_first()?;
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        let n = usize::from(len & 0x7f);
+        if n == 0 || n > std::mem::size_of::<usize>() || data.len() < n {
+            return None;
+        }
+        let (len, rest) = data.split_at(n);
+        data = rest;
+        len.iter().fold(0, |acc, &b| (acc << 8) | usize::from(b))
+    };
+    if data.len() < len {
+        return None;
+    }
+    let (contents, rest) = data.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Iterates over the tag and contents of the DER elements of `data`, stopping
+/// at the first malformed one
+fn der_elements(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (tag, contents, rest) = der_element(data)?;
+        data = rest;
+        Some((tag, contents))
+    })
+}
+
+fn expect(tag: u8, (actual, contents): (u8, &[u8])) -> Option<&[u8]> {
+    if tag == actual {
+        Some(contents)
+    } else {
+        None
+    }
+}
+
+fn common_name(subject: &[u8]) -> Option<String> {
+    der_elements(subject)
+        .filter(|&(tag, _)| tag == SET)
+        .flat_map(|(_, rdn)| der_elements(rdn))
+        .find_map(|attr| {
+            let mut attr = der_elements(expect(SEQUENCE, attr)?);
+            match (attr.next()?, attr.next()?) {
+                ((OID, oid), (_, value)) if oid == OID_COMMON_NAME => {
+                    String::from_utf8(value.to_vec()).ok()
+                }
+                _ => None,
+            }
+        })
+}
+
+fn alt_names(extensions: &[u8]) -> Vec<String> {
+    let san = der_elements(extensions).find_map(|ext| {
+        let mut ext = der_elements(expect(SEQUENCE, ext)?);
+        match ext.next()? {
+            (OID, oid) if oid == OID_SUBJECT_ALT_NAME => {
+                // Skip the optional criticality
+                ext.find(|&(tag, _)| tag == OCTET_STRING)
+            }
+            _ => None,
+        }
+    });
+    let names = match san.and_then(|(_, san)| der_element(san)) {
+        Some((SEQUENCE, names, _)) => names,
+        _ => return Vec::new(),
+    };
+    der_elements(names)
+        .filter_map(|name| match name {
+            (RFC822_NAME, n) | (DNS_NAME, n) | (URI, n) => String::from_utf8(n.to_vec()).ok(),
+            (IP_ADDRESS, ip) => match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?)).to_string()),
+                16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)).to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Extracts the subject common name and alternative names of `cert`, a
+/// DER-encoded X.509 certificate. Returns `None` if `cert` cannot be parsed.
+pub fn peer_identity(cert: &[u8]) -> Option<TlsPeerIdentity> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let mut fields = der_elements(tbs).peekable();
+    fields.next_if(|&(tag, _)| tag == VERSION);
+    // Skip the serial number, signature algorithm, issuer and validity
+    let subject = expect(SEQUENCE, fields.nth(4)?)?;
+    let extensions = fields
+        .find(|&(tag, _)| tag == EXTENSIONS)
+        .and_then(|(_, e)| der_element(e))
+        .map(|(_, e, _)| e)
+        .unwrap_or(&[]);
+    Some(TlsPeerIdentity {
+        subject_cn: common_name(subject),
+        sans: alt_names(extensions),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_client_certificate() {
+        // Generated with `openssl req -x509 -newkey rsa:2048 -nodes
+        // -subj "/O=Example/CN=client.example.org" -addext "subjectAltName=
+        // DNS:client.example.org,DNS:mail.example.org,email:user@example.org,
+        // IP:192.0.2.1" -outform DER`
+        let cert = include_bytes!("../res/tls-client.der");
+        assert_eq!(
+            peer_identity(cert),
+            Some(TlsPeerIdentity {
+                subject_cn: Some("client.example.org".into()),
+                sans: vec![
+                    "client.example.org".into(),
+                    "mail.example.org".into(),
+                    "user@example.org".into(),
+                    "192.0.2.1".into(),
+                ],
+            })
+        );
+        assert_eq!(peer_identity(&cert[..100]), None);
+        assert_eq!(peer_identity(b""), None);
+    }
+}
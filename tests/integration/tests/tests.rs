@@ -270,9 +270,16 @@ key_path = "{0}/key.pem"
             // We know only one message is incoming
             if let Some(stream) = incoming.next().await {
                 let stream = stream.expect("receiving new incoming stream");
-                smtp_server::interact(stream, smtp_server::IsAlreadyTls::No, (), recv_cfg2)
-                    .await
-                    .expect("Failed to receive mail");
+                let peer_addr = stream.peer_addr().ok().map(|a| a.ip());
+                smtp_server::interact(
+                    stream,
+                    peer_addr,
+                    smtp_server::IsAlreadyTls::No,
+                    (),
+                    recv_cfg2,
+                )
+                .await
+                .expect("Failed to receive mail");
             }
             evt.send(()).await.unwrap();
         });